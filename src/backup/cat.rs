@@ -0,0 +1,146 @@
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Result};
+use clap::Args;
+
+use super::{
+    chunker::Blocks,
+    manifest::{self, BackupKind, Manifest, BLOCK_SIZE},
+};
+use crate::context::Context;
+
+#[derive(Debug, Args)]
+pub struct Options {
+    /// Label of the backup, or "latest" for the newest one
+    #[arg(long)]
+    pub label: String,
+
+    /// Path of the file relative to the data directory
+    #[arg(long)]
+    pub path: PathBuf,
+
+    /// Write the file here instead of to stdout
+    #[arg(long)]
+    pub out: Option<PathBuf>,
+}
+
+// The file is put together the same way a restore would, from its chunks in
+// the full backup with the blocks changed by each later incremental on top.
+pub fn run(ctx: &Context, opts: &Options) -> Result<()> {
+    let manifests = manifest::load_all(ctx)?;
+    let manifest = manifest::find(&manifests, &opts.label)?;
+    let chain = manifest.chain(&manifests)?;
+
+    let mut base = None;
+    let mut changed = BTreeMap::new();
+    for backup in &chain {
+        match &backup.data {
+            BackupKind::Full { files } =>
+                base = files
+                    .get(&opts.path)
+                    .map(|info| (info, backup.chunk_compression)),
+            BackupKind::Incremental { changed_blocks, .. } =>
+                if changed_blocks.contains_key(&opts.path) {
+                    read_changed_blocks(ctx, backup, &opts.path, &mut changed)?;
+                },
+        }
+    }
+
+    if base.is_none() && changed.is_empty() {
+        bail!("{:?} is not in backup {}", opts.path, manifest.label);
+    }
+
+    let mut out: Box<dyn Write> = match &opts.out {
+        Some(path) => Box::new(File::create(path)?),
+        None => Box::new(io::stdout().lock()),
+    };
+
+    let mut index = 0;
+    let mut write_block = |block: &[u8]| -> Result<()> {
+        match changed.remove(&index) {
+            Some(changed) => out.write_all(&changed)?,
+            None => out.write_all(block)?,
+        }
+
+        index += 1;
+        Ok(())
+    };
+
+    if let Some((info, codec)) = base {
+        let mut blocks = Blocks::new();
+        let mut data = Vec::new();
+        for chunk in &info.chunks {
+            chunk.read(ctx, codec, &mut data)?;
+            blocks.push(&data, &mut write_block)?;
+        }
+
+        blocks.finish(&mut write_block)?;
+    }
+
+    // Blocks past the end of the base file, with a restore leaving any gap
+    // between them as a hole.
+    for (changed_index, data) in changed {
+        while index < changed_index {
+            out.write_all(&[0; BLOCK_SIZE])?;
+            index += 1;
+        }
+
+        out.write_all(&data)?;
+        index += 1;
+    }
+
+    out.flush()?;
+    Ok(())
+}
+
+// Bundles group the entries of each file together, so reading stops at the
+// first entry past the file's.
+fn read_changed_blocks(
+    ctx: &Context,
+    backup: &Manifest,
+    path: &Path,
+    changed: &mut BTreeMap<usize, Vec<u8>>,
+) -> Result<()> {
+    let bundle_key = backup.bundle_key();
+    let decoder = backup
+        .compression
+        .decoder(ctx.storage.open_reader(&bundle_key)?)?;
+    let mut bundle = tar::Archive::new(decoder);
+    let mut found = false;
+
+    for entry in bundle.entries()? {
+        let mut entry = entry?;
+        let block_path = entry.path()?.into_owned();
+        if block_path.parent() != Some(path) {
+            if found {
+                break;
+            }
+
+            continue;
+        }
+
+        let Some(index) = block_path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.parse::<usize>().ok())
+        else {
+            bail!(
+                "invalid block entry {:?} in bundle {}",
+                block_path,
+                bundle_key
+            );
+        };
+
+        let mut data = Vec::with_capacity(BLOCK_SIZE);
+        entry.read_to_end(&mut data)?;
+        changed.insert(index, data);
+        found = true;
+    }
+
+    Ok(())
+}
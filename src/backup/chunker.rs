@@ -0,0 +1,416 @@
+use std::io::{self, Read};
+
+use anyhow::Result;
+
+use super::manifest::BLOCK_SIZE;
+
+pub const MIN_CHUNK_SIZE: usize = 64 * 1024;
+pub const MAX_CHUNK_SIZE: usize = 64 * 1024 * 1024;
+
+// How much the chunker's buffer starts out with.
+const READ_SIZE: usize = 8 * 1024;
+
+// Random values for the gear hash, generated with splitmix64 so the table
+// doesn't have to be spelled out. Changing them changes every chunk boundary.
+const GEAR: [u64; 256] = {
+    let mut table = [0; 256];
+    let mut state = 0x9e37_79b9_7f4a_7c15_u64;
+    let mut i = 0;
+    while i < 256 {
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+
+    table
+};
+
+/// Splits streams into content-defined chunks using FastCDC, so that data
+/// shifting within a file only changes the chunks around the edit.
+///
+/// Chunks are between a quarter of and four times `avg_size` long, except for
+/// the last one which may be shorter. The buffer is kept from one stream to
+/// the next and grows only as far as the data read, up to two chunks of the
+/// maximum size.
+pub struct Chunker {
+    buffer: Vec<u8>,
+    start: usize,
+    end: usize,
+    eof: bool,
+    min_size: usize,
+    avg_size: usize,
+    max_size: usize,
+    // Normalized chunking: cut points are harder to hit before the average
+    // size and easier after it, which narrows the spread of chunk sizes.
+    mask_small: u64,
+    mask_large: u64,
+}
+
+impl Chunker {
+    pub fn new(avg_size: usize) -> Self {
+        assert!(avg_size.is_power_of_two() && avg_size >= 256);
+
+        // The gear hash shifts left, so only its high bits depend on the whole
+        // window and those are the ones tested.
+        let bits = avg_size.trailing_zeros();
+        Self {
+            buffer: Vec::new(),
+            start: 0,
+            end: 0,
+            eof: false,
+            min_size: avg_size / 4,
+            avg_size,
+            max_size: avg_size * 4,
+            mask_small: !0 << (64 - (bits + 1)),
+            mask_large: !0 << (64 - (bits - 1)),
+        }
+    }
+
+    pub fn min_size(&self) -> usize {
+        self.min_size
+    }
+
+    pub fn split<R: Read>(&mut self, reader: R) -> Chunks<'_, R> {
+        self.start = 0;
+        self.end = 0;
+        self.eof = false;
+        Chunks {
+            chunker: self,
+            reader,
+        }
+    }
+
+    fn fill(&mut self, reader: &mut impl Read) -> io::Result<()> {
+        self.buffer.copy_within(self.start..self.end, 0);
+        self.end -= self.start;
+        self.start = 0;
+
+        // Room for two chunks of the maximum size, so the buffer only has to
+        // be compacted every few chunks. It doubles on the way there, so short
+        // streams don't need all of it.
+        let capacity = self.max_size * 2;
+        while self.end < capacity {
+            if self.end == self.buffer.len() {
+                let len = (self.end * 2).clamp(READ_SIZE.min(capacity), capacity);
+                self.buffer.reserve_exact(len - self.buffer.len());
+                self.buffer.resize(len, 0);
+            }
+
+            match reader.read(&mut self.buffer[self.end..]) {
+                Ok(0) => {
+                    self.eof = true;
+                    break;
+                },
+                Ok(read) => self.end += read,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => (),
+                Err(err) => return Err(err),
+            }
+        }
+
+        Ok(())
+    }
+
+    fn cut_point(&self, data: &[u8]) -> usize {
+        if data.len() <= self.min_size {
+            return data.len();
+        }
+
+        let normal_size = self.avg_size.min(data.len());
+        let mut hash = 0u64;
+
+        for (i, &byte) in data.iter().enumerate().skip(self.min_size) {
+            hash = (hash << 1).wrapping_add(GEAR[byte as usize]);
+            let mask = if i < normal_size {
+                self.mask_small
+            } else {
+                self.mask_large
+            };
+
+            if hash & mask == 0 {
+                return i + 1;
+            }
+        }
+
+        data.len()
+    }
+}
+
+/// The chunks of one stream, see [`Chunker::split`].
+pub struct Chunks<'a, R> {
+    chunker: &'a mut Chunker,
+    reader: R,
+}
+
+impl<R> Chunks<'_, R>
+where
+    R: Read,
+{
+    pub fn next_chunk(&mut self) -> io::Result<Option<&[u8]>> {
+        let chunker = &mut *self.chunker;
+        if !chunker.eof && chunker.end - chunker.start < chunker.max_size {
+            chunker.fill(&mut self.reader)?;
+        }
+
+        if chunker.start == chunker.end {
+            return Ok(None);
+        }
+
+        let end = chunker.end.min(chunker.start + chunker.max_size);
+        let len = chunker.cut_point(&chunker.buffer[chunker.start..end]);
+        let chunk = &chunker.buffer[chunker.start..chunker.start + len];
+        chunker.start += len;
+        Ok(Some(chunk))
+    }
+}
+
+/// Regroups chunks into the fixed size blocks incremental backups track,
+/// which are aligned to offsets in the file rather than to chunk boundaries.
+pub struct Blocks {
+    partial: Vec<u8>,
+}
+
+impl Blocks {
+    pub fn new() -> Self {
+        Self {
+            partial: Vec::with_capacity(BLOCK_SIZE),
+        }
+    }
+
+    /// Calls `f` with every block completed by `data`.
+    pub fn push(&mut self, mut data: &[u8], mut f: impl FnMut(&[u8]) -> Result<()>) -> Result<()> {
+        if !self.partial.is_empty() {
+            let len = data.len().min(BLOCK_SIZE - self.partial.len());
+            self.partial.extend_from_slice(&data[..len]);
+            data = &data[len..];
+
+            if self.partial.len() < BLOCK_SIZE {
+                return Ok(());
+            }
+
+            f(&self.partial)?;
+            self.partial.clear();
+        }
+
+        let mut blocks = data.chunks_exact(BLOCK_SIZE);
+        for block in &mut blocks {
+            f(block)?;
+        }
+
+        self.partial.extend_from_slice(blocks.remainder());
+        Ok(())
+    }
+
+    /// Calls `f` with the trailing partial block, if any.
+    pub fn finish(self, mut f: impl FnMut(&[u8]) -> Result<()>) -> Result<()> {
+        if !self.partial.is_empty() {
+            f(&self.partial)?;
+        }
+
+        Ok(())
+    }
+}
+
+pub fn parse_chunk_size(s: &str) -> Result<usize, String> {
+    let (digits, multiplier) = match s.strip_suffix(['K', 'k']) {
+        Some(digits) => (digits, 1024),
+        None => match s.strip_suffix(['M', 'm']) {
+            Some(digits) => (digits, 1024 * 1024),
+            None => (s, 1),
+        },
+    };
+
+    let size = digits
+        .parse::<usize>()
+        .ok()
+        .and_then(|size| size.checked_mul(multiplier))
+        .ok_or_else(|| format!("invalid chunk size {:?}", s))?;
+
+    if !size.is_power_of_two() || !(MIN_CHUNK_SIZE..=MAX_CHUNK_SIZE).contains(&size) {
+        return Err(format!(
+            "chunk size must be a power of two between {}K and {}M",
+            MIN_CHUNK_SIZE / 1024,
+            MAX_CHUNK_SIZE / 1024 / 1024
+        ));
+    }
+
+    Ok(size)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashSet,
+        io::{self, Read},
+    };
+
+    use super::{parse_chunk_size, Blocks, Chunker, MAX_CHUNK_SIZE, MIN_CHUNK_SIZE, READ_SIZE};
+    use crate::backup::manifest::BLOCK_SIZE;
+
+    const AVG_SIZE: usize = 4096;
+
+    fn random_data(len: usize) -> Vec<u8> {
+        let mut data = vec![0; len];
+        blake3::Hasher::new()
+            .update(b"chunker")
+            .finalize_xof()
+            .fill(&mut data);
+        data
+    }
+
+    fn split(reader: impl Read) -> Vec<Vec<u8>> {
+        split_with(&mut Chunker::new(AVG_SIZE), reader)
+    }
+
+    fn split_with(chunker: &mut Chunker, reader: impl Read) -> Vec<Vec<u8>> {
+        let mut chunks = chunker.split(reader);
+        let mut result = Vec::new();
+        while let Some(chunk) = chunks.next_chunk().unwrap() {
+            result.push(chunk.to_vec());
+        }
+
+        result
+    }
+
+    // Hands out a few bytes at a time, like a pipe would.
+    struct ShortReads<'a>(&'a [u8]);
+
+    impl Read for ShortReads<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let len = buf.len().min(self.0.len()).min(1000);
+            buf[..len].copy_from_slice(&self.0[..len]);
+            self.0 = &self.0[len..];
+            Ok(len)
+        }
+    }
+
+    #[test]
+    fn chunk_sizes_stay_within_bounds() {
+        let data = random_data(1 << 20);
+        let chunks = split(&data[..]);
+        assert!(chunks.len() > 64, "{} chunks", chunks.len());
+
+        let (last, rest) = chunks.split_last().unwrap();
+        for chunk in rest {
+            assert!(
+                (AVG_SIZE / 4..=AVG_SIZE * 4).contains(&chunk.len()),
+                "chunk of {} bytes",
+                chunk.len()
+            );
+        }
+        assert!(!last.is_empty() && last.len() <= AVG_SIZE * 4);
+        assert_eq!(chunks.concat(), data);
+
+        // Long runs of the same byte never hit a cut point.
+        let zeros = split(&[0; AVG_SIZE * 10][..]);
+        assert_eq!(
+            zeros.iter().map(Vec::len).collect::<Vec<_>>(),
+            [AVG_SIZE * 4, AVG_SIZE * 4, AVG_SIZE * 2]
+        );
+        assert!(split(io::empty()).is_empty());
+    }
+
+    #[test]
+    fn cut_points_are_deterministic() {
+        let data = random_data(1 << 20);
+        let chunks_once = split(&data[..]);
+        assert_eq!(split(&data[..]), chunks_once);
+        assert_eq!(split(ShortReads(&data)), chunks_once);
+    }
+
+    #[test]
+    fn buffer_is_reused_and_bounded() {
+        let data = random_data(1 << 20);
+        let mut chunker = Chunker::new(AVG_SIZE);
+
+        assert_eq!(split_with(&mut chunker, &data[..1000]), [&data[..1000]]);
+        assert_eq!(chunker.buffer.capacity(), READ_SIZE);
+
+        assert_eq!(split_with(&mut chunker, &data[..]), split(&data[..]));
+        assert_eq!(chunker.buffer.capacity(), AVG_SIZE * 8);
+
+        // Nothing of the previous stream is left over.
+        assert_eq!(split_with(&mut chunker, &data[..1000]), [&data[..1000]]);
+        assert!(split_with(&mut chunker, io::empty()).is_empty());
+    }
+
+    #[test]
+    fn boundaries_resync_after_insertion() {
+        let data = random_data(1 << 20);
+        let mut edited = data.clone();
+        edited.insert(data.len() / 2, 0xff);
+
+        let original = split(&data[..]);
+        let known = original.iter().collect::<HashSet<_>>();
+        let changed = split(&edited[..])
+            .into_iter()
+            .filter(|chunk| !known.contains(chunk))
+            .count();
+        assert!(
+            (1..=2).contains(&changed),
+            "{} of {} chunks changed",
+            changed,
+            original.len()
+        );
+    }
+
+    #[test]
+    fn blocks_regroup_across_chunk_edges() {
+        let data = random_data(3 * BLOCK_SIZE + 100);
+        let mut blocks = Blocks::new();
+        let mut regrouped = Vec::new();
+        let mut add_block = |block: &[u8]| {
+            regrouped.push(block.to_vec());
+            Ok(())
+        };
+        for piece in data.chunks(BLOCK_SIZE / 3 + 7) {
+            blocks.push(piece, &mut add_block).unwrap();
+        }
+        blocks.push(&[], &mut add_block).unwrap();
+        blocks.finish(&mut add_block).unwrap();
+
+        assert_eq!(
+            regrouped.iter().map(Vec::len).collect::<Vec<_>>(),
+            [BLOCK_SIZE, BLOCK_SIZE, BLOCK_SIZE, 100]
+        );
+        assert_eq!(regrouped.concat(), data);
+
+        let mut count = 0;
+        let mut blocks = Blocks::new();
+        blocks
+            .push(&data[..2 * BLOCK_SIZE], |_| {
+                count += 1;
+                Ok(())
+            })
+            .unwrap();
+        blocks
+            .finish(|_| panic!("no partial block is left"))
+            .unwrap();
+        assert_eq!(count, 2);
+    }
+
+    #[test]
+    fn chunk_sizes() {
+        assert_eq!(parse_chunk_size("1M"), Ok(1 << 20));
+        assert_eq!(parse_chunk_size("64k"), Ok(MIN_CHUNK_SIZE));
+        assert_eq!(parse_chunk_size("65536"), Ok(MIN_CHUNK_SIZE));
+        assert_eq!(parse_chunk_size("64M"), Ok(MAX_CHUNK_SIZE));
+
+        for size in ["3M", "96K", "32K", "128M", "1"] {
+            assert_eq!(
+                parse_chunk_size(size),
+                Err("chunk size must be a power of two between 64K and 64M".to_owned()),
+                "{}",
+                size
+            );
+        }
+        for size in ["", "M", "1G", "-1M", "99999999999999999999K"] {
+            assert_eq!(
+                parse_chunk_size(size),
+                Err(format!("invalid chunk size {:?}", size)),
+            );
+        }
+    }
+}
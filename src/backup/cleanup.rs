@@ -0,0 +1,92 @@
+use std::time::{Duration, SystemTime};
+
+use anyhow::Result;
+use clap::Args;
+use log::{info, warn};
+
+use super::manifest::{self, BackupKind};
+use crate::context::Context;
+
+// wal-push doesn't take the repository lock, so a partial WAL object may
+// belong to a push still in progress.
+const WAL_PARTIAL_MAX_AGE: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Debug, Args)]
+pub struct Options {
+    /// Show what would be removed without removing anything
+    #[arg(long)]
+    pub dry_run: bool,
+
+    /// Wait for other operations on the repository to finish instead of
+    /// failing
+    #[arg(long)]
+    pub wait: bool,
+}
+
+pub fn run(ctx: &Context, opts: &Options) -> Result<()> {
+    let _lock = ctx.storage.lock(opts.wait)?;
+    let removed = cleanup(ctx, opts.dry_run)?;
+    info!(
+        "{} {} leftover objects",
+        if opts.dry_run {
+            "would remove"
+        } else {
+            "removed"
+        },
+        removed
+    );
+    Ok(())
+}
+
+/// Removes what interrupted operations left behind: objects whose writers
+/// never finished, and manifests of incremental backups whose bundle is
+/// missing. Needs the repository lock to be held. Chunks no manifest refers to
+/// are left for gc, which has to read every manifest to find them.
+pub(super) fn cleanup(ctx: &Context, dry_run: bool) -> Result<usize> {
+    let verb = if dry_run { "would remove" } else { "removing" };
+    let mut removed = 0;
+    let now = SystemTime::now();
+    for (key, modified) in ctx.storage.list_partial("")? {
+        let age = now.duration_since(modified).unwrap_or_default();
+        if key.starts_with("wal/") && age < WAL_PARTIAL_MAX_AGE {
+            continue;
+        }
+
+        info!("{} incomplete object {}", verb, key);
+        if !dry_run {
+            ctx.storage.delete(&key)?;
+        }
+        removed += 1;
+    }
+
+    for (key, manifest) in manifest::scan(ctx)? {
+        let Ok(manifest) = manifest else {
+            continue;
+        };
+
+        if !matches!(manifest.data, BackupKind::Incremental { .. })
+            || ctx.storage.exists(&manifest.bundle_key())?
+        {
+            continue;
+        }
+
+        if manifest.pinned {
+            warn!(
+                "bundle of pinned backup {} is missing, leaving it in place",
+                manifest.label
+            );
+            continue;
+        }
+
+        info!(
+            "{} backup {} ({}), its bundle is missing",
+            verb, manifest.label, manifest.id
+        );
+        if !dry_run {
+            ctx.storage.delete(&key)?;
+        }
+        removed += 1;
+    }
+
+    Ok(removed)
+}
@@ -0,0 +1,121 @@
+use std::{
+    fs::{self, File},
+    io::{self, Write},
+    path::PathBuf,
+};
+
+use anyhow::Result;
+use clap::Args;
+use log::info;
+
+use super::restore;
+use crate::context::Context;
+
+#[derive(Debug, Args)]
+pub struct Options {
+    /// Label of the backup, or "latest" for the newest one
+    #[arg(long)]
+    pub label: String,
+
+    #[arg(long)]
+    pub target_dir: PathBuf,
+
+    /// Port the clone listens on
+    #[arg(long)]
+    pub port: u16,
+
+    #[arg(long)]
+    pub force: bool,
+
+    /// Restore the tablespace located at OLD into NEW instead, can be given
+    /// multiple times
+    #[arg(long = "tablespace-mapping", value_name = "OLD=NEW", value_parser = restore::parse_tablespace_mapping)]
+    pub tablespace_mappings: Vec<(PathBuf, PathBuf)>,
+}
+
+// A clone is a restore that is set up to run alongside the cluster it was
+// taken from. It still fetches WAL from the repository while recovering, but
+// has archiving turned off so nothing it generates ends up there.
+pub fn run(ctx: &Context, opts: &Options) -> Result<()> {
+    prepare(ctx, opts, &[])?;
+
+    let target_dir = opts.target_dir.canonicalize()?;
+    println!(
+        "pg_ctl start -D {} -l {}",
+        restore::shell_quote(&target_dir.to_string_lossy()),
+        restore::shell_quote(&target_dir.join("clone.log").to_string_lossy())
+    );
+    Ok(())
+}
+
+// Restores the backup and sets it up as a clone, with `extra_settings` added
+// to the ones every clone gets.
+pub(super) fn prepare(
+    ctx: &Context,
+    opts: &Options,
+    extra_settings: &[(&str, &str)],
+) -> Result<()> {
+    restore::run(
+        ctx,
+        &restore::Options {
+            label: Some(opts.label.clone()),
+            input: None,
+            target_dir: opts.target_dir.clone(),
+            force: opts.force,
+            target_time: None,
+            target_lsn: None,
+            target_xid: None,
+            no_recovery_conf: false,
+            delta: false,
+            tablespace_mappings: opts.tablespace_mappings.clone(),
+            dry_run: false,
+            owner: None,
+            target_action: None,
+            resume: false,
+            force_system_id: false,
+            jobs: 1,
+            paths: Vec::new(),
+        },
+    )?;
+
+    let auto_conf_path = opts.target_dir.join("postgresql.auto.conf");
+    let port = opts.port.to_string();
+    let mut settings = vec![
+        ("port", port.as_str()),
+        ("archive_mode", "'off'"),
+        ("archive_command", "''"),
+    ];
+    settings.extend_from_slice(extra_settings);
+
+    let existing = fs::read_to_string(&auto_conf_path)?;
+    let mut auto_conf = File::create(&auto_conf_path)?;
+    for line in existing.lines() {
+        let name = line.split(['=', ' ', '\t']).next().unwrap_or_default();
+        if !settings.iter().any(|(setting, _)| *setting == name) {
+            writeln!(auto_conf, "{}", line)?;
+        }
+    }
+
+    for (name, value) in &settings {
+        info!("setting {} = {}", name, value);
+        writeln!(auto_conf, "{} = {}", name, value)?;
+    }
+
+    auto_conf.sync_all()?;
+
+    // Replication slots would hold back WAL on the clone for consumers of the
+    // original, and standby.signal would have it follow the original.
+    for entry in fs::read_dir(opts.target_dir.join("pg_replslot"))? {
+        let path = entry?.path();
+        info!("removing replication slot {:?}", path.file_name().unwrap());
+        fs::remove_dir_all(path)?;
+    }
+
+    match fs::remove_file(opts.target_dir.join("standby.signal")) {
+        Ok(()) => info!("removed standby.signal"),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => (),
+        Err(err) => return Err(err.into()),
+    }
+
+    Ok(())
+}
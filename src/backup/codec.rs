@@ -0,0 +1,213 @@
+use std::io::{self, BufReader, Read, Write};
+
+use anyhow::Result;
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+/// How the data of a backup is compressed: the chunks of full backups, the
+/// bundles of incremental backups and archives written with `--output` or
+/// exported. Only zstd takes a compression level.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Codec {
+    #[default]
+    Zstd,
+    Lz4,
+    Gzip,
+    None,
+}
+
+impl Codec {
+    pub fn name(self) -> &'static str {
+        match self {
+            Codec::Zstd => "zstd",
+            Codec::Lz4 => "lz4",
+            Codec::Gzip => "gzip",
+            Codec::None => "none",
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            Codec::Zstd => "tar.zst",
+            Codec::Lz4 => "tar.lz4",
+            Codec::Gzip => "tar.gz",
+            Codec::None => "tar",
+        }
+    }
+
+    // Appended to the keys of chunks, so that chunks compressed differently
+    // never share a key. Zstd has none, as chunks were always zstd before.
+    pub fn chunk_suffix(self) -> &'static str {
+        match self {
+            Codec::Zstd => "",
+            Codec::Lz4 => ".lz4",
+            Codec::Gzip => ".gz",
+            Codec::None => ".raw",
+        }
+    }
+
+    pub fn encoder<W>(self, writer: W, level: i32, jobs: u32) -> Result<Encoder<W>>
+    where
+        W: Write,
+    {
+        match self {
+            Codec::Zstd => {
+                let mut encoder = zstd::stream::Encoder::new(writer, level)?;
+                encoder.include_checksum(true)?;
+                if jobs > 1 {
+                    encoder.multithread(jobs)?;
+                }
+
+                Ok(Encoder::Zstd(encoder))
+            },
+            Codec::Lz4 => Ok(Encoder::Lz4(lz4_encoder(writer))),
+            Codec::Gzip => Ok(Encoder::Gzip(flate2::write::GzEncoder::new(
+                writer,
+                flate2::Compression::default(),
+            ))),
+            Codec::None => Ok(Encoder::None(writer)),
+        }
+    }
+
+    pub fn decoder<'a, R>(self, reader: R) -> Result<Box<dyn Read + 'a>>
+    where
+        R: Read + 'a,
+    {
+        match self {
+            Codec::Zstd => Ok(Box::new(zstd::stream::read::Decoder::new(reader)?)),
+            Codec::Lz4 => Ok(Box::new(lz4_flex::frame::FrameDecoder::new(reader))),
+            Codec::Gzip => Ok(Box::new(flate2::read::GzDecoder::new(reader))),
+            Codec::None => Ok(Box::new(BufReader::new(reader))),
+        }
+    }
+
+    pub fn chunk_compressor(self, level: i32, jobs: u32) -> Result<ChunkCompressor> {
+        match self {
+            // Chunks are compressed in one go, so the frames record their size
+            // along with the checksum.
+            Codec::Zstd => {
+                let mut compressor = zstd::bulk::Compressor::new(level)?;
+                compressor.include_checksum(true)?;
+                if jobs > 1 {
+                    compressor.multithread(jobs)?;
+                }
+
+                Ok(ChunkCompressor::Zstd(compressor))
+            },
+            codec => Ok(ChunkCompressor::Stream(codec)),
+        }
+    }
+}
+
+fn lz4_encoder<W>(writer: W) -> lz4_flex::frame::FrameEncoder<W>
+where
+    W: Write,
+{
+    let mut frame_info = lz4_flex::frame::FrameInfo::new();
+    frame_info.content_checksum = true;
+    lz4_flex::frame::FrameEncoder::with_frame_info(frame_info, writer)
+}
+
+pub enum Encoder<W: Write> {
+    Zstd(zstd::stream::Encoder<'static, W>),
+    Lz4(lz4_flex::frame::FrameEncoder<W>),
+    Gzip(flate2::write::GzEncoder<W>),
+    None(W),
+}
+
+impl<W> Encoder<W>
+where
+    W: Write,
+{
+    pub fn finish(self) -> io::Result<W> {
+        match self {
+            Encoder::Zstd(encoder) => encoder.finish(),
+            Encoder::Lz4(encoder) => encoder.finish().map_err(io::Error::other),
+            Encoder::Gzip(encoder) => encoder.finish(),
+            Encoder::None(writer) => Ok(writer),
+        }
+    }
+}
+
+impl<W> Write for Encoder<W>
+where
+    W: Write,
+{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Encoder::Zstd(encoder) => encoder.write(buf),
+            Encoder::Lz4(encoder) => encoder.write(buf),
+            Encoder::Gzip(encoder) => encoder.write(buf),
+            Encoder::None(writer) => writer.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Encoder::Zstd(encoder) => encoder.flush(),
+            Encoder::Lz4(encoder) => encoder.flush(),
+            Encoder::Gzip(encoder) => encoder.flush(),
+            Encoder::None(writer) => writer.flush(),
+        }
+    }
+}
+
+/// Compresses chunks with the codec of a backup, reading them back is done
+/// with [`Codec::decoder`].
+pub enum ChunkCompressor {
+    Zstd(zstd::bulk::Compressor<'static>),
+    Stream(Codec),
+}
+
+impl ChunkCompressor {
+    pub fn codec(&self) -> Codec {
+        match self {
+            ChunkCompressor::Zstd(_) => Codec::Zstd,
+            ChunkCompressor::Stream(codec) => *codec,
+        }
+    }
+
+    pub fn compress(&mut self, chunk: &[u8]) -> Result<Vec<u8>> {
+        match self {
+            ChunkCompressor::Zstd(compressor) => Ok(compressor.compress(chunk)?),
+            ChunkCompressor::Stream(codec) => {
+                let mut encoder = codec.encoder(Vec::with_capacity(chunk.len()), 0, 1)?;
+                encoder.write_all(chunk)?;
+                Ok(encoder.finish()?)
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use super::Codec;
+
+    #[test]
+    fn chunks_round_trip() {
+        let chunk = b"pgpitr ".repeat(1000);
+        for codec in [Codec::Zstd, Codec::Lz4, Codec::Gzip, Codec::None] {
+            let compressed = codec
+                .chunk_compressor(3, 1)
+                .unwrap()
+                .compress(&chunk)
+                .unwrap();
+            if codec == Codec::None {
+                assert_eq!(compressed, chunk);
+            } else {
+                assert!(compressed.len() < chunk.len(), "{:?}", codec);
+            }
+
+            let mut data = Vec::new();
+            codec
+                .decoder(&compressed[..])
+                .unwrap()
+                .read_to_end(&mut data)
+                .unwrap();
+            assert_eq!(data, chunk, "{:?}", codec);
+        }
+    }
+}
@@ -0,0 +1,214 @@
+use std::{
+    collections::{HashMap, HashSet},
+    io::{self, Read, Write},
+    path::PathBuf,
+};
+
+use anyhow::{anyhow, bail, Result};
+use clap::Args;
+use log::info;
+
+use super::{
+    codec::Codec,
+    manifest::{self, BackupKind, ChunkRef},
+};
+use crate::{
+    context::Context,
+    init,
+    storage::{EncryptedStorage, LocalStorage},
+};
+
+#[derive(Debug, Args)]
+pub struct Options {
+    /// Label of the backup, or "latest" for the newest one
+    #[arg(long)]
+    pub label: String,
+
+    /// Repository directory to copy the backup into
+    #[arg(long)]
+    pub dest_storage: PathBuf,
+
+    /// Key to encrypt the copied objects with in the destination repository
+    #[arg(long)]
+    pub dest_encryption_key_file: Option<PathBuf>,
+
+    /// Wait for other operations on either repository to finish instead of
+    /// failing
+    #[arg(long)]
+    pub wait: bool,
+}
+
+// The whole chain is copied so the backup can be restored from the
+// destination, oldest first and each manifest after its data, so that a copy
+// cut short leaves nothing behind that looks usable.
+pub fn run(ctx: &Context, opts: &Options) -> Result<()> {
+    let dest_storage = EncryptedStorage::new(
+        Box::new(LocalStorage::new(opts.dest_storage.clone())),
+        opts.dest_encryption_key_file.as_deref(),
+        false,
+    )?;
+    let dest = Context::new(Box::new(dest_storage), ctx.cluster_data.clone());
+    init::check(&dest)?;
+
+    let _lock = ctx.storage.lock(opts.wait)?;
+    let _dest_lock = dest.storage.lock(opts.wait)?;
+    let manifests = manifest::load_all(ctx)?;
+    let manifest = manifest::find(&manifests, &opts.label)?;
+    let dest_manifests = manifest::load_all(&dest)?;
+
+    let mut stats = Stats::default();
+    for backup in manifest.chain(&manifests)? {
+        match dest_manifests
+            .values()
+            .find(|existing| existing.label == backup.label)
+        {
+            Some(existing) if existing.id == backup.id => {
+                info!("backup {} is already in the destination", backup.label);
+                continue;
+            },
+            Some(_) => bail!(
+                "a different backup labeled {} is already in the destination",
+                backup.label
+            ),
+            None => (),
+        }
+
+        info!("copying backup {} ({})", backup.label, backup.id);
+        match &backup.data {
+            BackupKind::Full { files } => {
+                let mut objects = HashMap::<_, HashSet<_>>::new();
+                for chunk in files.values().flat_map(|info| &info.chunks) {
+                    objects
+                        .entry(chunk.key(backup.chunk_compression))
+                        .or_default()
+                        .insert(chunk);
+                }
+
+                for (key, chunks) in objects {
+                    if dest.storage.exists(&key)? {
+                        stats.skipped_objects += 1;
+                        continue;
+                    }
+
+                    stats.copied_bytes += match chunks.iter().next() {
+                        Some(chunk @ ChunkRef::Object(_)) =>
+                            copy_object(ctx, &dest, &key, backup.chunk_compression, Some(chunk))?,
+                        _ => copy_pack(ctx, &dest, &key, backup.chunk_compression, &chunks)?,
+                    };
+                    stats.copied_objects += 1;
+                }
+            },
+            BackupKind::Incremental {
+                bundle_checksum, ..
+            } => {
+                stats.copied_bytes += copy_object(
+                    ctx,
+                    &dest,
+                    &backup.bundle_key(),
+                    backup.compression,
+                    bundle_checksum.as_ref(),
+                )?;
+                stats.copied_objects += 1;
+            },
+        }
+
+        backup.save(&dest)?;
+    }
+
+    info!(
+        "copied backup {}: {} objects, {:.2} MiB, {} objects already present",
+        manifest.label,
+        stats.copied_objects,
+        stats.copied_bytes as f64 / 1024.0 / 1024.0,
+        stats.skipped_objects
+    );
+    Ok(())
+}
+
+#[derive(Default)]
+struct Stats {
+    copied_objects: usize,
+    copied_bytes: u64,
+    skipped_objects: usize,
+}
+
+// Objects are hashed decompressed while the stored bytes are passed through
+// to the destination. A mismatch drops the writer before it is finished, which
+// discards the object.
+fn copy_object(
+    ctx: &Context,
+    dest: &Context,
+    key: &str,
+    codec: Codec,
+    expected: Option<&ChunkRef>,
+) -> Result<u64> {
+    let mut reader = ctx.storage.open_reader(key)?;
+    let mut writer = dest.storage.create_writer(key)?;
+    let mut tee = Tee {
+        reader: &mut reader,
+        writer: &mut writer,
+        len: 0,
+    };
+
+    let mut hasher = blake3::Hasher::new();
+    io::copy(&mut codec.decoder(&mut tee)?, &mut hasher)?;
+    // Whatever the decoder didn't need still belongs to the object.
+    io::copy(&mut tee, &mut io::sink())?;
+    let len = tee.len;
+
+    if let Some(expected) = expected {
+        if *expected.hash() != hasher.finalize() {
+            bail!("checksum mismatch in {}", key);
+        }
+    }
+
+    writer.finish()?;
+    Ok(len)
+}
+
+// Packs are small enough to be checked in memory, only the chunks the backup
+// refers to are checked as the rest may belong to other backups.
+fn copy_pack(
+    ctx: &Context,
+    dest: &Context,
+    key: &str,
+    codec: Codec,
+    chunks: &HashSet<&ChunkRef>,
+) -> Result<u64> {
+    let pack = ctx.storage.read(key)?;
+    let mut data = Vec::new();
+    for chunk in chunks {
+        if let ChunkRef::Packed { offset, len, .. } = chunk {
+            let compressed = pack
+                .get(*offset as usize..(offset + len) as usize)
+                .ok_or_else(|| anyhow!("chunk {} is past the end", chunk.describe(codec)))?;
+            data.clear();
+            codec.decoder(compressed)?.read_to_end(&mut data)?;
+            if blake3::hash(&data) != *chunk.hash() {
+                bail!("checksum mismatch in chunk {}", chunk.describe(codec));
+            }
+        }
+    }
+
+    dest.storage.write(key, &pack)?;
+    Ok(pack.len() as u64)
+}
+
+struct Tee<R, W> {
+    reader: R,
+    writer: W,
+    len: u64,
+}
+
+impl<R, W> Read for Tee<R, W>
+where
+    R: Read,
+    W: Write,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.reader.read(buf)?;
+        self.writer.write_all(&buf[..len])?;
+        self.len += len as u64;
+        Ok(len)
+    }
+}
@@ -1,268 +1,1292 @@
 use std::{
-    cell::Cell,
+    cell::{Cell, RefCell},
     collections::HashMap,
+    env,
     fs::{self, File},
     io::{self, Read, Write},
+    iter,
+    os::unix::{
+        ffi::OsStrExt,
+        fs::{MetadataExt, PermissionsExt},
+    },
     path::{Path, PathBuf},
-    time::{Duration, Instant},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
-use anyhow::Result;
-use clap::Args;
-use log::info;
-use scopeguard::guard;
+use anyhow::{anyhow, bail, Context as _, Result};
+use clap::{Args, ValueEnum};
+use log::{error, info, warn};
+use scopeguard::{guard, ScopeGuard};
+use serde::Serialize;
+use time::format_description::well_known::Rfc3339;
 use uuid::Uuid;
 use walkdir::WalkDir;
 
-use super::manifest::{BackupKind, ChunkRef, FileInfo, Manifest};
-use crate::context::Context;
+use super::{
+    chunker::{parse_chunk_size, Blocks, Chunker},
+    cleanup,
+    codec::{ChunkCompressor, Codec, Encoder},
+    manifest::{self, BackupKind, ChunkRef, FileInfo, Manifest, PG_CONTROL},
+    pack::Packer,
+    prune,
+    restore::{parse_lsn, REQUIRED_DIRS},
+};
+use crate::{context::Context, init, pgpass, signal, wal_pull};
 
-const ZSTD_LEVEL: i32 = 3;
-const PROGRESS_LOG_INTERVAL: Duration = Duration::from_secs(5);
+const DEFAULT_ZSTD_LEVEL: i32 = 3;
 
 #[derive(Debug, Args)]
 pub struct Options {
+    /// Label of the backup, defaults to the time it was started at
+    #[arg(long)]
+    pub label: Option<String>,
+
+    /// Replace the backup with the same label if there is one, the data only
+    /// it used is left for gc
     #[arg(long)]
-    pub label: String,
+    pub overwrite: bool,
 
     #[arg(long)]
     pub delta: Option<String>,
+
+    /// Write the backup as a self-contained tar archive to FILE, or - for
+    /// stdout, instead of storing it in the repository
+    #[arg(long, value_name = "FILE", conflicts_with = "delta")]
+    pub output: Option<PathBuf>,
+
+    /// Write the manifest of the --output archive, listing the files in it,
+    /// to FILE
+    #[arg(long, value_name = "FILE", requires = "output")]
+    pub manifest_out: Option<PathBuf>,
+
+    /// How chunks, bundles and --output archives are compressed
+    #[arg(long, value_enum, default_value_t = Codec::Zstd)]
+    pub compression: Codec,
+
+    /// Level of zstd compression, 3 if not given
+    #[arg(long, value_parser = clap::value_parser!(i32).range(1..=22))]
+    pub compression_level: Option<i32>,
+
+    /// Average size of the content-defined chunks full backups are split
+    /// into, a power of two with an optional K or M suffix
+    #[arg(long, default_value = "1M", value_parser = parse_chunk_size)]
+    pub chunk_size: usize,
+
+    /// Number of zstd worker threads
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..=256))]
+    pub jobs: u32,
+
+    /// How progress is reported, json writes one record per line to stdout
+    #[arg(long, value_enum, default_value_t = ProgressFormat::Text)]
+    pub progress_format: ProgressFormat,
+
+    /// Seconds between progress reports
+    #[arg(long, default_value_t = 5, value_parser = clap::value_parser!(u64).range(1..))]
+    pub progress_interval: u64,
+
+    /// Wait for other operations on the repository to finish instead of
+    /// failing
+    #[arg(long)]
+    pub wait: bool,
+
+    /// Tag the backup, can be given multiple times
+    #[arg(long = "tag", value_name = "KEY=VALUE", value_parser = manifest::parse_tag)]
+    pub tags: Vec<(String, String)>,
+
+    /// Leave the files and directories matching GLOB, relative to the data
+    /// directory, out of the backup, can be given multiple times. `*` doesn't
+    /// match `/`, everything in an excluded directory is left out
+    #[arg(long, value_name = "GLOB", value_parser = manifest::parse_glob)]
+    pub exclude: Vec<String>,
+
+    /// What to do with the WAL written during the backup, fetch stores it
+    /// with the backup so that it can be restored without the WAL archive
+    #[arg(long, value_enum, default_value_t = WalMethod::None, conflicts_with = "delta")]
+    pub wal_method: WalMethod,
+
+    /// Same as --wal-method fetch
+    #[arg(long, conflicts_with_all = ["delta", "wal_method"])]
+    pub standalone: bool,
+
+    /// Write metrics about the backup to FILE once it succeeds, in the format
+    /// of the Prometheus node exporter's textfile collector
+    #[arg(long, value_name = "FILE")]
+    pub metrics_file: Option<PathBuf>,
+
+    /// Write a JSON object summarizing the backup to FILE once it succeeds
+    #[arg(long, value_name = "FILE")]
+    pub report: Option<PathBuf>,
+
+    /// Store the backup even if the repository holds backups of another
+    /// cluster
+    #[arg(long)]
+    pub force_system_id: bool,
+
+    /// Print the size of the cluster and the expected size of the backup
+    /// instead of taking one
+    #[arg(long)]
+    pub estimate: bool,
+
+    /// Give up on the backup once it has taken this long, such as 90m or 2h,
+    /// cleaning up like after SIGTERM
+    #[arg(long, value_parser = parse_timeout)]
+    pub timeout: Option<Duration>,
+
+    /// Compression ratio --estimate assumes for the backup
+    #[arg(long, default_value_t = 3.0, value_parser = parse_ratio)]
+    pub assumed_ratio: f64,
+
+    #[command(flatten)]
+    pub connection: ConnectionOptions,
 }
 
-pub fn run(ctx: &Context, opts: &Options) -> Result<()> {
-    let id = Uuid::new_v4();
-    let created_at = time::OffsetDateTime::now_utc();
-    let mut client =
-        postgres::Client::connect("host=localhost user=postgres", postgres::NoTls).unwrap();
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum WalMethod {
+    /// Rely on the WAL archive for the WAL needed to restore the backup
+    None,
+    /// Copy the WAL from pg_wal, or the archive if it was already removed,
+    /// once the backup has finished
+    Fetch,
+}
 
-    client
-        .execute("SELECT pg_backup_start($1, fast := true);", &[&opts.label])
-        .unwrap();
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum ProgressFormat {
+    Text,
+    Json,
+}
 
-    let client = guard(client, |mut client| {
-        client.execute("SELECT pg_backup_stop();", &[]).unwrap();
-    });
+// How to reach the server being backed up, falling back to the libpq
+// environment variables.
+#[derive(Debug, Args)]
+pub struct ConnectionOptions {
+    #[arg(long, env = "PGHOST", default_value = "localhost")]
+    pub host: String,
+
+    #[arg(long, env = "PGPORT", default_value_t = 5432)]
+    pub port: u16,
+
+    #[arg(long, env = "PGUSER", default_value = "postgres")]
+    pub user: String,
+
+    #[arg(long, env = "PGDATABASE")]
+    pub dbname: Option<String>,
+
+    /// Read the password from FILE, in the format of ~/.pgpass which is read
+    /// otherwise. PGPASSWORD takes precedence, there is no password prompt
+    #[arg(long, env = "PGPASSFILE", value_name = "FILE")]
+    pub password_file: Option<PathBuf>,
+}
+
+impl ConnectionOptions {
+    // The password comes from PGPASSWORD, the password file or nowhere, the
+    // same as with libpq except that it never prompts for one: a server
+    // asking for a password then fails the connection rather than a prompt
+    // waiting on a terminal there may not be.
+    pub fn config(&self) -> Result<postgres::Config> {
+        let mut config = postgres::Config::new();
+        config.host(&self.host).port(self.port).user(&self.user);
+        if let Some(dbname) = &self.dbname {
+            config.dbname(dbname);
+        }
+
+        if let Some(password) = env::var_os("PGPASSWORD").filter(|password| !password.is_empty()) {
+            config.password(password.as_bytes());
+            return Ok(config);
+        }
+
+        // An explicit password file has to be usable, ~/.pgpass is skipped
+        // like libpq does.
+        let password_file = match &self.password_file {
+            Some(path) => {
+                pgpass::check_permissions(path)?;
+                path.clone()
+            },
+            None => match pgpass::default_path().filter(|path| path.exists()) {
+                Some(path) => match pgpass::check_permissions(&path) {
+                    Ok(()) => path,
+                    Err(err) => {
+                        warn!("ignoring {:#}", err);
+                        return Ok(config);
+                    },
+                },
+                None => return Ok(config),
+            },
+        };
 
-    let backup_dir_path = ctx.storage.join("backups");
-    if !backup_dir_path.exists() {
-        fs::create_dir(&backup_dir_path)?;
+        let dbname = self.dbname.as_deref().unwrap_or(&self.user);
+        if let Some(password) =
+            pgpass::lookup(&password_file, &self.host, self.port, dbname, &self.user)?
+        {
+            config.password(password);
+        }
+
+        Ok(config)
     }
+}
+
+/// A backup into the repository, what create-backup takes without the options
+/// that only make sense on the command line.
+#[derive(Debug, Clone)]
+pub struct BackupOptions {
+    /// Label of the backup, defaults to the time it was started at.
+    pub label: Option<String>,
+    /// Replace the backup with the same label if there is one.
+    pub overwrite: bool,
+    /// Label of the backup to take an incremental backup on top of.
+    pub delta: Option<String>,
+    pub compression: Codec,
+    /// Only applies to zstd, which uses level 3 if not given.
+    pub compression_level: Option<i32>,
+    /// Average size of the chunks full backups are split into, a power of two.
+    pub chunk_size: usize,
+    pub jobs: u32,
+    pub tags: Vec<(String, String)>,
+    /// Globs of the paths, relative to the data directory, to leave out.
+    pub exclude: Vec<String>,
+    /// Store the WAL needed to restore the backup with it.
+    pub standalone: bool,
+    /// Take the backup even if the repository holds backups of another cluster.
+    pub force_system_id: bool,
+    /// Wait for the repository lock instead of failing.
+    pub wait: bool,
+    /// Connects to localhost if no host is set, which the host and port
+    /// setters add to rather than replace.
+    pub connection: postgres::Config,
+}
+
+impl Default for BackupOptions {
+    fn default() -> Self {
+        let mut connection = postgres::Config::new();
+        connection.user("postgres");
+        Self {
+            label: None,
+            overwrite: false,
+            delta: None,
+            compression: Codec::Zstd,
+            compression_level: None,
+            chunk_size: 1024 * 1024,
+            jobs: 1,
+            tags: Vec::new(),
+            exclude: Vec::new(),
+            standalone: false,
+            force_system_id: false,
+            wait: false,
+            connection,
+        }
+    }
+}
+
+impl BackupOptions {
+    // Everything the command line parser checks, for options that didn't come
+    // through it.
+    fn validate(&self) -> Result<()> {
+        if self.label.as_deref() == Some(manifest::LATEST) {
+            bail!(
+                "{} is reserved and can't be used as a label",
+                manifest::LATEST
+            );
+        }
+
+        if let Some(level) = self.compression_level {
+            if self.compression != Codec::Zstd {
+                bail!(
+                    "--compression-level only applies to zstd, not {}",
+                    self.compression.name()
+                );
+            }
+
+            if !(1..=22).contains(&level) {
+                bail!("invalid compression level {}", level);
+            }
+        }
+
+        if !(1..=256).contains(&self.jobs) {
+            bail!("invalid number of jobs {}", self.jobs);
+        }
+
+        if self.standalone && self.delta.is_some() {
+            bail!("incremental backups can't be standalone");
+        }
+
+        parse_chunk_size(&self.chunk_size.to_string()).map_err(|err| anyhow!(err))?;
+        for (key, value) in &self.tags {
+            manifest::parse_tag(&format!("{}={}", key, value)).map_err(|err| anyhow!(err))?;
+        }
+        for pattern in &self.exclude {
+            manifest::parse_glob(pattern).map_err(|err| anyhow!(err))?;
+        }
+
+        Ok(())
+    }
+
+    fn zstd_level(&self) -> i32 {
+        self.compression_level.unwrap_or(DEFAULT_ZSTD_LEVEL)
+    }
+}
+
+impl Options {
+    fn backup_options(&self) -> Result<BackupOptions> {
+        Ok(BackupOptions {
+            label: self.label.clone(),
+            overwrite: self.overwrite,
+            delta: self.delta.clone(),
+            compression: self.compression,
+            compression_level: self.compression_level,
+            chunk_size: self.chunk_size,
+            jobs: self.jobs,
+            tags: self.tags.clone(),
+            exclude: self.exclude.clone(),
+            standalone: self.standalone || self.wal_method == WalMethod::Fetch,
+            force_system_id: self.force_system_id,
+            wait: self.wait,
+            connection: self.connection.config()?,
+        })
+    }
+}
+
+pub fn run(ctx: &Context, opts: &Options) -> Result<()> {
+    signal::install()?;
+    if let Some(timeout) = opts.timeout {
+        signal::set_timeout(timeout);
+    }
+
+    let backup_opts = opts.backup_options()?;
+    backup_opts.validate()?;
+    let to_stdout = opts.output.as_deref() == Some(Path::new("-"));
+    if to_stdout && matches!(opts.progress_format, ProgressFormat::Json) {
+        bail!("--progress-format json can't be used with --output -, both write to stdout");
+    }
+
+    if opts.estimate {
+        let mut client = connect(&backup_opts)?;
+        return print_estimate(&mut client, opts.assumed_ratio);
+    }
+
+    let metrics = Metrics::new(
+        opts.compression,
+        backup_opts.zstd_level(),
+        opts.progress_format,
+        Duration::from_secs(opts.progress_interval),
+    );
 
-    let bundle_dir_path = ctx.storage.join("bundles");
-    if !bundle_dir_path.exists() {
-        fs::create_dir(&bundle_dir_path)?;
+    let manifest = match &opts.output {
+        Some(output) => write_output(
+            ctx,
+            &backup_opts,
+            &metrics,
+            output,
+            opts.manifest_out.as_deref(),
+        )?,
+        None => take_backup(ctx, &backup_opts, &metrics)?,
+    };
+
+    if let Some(path) = &opts.metrics_file {
+        metrics.write_prometheus(path)?;
     }
 
-    let chunk_dir_path = ctx.storage.join("chunks");
-    if !chunk_dir_path.exists() {
-        fs::create_dir(&chunk_dir_path)?;
+    if let Some(path) = &opts.report {
+        metrics.write_report(path, &manifest)?;
     }
 
-    let mut metrics = Metrics::new();
-    let data = match &opts.delta {
+    Ok(())
+}
+
+/// Takes a backup of the cluster in `ctx.cluster_data` into the repository,
+/// returning its manifest once it is stored.
+pub fn backup(ctx: &Context, opts: &BackupOptions) -> Result<Manifest> {
+    opts.validate()?;
+    let metrics = Metrics::new(
+        opts.compression,
+        opts.zstd_level(),
+        ProgressFormat::Text,
+        Duration::from_secs(5),
+    );
+    take_backup(ctx, opts, &metrics)
+}
+
+fn take_backup(ctx: &Context, opts: &BackupOptions, metrics: &Metrics) -> Result<Manifest> {
+    let mut client = connect(opts)?;
+    let _lock = ctx.storage.lock(opts.wait)?;
+    let started = start_backup(ctx, opts, &mut client, true)?;
+    let client = guard(client, abort_backup as fn(postgres::Client));
+
+    let mut data = match &opts.delta {
         Some(delta_from) => {
-            let delta_from_path = backup_dir_path.join(format!("{}.manifest", delta_from));
-            let delta_from_data = fs::read_to_string(&delta_from_path)?;
-            let delta_from: Manifest = serde_yaml::from_str(&delta_from_data)?;
-            do_incremental(ctx, &mut metrics, id, &delta_from)?
+            let delta_from = Manifest::load(ctx, &Manifest::key(delta_from))?;
+            do_incremental(
+                ctx,
+                metrics,
+                started.id,
+                &delta_from,
+                &started.excluded,
+                opts,
+            )?
         },
-        None => do_full(ctx, &mut metrics)?,
+        None => do_full(ctx, metrics, &started.excluded, opts)?,
+    };
+
+    let stop = stop_backup(client)?;
+    if let (true, BackupKind::Full { files }) = (opts.standalone, &mut data) {
+        let mut writer = ChunkWriter::new(
+            ctx,
+            opts.compression,
+            opts.zstd_level(),
+            opts.jobs,
+            opts.chunk_size,
+        )?;
+        for segment in stop.wal_segments(started.wal_segment_size)? {
+            let wal = read_wal_segment(ctx, &segment)?;
+            let info = writer.store_file(ctx, metrics, &wal[..], 0o600, None)?;
+            files.insert(Path::new("pg_wal").join(segment), info);
+        }
+
+        writer.finish(ctx)?;
+    }
+
+    let replaces = started.replaces;
+    let manifest = started.manifest(opts, stop, data)?;
+    manifest.save(ctx)?;
+    if replaces {
+        info!("replaced the previous backup {}", manifest.label);
+    }
+
+    Ok(manifest)
+}
+
+// Writes the backup as a tar archive to `output`, or stdout for -, with the
+// WAL it needs if standalone. The manifest returned isn't stored anywhere but
+// `manifest_out`.
+fn write_output(
+    ctx: &Context,
+    opts: &BackupOptions,
+    metrics: &Metrics,
+    output: &Path,
+    manifest_out: Option<&Path>,
+) -> Result<Manifest> {
+    // An archive has nowhere to put tablespaces, which live outside of the
+    // data directory.
+    if fs::read_dir(ctx.cluster_data.join("pg_tblspc"))?
+        .filter_map(|entry| entry.ok())
+        .any(|entry| {
+            !is_excluded(
+                &opts.exclude,
+                Path::new("pg_tblspc").join(entry.file_name()),
+            )
+        })
+    {
+        bail!("--output can't be used with clusters that have tablespaces");
+    }
+
+    // Nothing is written to the repository, so it isn't locked or cleaned up.
+    let mut client = connect(opts)?;
+    let started = start_backup(ctx, opts, &mut client, false)?;
+    let client = guard(client, abort_backup as fn(postgres::Client));
+
+    // Written next to the destination and renamed over it once complete, so
+    // an interrupted backup never looks like a finished one.
+    let to_stdout = output == Path::new("-");
+    let mut partial_path = output.as_os_str().to_owned();
+    partial_path.push(".partial");
+    let partial_path = guard(PathBuf::from(partial_path), |path| {
+        if !to_stdout {
+            let _ = fs::remove_file(path);
+        }
+    });
+    let file = if to_stdout {
+        None
+    } else {
+        Some(
+            File::create(&*partial_path)
+                .with_context(|| format!("failed to create {}", partial_path.display()))?,
+        )
+    };
+    let out: Box<dyn Write> = match &file {
+        Some(file) => Box::new(file),
+        None => Box::new(io::stdout().lock()),
+    };
+
+    let mut files = HashMap::new();
+    let mut archive = write_archive(ctx, metrics, out, &mut files, &started.excluded, opts)?;
+    let stop = stop_backup(client)?;
+    if opts.standalone {
+        for segment in stop.wal_segments(started.wal_segment_size)? {
+            let data = read_wal_segment(ctx, &segment)?;
+            metrics.add_read(data.len() as u64);
+            let path = Path::new("pg_wal").join(segment);
+            append_archive_file(&mut archive, &path, &data)?;
+            files.insert(path, archived_file(data.len() as u64, 0o600, None));
+        }
+    }
+
+    append_archive_file(&mut archive, "backup_label", stop.backup_label.as_bytes())?;
+
+    archive.into_inner()?.finish()?.flush()?;
+    if let Some(file) = file {
+        file.sync_all()?;
+        fs::rename(&*partial_path, output)?;
+    }
+
+    ScopeGuard::into_inner(partial_path);
+    let manifest = started.manifest(opts, stop, BackupKind::Full { files })?;
+    if let Some(path) = manifest_out {
+        fs::write(path, serde_yaml::to_string(&manifest)?)
+            .with_context(|| format!("failed to write {}", path.display()))?;
+    }
+
+    metrics.log_progress(true);
+    Ok(manifest)
+}
+
+fn connect(opts: &BackupOptions) -> Result<postgres::Client> {
+    let mut config = opts.connection.clone();
+    if config.get_hosts().is_empty() {
+        config.host("localhost");
+    }
+
+    if let Some(remaining) = signal::remaining() {
+        config.connect_timeout(remaining);
+    }
+
+    config
+        .connect(postgres::NoTls)
+        .map_err(|err| connect_error(err, &describe_connection(&config)))
+}
+
+// tokio-postgres only says "password missing" when the server asks for a
+// password none was given for.
+pub(crate) fn connect_error(err: postgres::Error, target: &str) -> anyhow::Error {
+    let missing_password = err.to_string().contains("password missing");
+    let err = anyhow::Error::new(err).context(format!("failed to connect to {}", target));
+    if missing_password {
+        return err.context(
+            "the server asks for a password, set PGPASSWORD or add it to the password file",
+        );
+    }
+
+    err
+}
+
+fn describe_connection(config: &postgres::Config) -> String {
+    let host = match config.get_hosts().first() {
+        Some(postgres::config::Host::Tcp(host)) => host.clone(),
+        Some(postgres::config::Host::Unix(path)) => path.display().to_string(),
+        None => "localhost".to_owned(),
+    };
+    let port = config.get_ports().first().copied().unwrap_or(5432);
+    match config.get_user() {
+        Some(user) => format!("{}:{} as {}", host, port, user),
+        None => format!("{}:{}", host, port),
+    }
+}
+
+// A backup started on the server, along with what its manifest needs to know
+// about the server.
+struct Started {
+    id: Uuid,
+    created_at: time::OffsetDateTime,
+    label: String,
+    replaces: bool,
+    system_identifier: u64,
+    pg_version: String,
+    wal_segment_size: u64,
+    excluded: RefCell<Vec<PathBuf>>,
+}
+
+impl Started {
+    fn manifest(
+        self,
+        opts: &BackupOptions,
+        stop: BackupStop,
+        data: BackupKind,
+    ) -> Result<Manifest> {
+        Ok(Manifest {
+            schema_version: manifest::SCHEMA_VERSION,
+            id: self.id,
+            created_at: self.created_at,
+            label: self.label,
+            start_wal: manifest::start_wal_segment(&stop.backup_label)?.to_owned(),
+            backup_label: Some(stop.backup_label),
+            tablespace_map: Some(stop.tablespace_map).filter(|map| !map.is_empty()),
+            pg_version: self.pg_version,
+            tool_version: env!("CARGO_PKG_VERSION").to_owned(),
+            system_identifier: Some(self.system_identifier),
+            wal_segment_size: self.wal_segment_size,
+            standalone: opts.standalone,
+            pinned: false,
+            tags: opts.tags.iter().cloned().collect(),
+            excluded: excluded_paths(&self.excluded, &opts.exclude),
+            compression: opts.compression,
+            chunk_compression: opts.compression,
+            data,
+        })
+    }
+}
+
+// Backups stored in the repository must be of the cluster it holds backups of
+// and may only replace another backup as allowed by `check_label`, and what
+// interrupted operations left behind is cleaned up before they start.
+fn start_backup(
+    ctx: &Context,
+    opts: &BackupOptions,
+    client: &mut postgres::Client,
+    to_repository: bool,
+) -> Result<Started> {
+    let created_at = time::OffsetDateTime::now_utc().replace_nanosecond(0)?;
+    let label = match &opts.label {
+        Some(label) => label.clone(),
+        None => created_at.format(&Rfc3339)?,
     };
 
-    let manifest = Manifest {
-        id,
+    let system_identifier = client
+        .query_one("SELECT system_identifier FROM pg_control_system();", &[])?
+        .get::<_, i64>(0) as u64;
+    if to_repository {
+        init::check_system_identifier(ctx, system_identifier, opts.force_system_id)?;
+        cleanup::cleanup(ctx, false)?;
+    }
+
+    let replaces = to_repository && check_label(ctx, &label, opts)?;
+
+    let pg_version = client
+        .query_one("SHOW server_version;", &[])?
+        .get::<_, String>(0);
+    let wal_segment_size = client
+        .query_one(
+            "SELECT setting::bigint FROM pg_settings WHERE name = 'wal_segment_size';",
+            &[],
+        )?
+        .get::<_, i64>(0) as u64;
+    limit_statement_time(client)?;
+    client
+        .execute("SELECT pg_backup_start($1, fast := true);", &[&label])
+        .or_else(|err| {
+            signal::check()?;
+            Err(err).context("failed to start backup")
+        })?;
+
+    Ok(Started {
+        id: Uuid::new_v4(),
         created_at,
-        label: opts.label.clone(),
-        data,
+        label,
+        replaces,
+        system_identifier,
+        pg_version,
+        wal_segment_size,
+        excluded: RefCell::new(Vec::new()),
+    })
+}
+
+// Only runs when the backup failed, whose error is the one worth returning, so
+// a failure to stop is just logged.
+fn abort_backup(mut client: postgres::Client) {
+    if let Err(err) = client.execute("SELECT pg_backup_stop();", &[]) {
+        error!("failed to stop backup: {}", err);
+    }
+}
+
+// The databases make up nearly all of a cluster, what's left is WAL, which
+// isn't backed up, and a few small files.
+fn print_estimate(client: &mut postgres::Client, ratio: f64) -> Result<()> {
+    let size = client
+        .query_one(
+            "SELECT sum(pg_database_size(datname))::bigint FROM pg_database;",
+            &[],
+        )?
+        .get::<_, Option<i64>>(0)
+        .unwrap_or(0) as u64;
+
+    println!("cluster size:     {} MiB", size / 1024 / 1024);
+    println!(
+        "estimated backup: {} MiB at a compression ratio of {:.2}x",
+        (size as f64 / ratio) as u64 / 1024 / 1024,
+        ratio
+    );
+    Ok(())
+}
+
+fn parse_timeout(s: &str) -> Result<Duration, String> {
+    prune::parse_duration(s)?
+        .try_into()
+        .map_err(|_| format!("invalid duration {:?}", s))
+}
+
+fn parse_ratio(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(ratio) if ratio >= 1.0 && ratio.is_finite() => Ok(ratio),
+        _ => Err(format!(
+            "invalid ratio {:?}, expected a number of at least 1",
+            s
+        )),
+    }
+}
+
+// Returns whether an existing backup is being replaced, which is only allowed
+// with --overwrite and as long as no other backup depends on it.
+fn check_label(ctx: &Context, label: &str, opts: &BackupOptions) -> Result<bool> {
+    if !ctx.storage.exists(&Manifest::key(label))? {
+        return Ok(false);
+    }
+
+    if !opts.overwrite {
+        bail!(
+            "backup {} already exists, use --overwrite to replace it",
+            label
+        );
+    }
+
+    if opts.delta.as_deref() == Some(label) {
+        bail!("backup {} can't be replaced by a delta from itself", label);
+    }
+
+    let manifests = manifest::load_all(ctx)?;
+    let Some(existing) = manifests.values().find(|manifest| manifest.label == label) else {
+        return Ok(true);
     };
 
-    let manifest_data = serde_yaml::to_string(&manifest).unwrap();
-    let manifest_path = backup_dir_path.join(format!("{}.manifest", opts.label));
-    let mut manifest_file = File::create_new(manifest_path)?;
-    manifest_file.write_all(manifest_data.as_bytes())?;
-    manifest_file.sync_all()?;
+    if existing.pinned {
+        bail!("backup {} is pinned, unpin it to replace it", label);
+    }
+
+    let mut dependents = manifests
+        .values()
+        .filter(|manifest| manifest.depends_on(existing.id, &manifests))
+        .map(|manifest| manifest.label.as_str())
+        .collect::<Vec<_>>();
+    if !dependents.is_empty() {
+        dependents.sort();
+        bail!(
+            "backup {} is needed to restore {} and can't be replaced",
+            label,
+            dependents.join(", ")
+        );
+    }
+
+    Ok(true)
+}
+
+struct BackupStop {
+    backup_label: String,
+    tablespace_map: String,
+    lsn: u64,
+}
+
+impl BackupStop {
+    /// Names of the WAL segments from the start of the backup to its end.
+    fn wal_segments(&self, segment_size: u64) -> Result<Vec<String>> {
+        wal_segments(
+            manifest::start_wal_segment(&self.backup_label)?,
+            self.lsn,
+            segment_size,
+        )
+    }
+}
+
+fn stop_backup<F>(client: ScopeGuard<postgres::Client, F>) -> Result<BackupStop>
+where
+    F: FnOnce(postgres::Client),
+{
+    let mut client = ScopeGuard::into_inner(client);
+    limit_statement_time(&mut client)?;
+    let stop_row = client
+        .query_one(
+            "SELECT labelfile, spcmapfile, lsn::text FROM pg_backup_stop();",
+            &[],
+        )
+        .or_else(|err| {
+            signal::check()?;
+            Err(err).context("failed to stop backup")
+        })?;
+    Ok(BackupStop {
+        backup_label: stop_row.get(0),
+        tablespace_map: stop_row.get(1),
+        lsn: parse_lsn(stop_row.get(2)).map_err(|err| anyhow!(err))?,
+    })
+}
+
+// Starting and stopping the backup wait on the server, for a checkpoint and for
+// the WAL to be archived, which --timeout can only cut short by having the
+// server cancel them.
+fn limit_statement_time(client: &mut postgres::Client) -> Result<()> {
+    if let Some(remaining) = signal::remaining() {
+        signal::check()?;
+        client.batch_execute(&format!(
+            "SET statement_timeout = {};",
+            remaining.as_millis().max(1)
+        ))?;
+    }
+
+    Ok(())
+}
+
+// The stop LSN is the end of the last record needed, which is in the segment
+// before it when it falls on a segment boundary.
+fn wal_segments(start_segment: &str, stop_lsn: u64, segment_size: u64) -> Result<Vec<String>> {
+    let Some((timeline, start)) = parse_wal_segment(start_segment, segment_size) else {
+        bail!("invalid WAL segment name {}", start_segment);
+    };
+
+    let stop = stop_lsn.saturating_sub(1) / segment_size;
+    Ok((start..=stop)
+        .map(|segment| wal_segment_name(timeline, segment, segment_size))
+        .collect())
+}
+
+/// Splits a WAL segment name into its timeline and the number of the segment
+/// counted from the start of WAL.
+pub(crate) fn parse_wal_segment(name: &str, segment_size: u64) -> Option<(u32, u64)> {
+    if name.len() != 24 || !name.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+
+    let parse = |hex: &str| u64::from_str_radix(hex, 16).ok();
+    let timeline = parse(&name[..8])?;
+    let log = parse(&name[8..16])?;
+    let seg = parse(&name[16..])?;
+    Some((timeline as u32, log * (0x1_0000_0000 / segment_size) + seg))
+}
+
+pub(crate) fn wal_segment_name(timeline: u32, segment: u64, segment_size: u64) -> String {
+    let segments_per_log = 0x1_0000_0000 / segment_size;
+    format!(
+        "{:08X}{:08X}{:08X}",
+        timeline,
+        segment / segments_per_log,
+        segment % segments_per_log
+    )
+}
+
+// pg_backup_stop waits for the segments to be archived, by which point they
+// may already have been recycled out of pg_wal.
+fn read_wal_segment(ctx: &Context, segment: &str) -> Result<Vec<u8>> {
+    signal::check()?;
+    match fs::read(ctx.cluster_data.join("pg_wal").join(segment)) {
+        Ok(data) => Ok(data),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => wal_pull::read_wal(ctx, segment),
+        Err(err) => Err(err.into()),
+    }
+}
+
+type Archive<'a> = tar::Builder<Encoder<TrackedWriter<'a, Box<dyn Write + 'a>>>>;
+
+// Archives hold the files as they are, to be extracted into an empty data
+// directory, with backup_label added once the backup stops.
+fn write_archive<'a>(
+    ctx: &Context,
+    metrics: &'a Metrics,
+    out: Box<dyn Write + 'a>,
+    files: &mut HashMap<PathBuf, FileInfo>,
+    excluded: &RefCell<Vec<PathBuf>>,
+    opts: &BackupOptions,
+) -> Result<Archive<'a>> {
+    let encoder =
+        opts.compression
+            .encoder(metrics.track_writer(out), opts.zstd_level(), opts.jobs)?;
+
+    let mut archive = tar::Builder::new(encoder);
+    for path in archive_files(ctx, &opts.exclude, excluded) {
+        signal::check()?;
+        let path = path?;
+        let stripped_path = path.strip_prefix(&ctx.cluster_data)?;
+        if path.is_symlink() {
+            let target = fs::read_link(&path)?;
+            let mut header = tar::Header::new_gnu();
+            header.set_entry_type(tar::EntryType::Symlink);
+            header.set_mode(0o777);
+            header.set_size(0);
+            archive.append_link(&mut header, stripped_path, &target)?;
+            files.insert(stripped_path.to_owned(), FileInfo::symlink(target));
+            continue;
+        }
+
+        let mut file = File::open(&path)?;
+        let metadata = file.metadata()?;
+
+        // Files can change size while being read, the entry gets exactly the
+        // size in its header and WAL replay fixes up the contents.
+        let size = metadata.len();
+        let mut header = tar::Header::new_gnu();
+        header.set_metadata(&metadata);
+        header.set_size(size);
+        let data = (&mut file).take(size).chain(io::repeat(0)).take(size);
+        archive.append_data(&mut header, stripped_path, data)?;
+        files.insert(
+            stripped_path.to_owned(),
+            archived_file(
+                size,
+                metadata.permissions().mode() & 0o7777,
+                Some(metadata.mtime()),
+            ),
+        );
+
+        metrics.add_read(size);
+        metrics.log_progress(false);
+    }
+
+    for dir in REQUIRED_DIRS {
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Directory);
+        header.set_mode(0o700);
+        header.set_size(0);
+        archive.append_data(&mut header, dir, io::empty())?;
+    }
+
+    Ok(archive)
+}
+
+// Files in an archive aren't chunked, so their entries in its manifest only
+// describe them.
+fn archived_file(size: u64, mode: u32, mtime: Option<i64>) -> FileInfo {
+    FileInfo {
+        chunks: Vec::new(),
+        blocks: Vec::new(),
+        size: Some(size),
+        mode: Some(mode),
+        mtime,
+        link_target: None,
+    }
+}
 
-    drop(client);
+fn append_archive_file(
+    archive: &mut Archive,
+    path: impl AsRef<Path>,
+    contents: &[u8],
+) -> Result<()> {
+    let mut header = tar::Header::new_gnu();
+    header.set_size(contents.len() as u64);
+    header.set_mode(0o600);
+    archive.append_data(&mut header, path, contents)?;
     Ok(())
 }
 
 fn do_incremental(
     ctx: &Context,
-    metrics: &mut Metrics,
+    metrics: &Metrics,
     id: Uuid,
     delta_from: &Manifest,
+    excluded: &RefCell<Vec<PathBuf>>,
+    opts: &BackupOptions,
 ) -> Result<BackupKind> {
-    let mut block_changed = block_changed(ctx, delta_from.id);
+    let mut block_changed = block_changed(ctx, delta_from.id)?;
     let mut changed_files = HashMap::new();
-    let bundle_path = ctx.storage.join("bundles").join(format!("{}.tar.zst", id));
-    let bundle_file = File::create_new(&bundle_path)?;
-    let tracked_writer = metrics.track_writer(&bundle_file);
-    let mut bundle =
-        tar::Builder::new(zstd::stream::Encoder::new(tracked_writer, ZSTD_LEVEL).unwrap());
+    let mut file_sizes = HashMap::new();
+    let mut chunker = Chunker::new(opts.chunk_size);
+    let mut bundle_writer = ctx
+        .storage
+        .create_writer(&manifest::bundle_key(id, opts.compression))?;
+    let tracked_writer = metrics.track_writer(&mut bundle_writer);
+    let encoder = opts
+        .compression
+        .encoder(tracked_writer, opts.zstd_level(), opts.jobs)?;
+
+    let mut bundle = tar::Builder::new(HashingWriter::new(encoder));
 
-    for path in target_files(ctx) {
+    for path in archive_files(ctx, &opts.exclude, excluded) {
+        signal::check()?;
         let path = path?;
         let stripped_path = path.as_path().strip_prefix(&ctx.cluster_data)?;
+        // Symlinks are only recorded by full backups.
+        if path.is_symlink() {
+            continue;
+        }
 
         let mut changed_blocks = HashMap::new();
-        let mut small_block_index = 0;
-
         let file = File::open(&path)?;
-        let mut chunker = Chunker::new(file);
-        while let Some(chunk) = chunker.next_chunk() {
-            metrics.add_read(chunk.len() as u64);
-
-            for small_block in chunk.chunks(8 * 1024) {
-                let hash = blake3::hash(small_block);
+        let metadata = file.metadata()?;
+        let mode = metadata.permissions().mode() & 0o7777;
+        let mut small_block_index = 0;
+        let mut add_block = |small_block: &[u8]| -> Result<()> {
+            let hash = blake3::hash(small_block);
 
-                if block_changed(stripped_path, small_block_index, hash) {
-                    let mut header = tar::Header::new_old();
-                    let block_path = stripped_path.join(small_block_index.to_string());
+            if block_changed(stripped_path, small_block_index, hash) {
+                let mut header = tar::Header::new_old();
+                let block_path = stripped_path.join(small_block_index.to_string());
 
-                    header.set_path(&block_path)?;
-                    header.set_size(small_block.len() as u64);
+                header.set_path(&block_path)?;
+                header.set_size(small_block.len() as u64);
+                header.set_mode(mode);
+                header.set_mtime(metadata.mtime().max(0) as u64);
+                header.set_cksum();
 
-                    bundle.append(&header, small_block)?;
-                    changed_blocks.insert(small_block_index, ChunkRef(hash));
-                } else {
-                    metrics.add_deduplicated(small_block.len() as u64);
-                }
-
-                small_block_index += 1;
+                bundle.append(&header, small_block)?;
+                changed_blocks.insert(small_block_index, ChunkRef::Object(hash));
+            } else {
+                metrics.add_deduplicated(small_block.len() as u64);
             }
 
+            small_block_index += 1;
+            Ok(())
+        };
+
+        let mut size = 0;
+        let mut chunks = chunker.split(file);
+        let mut blocks = Blocks::new();
+        while let Some(chunk) = chunks.next_chunk()? {
+            signal::check()?;
+            metrics.add_read(chunk.len() as u64);
+            size += chunk.len() as u64;
+            blocks.push(chunk, &mut add_block)?;
             metrics.log_progress(false);
         }
 
+        blocks.finish(&mut add_block)?;
+        file_sizes.insert(stripped_path.to_owned(), size);
+
         if !changed_blocks.is_empty() {
             changed_files.insert(stripped_path.to_owned(), changed_blocks);
         }
     }
 
-    bundle.into_inner().unwrap().finish()?;
-    bundle_file.sync_all()?;
+    let HashingWriter { inner, hasher } = bundle.into_inner()?;
+    let bundle_checksum = ChunkRef::Object(hasher.finalize());
+    inner.finish()?;
+    bundle_writer.finish()?;
 
     metrics.log_progress(true);
     Ok(BackupKind::Incremental {
         references: delta_from.id,
         changed_blocks: changed_files,
+        file_sizes: Some(file_sizes),
+        bundle_checksum: Some(bundle_checksum),
     })
 }
 
-fn do_full(ctx: &Context, metrics: &mut Metrics) -> Result<BackupKind> {
+fn do_full(
+    ctx: &Context,
+    metrics: &Metrics,
+    excluded: &RefCell<Vec<PathBuf>>,
+    opts: &BackupOptions,
+) -> Result<BackupKind> {
+    let mut writer = ChunkWriter::new(
+        ctx,
+        opts.compression,
+        opts.zstd_level(),
+        opts.jobs,
+        opts.chunk_size,
+    )?;
     let mut files = HashMap::new();
-    for path in target_files(ctx) {
+    for path in target_files(ctx, &opts.exclude, excluded) {
+        signal::check()?;
         let path = path?;
+        let stripped_path = path.strip_prefix(&ctx.cluster_data)?.to_owned();
+        if path.is_symlink() {
+            files.insert(stripped_path, FileInfo::symlink(fs::read_link(&path)?));
+            continue;
+        }
 
+        let file = File::open(&path)?;
+        let metadata = file.metadata()?;
+        let info = writer.store_file(
+            ctx,
+            metrics,
+            file,
+            metadata.permissions().mode() & 0o7777,
+            Some(metadata.mtime()),
+        )?;
+        files.insert(stripped_path, info);
+    }
+
+    writer.finish(ctx)?;
+    metrics.log_progress(true);
+    Ok(BackupKind::Full { files })
+}
+
+/// Compresses and stores the chunks of a full backup. Chunks the chunker cut
+/// short, which only happens at the end of a file, are collected into packs.
+pub(super) struct ChunkWriter {
+    chunker: Chunker,
+    compressor: ChunkCompressor,
+    packer: Packer,
+}
+
+impl ChunkWriter {
+    pub(super) fn new(
+        ctx: &Context,
+        codec: Codec,
+        level: i32,
+        jobs: u32,
+        chunk_size: usize,
+    ) -> Result<Self> {
+        let manifests = manifest::load_all(ctx)?;
+        Ok(Self {
+            chunker: Chunker::new(chunk_size),
+            compressor: codec.chunk_compressor(level, jobs)?,
+            packer: Packer::new(manifests.values(), codec),
+        })
+    }
+
+    /// Splits `reader` into chunks, storing the ones the repository doesn't
+    /// have yet.
+    pub(super) fn store_file(
+        &mut self,
+        ctx: &Context,
+        metrics: &Metrics,
+        reader: impl Read,
+        mode: u32,
+        mtime: Option<i64>,
+    ) -> Result<FileInfo> {
         let mut chunks = Vec::new();
         let mut blocks = Vec::new();
+        let mut add_block = |small_block: &[u8]| {
+            blocks.push(ChunkRef::Object(blake3::hash(small_block)));
+            Ok(())
+        };
 
-        let file = File::open(&path)?;
-        let mut chunker = Chunker::new(file);
-        while let Some(chunk) = chunker.next_chunk() {
+        let mut size = 0;
+        let min_size = self.chunker.min_size();
+        let mut file_chunks = self.chunker.split(reader);
+        let mut small_blocks = Blocks::new();
+        while let Some(chunk) = file_chunks.next_chunk()? {
+            signal::check()?;
             metrics.add_read(chunk.len() as u64);
-            let hash = blake3::hash(chunk);
-            chunks.push(ChunkRef(hash));
-
-            let checksum = hex::encode(hash.as_bytes());
-            let chunk_path = ctx.storage.join("chunks").join(&checksum);
-
-            if !chunk_path.exists() {
-                let chunk_data = zstd::bulk::compress(chunk, ZSTD_LEVEL).unwrap();
-                let mut chunk_file = File::create_new(&chunk_path)?;
-                chunk_file.write_all(&chunk_data)?;
-                chunk_file.sync_all()?;
-                metrics.add_written(chunk_data.len() as u64);
-            } else {
-                metrics.add_deduplicated(chunk.len() as u64);
-            }
+            size += chunk.len() as u64;
+            chunks.push(store_chunk(
+                ctx,
+                metrics,
+                &mut self.compressor,
+                &mut self.packer,
+                chunk,
+                min_size,
+            )?);
+            small_blocks.push(chunk, &mut add_block)?;
+            metrics.log_progress(false);
+        }
 
-            for small_block in chunk.chunks(8 * 1024) {
-                let hash = blake3::hash(small_block);
-                blocks.push(ChunkRef(hash));
-            }
+        small_blocks.finish(&mut add_block)?;
+        Ok(FileInfo {
+            chunks,
+            blocks,
+            size: Some(size),
+            mode: Some(mode),
+            mtime,
+            link_target: None,
+        })
+    }
 
-            metrics.log_progress(false);
+    /// Writes out the last pack, before the manifest referring to it is saved.
+    pub(super) fn finish(mut self, ctx: &Context) -> Result<()> {
+        self.packer.flush(ctx)
+    }
+}
+
+fn store_chunk(
+    ctx: &Context,
+    metrics: &Metrics,
+    compressor: &mut ChunkCompressor,
+    packer: &mut Packer,
+    chunk: &[u8],
+    min_size: usize,
+) -> Result<ChunkRef> {
+    let hash = blake3::hash(chunk);
+    let object = ChunkRef::Object(hash);
+    let object_key = object.key(compressor.codec());
+    if chunk.len() >= min_size {
+        if !ctx.storage.exists(&object_key)? {
+            let chunk_data = compressor.compress(chunk)?;
+            ctx.storage.write(&object_key, &chunk_data)?;
+            metrics.add_written(chunk_data.len() as u64);
+        } else {
+            metrics.add_deduplicated(chunk.len() as u64);
         }
 
-        let path = path.strip_prefix(&ctx.cluster_data)?;
-        files.insert(path.to_owned(), FileInfo { chunks, blocks });
+        return Ok(object);
     }
 
-    metrics.log_progress(true);
-    Ok(BackupKind::Full { files })
+    if let Some(packed) = packer.find(&hash) {
+        metrics.add_deduplicated(chunk.len() as u64);
+        return Ok(packed.clone());
+    } else if ctx.storage.exists(&object_key)? {
+        metrics.add_deduplicated(chunk.len() as u64);
+        return Ok(object);
+    }
+
+    let chunk_data = compressor.compress(chunk)?;
+    metrics.add_written(chunk_data.len() as u64);
+    packer.add(ctx, hash, &chunk_data)
 }
 
-// TODO: error handling + avoid loading all manifests?
+// TODO: avoid loading all manifests?
 fn block_changed(
     ctx: &Context,
     delta_from: Uuid,
-) -> impl FnMut(&Path, usize, blake3::Hash) -> bool + '_ {
-    let mut manifests = HashMap::new();
-    ctx.storage
-        .join("backups")
-        .read_dir()
-        .unwrap()
-        .for_each(|entry| {
-            let entry = entry.unwrap();
-            let manifest_path = entry.path();
-            let manifest_data = fs::read_to_string(&manifest_path).unwrap();
-            let manifest: Manifest = serde_yaml::from_str(&manifest_data).unwrap();
-            manifests.insert(manifest.id, manifest);
-        });
-
-    move |file, index, hash| {
+) -> Result<impl FnMut(&Path, usize, blake3::Hash) -> bool> {
+    let manifests = manifest::load_all(ctx)?;
+
+    Ok(move |file: &Path, index, hash| {
         let mut node = delta_from;
 
         loop {
             match &manifests[&node].data {
                 BackupKind::Full { files } => {
                     let info = files.get(file);
-                    break info.map(|info| info.blocks.get(index)).flatten()
-                        != Some(&ChunkRef(hash));
+                    break info.and_then(|info| info.blocks.get(index))
+                        != Some(&ChunkRef::Object(hash));
                 },
                 BackupKind::Incremental {
                     references,
                     changed_blocks,
+                    ..
                 } => {
                     let blocks = changed_blocks.get(file);
-                    let chunk_ref = blocks.map(|blocks| blocks.get(&index)).flatten();
+                    let chunk_ref = blocks.and_then(|blocks| blocks.get(&index));
 
                     if let Some(chunk_ref) = chunk_ref {
-                        break chunk_ref != &ChunkRef(hash);
+                        break chunk_ref != &ChunkRef::Object(hash);
                     } else {
                         node = *references;
                     }
                 },
             }
         }
-    }
+    })
 }
 
 // TODO: don't include unnecessary files + error handling
-fn target_files(ctx: &Context) -> impl Iterator<Item = Result<PathBuf>> + '_ {
-    let pred = |path: &Path| {
-        let path = path.strip_prefix(&ctx.cluster_data).unwrap();
-        !matches!(
-            path.components()
-                .nth(0)
-                .map(|c| c.as_os_str().to_str().unwrap()),
-            Some("pg_wal" | "current_logfiles" | "postmaster.pid")
-        )
+// Tablespaces are symlinked from pg_tblspc, following the links puts their
+// files in the backup under that directory. Other symlinks are not followed
+// and are yielded themselves. Paths matching `exclude` are skipped without
+// descending into them, and collected in `excluded`.
+fn target_files<'a>(
+    ctx: &'a Context,
+    exclude: &'a [String],
+    excluded: &'a RefCell<Vec<PathBuf>>,
+) -> impl Iterator<Item = Result<PathBuf>> + 'a {
+    let tablespace_dir = ctx.cluster_data.join("pg_tblspc");
+    let tablespaces = fs::read_dir(&tablespace_dir)
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| Some(entry.ok()?.path()));
+    let pred = move |path: &Path| {
+        let stripped_path = path.strip_prefix(&ctx.cluster_data).unwrap();
+        path.parent() != Some(&tablespace_dir)
+            && !matches!(
+                stripped_path
+                    .components()
+                    .next()
+                    .map(|c| c.as_os_str().to_str().unwrap()),
+                Some("pg_wal" | "current_logfiles" | "postmaster.pid")
+            )
     };
 
-    WalkDir::new(&ctx.cluster_data)
-        .into_iter()
-        .filter(move |entry| {
-            entry
-                .as_ref()
-                .map(|entry| {
-                    entry
-                        .metadata()
-                        .map(|metadata| metadata.is_file() && pred(entry.path()))
+    iter::once(ctx.cluster_data.clone())
+        .chain(tablespaces)
+        .flat_map(move |root| {
+            WalkDir::new(root)
+                .sort_by_file_name()
+                .into_iter()
+                .filter_entry(move |entry| {
+                    let path = entry.path().strip_prefix(&ctx.cluster_data).unwrap();
+                    if is_excluded(exclude, path) {
+                        excluded.borrow_mut().push(path.to_owned());
+                        return false;
+                    }
+
+                    true
                 })
-                .unwrap_or(Ok(true))
-                .unwrap_or(true)
+        })
+        .filter(move |entry| match entry {
+            Ok(entry) =>
+                (entry.file_type().is_file() || entry.path_is_symlink()) && pred(entry.path()),
+            Err(_) => true,
         })
         .map(|entry| {
             entry
@@ -271,7 +1295,82 @@ fn target_files(ctx: &Context) -> impl Iterator<Item = Result<PathBuf>> + '_ {
         })
 }
 
-struct Metrics {
+// The files in the order archives and bundles list them: by path, except for
+// pg_control which comes first. Restores check the system identifier in it
+// before writing anything else, and exports read the bundles of a chain along
+// with each other in this order.
+fn archive_files<'a>(
+    ctx: &'a Context,
+    exclude: &'a [String],
+    excluded: &'a RefCell<Vec<PathBuf>>,
+) -> impl Iterator<Item = Result<PathBuf>> + 'a {
+    let pg_control = ctx.cluster_data.join(PG_CONTROL);
+    let pg_control_excluded = Path::new(PG_CONTROL)
+        .ancestors()
+        .any(|path| is_excluded(exclude, path));
+
+    iter::once(Ok(pg_control.clone()))
+        .filter(move |_| !pg_control_excluded)
+        .chain(
+            target_files(ctx, exclude, excluded)
+                .filter(move |path| !matches!(path, Ok(path) if *path == pg_control)),
+        )
+}
+
+fn is_excluded(exclude: &[String], path: impl AsRef<Path>) -> bool {
+    let path = path.as_ref();
+    !path.as_os_str().is_empty()
+        && exclude
+            .iter()
+            .any(|pattern| manifest::glob_matches(pattern, path))
+}
+
+// Tablespace links are seen both from pg_tblspc and as the root of their walk.
+fn excluded_paths(excluded: &RefCell<Vec<PathBuf>>, exclude: &[String]) -> Vec<PathBuf> {
+    let mut excluded = excluded.borrow().clone();
+    excluded.sort();
+    excluded.dedup();
+    for pattern in exclude {
+        if !excluded
+            .iter()
+            .any(|path| manifest::glob_matches(pattern, path))
+        {
+            warn!("--exclude {} matched nothing", pattern);
+        }
+    }
+
+    excluded
+}
+
+#[derive(Serialize)]
+struct ProgressRecord {
+    read_mib: f64,
+    read_rate: f64,
+    written_mib: f64,
+    written_rate: f64,
+    ratio: f64,
+    elapsed_secs: f64,
+}
+
+// What --report writes once the backup is done, for scripts around it to read
+// instead of the log.
+#[derive(Debug, Serialize)]
+struct BackupReport {
+    label: String,
+    id: Uuid,
+    created_at: i64,
+    bytes_read: u64,
+    bytes_written: u64,
+    compression_ratio: f64,
+    duration_secs: f64,
+    start_wal: String,
+}
+
+pub(super) struct Metrics {
+    compression: Codec,
+    compression_level: i32,
+    format: ProgressFormat,
+    interval: Duration,
     start_time: Instant,
     last_log_time: Cell<Instant>,
     read_bytes: Cell<u64>,
@@ -280,8 +1379,17 @@ struct Metrics {
 }
 
 impl Metrics {
-    fn new() -> Self {
+    pub(super) fn new(
+        compression: Codec,
+        compression_level: i32,
+        format: ProgressFormat,
+        interval: Duration,
+    ) -> Self {
         Self {
+            compression,
+            compression_level,
+            format,
+            interval,
             start_time: Instant::now(),
             last_log_time: Cell::new(Instant::now()),
             read_bytes: Cell::new(0),
@@ -310,9 +1418,14 @@ impl Metrics {
             .set(self.deduplicated_bytes.get() + bytes);
     }
 
-    fn log_progress(&self, last: bool) {
-        if last || self.last_log_time.get().elapsed() >= PROGRESS_LOG_INTERVAL {
+    pub(super) fn log_progress(&self, last: bool) {
+        if last || self.last_log_time.get().elapsed() >= self.interval {
             self.last_log_time.set(Instant::now());
+            if let ProgressFormat::Json = self.format {
+                self.write_record();
+                return;
+            }
+
             let read_bytes = self.read_bytes.get();
             let deduplicated_bytes = self.deduplicated_bytes.get();
             let written_bytes = self.written_bytes.get();
@@ -321,17 +1434,123 @@ impl Metrics {
             let throughput = read_bytes as f32 / elapsed_secs / 1024.0 / 1024.0;
             info!(
                 "{}read: {} MiB, dedup: {} MiB ({:.2}%), write: {} MiB, compression ratio: \
-                 {:.2}x, throughput: {:.2} MiB/s",
+                 {:.2}x{}, throughput: {:.2} MiB/s",
                 if !last { "progress: " } else { "" },
                 read_bytes / 1024 / 1024,
                 deduplicated_bytes / 1024 / 1024,
                 dedup_ratio * 100.0,
                 written_bytes / 1024 / 1024,
                 (read_bytes - deduplicated_bytes) as f32 / written_bytes as f32,
+                if !last {
+                    String::new()
+                } else if self.compression == Codec::Zstd {
+                    format!(" (zstd level {})", self.compression_level)
+                } else {
+                    format!(" ({})", self.compression.name())
+                },
                 throughput
             );
         }
     }
+
+    fn write_prometheus(&self, path: &Path) -> Result<()> {
+        let read_bytes = self.read_bytes.get();
+        let written_bytes = self.written_bytes.get();
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+
+        let mut contents = String::new();
+        for (name, help, value) in [
+            (
+                "pgpitr_backup_bytes_read",
+                "Bytes read from the data directory.",
+                read_bytes.to_string(),
+            ),
+            (
+                "pgpitr_backup_bytes_written",
+                "Bytes written to the repository after deduplication and compression.",
+                written_bytes.to_string(),
+            ),
+            (
+                "pgpitr_backup_duration_seconds",
+                "How long the backup took.",
+                self.start_time.elapsed().as_secs_f64().to_string(),
+            ),
+            (
+                "pgpitr_backup_compression_ratio",
+                "Ratio of new data read to bytes written.",
+                self.compression_ratio().to_string(),
+            ),
+            (
+                "pgpitr_backup_last_success_timestamp",
+                "Unix time the last successful backup finished.",
+                now.to_string(),
+            ),
+        ] {
+            contents.push_str(&format!(
+                "# HELP {name} {help}\n# TYPE {name} gauge\n{name} {value}\n"
+            ));
+        }
+
+        write_replacing(path, contents.as_bytes())
+    }
+
+    fn write_report(&self, path: &Path, manifest: &Manifest) -> Result<()> {
+        let report = BackupReport {
+            label: manifest.label.clone(),
+            id: manifest.id,
+            created_at: manifest.created_at.unix_timestamp(),
+            bytes_read: self.read_bytes.get(),
+            bytes_written: self.written_bytes.get(),
+            compression_ratio: self.compression_ratio(),
+            duration_secs: self.start_time.elapsed().as_secs_f64(),
+            start_wal: manifest.start_wal.clone(),
+        };
+
+        let mut contents = serde_json::to_vec_pretty(&report)?;
+        contents.push(b'\n');
+        write_replacing(path, &contents)
+    }
+
+    // New data read per byte written, 0 when nothing was written.
+    fn compression_ratio(&self) -> f64 {
+        let written_bytes = self.written_bytes.get();
+        if written_bytes > 0 {
+            (self.read_bytes.get() - self.deduplicated_bytes.get()) as f64 / written_bytes as f64
+        } else {
+            0.0
+        }
+    }
+
+    fn write_record(&self) {
+        let mib = |bytes: u64| bytes as f64 / 1024.0 / 1024.0;
+        let read_bytes = self.read_bytes.get();
+        let written_bytes = self.written_bytes.get();
+        let elapsed_secs = self.start_time.elapsed().as_secs_f64();
+        let record = ProgressRecord {
+            read_mib: mib(read_bytes),
+            read_rate: mib(read_bytes) / elapsed_secs,
+            written_mib: mib(written_bytes),
+            written_rate: mib(written_bytes) / elapsed_secs,
+            ratio: (read_bytes - self.deduplicated_bytes.get()) as f64 / written_bytes as f64,
+            elapsed_secs,
+        };
+
+        // Progress is best effort, a closed stdout shouldn't fail the backup.
+        let mut stdout = io::stdout().lock();
+        let _ = serde_json::to_writer(&mut stdout, &record);
+        let _ = writeln!(stdout);
+    }
+}
+
+// Whatever reads `path` may do so at any time, so it is written next to it and
+// renamed into place.
+fn write_replacing(path: &Path, contents: &[u8]) -> Result<()> {
+    let mut temp_path = path.to_owned().into_os_string();
+    temp_path.push(".tmp");
+    fs::write(&temp_path, contents)
+        .with_context(|| format!("failed to write {}", path.display()))?;
+    fs::rename(&temp_path, path).with_context(|| format!("failed to write {}", path.display()))?;
+    Ok(())
 }
 
 struct TrackedWriter<'tracker, W> {
@@ -354,46 +1573,77 @@ where
     }
 }
 
-struct Chunker<R> {
-    reader: R,
-    buffer: Vec<u8>,
-    buffer_pos: usize,
+// Hashes the uncompressed bundle so it can be checked end to end, independent
+// of the compression.
+struct HashingWriter<W> {
+    inner: W,
+    hasher: blake3::Hasher,
 }
 
-impl<R> Chunker<R>
-where
-    R: Read,
-{
-    fn new(reader: R) -> Self {
+impl<W> HashingWriter<W> {
+    fn new(inner: W) -> Self {
         Self {
-            reader,
-            buffer: vec![0; 4 * 1024 * 1024],
-            buffer_pos: 0,
+            inner,
+            hasher: blake3::Hasher::new(),
         }
     }
+}
 
-    fn next_chunk(&mut self) -> Option<&[u8]> {
-        loop {
-            let read = self
-                .reader
-                .read(&mut self.buffer[self.buffer_pos..])
-                .unwrap();
-            if read == 0 {
-                if self.buffer_pos == 0 {
-                    return None;
-                } else {
-                    let buf = &self.buffer[..self.buffer_pos];
-                    self.buffer_pos = 0;
-                    return Some(buf);
-                }
-            }
+impl<W> Write for HashingWriter<W>
+where
+    W: Write,
+{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.inner.write(buf)?;
+        self.hasher.update(&buf[..len]);
+        Ok(len)
+    }
 
-            self.buffer_pos += read;
-            if self.buffer_pos == self.buffer.len() {
-                let buf = &self.buffer[..self.buffer_pos];
-                self.buffer_pos = 0;
-                return Some(buf);
-            }
-        }
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_wal_segment, wal_segment_name, wal_segments};
+
+    const SEGMENT_SIZE: u64 = 16 * 1024 * 1024;
+
+    #[test]
+    fn wal_segments_between_start_and_stop() {
+        assert_eq!(
+            wal_segments("000000010000000000000009", 0x9000138, SEGMENT_SIZE).unwrap(),
+            ["000000010000000000000009"]
+        );
+        assert_eq!(
+            wal_segments("000000010000000000000009", 0xB000000, SEGMENT_SIZE).unwrap(),
+            ["000000010000000000000009", "00000001000000000000000A"]
+        );
+        assert_eq!(
+            wal_segments("0000000200000000000000FF", 0x1_0100_0028, SEGMENT_SIZE).unwrap(),
+            [
+                "0000000200000000000000FF",
+                "000000020000000100000000",
+                "000000020000000100000001"
+            ]
+        );
+    }
+
+    #[test]
+    fn wal_segment_names() {
+        assert_eq!(
+            parse_wal_segment("000000020000000100000003", SEGMENT_SIZE),
+            Some((2, 0x103))
+        );
+        assert_eq!(
+            wal_segment_name(2, 0x103, SEGMENT_SIZE),
+            "000000020000000100000003"
+        );
+        assert_eq!(parse_wal_segment("00000002.history", SEGMENT_SIZE), None);
+        assert_eq!(
+            parse_wal_segment("00000002000000010000000G", SEGMENT_SIZE),
+            None
+        );
     }
 }
@@ -0,0 +1,112 @@
+use std::{
+    collections::HashSet,
+    io::{self, IsTerminal, Write},
+};
+
+use anyhow::{anyhow, bail, Result};
+use clap::Args;
+use log::{info, warn};
+
+use super::{
+    manifest,
+    prune::{chunk_keys, remove_backup},
+};
+use crate::context::Context;
+
+#[derive(Debug, Args)]
+pub struct Options {
+    #[arg(long)]
+    pub label: String,
+
+    /// Delete the backup even if it is pinned or other backups depend on it
+    #[arg(long)]
+    pub force: bool,
+
+    /// Don't ask for confirmation
+    #[arg(long, short)]
+    pub yes: bool,
+
+    /// Wait for other operations on the repository to finish instead of
+    /// failing
+    #[arg(long)]
+    pub wait: bool,
+}
+
+// The manifest is removed before the data, so a delete that is interrupted
+// leaves no backup behind, only data no manifest refers to. Running it again
+// finds no backup by that label, gc removes that data instead.
+pub fn run(ctx: &Context, opts: &Options) -> Result<()> {
+    let _lock = ctx.storage.lock(opts.wait)?;
+    let manifests = manifest::load_all(ctx)?;
+    let backup = manifests
+        .values()
+        .find(|manifest| manifest.label == opts.label)
+        .ok_or_else(|| {
+            anyhow!(
+                "backup {} not found, if deleting it was interrupted run gc to remove the data it \
+                 left behind",
+                opts.label
+            )
+        })?;
+
+    if backup.pinned && !opts.force {
+        bail!(
+            "backup {} is pinned, unpin it or use --force to delete it anyway",
+            backup.label
+        );
+    }
+
+    let mut dependents = manifests
+        .values()
+        .filter(|manifest| manifest.depends_on(backup.id, &manifests))
+        .map(|manifest| manifest.label.as_str())
+        .collect::<Vec<_>>();
+    dependents.sort();
+
+    if !dependents.is_empty() {
+        if !opts.force {
+            bail!(
+                "backup {} is needed to restore {}, use --force to delete it anyway",
+                backup.label,
+                dependents.join(", ")
+            );
+        }
+
+        warn!("{} will no longer be restorable", dependents.join(", "));
+    }
+
+    if !opts.yes && !confirm(&format!("delete backup {} ({})?", backup.label, backup.id))? {
+        bail!("aborted");
+    }
+
+    let mut retained_chunks = manifests
+        .values()
+        .filter(|manifest| manifest.id != backup.id)
+        .flat_map(chunk_keys)
+        .collect::<HashSet<_>>();
+
+    let (removed, reclaimed) = remove_backup(ctx, backup, &mut retained_chunks, false)?;
+    info!(
+        "removed backup {} ({}): {} objects, {:.2} MiB reclaimed",
+        backup.label,
+        backup.id,
+        removed,
+        reclaimed as f64 / 1024.0 / 1024.0
+    );
+
+    Ok(())
+}
+
+fn confirm(prompt: &str) -> Result<bool> {
+    let stdin = io::stdin();
+    if !stdin.is_terminal() {
+        bail!("refusing to delete without confirmation, use --yes when not running interactively");
+    }
+
+    eprint!("{} [y/N] ", prompt);
+    io::stderr().flush()?;
+
+    let mut answer = String::new();
+    stdin.read_line(&mut answer)?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
@@ -0,0 +1,153 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    io,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Result;
+use clap::Args;
+use serde::Serialize;
+use uuid::Uuid;
+
+use super::manifest::{self, BackupKind, ChunkRef, Manifest, BLOCK_SIZE};
+use crate::context::Context;
+
+#[derive(Debug, Args)]
+pub struct Options {
+    /// Label of the older backup
+    #[arg(long)]
+    pub from: String,
+
+    /// Label of the newer backup
+    #[arg(long)]
+    pub to: String,
+
+    #[arg(long)]
+    pub json: bool,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum Change {
+    Added,
+    Removed,
+    Changed,
+}
+
+#[derive(Debug, Serialize)]
+struct FileDiff {
+    path: PathBuf,
+    change: Change,
+    changed_bytes: u64,
+}
+
+#[derive(Debug, Serialize)]
+struct Report {
+    files: Vec<FileDiff>,
+    added: usize,
+    removed: usize,
+    changed: usize,
+    changed_bytes: u64,
+}
+
+// Files are compared by the hashes of their blocks, which incremental backups
+// record for what they changed on top of the blocks of the backups before them.
+// Byte counts are estimates in whole blocks.
+pub fn run(ctx: &Context, opts: &Options) -> Result<()> {
+    let manifests = manifest::load_all(ctx)?;
+    let from = file_blocks(manifest::find(&manifests, &opts.from)?, &manifests)?;
+    let to = file_blocks(manifest::find(&manifests, &opts.to)?, &manifests)?;
+
+    let paths = from.keys().chain(to.keys()).collect::<BTreeSet<_>>();
+    let mut files = Vec::new();
+    for path in paths {
+        let (change, changed_blocks) = match (from.get(path), to.get(path)) {
+            (Some(from), Some(to)) => {
+                let differing = from.iter().zip(to).filter(|(a, b)| a != b).count();
+                let changed_blocks = differing + from.len().abs_diff(to.len());
+                if changed_blocks == 0 {
+                    continue;
+                }
+
+                (Change::Changed, changed_blocks)
+            },
+            (None, Some(to)) => (Change::Added, to.len()),
+            (Some(from), None) => (Change::Removed, from.len()),
+            (None, None) => unreachable!(),
+        };
+
+        files.push(FileDiff {
+            path: path.to_path_buf(),
+            change,
+            changed_bytes: (changed_blocks * BLOCK_SIZE) as u64,
+        });
+    }
+
+    let count =
+        |change: fn(&Change) -> bool| files.iter().filter(|file| change(&file.change)).count();
+    let report = Report {
+        added: count(|change| matches!(change, Change::Added)),
+        removed: count(|change| matches!(change, Change::Removed)),
+        changed: count(|change| matches!(change, Change::Changed)),
+        changed_bytes: files.iter().map(|file| file.changed_bytes).sum(),
+        files,
+    };
+
+    if opts.json {
+        serde_json::to_writer_pretty(io::stdout().lock(), &report)?;
+        println!();
+        return Ok(());
+    }
+
+    for file in &report.files {
+        println!(
+            "{} {:>12} {}",
+            match file.change {
+                Change::Added => "A",
+                Change::Removed => "D",
+                Change::Changed => "M",
+            },
+            file.changed_bytes,
+            file.path.display()
+        );
+    }
+
+    println!(
+        "{} added, {} removed, {} changed, ~{:.2} MiB changed",
+        report.added,
+        report.removed,
+        report.changed,
+        report.changed_bytes as f64 / 1024.0 / 1024.0
+    );
+    Ok(())
+}
+
+// The block hashes of every file as of `manifest`, applying the changes of
+// each backup in its chain in order.
+fn file_blocks<'a>(
+    manifest: &'a Manifest,
+    manifests: &'a HashMap<Uuid, Manifest>,
+) -> Result<HashMap<&'a Path, Vec<&'a ChunkRef>>> {
+    let mut files = HashMap::new();
+    for backup in manifest.chain(manifests)? {
+        match &backup.data {
+            BackupKind::Full { files: full } =>
+                for (path, info) in full {
+                    files.insert(path.as_path(), info.blocks.iter().collect());
+                },
+            BackupKind::Incremental { changed_blocks, .. } =>
+                for (path, blocks) in changed_blocks {
+                    let file = files.entry(path.as_path()).or_insert_with(Vec::new);
+                    for (&index, hash) in blocks {
+                        if file.len() <= index {
+                            file.resize(index + 1, hash);
+                        }
+
+                        file[index] = hash;
+                    }
+                },
+        }
+    }
+
+    Ok(files)
+}
@@ -0,0 +1,196 @@
+use std::collections::HashMap;
+
+use anyhow::Result;
+use clap::Args;
+
+use super::{
+    manifest::{self, BackupKind},
+    prune::stored_chunks,
+};
+use crate::context::Context;
+
+#[derive(Debug, Args)]
+pub struct Options {}
+
+#[derive(Debug, Default, PartialEq)]
+struct Usage {
+    // Freed if only this backup were deleted.
+    exclusive: u64,
+    // Also referenced by other backups.
+    shared: u64,
+}
+
+pub fn run(ctx: &Context, _opts: &Options) -> Result<()> {
+    let manifests = manifest::load_all(ctx)?;
+    let mut backups = manifests.values().collect::<Vec<_>>();
+    backups.sort_by_key(|manifest| manifest.created_at);
+
+    // How many bytes of each object every backup refers to, for a pack only
+    // its chunks the backup uses.
+    let mut sizes = HashMap::new();
+    let mut objects = Vec::new();
+    for backup in &backups {
+        let mut referenced = HashMap::new();
+        match &backup.data {
+            BackupKind::Full { .. } =>
+                for ((key, _), size) in stored_chunks(ctx, backup, &mut sizes)? {
+                    *referenced.entry(key).or_default() += size;
+                },
+            BackupKind::Incremental { .. } => {
+                let key = backup.bundle_key();
+                let size = ctx.storage.size(&key)?;
+                sizes.insert(key.clone(), size);
+                referenced.insert(key, size);
+            },
+        }
+
+        objects.push(referenced);
+    }
+
+    println!(
+        "{:<24} {:>16} {:>16}",
+        "LABEL", "EXCLUSIVE (MiB)", "SHARED (MiB)"
+    );
+
+    let mib = |bytes: u64| bytes as f64 / 1024.0 / 1024.0;
+    for (backup, usage) in backups.iter().zip(usage(&objects, &sizes)) {
+        println!(
+            "{:<24} {:>16.2} {:>16.2}",
+            backup.label,
+            mib(usage.exclusive),
+            mib(usage.shared)
+        );
+    }
+
+    Ok(())
+}
+
+// Splits what each backup references into the objects only it references,
+// which deleting it frees whole, and its part of the objects others reference
+// too.
+fn usage(objects: &[HashMap<String, u64>], sizes: &HashMap<String, u64>) -> Vec<Usage> {
+    let mut references = HashMap::<&str, usize>::new();
+    for key in objects.iter().flat_map(HashMap::keys) {
+        *references.entry(key).or_default() += 1;
+    }
+
+    objects
+        .iter()
+        .map(|referenced| {
+            let mut usage = Usage::default();
+            for (key, size) in referenced {
+                if references[key.as_str()] == 1 {
+                    usage.exclusive += sizes[key];
+                } else {
+                    usage.shared += size;
+                }
+            }
+
+            usage
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::{usage, Usage};
+
+    // Backups referring to all of each object.
+    fn objects(backups: &[&[&str]]) -> Vec<HashMap<String, u64>> {
+        let sizes = sizes(&[("s", 100), ("p", 10), ("a", 1), ("b", 2), ("c", 4)]);
+        backups
+            .iter()
+            .map(|keys| {
+                keys.iter()
+                    .map(|key| (key.to_string(), sizes[*key]))
+                    .collect()
+            })
+            .collect()
+    }
+
+    fn sizes(sizes: &[(&str, u64)]) -> HashMap<String, u64> {
+        sizes
+            .iter()
+            .map(|(key, size)| (key.to_string(), *size))
+            .collect()
+    }
+
+    #[test]
+    fn unshared() {
+        assert_eq!(
+            usage(
+                &objects(&[&["a"], &["b", "c"]]),
+                &sizes(&[("a", 1), ("b", 2), ("c", 4)])
+            ),
+            vec![
+                Usage {
+                    exclusive: 1,
+                    shared: 0
+                },
+                Usage {
+                    exclusive: 6,
+                    shared: 0
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn shared_by_three() {
+        // "s" is in all three backups, "p" in the first two, deleting any one
+        // backup frees neither.
+        assert_eq!(
+            usage(
+                &objects(&[&["s", "p", "a"], &["s", "p"], &["s", "c"]]),
+                &sizes(&[("s", 100), ("p", 10), ("a", 1), ("c", 4)])
+            ),
+            vec![
+                Usage {
+                    exclusive: 1,
+                    shared: 110
+                },
+                Usage {
+                    exclusive: 0,
+                    shared: 110
+                },
+                Usage {
+                    exclusive: 4,
+                    shared: 100
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn packs() {
+        // Both backups use some of "pack", only the first has all of "own".
+        let objects = vec![
+            HashMap::from([("pack".to_owned(), 30), ("own".to_owned(), 5)]),
+            HashMap::from([("pack".to_owned(), 2)]),
+        ];
+        assert_eq!(
+            usage(&objects, &sizes(&[("pack", 1000), ("own", 5)])),
+            vec![
+                Usage {
+                    exclusive: 5,
+                    shared: 30
+                },
+                Usage {
+                    exclusive: 0,
+                    shared: 2
+                },
+            ]
+        );
+
+        // Once only one backup uses it, deleting that backup frees all of it.
+        assert_eq!(
+            usage(&objects[1..], &sizes(&[("pack", 1000)])),
+            vec![Usage {
+                exclusive: 1000,
+                shared: 0
+            }]
+        );
+    }
+}
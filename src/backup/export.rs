@@ -0,0 +1,460 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::{self, File},
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context as _, Result};
+use clap::Args;
+use log::info;
+use scopeguard::{guard, ScopeGuard};
+
+use super::{
+    codec::Codec,
+    manifest::{self, BackupKind, FileInfo, Manifest, BLOCK_SIZE, PG_CONTROL},
+    restore::REQUIRED_DIRS,
+};
+use crate::context::Context;
+
+#[derive(Debug, Args)]
+pub struct Options {
+    /// Label of the backup, or "latest" for the newest one
+    #[arg(long)]
+    pub label: String,
+
+    /// File to write the tar archive to, or - for stdout
+    #[arg(long, value_name = "FILE")]
+    pub out: PathBuf,
+
+    #[arg(long, value_enum, default_value_t = Codec::None)]
+    pub compress: Codec,
+}
+
+// Files are put together the same way a restore would write them, so the
+// archive extracts to the same data directory a restore produces.
+pub fn run(ctx: &Context, opts: &Options) -> Result<()> {
+    let manifests = manifest::load_all(ctx)?;
+    let manifest = manifest::find(&manifests, &opts.label)?;
+    let chain = manifest.chain(&manifests)?;
+    let file_sizes = manifest.file_sizes()?;
+
+    let Some(backup_label) = &manifest.backup_label else {
+        bail!(
+            "backup {} has no backup_label and cannot be restored consistently",
+            manifest.label
+        );
+    };
+
+    // Same as for create-backup --output, tablespaces have nowhere to go in
+    // a single archive.
+    if manifest.tablespaces().next().is_some() {
+        bail!("backups of clusters with tablespaces can't be exported");
+    }
+
+    let to_stdout = opts.out == Path::new("-");
+    let mut partial_path = opts.out.clone().into_os_string();
+    partial_path.push(".partial");
+    let partial_path = guard(PathBuf::from(partial_path), |path| {
+        if !to_stdout {
+            let _ = fs::remove_file(path);
+        }
+    });
+    let file = if to_stdout {
+        None
+    } else {
+        Some(
+            File::create(&*partial_path)
+                .with_context(|| format!("failed to create {}", partial_path.display()))?,
+        )
+    };
+    let out: Box<dyn Write> = match &file {
+        Some(file) => Box::new(file),
+        None => Box::new(io::stdout().lock()),
+    };
+
+    let archive = Archive {
+        ctx,
+        manifest,
+        chain: &chain,
+        file_sizes,
+        backup_label,
+    };
+    let mut out = archive.write(opts.compress.encoder(out, 3, 1)?)?.finish()?;
+
+    out.flush()?;
+    drop(out);
+    if let Some(file) = file {
+        file.sync_all()?;
+        fs::rename(&*partial_path, &opts.out)?;
+    }
+
+    ScopeGuard::into_inner(partial_path);
+    info!("exported backup {}", manifest.label);
+    Ok(())
+}
+
+struct Archive<'a> {
+    ctx: &'a Context,
+    manifest: &'a Manifest,
+    chain: &'a [&'a Manifest],
+    file_sizes: Option<&'a HashMap<PathBuf, u64>>,
+    backup_label: &'a str,
+}
+
+impl Archive<'_> {
+    fn write<W>(&self, out: W) -> Result<W>
+    where
+        W: Write,
+    {
+        let mtime = self.manifest.created_at.unix_timestamp().max(0) as u64;
+        let Some((base, codec)) = self.chain.first().and_then(|backup| match &backup.data {
+            BackupKind::Full { files } => Some((files, backup.chunk_compression)),
+            BackupKind::Incremental { .. } => None,
+        }) else {
+            bail!(
+                "backup {} has no full backup in its chain",
+                self.manifest.label
+            );
+        };
+
+        // The bundles of the incrementals are read along with each other, a
+        // file at a time, and their blocks written over the chunks of the full
+        // backup as the file goes into the archive.
+        let mut bundle_archives = Vec::new();
+        for backup in &self.chain[1..] {
+            let bundle_key = backup.bundle_key();
+            let decoder = backup
+                .compression
+                .decoder(self.ctx.storage.open_reader(&bundle_key)?)?;
+            bundle_archives.push((bundle_key, tar::Archive::new(decoder)));
+        }
+        let mut bundles = bundle_archives
+            .iter_mut()
+            .map(|(key, archive)| Bundle::new(key, archive))
+            .collect::<Result<Vec<_>>>()?;
+
+        // As in a restore, files the incremental doesn't list were deleted and
+        // the rest are cut to the size it records.
+        let mut paths = base
+            .keys()
+            .chain(self.file_sizes.into_iter().flat_map(|sizes| sizes.keys()))
+            .collect::<Vec<_>>();
+        paths.sort_by_key(|path| archive_order(path));
+        paths.dedup();
+
+        let mut archive = tar::Builder::new(out);
+        for path in paths {
+            let info = base.get(path);
+            let is_symlink = info.is_some_and(|info| info.link_target.is_some());
+            let size = self.file_sizes.map(|sizes| sizes.get(path).copied());
+            if size == Some(None) && !is_symlink {
+                continue;
+            }
+
+            if let Some(target) = info.and_then(|info| info.link_target.as_ref()) {
+                let mut header = tar::Header::new_gnu();
+                header.set_entry_type(tar::EntryType::Symlink);
+                header.set_mode(0o777);
+                header.set_mtime(mtime);
+                header.set_size(0);
+                archive.append_link(&mut header, path, target)?;
+                continue;
+            }
+
+            for bundle in &mut bundles {
+                bundle.seek(path)?;
+            }
+            // Files the full backup doesn't have take their mode from the
+            // latest bundle with blocks of them.
+            let changed_mode = match bundles
+                .iter()
+                .rev()
+                .find_map(|bundle| bundle.next.as_ref().filter(|block| block.path == *path))
+            {
+                Some(block) => block.entry.header().mode()?,
+                None => 0,
+            };
+
+            let mut contents =
+                FileContents::new(self.ctx, codec, info, path, &mut bundles, size.flatten())?;
+
+            let mut header = tar::Header::new_gnu();
+            header.set_size(contents.size);
+            header.set_mode(info.and_then(|info| info.mode).unwrap_or(changed_mode));
+            header.set_mtime(
+                info.and_then(|info| info.mtime)
+                    .map_or(mtime, |mtime| mtime.max(0) as u64),
+            );
+            archive.append_data(&mut header, path, &mut contents)?;
+        }
+
+        for dir in REQUIRED_DIRS {
+            let mut header = tar::Header::new_gnu();
+            header.set_entry_type(tar::EntryType::Directory);
+            header.set_mode(0o700);
+            header.set_mtime(mtime);
+            header.set_size(0);
+            archive.append_data(&mut header, dir, io::empty())?;
+        }
+
+        let mut header = tar::Header::new_gnu();
+        header.set_size(self.backup_label.len() as u64);
+        header.set_mode(0o600);
+        header.set_mtime(mtime);
+        archive.append_data(&mut header, "backup_label", self.backup_label.as_bytes())?;
+
+        Ok(archive.into_inner()?)
+    }
+}
+
+// The order archives and bundles list files in: pg_control first, as restores
+// check it before writing anything else, and the rest by path.
+fn archive_order(path: &Path) -> (bool, &Path) {
+    (path != Path::new(PG_CONTROL), path)
+}
+
+// The bundle of an incremental, read along with the archive being written.
+// Bundles hold the changed blocks of each file as tar entries named
+// `{file}/{block index}`, with the files in archive order.
+struct Bundle<'a, R>
+where
+    R: Read,
+{
+    key: &'a str,
+    entries: tar::Entries<'a, R>,
+    next: Option<Block<'a, R>>,
+}
+
+struct Block<'a, R>
+where
+    R: Read,
+{
+    path: PathBuf,
+    index: u64,
+    entry: tar::Entry<'a, R>,
+}
+
+impl<'a, R> Bundle<'a, R>
+where
+    R: Read,
+{
+    fn new(key: &'a str, archive: &'a mut tar::Archive<R>) -> Result<Self> {
+        let mut bundle = Self {
+            key,
+            entries: archive.entries()?,
+            next: None,
+        };
+        bundle.advance()?;
+        Ok(bundle)
+    }
+
+    fn advance(&mut self) -> Result<()> {
+        let previous = self.next.take().map(|block| block.path);
+        let Some(entry) = self.entries.next() else {
+            return Ok(());
+        };
+
+        let entry = entry?;
+        let block_path = entry.path()?.into_owned();
+        let (Some(path), Some(index)) = (
+            block_path.parent(),
+            block_path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .and_then(|name| name.parse::<u64>().ok()),
+        ) else {
+            bail!(
+                "invalid block entry {:?} in bundle {}",
+                block_path,
+                self.key
+            );
+        };
+
+        if previous.is_some_and(|previous| archive_order(path) < archive_order(&previous)) {
+            bail!(
+                "bundle {} doesn't list its files in order and can't be exported, restore the \
+                 backup instead",
+                self.key
+            );
+        }
+
+        self.next = Some(Block {
+            path: path.to_owned(),
+            index,
+            entry,
+        });
+        Ok(())
+    }
+
+    // Skips the blocks of files before `path`, which were deleted later on.
+    fn seek(&mut self, path: &Path) -> Result<()> {
+        while self
+            .next
+            .as_ref()
+            .is_some_and(|block| archive_order(&block.path) < archive_order(path))
+        {
+            self.advance()?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Reads a file as restore would leave it: the chunks of the full backup with
+/// the blocks of the bundles written over them, later bundles winning, and
+/// zeros in any gap before a block past the end of the original file. Blocks
+/// are read from the bundles as the file gets to them. Files are cut to the
+/// size an incremental records for them.
+struct FileContents<'a, 'b, R>
+where
+    R: Read,
+{
+    ctx: &'a Context,
+    codec: Codec,
+    chunks: &'a [manifest::ChunkRef],
+    path: &'a Path,
+    bundles: &'a mut [Bundle<'b, R>],
+    changed: BTreeMap<u64, Vec<u8>>,
+    buffer: Vec<u8>,
+    buffer_pos: usize,
+    offset: u64,
+    size: u64,
+}
+
+impl<'a, 'b, R> FileContents<'a, 'b, R>
+where
+    R: Read,
+{
+    fn new(
+        ctx: &'a Context,
+        codec: Codec,
+        info: Option<&'a FileInfo>,
+        path: &'a Path,
+        bundles: &'a mut [Bundle<'b, R>],
+        size: Option<u64>,
+    ) -> Result<Self> {
+        // Chains with incrementals always record the size.
+        let size = match (size, info) {
+            (Some(size), _) => size,
+            (
+                None,
+                Some(FileInfo {
+                    size: Some(size), ..
+                }),
+            ) => *size,
+            (None, Some(info)) => chunks_size(ctx, codec, &info.chunks)?,
+            (None, None) => 0,
+        };
+
+        Ok(Self {
+            ctx,
+            codec,
+            chunks: info.map(|info| &info.chunks[..]).unwrap_or_default(),
+            path,
+            bundles,
+            changed: BTreeMap::new(),
+            buffer: Vec::new(),
+            buffer_pos: 0,
+            offset: 0,
+            size,
+        })
+    }
+
+    fn fill(&mut self) -> io::Result<()> {
+        self.buffer.clear();
+        self.buffer_pos = 0;
+        match self.chunks.split_first() {
+            Some((chunk, rest)) => {
+                self.chunks = rest;
+                chunk
+                    .read(self.ctx, self.codec, &mut self.buffer)
+                    .map_err(io::Error::other)?;
+            },
+            None => {
+                let len = (self.size - self.offset).min(BLOCK_SIZE as u64);
+                self.buffer.resize(len as usize, 0);
+            },
+        }
+
+        Ok(())
+    }
+
+    // Reads the blocks starting before `end` from the bundles. Going through
+    // them in the order of the chain has later ones replace earlier ones.
+    fn read_blocks(&mut self, end: u64) -> Result<()> {
+        for bundle in self.bundles.iter_mut() {
+            while let Some(block) = bundle
+                .next
+                .as_mut()
+                .filter(|block| block.path == self.path && block.index * (BLOCK_SIZE as u64) < end)
+            {
+                let mut data = Vec::with_capacity(BLOCK_SIZE);
+                block.entry.read_to_end(&mut data)?;
+                self.changed.insert(block.index, data);
+                bundle.advance()?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl<R> Read for FileContents<'_, '_, R>
+where
+    R: Read,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.offset >= self.size {
+            return Ok(0);
+        }
+
+        if self.buffer_pos == self.buffer.len() {
+            self.fill()?;
+        }
+
+        let len = buf
+            .len()
+            .min(self.buffer.len() - self.buffer_pos)
+            .min((self.size - self.offset) as usize);
+        buf[..len].copy_from_slice(&self.buffer[self.buffer_pos..self.buffer_pos + len]);
+
+        let start = self.offset;
+        let end = start + len as u64;
+        self.read_blocks(end).map_err(io::Error::other)?;
+        let first_block = start / BLOCK_SIZE as u64;
+        for (index, data) in self.changed.range(first_block..) {
+            let block_start = index * BLOCK_SIZE as u64;
+            if block_start >= end {
+                break;
+            }
+
+            let from = start.max(block_start);
+            let to = end.min(block_start + data.len() as u64);
+            if from < to {
+                buf[(from - start) as usize..(to - start) as usize].copy_from_slice(
+                    &data[(from - block_start) as usize..(to - block_start) as usize],
+                );
+            }
+        }
+
+        // Blocks that end before `end` aren't needed anymore.
+        self.changed = self.changed.split_off(&(end / BLOCK_SIZE as u64));
+        self.buffer_pos += len;
+        self.offset = end;
+        Ok(len)
+    }
+}
+
+// Only backups from before sizes were recorded get here.
+fn chunks_size(ctx: &Context, codec: Codec, chunks: &[manifest::ChunkRef]) -> Result<u64> {
+    let mut size = 0;
+    for chunk in chunks {
+        size += io::copy(
+            &mut codec.decoder(chunk.open(ctx, codec)?)?,
+            &mut io::sink(),
+        )?;
+    }
+
+    Ok(size)
+}
@@ -0,0 +1,66 @@
+use std::collections::HashSet;
+
+use anyhow::Result;
+use clap::Args;
+use log::info;
+
+use super::{
+    manifest::{self, BackupKind},
+    prune::{chunk_keys, delete},
+};
+use crate::context::Context;
+
+#[derive(Debug, Args)]
+pub struct Options {
+    /// Show what would be removed without removing anything
+    #[arg(long)]
+    pub dry_run: bool,
+
+    /// Wait for other operations on the repository to finish instead of
+    /// failing
+    #[arg(long)]
+    pub wait: bool,
+}
+
+// Removes chunks, packs and bundles no manifest refers to, such as those left behind
+// by a backup that failed part way through. Any manifest that can't be read
+// makes this fail, as its data would otherwise look unreferenced. Holding the
+// lock keeps a backup from writing chunks for a manifest not saved yet.
+pub fn run(ctx: &Context, opts: &Options) -> Result<()> {
+    let _lock = ctx.storage.lock(opts.wait)?;
+    let manifests = manifest::load_all(ctx)?;
+    let mut live = HashSet::new();
+    for manifest in manifests.values() {
+        match &manifest.data {
+            BackupKind::Full { .. } => live.extend(chunk_keys(manifest)),
+            BackupKind::Incremental { .. } => {
+                live.insert(manifest.bundle_key());
+            },
+        }
+    }
+
+    let mut keys = ctx.storage.list("chunks/")?;
+    keys.extend(ctx.storage.list("packs/")?);
+    keys.extend(ctx.storage.list("bundles/")?);
+    keys.retain(|key| !live.contains(key));
+    keys.sort();
+
+    let mut reclaimed = 0;
+    for key in &keys {
+        let size = delete(ctx, key, opts.dry_run)?;
+        println!("{} {}", key, size);
+        reclaimed += size;
+    }
+
+    info!(
+        "{} {} orphaned objects, {:.2} MiB reclaimed",
+        if opts.dry_run {
+            "would remove"
+        } else {
+            "removed"
+        },
+        keys.len(),
+        reclaimed as f64 / 1024.0 / 1024.0
+    );
+    Ok(())
+}
@@ -0,0 +1,317 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufRead, BufReader, Read},
+    path::PathBuf,
+    time::Duration,
+};
+
+use anyhow::{bail, Context as _, Result};
+use clap::Args;
+use log::info;
+use time::OffsetDateTime;
+use uuid::Uuid;
+
+use super::{
+    chunker::parse_chunk_size,
+    codec::Codec,
+    create::{self, Metrics, ProgressFormat},
+    manifest::{self, BackupKind, FileInfo, Manifest},
+    restore::parse_timestamp,
+};
+use crate::{context::Context, init};
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+
+#[derive(Debug, Args)]
+pub struct Options {
+    /// base.tar from pg_basebackup --format=tar, optionally gzip or zstd
+    /// compressed
+    #[arg(long)]
+    pub file: PathBuf,
+
+    #[arg(long)]
+    pub label: String,
+
+    /// When the backup was taken, defaults to the modification time of the
+    /// file
+    #[arg(long, value_parser = parse_timestamp)]
+    pub created_at: Option<OffsetDateTime>,
+
+    #[arg(long, default_value_t = 3, value_parser = clap::value_parser!(i32).range(1..=22))]
+    pub compression_level: i32,
+
+    /// Average size of the content-defined chunks the files are split into, a
+    /// power of two with an optional K or M suffix
+    #[arg(long, default_value = "1M", value_parser = parse_chunk_size)]
+    pub chunk_size: usize,
+
+    /// Wait for other operations on the repository to finish instead of
+    /// failing
+    #[arg(long)]
+    pub wait: bool,
+
+    /// Store the backup even if the repository holds backups of another
+    /// cluster
+    #[arg(long)]
+    pub force_system_id: bool,
+}
+
+// The archive is stored the same way as a full backup taken by create-backup,
+// so everything else works on it unchanged.
+pub fn run(ctx: &Context, opts: &Options) -> Result<()> {
+    if opts.label == manifest::LATEST {
+        bail!(
+            "{} is reserved and can't be used as a label",
+            manifest::LATEST
+        );
+    }
+
+    let _lock = ctx.storage.lock(opts.wait)?;
+    let manifest_key = Manifest::key(&opts.label);
+    if ctx.storage.exists(&manifest_key)? {
+        bail!("backup {} already exists", opts.label);
+    }
+
+    let file = File::open(&opts.file)
+        .with_context(|| format!("failed to open {}", opts.file.display()))?;
+    let created_at = match opts.created_at {
+        Some(created_at) => created_at,
+        None => file.metadata()?.modified()?.into(),
+    };
+
+    let metrics = Metrics::new(
+        Codec::Zstd,
+        opts.compression_level,
+        ProgressFormat::Text,
+        Duration::from_secs(5),
+    );
+    let mut writer =
+        create::ChunkWriter::new(ctx, Codec::Zstd, opts.compression_level, 1, opts.chunk_size)?;
+    let mut archive = tar::Archive::new(decompress(file)?);
+    let mut files = HashMap::new();
+    let mut backup_label = None;
+    let mut pg_version = String::new();
+    let mut system_identifier = None;
+
+    for entry in archive.entries()? {
+        let mut entry =
+            entry.with_context(|| format!("failed to read archive {}", opts.file.display()))?;
+        let path = entry.path()?.into_owned();
+        // Hardlinks and devices have no place in a data directory, and could
+        // reach outside of it.
+        match entry.header().entry_type() {
+            tar::EntryType::Regular => manifest::validate_path(&path)?,
+            tar::EntryType::Directory | tar::EntryType::XGlobalHeader => continue,
+            tar::EntryType::Symlink => {
+                manifest::validate_path(&path)?;
+                let Some(target) = entry.link_name()? else {
+                    bail!("symlink {:?} in archive has no target", path);
+                };
+                files.insert(path, FileInfo::symlink(target.into_owned()));
+                continue;
+            },
+            entry_type => bail!(
+                "unsupported entry {:?} of type {:?} in archive",
+                path,
+                entry_type
+            ),
+        }
+        match path.to_str() {
+            Some("backup_label") => {
+                let mut contents = String::new();
+                entry.read_to_string(&mut contents)?;
+                backup_label = Some(contents);
+                continue;
+            },
+            // pg_basebackup writes each tablespace to a tarball of its own.
+            Some("tablespace_map") if entry.size() > 0 =>
+                bail!("importing clusters with tablespaces is not supported"),
+            Some("tablespace_map") => continue,
+            _ => (),
+        }
+
+        let mode = entry.header().mode()? & 0o7777;
+        let mtime = Some(entry.header().mtime()? as i64);
+        let info = if matches!(path.to_str(), Some("PG_VERSION" | "global/pg_control")) {
+            let mut contents = Vec::new();
+            entry.read_to_end(&mut contents)?;
+            if path.to_str() == Some("PG_VERSION") {
+                pg_version = String::from_utf8_lossy(&contents).trim().to_owned();
+            } else {
+                system_identifier = manifest::parse_system_identifier(&contents);
+            }
+
+            writer.store_file(ctx, &metrics, &contents[..], mode, mtime)?
+        } else {
+            writer.store_file(ctx, &metrics, &mut entry, mode, mtime)?
+        };
+        files.insert(path, info);
+    }
+
+    writer.finish(ctx)?;
+    metrics.log_progress(true);
+    if let Some(system_identifier) = system_identifier {
+        init::check_system_identifier(ctx, system_identifier, opts.force_system_id)?;
+    }
+
+    let start_wal = backup_label
+        .as_deref()
+        .map(manifest::start_wal_segment)
+        .transpose()?
+        .context("archive is not a base backup, it has no backup_label")?
+        .to_owned();
+    let manifest = Manifest {
+        schema_version: manifest::SCHEMA_VERSION,
+        id: Uuid::new_v4(),
+        created_at,
+        label: opts.label.clone(),
+        backup_label,
+        tablespace_map: None,
+        start_wal,
+        pg_version,
+        tool_version: env!("CARGO_PKG_VERSION").to_owned(),
+        system_identifier,
+        wal_segment_size: manifest::DEFAULT_WAL_SEGMENT_SIZE,
+        standalone: false,
+        pinned: false,
+        tags: Default::default(),
+        excluded: Vec::new(),
+        compression: Codec::Zstd,
+        chunk_compression: Codec::Zstd,
+        data: BackupKind::Full { files },
+    };
+
+    info!(
+        "imported backup {} starting at WAL segment {}",
+        manifest.label, manifest.start_wal
+    );
+    manifest.save(ctx)
+}
+
+pub(super) fn decompress(reader: impl Read + 'static) -> Result<Box<dyn Read>> {
+    let mut reader = BufReader::new(reader);
+    let magic = reader.fill_buf()?;
+    if magic.starts_with(GZIP_MAGIC) {
+        Ok(Box::new(flate2::read::GzDecoder::new(reader)))
+    } else if magic.starts_with(ZSTD_MAGIC) {
+        Ok(Box::new(zstd::stream::read::Decoder::with_buffer(reader)?))
+    } else {
+        Ok(Box::new(reader))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use anyhow::Result;
+
+    use super::{run, Options};
+    use crate::{backup::manifest::Manifest, test_support::TestDir};
+
+    // backup_label as pg_basebackup of each version writes it, along with the
+    // segment it starts at.
+    const LABELS: [(&str, &str); 5] = [
+        (
+            "START WAL LOCATION: 0/2000028 (file 000000010000000000000002)\nCHECKPOINT LOCATION: \
+             0/2000060\nBACKUP METHOD: streamed\nBACKUP FROM: master\nSTART TIME: 2017-05-11 \
+             10:21:03 UTC\nLABEL: pg_basebackup base backup\n",
+            "000000010000000000000002",
+        ),
+        (
+            "START WAL LOCATION: 0/5000028 (file 000000010000000000000005)\nCHECKPOINT LOCATION: \
+             0/5000060\nBACKUP METHOD: streamed\nBACKUP FROM: primary\nSTART TIME: 2021-03-01 \
+             12:00:00 UTC\nLABEL: pg_basebackup base backup\nSTART TIMELINE: 1\n",
+            "000000010000000000000005",
+        ),
+        (
+            "START WAL LOCATION: 1/A4000028 (file 0000000300000001000000A4)\nCHECKPOINT LOCATION: \
+             1/A4000060\nBACKUP METHOD: streamed\nBACKUP FROM: standby\nSTART TIME: 2023-06-14 \
+             08:30:00 UTC\nLABEL: pg_basebackup base backup\nSTART TIMELINE: 3\n",
+            "0000000300000001000000A4",
+        ),
+        (
+            "START WAL LOCATION: 0/8000028 (file 000000010000000000000008)\nCHECKPOINT LOCATION: \
+             0/8000080\nBACKUP METHOD: streamed\nBACKUP FROM: primary\nSTART TIME: 2024-09-26 \
+             12:00:00 UTC\nLABEL: pg_basebackup base backup\nSTART TIMELINE: 1\nINCREMENTAL FROM \
+             LSN: 0/6000028\nINCREMENTAL FROM TLI: 1\n",
+            "000000010000000000000008",
+        ),
+        (
+            "START WAL LOCATION: 0/B000028 (file 00000002000000000000000B)\r\nCHECKPOINT \
+             LOCATION: 0/B000060\r\nBACKUP METHOD: streamed\r\nBACKUP FROM: primary\r\nSTART \
+             TIME: 2022-11-02 17:45:12 CET\r\nLABEL: nightly\r\nSTART TIMELINE: 2\r\n",
+            "00000002000000000000000B",
+        ),
+    ];
+
+    // Imports a base.tar built in memory holding `backup_label`.
+    fn import(dir: &TestDir, label: &str, backup_label: &str) -> Result<Manifest> {
+        let mut builder = tar::Builder::new(Vec::new());
+        for (path, contents) in [
+            ("PG_VERSION", "15\n"),
+            ("backup_label", backup_label),
+            ("base/1/1259", "relation"),
+        ] {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(contents.len() as u64);
+            header.set_mode(0o600);
+            header.set_cksum();
+            builder
+                .append_data(&mut header, path, contents.as_bytes())
+                .unwrap();
+        }
+
+        let file = dir.path(format!("{}.tar", label));
+        fs::write(&file, builder.into_inner().unwrap()).unwrap();
+        run(
+            &dir.ctx,
+            &Options {
+                file,
+                label: label.to_owned(),
+                created_at: None,
+                compression_level: 3,
+                chunk_size: 1024 * 1024,
+                wait: false,
+                force_system_id: false,
+            },
+        )?;
+
+        Manifest::load(&dir.ctx, &Manifest::key(label))
+    }
+
+    #[test]
+    fn start_wal_from_backup_label() {
+        let dir = TestDir::new();
+        for (i, (backup_label, segment)) in LABELS.iter().enumerate() {
+            let manifest = import(&dir, &format!("import-{}", i), backup_label).unwrap();
+            assert_eq!(manifest.start_wal, *segment);
+            assert_eq!(manifest.start_wal_segment().unwrap(), *segment);
+            assert_eq!(manifest.backup_label.as_deref(), Some(*backup_label));
+        }
+    }
+
+    #[test]
+    fn malformed_backup_label() {
+        let dir = TestDir::new();
+        for backup_label in [
+            "",
+            "CHECKPOINT LOCATION: 0/2000060\nBACKUP METHOD: streamed\n",
+            "START WAL LOCATION: 0/2000028\nCHECKPOINT LOCATION: 0/2000060\n",
+            "START WAL LOCATION: 0/2000028 (file 0000000100000000000000",
+            "START WAL LOCATION: 0/2000028 (file 00000001000000000000000G)\n",
+            "LABEL: START WAL LOCATION: 0/2000028 (file 000000010000000000000002)\n",
+        ] {
+            let err = import(&dir, "malformed", backup_label).expect_err(backup_label);
+            assert!(
+                err.to_string().contains("START WAL LOCATION"),
+                "{:?}: {}",
+                backup_label,
+                err
+            );
+            assert!(!dir.ctx.storage.exists(&Manifest::key("malformed")).unwrap());
+        }
+    }
+}
@@ -0,0 +1,125 @@
+use std::collections::HashSet;
+
+use anyhow::{bail, Result};
+use clap::Args;
+use log::warn;
+use time::format_description::well_known::Rfc3339;
+
+use super::manifest::{self, BackupKind, Manifest};
+use crate::context::Context;
+
+const MAX_SUGGESTIONS: usize = 3;
+
+#[derive(Debug, Args)]
+pub struct Options {
+    /// Label of the backup, or "latest" for the newest one
+    #[arg(long)]
+    pub label: String,
+}
+
+pub fn run(ctx: &Context, opts: &Options) -> Result<()> {
+    let manifests = manifest::load_all(ctx)?;
+    let manifest = match manifest::find(&manifests, &opts.label) {
+        Ok(manifest) => manifest,
+        Err(err) => {
+            let suggestions = closest_labels(&opts.label, manifests.values());
+            if suggestions.is_empty() {
+                return Err(err);
+            }
+
+            bail!("{}, did you mean: {}", err, suggestions.join(", "));
+        },
+    };
+
+    println!("label:       {}", manifest.label);
+    println!("id:          {}", manifest.id);
+    println!("created at:  {}", manifest.created_at.format(&Rfc3339)?);
+    if !manifest.pg_version.is_empty() {
+        println!("postgres:    {}", manifest.pg_version);
+    }
+    if !manifest.tool_version.is_empty() {
+        println!("pg_pitr:     {}", manifest.tool_version);
+    }
+    if let Some(system_identifier) = manifest.system_identifier {
+        println!("system id:   {}", system_identifier);
+    }
+    match manifest.start_wal_segment() {
+        Ok(segment) => println!("start WAL:   {}", segment),
+        Err(err) => {
+            warn!("{:#}", err);
+            println!("start WAL:   unknown");
+        },
+    }
+    if manifest.standalone {
+        println!("standalone:  yes");
+    }
+    if manifest.pinned {
+        println!("pinned:      yes");
+    }
+    if !manifest.tags.is_empty() {
+        println!("tags:        {}", manifest::format_tags(&manifest.tags));
+    }
+    for path in &manifest.excluded {
+        println!("excluded:    {}", path.display());
+    }
+    println!(
+        "size:        {} MiB",
+        manifest::disk_size(ctx, manifest)? / 1024 / 1024
+    );
+
+    match &manifest.data {
+        BackupKind::Full { files } => {
+            let chunks = files.values().flat_map(|info| &info.chunks);
+            let unique_chunks = chunks.clone().collect::<HashSet<_>>();
+            println!("kind:        full");
+            println!("files:       {}", files.len());
+            println!(
+                "chunks:      {} ({} unique)",
+                chunks.count(),
+                unique_chunks.len()
+            );
+        },
+        BackupKind::Incremental {
+            references,
+            changed_blocks,
+            ..
+        } => {
+            let parent = manifests
+                .get(references)
+                .map(|parent| parent.label.as_str())
+                .unwrap_or("missing");
+            println!("kind:        incremental");
+            println!("references:  {} ({})", references, parent);
+            println!("files:       {}", changed_blocks.len());
+            println!(
+                "blocks:      {}",
+                changed_blocks
+                    .values()
+                    .map(|blocks| blocks.len())
+                    .sum::<usize>()
+            );
+        },
+    }
+
+    Ok(())
+}
+
+fn closest_labels<'a>(label: &str, manifests: impl Iterator<Item = &'a Manifest>) -> Vec<&'a str> {
+    let max_distance = (label.len() / 3).max(2);
+    let mut candidates = manifests
+        .map(|manifest| {
+            (
+                strsim::levenshtein(label, &manifest.label),
+                &*manifest.label,
+            )
+        })
+        .filter(|(distance, _)| *distance <= max_distance)
+        .collect::<Vec<_>>();
+
+    candidates.sort();
+    candidates
+        .into_iter()
+        .take(MAX_SUGGESTIONS)
+        .map(|(_, label)| label)
+        .collect()
+}
@@ -0,0 +1,111 @@
+use std::{collections::BTreeMap, io};
+
+use anyhow::Result;
+use clap::Args;
+use log::warn;
+use serde::Serialize;
+use time::format_description::well_known::Rfc3339;
+use uuid::Uuid;
+
+use super::manifest::{self, Manifest};
+use crate::context::Context;
+
+#[derive(Debug, Args)]
+pub struct Options {
+    #[arg(long)]
+    pub json: bool,
+
+    /// Only list backups with this tag, can be given multiple times to
+    /// require all of them
+    #[arg(long = "filter", value_name = "KEY=VALUE", value_parser = manifest::parse_tag)]
+    pub filters: Vec<(String, String)>,
+}
+
+#[derive(Debug, Serialize)]
+struct BackupRecord {
+    id: Uuid,
+    label: String,
+    created_at: i64,
+    files: usize,
+    size: Option<u64>,
+    pinned: bool,
+    tags: BTreeMap<String, String>,
+    manifest_key: String,
+}
+
+pub fn run(ctx: &Context, opts: &Options) -> Result<()> {
+    let mut manifests = Vec::new();
+    for (manifest_key, manifest) in manifest::scan(ctx)? {
+        match manifest {
+            Ok(manifest) => {
+                let matches = opts
+                    .filters
+                    .iter()
+                    .all(|(key, value)| manifest.tags.get(key) == Some(value));
+                if matches {
+                    manifests.push((manifest_key, manifest));
+                }
+            },
+            Err(err) => warn!("skipping unreadable manifest {}: {:#}", manifest_key, err),
+        }
+    }
+
+    manifests.sort_by_key(|(_, manifest)| std::cmp::Reverse(manifest.created_at));
+
+    // Only the records go to stdout so the output can be piped as-is, logging
+    // stays on stderr.
+    if opts.json {
+        let records = manifests
+            .into_iter()
+            .map(|(manifest_key, manifest)| BackupRecord {
+                id: manifest.id,
+                label: manifest.label.clone(),
+                created_at: manifest.created_at.unix_timestamp(),
+                files: manifest.file_count(),
+                size: disk_size(ctx, &manifest),
+                pinned: manifest.pinned,
+                tags: manifest.tags,
+                manifest_key,
+            })
+            .collect::<Vec<_>>();
+
+        serde_json::to_writer_pretty(io::stdout().lock(), &records)?;
+        println!();
+        return Ok(());
+    }
+
+    println!(
+        "{:<24} {:<36} {:<26} {:>8} {:>12} {:<6} TAGS",
+        "LABEL", "ID", "CREATED AT", "FILES", "SIZE (MiB)", "PINNED"
+    );
+
+    for (_, manifest) in &manifests {
+        println!(
+            "{:<24} {:<36} {:<26} {:>8} {:>12} {:<6} {}",
+            manifest.label,
+            manifest.id,
+            manifest.created_at.format(&Rfc3339)?,
+            manifest.file_count(),
+            disk_size(ctx, manifest)
+                .map(|size| (size / 1024 / 1024).to_string())
+                .unwrap_or_else(|| "-".to_owned()),
+            if manifest.pinned { "yes" } else { "" },
+            manifest::format_tags(&manifest.tags)
+        );
+    }
+
+    Ok(())
+}
+
+fn disk_size(ctx: &Context, manifest: &Manifest) -> Option<u64> {
+    match manifest::disk_size(ctx, manifest) {
+        Ok(size) => Some(size),
+        Err(err) => {
+            warn!(
+                "failed to determine size of backup {}: {}",
+                manifest.label, err
+            );
+            None
+        },
+    }
+}
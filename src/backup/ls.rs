@@ -0,0 +1,75 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use clap::Args;
+
+use super::manifest::{self, BackupKind};
+use crate::context::Context;
+
+#[derive(Debug, Args)]
+pub struct Options {
+    /// Label of the backup, or "latest" for the newest one
+    #[arg(long)]
+    pub label: String,
+
+    /// Only list paths under this directory or file
+    #[arg(long)]
+    pub path: Option<PathBuf>,
+}
+
+// Full backups list the files they contain straight from the manifest, while
+// incremental ones list the block entries actually present in their bundle.
+pub fn run(ctx: &Context, opts: &Options) -> Result<()> {
+    let manifests = manifest::load_all(ctx)?;
+    let manifest = manifest::find(&manifests, &opts.label)?;
+    let matches = |path: &Path| {
+        opts.path
+            .as_ref()
+            .map_or(true, |prefix| path.starts_with(prefix))
+    };
+
+    match &manifest.data {
+        BackupKind::Full { files } => {
+            let mut files = files
+                .iter()
+                .filter(|(path, _)| matches(path))
+                .collect::<Vec<_>>();
+            files.sort_by_key(|(path, _)| *path);
+
+            for (path, info) in files {
+                print_entry(info.mode, info.size, &path.to_string_lossy());
+            }
+        },
+        BackupKind::Incremental { .. } => {
+            let reader = ctx.storage.open_reader(&manifest.bundle_key())?;
+            let decoder = manifest.compression.decoder(reader)?;
+            let mut bundle = tar::Archive::new(decoder);
+            for entry in bundle.entries()? {
+                let entry = entry?;
+                let path = entry.path()?.into_owned();
+                if matches(&path) {
+                    let header = entry.header();
+                    print_entry(
+                        header.mode().ok().filter(|mode| *mode != 0),
+                        Some(header.size()?),
+                        &path.to_string_lossy(),
+                    );
+                }
+            }
+        },
+    }
+
+    Ok(())
+}
+
+// Backups taken before sizes and modes were recorded show them as "-".
+fn print_entry(mode: Option<u32>, size: Option<u64>, path: &str) {
+    println!(
+        "{:>6} {:>12} {}",
+        mode.map(|mode| format!("{:04o}", mode))
+            .unwrap_or_else(|| "-".to_owned()),
+        size.map(|size| size.to_string())
+            .unwrap_or_else(|| "-".to_owned()),
+        path
+    );
+}
@@ -1,11 +1,39 @@
-use std::{collections::HashMap, path::PathBuf};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    ffi::CString,
+    fs,
+    io::{self, Read},
+    os::unix::ffi::OsStrExt,
+    path::{Component, Path, PathBuf},
+};
 
-use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use anyhow::{anyhow, bail, Context as _, Result};
+use log::info;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
 use time::OffsetDateTime;
 use uuid::Uuid;
 
+use super::{codec::Codec, pack};
+use crate::context::Context;
+
+pub const BLOCK_SIZE: usize = 8 * 1024;
+
+/// Label that resolves to the newest backup wherever a backup is looked up by
+/// label, it can't be used as the label of a backup.
+pub const LATEST: &str = "latest";
+
+/// Version of the manifest format written by this build, bumped whenever a
+/// change would be misread by older builds. Version 2 added packed chunks.
+pub const SCHEMA_VERSION: u32 = 2;
+
+pub const DEFAULT_WAL_SEGMENT_SIZE: u64 = 16 * 1024 * 1024;
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Manifest {
+    /// Missing in manifests written before the format was versioned, which
+    /// are version 1.
+    #[serde(default = "first_schema_version")]
+    pub schema_version: u32,
     pub id: Uuid,
     #[serde(
         serialize_with = "serialize_timestamp",
@@ -13,13 +41,432 @@ pub struct Manifest {
     )]
     pub created_at: OffsetDateTime,
     pub label: String,
+    pub backup_label: Option<String>,
+    pub tablespace_map: Option<String>,
+    /// Name of the WAL segment replay of the backup starts from, empty in
+    /// backups taken before it was recorded.
+    #[serde(default)]
+    pub start_wal: String,
+    /// `server_version` of the server the backup was taken from.
+    #[serde(default)]
+    pub pg_version: String,
+    /// Version of pg_pitr that took the backup.
+    #[serde(default)]
+    pub tool_version: String,
+    /// Identifies the cluster the backup was taken from, missing in backups
+    /// taken before it was recorded.
+    #[serde(default)]
+    pub system_identifier: Option<u64>,
+    /// `wal_segment_size` of the server, missing in backups taken before it
+    /// was recorded which assume the default.
+    #[serde(default = "default_wal_segment_size")]
+    pub wal_segment_size: u64,
+    /// Whether the WAL needed to make the backup consistent is stored in its
+    /// pg_wal, so restoring it doesn't need the WAL archive.
+    #[serde(default)]
+    pub standalone: bool,
+    /// Pinned backups are never removed by prune, and delete needs --force.
+    #[serde(default)]
+    pub pinned: bool,
+    #[serde(default)]
+    pub tags: BTreeMap<String, String>,
+    /// Paths left out of the backup with `--exclude`, relative to the data
+    /// directory. Restores skip them in the backups it builds on.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub excluded: Vec<PathBuf>,
+    /// Compression of the bundle of an incremental backup.
+    #[serde(default)]
+    pub compression: Codec,
+    /// Compression of the chunks of a full backup, missing in backups taken
+    /// before it was recorded, whose chunks are all zstd.
+    #[serde(default)]
+    pub chunk_compression: Codec,
     pub data: BackupKind,
 }
 
+impl Manifest {
+    pub fn key(label: &str) -> String {
+        format!("backups/{}.manifest", encode_label(label))
+    }
+
+    pub fn bundle_key(&self) -> String {
+        bundle_key(self.id, self.compression)
+    }
+
+    pub fn load(ctx: &Context, key: &str) -> Result<Self> {
+        let data = ctx
+            .storage
+            .read(key)
+            .with_context(|| format!("failed to read manifest {}", key))?;
+        Self::parse(&data).with_context(|| format!("failed to parse manifest {}", key))
+    }
+
+    // The version is checked on its own first, a newer manifest may not
+    // deserialize as this version's struct at all.
+    fn parse(data: &[u8]) -> Result<Self> {
+        #[derive(Deserialize)]
+        struct Versioned {
+            #[serde(default = "first_schema_version")]
+            schema_version: u32,
+        }
+
+        let Versioned { schema_version } = serde_yaml::from_slice(data)?;
+        if schema_version > SCHEMA_VERSION {
+            bail!(
+                "manifest schema version {} is newer than the supported version {}, upgrade \
+                 pg_pitr to read it",
+                schema_version,
+                SCHEMA_VERSION
+            );
+        } else if schema_version == 0 {
+            bail!("invalid manifest schema version 0");
+        }
+
+        Ok(serde_yaml::from_slice(data)?)
+    }
+
+    pub fn save(&self, ctx: &Context) -> Result<()> {
+        let data = serde_yaml::to_string(self)?;
+        ctx.storage.write(&Self::key(&self.label), data.as_bytes())
+    }
+
+    /// Walks the `references` links back to the full backup, returning the chain
+    /// oldest first.
+    pub fn chain<'a>(
+        &'a self,
+        manifests: &'a HashMap<Uuid, Manifest>,
+    ) -> Result<Vec<&'a Manifest>> {
+        let mut chain = vec![self];
+        while let BackupKind::Incremental { references, .. } = &chain[chain.len() - 1].data {
+            let parent = manifests.get(references).ok_or_else(|| {
+                anyhow!(
+                    "backup {} references missing backup {}",
+                    self.label,
+                    references
+                )
+            })?;
+            chain.push(parent);
+        }
+
+        chain.reverse();
+        Ok(chain)
+    }
+
+    /// Whether restoring this backup needs the backup with the given id,
+    /// directly or further up the chain.
+    pub fn depends_on(&self, id: Uuid, manifests: &HashMap<Uuid, Manifest>) -> bool {
+        let mut node = self;
+        while let BackupKind::Incremental { references, .. } = &node.data {
+            if *references == id {
+                return true;
+            }
+
+            match manifests.get(references) {
+                Some(parent) => node = parent,
+                None => break,
+            }
+        }
+
+        false
+    }
+
+    pub fn file_count(&self) -> usize {
+        match &self.data {
+            BackupKind::Full { files } => files.len(),
+            BackupKind::Incremental { changed_blocks, .. } => changed_blocks.len(),
+        }
+    }
+
+    /// The OIDs and locations of the tablespaces listed in the tablespace map.
+    pub fn tablespaces(&self) -> impl Iterator<Item = (&str, &Path)> {
+        self.tablespace_map
+            .iter()
+            .flat_map(|map| map.lines())
+            .filter_map(|line| line.split_once(' '))
+            .map(|(oid, location)| (oid, Path::new(location)))
+    }
+
+    /// The size of every regular file as of an incremental backup, which the
+    /// files restored from its chain are cut to, dropping those missing. Fails
+    /// for incrementals taken before sizes were recorded, as truncated and
+    /// deleted files would come back with stale data.
+    pub fn file_sizes(&self) -> Result<Option<&HashMap<PathBuf, u64>>> {
+        match &self.data {
+            BackupKind::Full { .. } => Ok(None),
+            BackupKind::Incremental {
+                file_sizes: Some(file_sizes),
+                ..
+            } => Ok(Some(file_sizes)),
+            BackupKind::Incremental {
+                file_sizes: None, ..
+            } => bail!(
+                "incremental backup {} doesn't record the sizes of its files and can't be \
+                 restored correctly, restore the full backup it builds on instead",
+                self.label
+            ),
+        }
+    }
+
+    /// The major version of the server the backup was taken from, if known.
+    pub fn pg_major_version(&self) -> Option<u32> {
+        parse_major_version(&self.pg_version)
+    }
+
+    /// The WAL segment replay of the backup starts from.
+    pub fn start_wal_segment(&self) -> Result<&str> {
+        if !self.start_wal.is_empty() {
+            return Ok(&self.start_wal);
+        }
+
+        let backup_label = self
+            .backup_label
+            .as_deref()
+            .ok_or_else(|| anyhow!("backup {} has no backup_label", self.label))?;
+        start_wal_segment(backup_label).with_context(|| format!("backup {}", self.label))
+    }
+}
+
+// Chunks may be shared with other backups, so the size of a full backup is
+// the size of every chunk it references rather than what deleting it frees.
+pub fn disk_size(ctx: &Context, manifest: &Manifest) -> Result<u64> {
+    match &manifest.data {
+        BackupKind::Full { files } => {
+            let keys = files
+                .values()
+                .flat_map(|info| &info.chunks)
+                .map(|chunk| chunk.key(manifest.chunk_compression))
+                .collect::<HashSet<_>>();
+
+            keys.into_iter()
+                .try_fold(0, |size, key| Ok(size + ctx.storage.size(&key)?))
+        },
+        BackupKind::Incremental { .. } => ctx.storage.size(&manifest.bundle_key()),
+    }
+}
+
+pub fn find<'a>(manifests: &'a HashMap<Uuid, Manifest>, label: &str) -> Result<&'a Manifest> {
+    if label == LATEST {
+        let manifest = manifests
+            .values()
+            .max_by_key(|manifest| manifest.created_at)
+            .ok_or_else(|| anyhow!("no backups found"))?;
+        info!("using latest backup {}", manifest.label);
+        return Ok(manifest);
+    }
+
+    manifests
+        .values()
+        .find(|manifest| manifest.label == label)
+        .ok_or_else(|| anyhow!("backup {} not found", label))
+}
+
+pub fn load_all(ctx: &Context) -> Result<HashMap<Uuid, Manifest>> {
+    let mut manifests = HashMap::new();
+    for (_, manifest) in scan(ctx)? {
+        let manifest = manifest?;
+        manifests.insert(manifest.id, manifest);
+    }
+
+    Ok(manifests)
+}
+
+/// Reads every manifest in storage, leaving it to the caller to decide what to
+/// do with ones that fail to load.
+pub fn scan(ctx: &Context) -> Result<Vec<(String, Result<Manifest>)>> {
+    let manifests = ctx
+        .storage
+        .list("backups/")?
+        .into_iter()
+        .filter(|key| key.ends_with(".manifest"))
+        .map(|key| {
+            let manifest = Manifest::load(ctx, &key);
+            (key, manifest)
+        })
+        .collect();
+
+    Ok(manifests)
+}
+
+// Labels can hold anything, only the characters that are safe in file names and
+// object keys are kept as they are in the key of the manifest and the rest is
+// percent-encoded, so `/` can't lead out of `backups/`. A leading `.` is
+// encoded too, it would hide the manifest. Labels made of the usual characters,
+// like the default timestamps, keep the keys they always had.
+fn encode_label(label: &str) -> String {
+    let mut encoded = String::with_capacity(label.len());
+    for (i, byte) in label.bytes().enumerate() {
+        match byte {
+            b'.' if i == 0 => encoded.push_str("%2E"),
+            b'A'..=b'Z'
+            | b'a'..=b'z'
+            | b'0'..=b'9'
+            | b'-'
+            | b'_'
+            | b'.'
+            | b':'
+            | b'+'
+            | b'@'
+            | b'='
+            | b',' => encoded.push(byte as char),
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+
+    encoded
+}
+
+pub fn bundle_key(id: Uuid, compression: Codec) -> String {
+    format!("bundles/{}.{}", id, compression.extension())
+}
+
+/// Extracts the WAL segment name from the `START WAL LOCATION` line of a
+/// backup label.
+pub fn start_wal_segment(backup_label: &str) -> Result<&str> {
+    let line = backup_label
+        .lines()
+        .find_map(|line| line.strip_prefix("START WAL LOCATION:"))
+        .ok_or_else(|| anyhow!("backup_label has no START WAL LOCATION"))?;
+
+    // The line reads `START WAL LOCATION: 0/2000028 (file 000000010000000000000002)`,
+    // the segment name is the only run of 24 hex digits in it.
+    line.split(|c: char| !c.is_ascii_hexdigit())
+        .find(|token| token.len() == 24)
+        .ok_or_else(|| anyhow!("no WAL segment name in START WAL LOCATION:{}", line))
+}
+
+/// Control file of a cluster, relative to its data directory. Archives have it
+/// as their first entry.
+pub const PG_CONTROL: &str = "global/pg_control";
+
+/// The system identifier in a `global/pg_control`, which is its first field.
+pub fn parse_system_identifier(pg_control: &[u8]) -> Option<u64> {
+    Some(u64::from_ne_bytes(pg_control.get(..8)?.try_into().ok()?))
+}
+
+/// Reads the system identifier of the cluster in a data directory, if it has
+/// a control file.
+pub fn read_system_identifier(data_dir: &Path) -> Result<Option<u64>> {
+    let path = data_dir.join(PG_CONTROL);
+    match fs::read(&path) {
+        Ok(pg_control) => parse_system_identifier(&pg_control)
+            .map(Some)
+            .ok_or_else(|| anyhow!("{:?} is truncated", path)),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err).with_context(|| format!("failed to read {:?}", path)),
+    }
+}
+
+/// Checks that a path from a manifest or an archive stays within the directory
+/// it is restored into.
+pub fn validate_path(path: &Path) -> Result<()> {
+    if path.as_os_str().is_empty()
+        || path
+            .components()
+            .any(|component| !matches!(component, Component::Normal(_)))
+    {
+        bail!("path {:?} leads outside of the data directory", path);
+    }
+
+    Ok(())
+}
+
+/// Parses a glob matched against paths relative to the data directory, where
+/// `*` and `?` don't match `/`.
+pub fn parse_glob(s: &str) -> Result<String, String> {
+    if s.is_empty() || s.contains('\0') || Path::new(s).is_absolute() {
+        return Err(format!(
+            "invalid pattern {:?}, expected a path relative to the data directory",
+            s
+        ));
+    }
+
+    Ok(s.trim_end_matches('/').to_owned())
+}
+
+pub fn glob_matches(pattern: &str, path: &Path) -> bool {
+    let (Ok(pattern), Ok(path)) = (
+        CString::new(pattern),
+        CString::new(path.as_os_str().as_bytes()),
+    ) else {
+        return false;
+    };
+
+    // SAFETY: both are NUL-terminated strings that outlive the call.
+    unsafe { libc::fnmatch(pattern.as_ptr(), path.as_ptr(), libc::FNM_PATHNAME) == 0 }
+}
+
+/// Parses a `key=value` tag. Keys are limited to ASCII letters, digits and
+/// `_.-` as they end up in JSON, logs and command lines.
+pub fn parse_tag(s: &str) -> Result<(String, String), String> {
+    let (key, value) = s
+        .split_once('=')
+        .ok_or_else(|| format!("invalid tag {:?}, expected KEY=VALUE", s))?;
+    validate_tag_key(key)?;
+    if value.chars().any(char::is_control) {
+        return Err(format!("tag value {:?} contains control characters", value));
+    }
+
+    Ok((key.to_owned(), value.to_owned()))
+}
+
+/// Formats tags as a comma separated list of `key=value` pairs.
+pub fn format_tags(tags: &BTreeMap<String, String>) -> String {
+    tags.iter()
+        .map(|(key, value)| format!("{}={}", key, value))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+pub fn validate_tag_key(key: &str) -> Result<(), String> {
+    let is_valid = |c: char| c.is_ascii_alphanumeric() || "_.-".contains(c);
+    if key.is_empty() || key.len() > 64 || !key.chars().all(is_valid) {
+        return Err(format!(
+            "invalid tag key {:?}, use up to 64 ASCII letters, digits, '_', '.' or '-'",
+            key
+        ));
+    }
+
+    Ok(())
+}
+
+/// Picks the major version out of a PostgreSQL version string, either a bare
+/// `server_version` like "15.4 (Debian 15.4-1)" or the output of `--version`
+/// like "pg_ctl (PostgreSQL) 15.4".
+pub fn parse_major_version(version: &str) -> Option<u32> {
+    let number = version
+        .split_whitespace()
+        .find(|word| word.starts_with(|c: char| c.is_ascii_digit()))?;
+    let major = number.split(|c: char| !c.is_ascii_digit()).next()?;
+    major.parse().ok()
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct FileInfo {
     pub chunks: Vec<ChunkRef>,
     pub blocks: Vec<ChunkRef>,
+    #[serde(default)]
+    pub size: Option<u64>,
+    #[serde(default)]
+    pub mode: Option<u32>,
+    /// Modification time in seconds since the epoch.
+    #[serde(default)]
+    pub mtime: Option<i64>,
+    /// Where the file links to if it is a symlink, it has no contents then.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub link_target: Option<PathBuf>,
+}
+
+impl FileInfo {
+    pub fn symlink(target: PathBuf) -> Self {
+        Self {
+            chunks: Vec::new(),
+            blocks: Vec::new(),
+            size: Some(0),
+            mode: None,
+            mtime: None,
+            link_target: Some(target),
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -30,18 +477,114 @@ pub enum BackupKind {
     Incremental {
         references: Uuid,
         changed_blocks: HashMap<PathBuf, HashMap<usize, ChunkRef>>,
+        /// Size of every regular file when the backup was taken, so that files
+        /// truncated or deleted since the backup it references are cut to size
+        /// or removed. Missing in backups taken before it was recorded, which
+        /// can't be restored correctly.
+        #[serde(default)]
+        file_sizes: Option<HashMap<PathBuf, u64>>,
+        /// blake3 hash of the uncompressed bundle, missing in backups taken
+        /// before it was recorded.
+        #[serde(default)]
+        bundle_checksum: Option<ChunkRef>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum ChunkRef {
+    /// A chunk stored as an object of its own. Blocks and bundles record their
+    /// checksums this way too.
+    Object(blake3::Hash),
+    /// A small chunk stored `len` bytes into a pack at `offset`, compressed on
+    /// its own.
+    Packed {
+        hash: blake3::Hash,
+        pack: Uuid,
+        offset: u64,
+        len: u64,
     },
 }
 
-#[derive(Debug, PartialEq, Eq, Hash)]
-pub struct ChunkRef(pub blake3::Hash);
+impl ChunkRef {
+    pub fn hash(&self) -> &blake3::Hash {
+        match self {
+            ChunkRef::Object(hash) | ChunkRef::Packed { hash, .. } => hash,
+        }
+    }
+
+    /// Chunks are sharded by the first two bytes of their hash, like git's
+    /// objects, so that no directory grows to millions of entries. Packed
+    /// chunks are read from their pack.
+    pub fn key(&self, codec: Codec) -> String {
+        match self {
+            ChunkRef::Object(hash) => {
+                let hex = hash.to_hex();
+                format!(
+                    "chunks/{}/{}/{}{}",
+                    &hex[..2],
+                    &hex[2..4],
+                    hex,
+                    codec.chunk_suffix()
+                )
+            },
+            ChunkRef::Packed { pack, .. } => pack::key(*pack),
+        }
+    }
+
+    /// Decompresses the chunk into `data`, which is cleared first, failing if
+    /// its contents don't have its hash so that a corrupted chunk is never
+    /// restored.
+    pub fn read(&self, ctx: &Context, codec: Codec, data: &mut Vec<u8>) -> Result<()> {
+        data.clear();
+        codec
+            .decoder(self.open(ctx, codec)?)?
+            .read_to_end(data)
+            .with_context(|| format!("failed to read chunk {}", self.describe(codec)))?;
+        if blake3::hash(data) != *self.hash() {
+            bail!("checksum mismatch in chunk {}", self.describe(codec));
+        }
+
+        Ok(())
+    }
+
+    /// Reads the compressed chunk, only its part of the pack for a packed one.
+    pub fn open<'a>(&self, ctx: &'a Context, codec: Codec) -> Result<Box<dyn Read + 'a>> {
+        let key = self.key(codec);
+        match self {
+            ChunkRef::Object(_) => ctx.storage.open_reader(&key),
+            ChunkRef::Packed { offset, len, .. } => Ok(Box::new(io::Cursor::new(
+                ctx.storage.read_range(&key, *offset, *len)?,
+            ))),
+        }
+    }
 
+    /// The key of the chunk, with where in its pack a packed chunk is.
+    pub fn describe(&self, codec: Codec) -> String {
+        match self {
+            ChunkRef::Object(_) => self.key(codec),
+            ChunkRef::Packed { hash, offset, .. } =>
+                format!("{} at {} of {}", hash.to_hex(), offset, self.key(codec)),
+        }
+    }
+}
+
+// Packed chunks are written as "hash:pack:offset:len", chunks of their own as
+// just the hash as they were before packs.
 impl Serialize for ChunkRef {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
         S: Serializer,
     {
-        serializer.serialize_str(&self.0.to_hex())
+        match self {
+            ChunkRef::Object(hash) => serializer.serialize_str(&hash.to_hex()),
+            ChunkRef::Packed {
+                hash,
+                pack,
+                offset,
+                len,
+            } =>
+                serializer.serialize_str(&format!("{}:{}:{}:{}", hash.to_hex(), pack, offset, len)),
+        }
     }
 }
 
@@ -51,10 +594,33 @@ impl<'de> Deserialize<'de> for ChunkRef {
         D: Deserializer<'de>,
     {
         let s = String::deserialize(deserializer)?;
-        Ok(ChunkRef(blake3::Hash::from_hex(&s).unwrap())) // TODO: fix this
+        let invalid = |err: &dyn std::fmt::Display| {
+            de::Error::custom(format!("invalid chunk {:?}: {}", s, err))
+        };
+        let mut fields = s.split(':');
+        let hash = blake3::Hash::from_hex(fields.next().unwrap_or_default())
+            .map_err(|err| invalid(&err))?;
+        match (fields.next(), fields.next(), fields.next(), fields.next()) {
+            (None, ..) => Ok(ChunkRef::Object(hash)),
+            (Some(pack), Some(offset), Some(len), None) => Ok(ChunkRef::Packed {
+                hash,
+                pack: pack.parse().map_err(|err| invalid(&err))?,
+                offset: offset.parse().map_err(|err| invalid(&err))?,
+                len: len.parse().map_err(|err| invalid(&err))?,
+            }),
+            _ => Err(invalid(&"expected hash:pack:offset:len")),
+        }
     }
 }
 
+fn first_schema_version() -> u32 {
+    1
+}
+
+fn default_wal_segment_size() -> u64 {
+    DEFAULT_WAL_SEGMENT_SIZE
+}
+
 fn serialize_timestamp<S>(dt: &OffsetDateTime, serializer: S) -> Result<S::Ok, S::Error>
 where
     S: Serializer,
@@ -67,5 +633,320 @@ where
     D: Deserializer<'de>,
 {
     let ts = i64::deserialize(deserializer)?;
-    Ok(OffsetDateTime::from_unix_timestamp(ts).unwrap()) // TODO: fix this
+    OffsetDateTime::from_unix_timestamp(ts)
+        .map_err(|err| de::Error::custom(format!("invalid timestamp {}: {}", ts, err)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::{BTreeMap, HashMap},
+        path::{Path, PathBuf},
+    };
+
+    use time::OffsetDateTime;
+    use uuid::Uuid;
+
+    use super::{
+        glob_matches,
+        pack::Packer,
+        parse_glob,
+        parse_major_version,
+        parse_system_identifier,
+        parse_tag,
+        BackupKind,
+        ChunkRef,
+        Codec,
+        Manifest,
+        DEFAULT_WAL_SEGMENT_SIZE,
+        SCHEMA_VERSION,
+    };
+    use crate::test_support::TestDir;
+
+    fn with_backup_label(backup_label: &str) -> Manifest {
+        Manifest {
+            schema_version: SCHEMA_VERSION,
+            id: Uuid::new_v4(),
+            created_at: OffsetDateTime::UNIX_EPOCH,
+            label: "test".to_owned(),
+            backup_label: Some(backup_label.to_owned()),
+            tablespace_map: None,
+            start_wal: String::new(),
+            pg_version: String::new(),
+            tool_version: String::new(),
+            system_identifier: None,
+            wal_segment_size: DEFAULT_WAL_SEGMENT_SIZE,
+            standalone: false,
+            pinned: false,
+            tags: BTreeMap::new(),
+            excluded: Vec::new(),
+            compression: Codec::Zstd,
+            chunk_compression: Codec::Zstd,
+            data: BackupKind::Full {
+                files: HashMap::new(),
+            },
+        }
+    }
+
+    #[test]
+    fn schema_version() {
+        let manifest = with_backup_label("");
+        let mut yaml = serde_yaml::to_string(&manifest).unwrap();
+        assert!(yaml.starts_with("schema_version: 2\n"));
+        assert_eq!(Manifest::parse(yaml.as_bytes()).unwrap().id, manifest.id);
+
+        yaml = yaml.replacen("schema_version: 2\n", "", 1);
+        assert_eq!(Manifest::parse(yaml.as_bytes()).unwrap().schema_version, 1);
+
+        yaml = format!(
+            "schema_version: 3\n{}",
+            yaml.replacen("data:", "payload:", 1)
+        );
+        let err = Manifest::parse(yaml.as_bytes()).unwrap_err();
+        assert!(err.to_string().contains("schema version 3"));
+    }
+
+    #[test]
+    fn label_keys() {
+        assert_eq!(
+            Manifest::key("2024-06-07T12:00:00Z"),
+            "backups/2024-06-07T12:00:00Z.manifest"
+        );
+        assert_eq!(
+            Manifest::key("nightly_v1.2"),
+            "backups/nightly_v1.2.manifest"
+        );
+        assert_eq!(
+            Manifest::key("../../etc"),
+            "backups/%2E.%2F..%2Fetc.manifest"
+        );
+        assert_eq!(Manifest::key("a/b"), "backups/a%2Fb.manifest");
+        assert_eq!(Manifest::key("a\\b c"), "backups/a%5Cb%20c.manifest");
+        assert_eq!(Manifest::key("50%"), "backups/50%25.manifest");
+        assert_eq!(
+            Manifest::key("säkerhetskopia"),
+            "backups/s%C3%A4kerhetskopia.manifest"
+        );
+        assert_ne!(Manifest::key("a/b"), Manifest::key("a%2Fb"));
+    }
+
+    #[test]
+    fn tags() {
+        assert_eq!(
+            parse_tag("ticket=OPS-123"),
+            Ok(("ticket".to_owned(), "OPS-123".to_owned()))
+        );
+        assert_eq!(
+            parse_tag("note=a=b c"),
+            Ok(("note".to_owned(), "a=b c".to_owned()))
+        );
+        assert_eq!(parse_tag("env="), Ok(("env".to_owned(), String::new())));
+        assert!(parse_tag("env").is_err());
+        assert!(parse_tag("=staging").is_err());
+        assert!(parse_tag("my env=staging").is_err());
+        assert!(parse_tag("env\"=x").is_err());
+        assert!(parse_tag("env=a\nb").is_err());
+    }
+
+    #[test]
+    fn major_version() {
+        assert_eq!(
+            parse_major_version("15.4 (Debian 15.4-1.pgdg120+1)"),
+            Some(15)
+        );
+        assert_eq!(parse_major_version("16beta2"), Some(16));
+        assert_eq!(
+            parse_major_version("pg_ctl (PostgreSQL) 17.0 (Ubuntu 17.0-1)"),
+            Some(17)
+        );
+        assert_eq!(parse_major_version(""), None);
+    }
+
+    #[test]
+    fn start_wal_segment() {
+        let manifest = with_backup_label(
+            "START WAL LOCATION: 0/9000028 (file 000000010000000000000009)\nCHECKPOINT LOCATION: \
+             0/9000060\n",
+        );
+        assert_eq!(
+            manifest.start_wal_segment().unwrap(),
+            "000000010000000000000009"
+        );
+
+        let manifest = with_backup_label(
+            "START WAL LOCATION: 0/9000028  (file 00000002000000000000000A, timeline 2)\n",
+        );
+        assert_eq!(
+            manifest.start_wal_segment().unwrap(),
+            "00000002000000000000000A"
+        );
+    }
+
+    #[test]
+    fn recorded_start_wal_segment() {
+        let mut manifest =
+            with_backup_label("START WAL LOCATION: 0/9000028 (file 000000010000000000000009)\n");
+        manifest.start_wal = "00000002000000000000000A".to_owned();
+        assert_eq!(
+            manifest.start_wal_segment().unwrap(),
+            "00000002000000000000000A"
+        );
+    }
+
+    #[test]
+    fn start_wal_segment_errors() {
+        assert!(with_backup_label("CHECKPOINT LOCATION: 0/9000060\n")
+            .start_wal_segment()
+            .is_err());
+        assert!(
+            with_backup_label("START WAL LOCATION: 0/9000028 (file 0000000100000000000000)\n")
+                .start_wal_segment()
+                .is_err()
+        );
+        assert!(with_backup_label(
+            "START WAL LOCATION: 0/9000028 (file 00000001000000000000000G)\n"
+        )
+        .start_wal_segment()
+        .is_err());
+    }
+
+    #[test]
+    fn system_identifier() {
+        let mut pg_control = 7407386213155542823u64.to_ne_bytes().to_vec();
+        pg_control.extend([0; 16]);
+        assert_eq!(
+            parse_system_identifier(&pg_control),
+            Some(7407386213155542823)
+        );
+        assert_eq!(parse_system_identifier(&pg_control[..7]), None);
+    }
+
+    #[test]
+    fn globs() {
+        assert_eq!(
+            parse_glob("pg_tblspc/16384/"),
+            Ok("pg_tblspc/16384".to_owned())
+        );
+        assert!(parse_glob("").is_err());
+        assert!(parse_glob("/srv/scratch").is_err());
+
+        assert!(glob_matches("pg_tblspc/*", Path::new("pg_tblspc/16384")));
+        assert!(!glob_matches(
+            "pg_tblspc/*",
+            Path::new("pg_tblspc/16384/PG_15")
+        ));
+        assert!(glob_matches(
+            "base/*/pgsql_tmp*",
+            Path::new("base/5/pgsql_tmp12.0")
+        ));
+        assert!(glob_matches("*.tmp", Path::new("scratch.tmp")));
+        assert!(!glob_matches("*.tmp", Path::new("base/scratch.tmp")));
+        assert!(glob_matches(
+            "log/postgresql-[0-9]*",
+            Path::new("log/postgresql-1.log")
+        ));
+    }
+
+    #[test]
+    fn excluded() {
+        let mut manifest = with_backup_label("");
+        manifest.excluded = vec![PathBuf::from("pg_tblspc/16384")];
+        let yaml = serde_yaml::to_string(&manifest).unwrap();
+        assert_eq!(
+            Manifest::parse(yaml.as_bytes()).unwrap().excluded,
+            manifest.excluded
+        );
+        manifest.excluded.clear();
+        assert!(!serde_yaml::to_string(&manifest)
+            .unwrap()
+            .contains("excluded"));
+    }
+
+    #[test]
+    fn chunk_checksums() {
+        let dir = TestDir::new();
+        let ctx = &dir.ctx;
+        let chunk = ChunkRef::Object(blake3::hash(b"hello"));
+        let write = |data: &[u8]| {
+            ctx.storage
+                .write(
+                    &chunk.key(Codec::Zstd),
+                    &zstd::bulk::compress(data, 3).unwrap(),
+                )
+                .unwrap()
+        };
+
+        write(b"hello");
+        let mut data = b"stale".to_vec();
+        chunk.read(ctx, Codec::Zstd, &mut data).unwrap();
+        assert_eq!(data, b"hello");
+
+        // Bit rot that still decompresses.
+        write(b"hellp");
+        let err = chunk.read(ctx, Codec::Zstd, &mut data).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!("checksum mismatch in chunk {}", chunk.key(Codec::Zstd))
+        );
+    }
+
+    #[test]
+    fn incremental_file_sizes() {
+        let mut manifest = with_backup_label("");
+        assert_eq!(manifest.file_sizes().unwrap(), None);
+
+        manifest.data = BackupKind::Incremental {
+            references: Uuid::new_v4(),
+            changed_blocks: HashMap::new(),
+            file_sizes: Some(HashMap::from([(PathBuf::from("base/1/1259"), 8192)])),
+            bundle_checksum: None,
+        };
+        let yaml = serde_yaml::to_string(&manifest).unwrap();
+        let parsed = Manifest::parse(yaml.as_bytes()).unwrap();
+        assert_eq!(
+            parsed.file_sizes().unwrap().unwrap()[Path::new("base/1/1259")],
+            8192
+        );
+
+        // Taken before sizes were recorded.
+        let yaml = yaml.replace("file_sizes", "unknown_field");
+        let err = Manifest::parse(yaml.as_bytes())
+            .unwrap()
+            .file_sizes()
+            .unwrap_err();
+        assert!(err.to_string().contains("can't be restored correctly"));
+    }
+
+    #[test]
+    fn packed_chunks() {
+        let dir = TestDir::new();
+        let ctx = &dir.ctx;
+        let mut packer = Packer::new([], Codec::Zstd);
+        let chunks = [&b"hello"[..], b"world", b""]
+            .map(|data| {
+                let compressed = zstd::bulk::compress(data, 3).unwrap();
+                packer.add(ctx, blake3::hash(data), &compressed).unwrap()
+            })
+            .to_vec();
+        packer.flush(ctx).unwrap();
+        assert_eq!(packer.find(&blake3::hash(b"world")), Some(&chunks[1]));
+        assert_eq!(chunks[0].key(Codec::Zstd), chunks[2].key(Codec::Zstd));
+
+        let yaml = serde_yaml::to_string(&chunks).unwrap();
+        assert_eq!(
+            serde_yaml::from_str::<Vec<ChunkRef>>(&yaml).unwrap(),
+            chunks
+        );
+        let object = ChunkRef::Object(blake3::hash(b"hello"));
+        let yaml = serde_yaml::to_string(&object).unwrap();
+        assert_eq!(yaml.trim(), blake3::hash(b"hello").to_hex().as_str());
+        assert_eq!(serde_yaml::from_str::<ChunkRef>(&yaml).unwrap(), object);
+        assert!(serde_yaml::from_str::<ChunkRef>("abc:def").is_err());
+
+        let mut data = Vec::new();
+        for (chunk, expected) in chunks.iter().zip([&b"hello"[..], b"world", b""]) {
+            chunk.read(ctx, Codec::Zstd, &mut data).unwrap();
+            assert_eq!(data, expected);
+        }
+    }
 }
@@ -1,2 +1,26 @@
+pub mod cat;
+mod chunker;
+pub mod cleanup;
+pub mod clone;
+pub mod codec;
+pub mod copy;
 pub mod create;
-mod manifest;
+pub mod delete;
+pub mod diff;
+pub mod du;
+pub mod export;
+pub mod gc;
+pub mod import;
+pub mod info;
+pub mod list;
+pub mod ls;
+pub mod manifest;
+pub mod pack;
+pub mod pin;
+pub mod prune;
+pub mod relabel;
+pub mod restore;
+pub mod stats;
+pub mod tag;
+pub mod test_restore;
+pub mod verify;
@@ -0,0 +1,92 @@
+use std::collections::HashMap;
+
+use anyhow::Result;
+use uuid::Uuid;
+
+use super::{
+    codec::Codec,
+    manifest::{BackupKind, ChunkRef, Manifest},
+};
+use crate::context::Context;
+
+// Packs are written out once they grow past this.
+const PACK_SIZE: usize = 16 * 1024 * 1024;
+
+pub fn key(id: Uuid) -> String {
+    format!("packs/{}.pack", id)
+}
+
+/// Collects small chunks into packs, so that they don't each take up an object
+/// of their own. Every chunk is compressed separately and can be read on its
+/// own. Packs are written whole and never changed, one is only removed once no
+/// backup refers to any chunk in it. Nothing repacks them, so a single chunk
+/// still in use keeps all of its pack, up to 16 MiB, in the repository.
+pub struct Packer {
+    id: Uuid,
+    data: Vec<u8>,
+    packed: HashMap<blake3::Hash, ChunkRef>,
+}
+
+impl Packer {
+    /// Chunks packed by the backups in `manifests` are reused if they were
+    /// compressed with `codec`.
+    pub fn new<'a>(manifests: impl IntoIterator<Item = &'a Manifest>, codec: Codec) -> Self {
+        let packed = manifests
+            .into_iter()
+            .filter(|manifest| manifest.chunk_compression == codec)
+            .filter_map(|manifest| match &manifest.data {
+                BackupKind::Full { files } => Some(files),
+                BackupKind::Incremental { .. } => None,
+            })
+            .flat_map(|files| files.values())
+            .flat_map(|info| &info.chunks)
+            .filter(|chunk| matches!(chunk, ChunkRef::Packed { .. }))
+            .map(|chunk| (*chunk.hash(), chunk.clone()))
+            .collect();
+
+        Self {
+            id: Uuid::new_v4(),
+            data: Vec::new(),
+            packed,
+        }
+    }
+
+    pub fn find(&self, hash: &blake3::Hash) -> Option<&ChunkRef> {
+        self.packed.get(hash)
+    }
+
+    /// Adds the compressed chunk to the current pack, writing the pack out
+    /// once it is full.
+    pub fn add(
+        &mut self,
+        ctx: &Context,
+        hash: blake3::Hash,
+        compressed: &[u8],
+    ) -> Result<ChunkRef> {
+        let chunk = ChunkRef::Packed {
+            hash,
+            pack: self.id,
+            offset: self.data.len() as u64,
+            len: compressed.len() as u64,
+        };
+        self.data.extend_from_slice(compressed);
+        self.packed.insert(hash, chunk.clone());
+        if self.data.len() >= PACK_SIZE {
+            self.flush(ctx)?;
+        }
+
+        Ok(chunk)
+    }
+
+    /// Writes out the current pack, which has to happen before a manifest
+    /// referring to its chunks is saved.
+    pub fn flush(&mut self, ctx: &Context) -> Result<()> {
+        if !self.data.is_empty() {
+            ctx.storage.write(&key(self.id), &self.data)?;
+            self.data.clear();
+            self.id = Uuid::new_v4();
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,44 @@
+use anyhow::Result;
+use clap::Args;
+use log::info;
+
+use super::manifest;
+use crate::context::Context;
+
+#[derive(Debug, Args)]
+pub struct Options {
+    /// Label of the backup, or "latest" for the newest one
+    #[arg(long)]
+    pub label: String,
+
+    /// Wait for other operations on the repository to finish instead of
+    /// failing
+    #[arg(long)]
+    pub wait: bool,
+}
+
+// Backs both pin and unpin.
+pub fn run(ctx: &Context, opts: &Options, pinned: bool) -> Result<()> {
+    let _lock = ctx.storage.lock(opts.wait)?;
+    let mut manifests = manifest::load_all(ctx)?;
+    let id = manifest::find(&manifests, &opts.label)?.id;
+    let mut manifest = manifests.remove(&id).unwrap();
+
+    if manifest.pinned == pinned {
+        info!(
+            "backup {} is already {}",
+            manifest.label,
+            if pinned { "pinned" } else { "unpinned" }
+        );
+        return Ok(());
+    }
+
+    manifest.pinned = pinned;
+    manifest.save(ctx)?;
+    info!(
+        "{} backup {}",
+        if pinned { "pinned" } else { "unpinned" },
+        manifest.label
+    );
+    Ok(())
+}
@@ -0,0 +1,686 @@
+use std::{
+    cmp::Reverse,
+    collections::{HashMap, HashSet},
+};
+
+use anyhow::Result;
+use clap::{ArgGroup, Args};
+use log::{info, warn};
+use time::{Duration, OffsetDateTime, UtcOffset};
+use uuid::Uuid;
+
+use super::{
+    create,
+    manifest::{self, BackupKind, ChunkRef, Manifest},
+};
+use crate::{context::Context, wal_pull::WalFileKind};
+
+#[derive(Debug, Args)]
+#[command(group(
+    ArgGroup::new("policy")
+        .required(true)
+        .multiple(true)
+        .args(["keep_last", "keep_within", "keep_daily", "keep_weekly", "keep_monthly"])
+))]
+pub struct Options {
+    /// Keep the newest N backups
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    pub keep_last: Option<u64>,
+
+    /// Keep backups taken within this long of now, such as 36h or 2w3d
+    #[arg(long, value_parser = parse_duration)]
+    pub keep_within: Option<Duration>,
+
+    /// Keep the newest backup of each of the last N days with backups
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    pub keep_daily: Option<u64>,
+
+    /// Keep the newest backup of each of the last N ISO weeks with backups
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    pub keep_weekly: Option<u64>,
+
+    /// Keep the newest backup of each of the last N months with backups
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    pub keep_monthly: Option<u64>,
+
+    /// Show what would be removed without removing anything
+    #[arg(long)]
+    pub dry_run: bool,
+
+    /// Wait for other operations on the repository to finish instead of
+    /// failing
+    #[arg(long)]
+    pub wait: bool,
+}
+
+pub fn run(ctx: &Context, opts: &Options) -> Result<()> {
+    let _lock = ctx.storage.lock(opts.wait)?;
+    let manifests = manifest::load_all(ctx)?;
+    let mut backups = manifests.values().collect::<Vec<_>>();
+    backups.sort_by_key(|manifest| Reverse(manifest.created_at));
+
+    // Incremental backups are useless without the backups they build on, so
+    // those are kept as long as anything kept depends on them.
+    let retained = retained(&backups, opts, OffsetDateTime::now_utc());
+    let mut keep = HashSet::new();
+    for backup in backups
+        .iter()
+        .filter(|backup| retained.contains(&backup.id))
+    {
+        for ancestor in backup.chain(&manifests)?.into_iter().rev() {
+            if keep.insert(ancestor.id) && !retained.contains(&ancestor.id) {
+                info!(
+                    "keeping backup {} as backup {} depends on it",
+                    ancestor.label, backup.label
+                );
+            }
+        }
+    }
+
+    let (kept, expired): (Vec<_>, Vec<_>) = backups
+        .into_iter()
+        .partition(|manifest| keep.contains(&manifest.id));
+
+    let oldest_start = oldest_start(&kept);
+    if expired.is_empty() && oldest_start.is_none() {
+        info!("nothing to prune");
+        return Ok(());
+    }
+
+    // Chunks are shared between full backups, only the ones no kept backup
+    // references can go.
+    let mut retained_chunks = kept
+        .iter()
+        .flat_map(|manifest| chunk_keys(manifest))
+        .collect::<HashSet<_>>();
+
+    // Newest first, so an interrupted prune never leaves a backup behind whose
+    // parent is already gone.
+    let mut total_reclaimed = 0;
+    for backup in &expired {
+        let (removed, reclaimed) = remove_backup(ctx, backup, &mut retained_chunks, opts.dry_run)?;
+
+        // Only these lines and the ones for expired WAL go to stdout, and they
+        // look the same with and without --dry-run so the two can be diffed.
+        println!("backup {} {} {}", backup.label, backup.id, reclaimed);
+        info!(
+            "{} backup {} ({}): {} objects, {:.2} MiB reclaimed",
+            if opts.dry_run {
+                "would remove"
+            } else {
+                "removed"
+            },
+            backup.label,
+            backup.id,
+            removed,
+            reclaimed as f64 / 1024.0 / 1024.0
+        );
+        total_reclaimed += reclaimed;
+    }
+
+    info!(
+        "{} {} backups, kept {}, reclaimed {:.2} MiB",
+        if opts.dry_run {
+            "would prune"
+        } else {
+            "pruned"
+        },
+        expired.len(),
+        kept.len(),
+        total_reclaimed as f64 / 1024.0 / 1024.0
+    );
+
+    if let Some((backup, start)) = oldest_start {
+        expire_wal(ctx, backup, start, opts.dry_run)?;
+    }
+
+    Ok(())
+}
+
+// The kept backup starting replay from the oldest segment, along with the
+// position of that segment. None if that isn't known for every kept backup, in
+// which case no WAL is expired.
+fn oldest_start<'a>(kept: &[&'a Manifest]) -> Option<(&'a Manifest, u64)> {
+    let mut oldest = None;
+    for backup in kept {
+        let start = backup
+            .start_wal_segment()
+            .ok()
+            .and_then(|start_wal| create::parse_wal_segment(start_wal, backup.wal_segment_size));
+        let Some((_, segment)) = start else {
+            warn!(
+                "not expiring WAL, the start of backup {} isn't known",
+                backup.label
+            );
+            return None;
+        };
+
+        if oldest.map_or(true, |(_, oldest)| segment < oldest) {
+            oldest = Some((*backup, segment));
+        }
+    }
+
+    oldest
+}
+
+// Removes the archived WAL older than the segment at `start`, where `oldest`
+// starts, which no kept backup can replay. Timeline history files are never
+// removed, recovery follows timeline switches through them however old they
+// are.
+fn expire_wal(ctx: &Context, oldest: &Manifest, start: u64, dry_run: bool) -> Result<()> {
+    let mut removed = 0;
+    let mut reclaimed = 0;
+    let mut keys = ctx.storage.list("wal/")?;
+    keys.sort();
+    for key in keys {
+        let Some(name) = key
+            .strip_prefix("wal/")
+            .and_then(|key_name| key_name.split('-').next())
+        else {
+            continue;
+        };
+
+        if !wal_expired(name, start, oldest.wal_segment_size) {
+            continue;
+        }
+
+        let size = delete(ctx, &key, dry_run)?;
+        println!("wal {} {}", name, size);
+        removed += 1;
+        reclaimed += size;
+    }
+
+    info!(
+        "{} {} WAL files older than the start of backup {}, reclaimed {:.2} MiB",
+        if dry_run { "would remove" } else { "removed" },
+        removed,
+        oldest.label,
+        reclaimed as f64 / 1024.0 / 1024.0
+    );
+    Ok(())
+}
+
+// Segments are compared by their position in WAL alone, whatever timeline
+// they are on: names sort by timeline first, and recovery from a backup never
+// reads WAL from before its start, on its own timeline or any other. Partial
+// segments and backup history files go with the segment they are named after.
+fn wal_expired(name: &str, start: u64, segment_size: u64) -> bool {
+    match WalFileKind::of(name) {
+        Some(WalFileKind::Segment | WalFileKind::Partial | WalFileKind::BackupHistory) =>
+            create::parse_wal_segment(&name[..24], segment_size)
+                .is_some_and(|(_, segment)| segment < start),
+        Some(WalFileKind::History) | None => false,
+    }
+}
+
+// Maps a backup's creation time to the calendar period it falls in.
+type Bucket = fn(OffsetDateTime) -> (i32, u16);
+
+// Applies the retention policies to `backups`, sorted newest first, returning
+// the union of what each of them keeps. Pinned backups are always kept and
+// left out of the policies, so they don't take the place of another backup.
+fn retained(backups: &[&Manifest], opts: &Options, now: OffsetDateTime) -> HashSet<Uuid> {
+    let (pinned, backups): (Vec<&Manifest>, Vec<&Manifest>) =
+        backups.iter().partition(|manifest| manifest.pinned);
+    let last = backups
+        .iter()
+        .take(opts.keep_last.unwrap_or(0) as usize)
+        .map(|manifest| manifest.id);
+    let mut keep = last.collect::<HashSet<_>>();
+
+    if let Some(keep_within) = opts.keep_within {
+        let within = backups
+            .iter()
+            .take_while(|manifest| now - manifest.created_at <= keep_within)
+            .map(|manifest| manifest.id);
+        keep.extend(within);
+    }
+
+    let policies: [(Option<u64>, Bucket); 3] = [
+        (opts.keep_daily, |time| (time.year(), time.ordinal())),
+        (opts.keep_weekly, |time| {
+            let (year, week, _) = time.to_iso_week_date();
+            (year, week as u16)
+        }),
+        (opts.keep_monthly, |time| (time.year(), time.month() as u16)),
+    ];
+
+    for (count, bucket) in policies {
+        let Some(count) = count else {
+            continue;
+        };
+
+        let mut last_bucket = None;
+        let mut buckets = 0;
+        for manifest in &backups {
+            let bucket = bucket(manifest.created_at.to_offset(UtcOffset::UTC));
+            if last_bucket != Some(bucket) {
+                if buckets == count {
+                    break;
+                }
+
+                keep.insert(manifest.id);
+                last_bucket = Some(bucket);
+                buckets += 1;
+            }
+        }
+    }
+
+    keep.extend(pinned.into_iter().map(|manifest| manifest.id));
+    keep
+}
+
+// Parses durations like 90m, 36h or 2w3d, made up of numbers suffixed with one
+// of s, m, h, d or w.
+pub(super) fn parse_duration(s: &str) -> Result<Duration, String> {
+    let invalid = || format!("invalid duration {:?}, expected e.g. 36h or 2w3d", s);
+    let mut duration = Duration::ZERO;
+    let mut rest = s;
+    while !rest.is_empty() {
+        let digits = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .ok_or_else(invalid)?;
+        let value = rest[..digits].parse::<i64>().map_err(|_| invalid())?;
+        let unit = match rest[digits..].chars().next() {
+            Some('s') => Duration::SECOND,
+            Some('m') => Duration::MINUTE,
+            Some('h') => Duration::HOUR,
+            Some('d') => Duration::DAY,
+            Some('w') => Duration::WEEK,
+            _ => return Err(invalid()),
+        };
+
+        duration = unit
+            .checked_mul(value.try_into().map_err(|_| invalid())?)
+            .and_then(|value| duration.checked_add(value))
+            .ok_or_else(invalid)?;
+        rest = &rest[digits + 1..];
+    }
+
+    if duration.is_zero() {
+        return Err(invalid());
+    }
+
+    Ok(duration)
+}
+
+/// Removes the manifest of a backup and then its data, leaving chunks in
+/// `retained_chunks` alone and adding the removed ones to it. Returns the
+/// number of objects removed and the bytes reclaimed.
+pub(super) fn remove_backup(
+    ctx: &Context,
+    backup: &Manifest,
+    retained_chunks: &mut HashSet<String>,
+    dry_run: bool,
+) -> Result<(usize, u64)> {
+    // The manifest goes first, so a crash part way through never leaves a
+    // backup listed whose data is partly gone. Whatever data is left behind is
+    // referenced by no manifest then, which is what gc removes.
+    let mut keys = vec![Manifest::key(&backup.label)];
+    match &backup.data {
+        BackupKind::Full { .. } =>
+            keys.extend(chunk_keys(backup).filter(|key| retained_chunks.insert(key.clone()))),
+        BackupKind::Incremental { .. } => keys.push(backup.bundle_key()),
+    }
+
+    let mut reclaimed = 0;
+    for key in &keys {
+        reclaimed += delete(ctx, key, dry_run)?;
+    }
+
+    Ok((keys.len(), reclaimed))
+}
+
+pub(super) fn chunk_keys(manifest: &Manifest) -> impl Iterator<Item = String> + '_ {
+    let files = match &manifest.data {
+        BackupKind::Full { files } => Some(files),
+        BackupKind::Incremental { .. } => None,
+    };
+
+    files
+        .into_iter()
+        .flat_map(|files| files.values())
+        .flat_map(|info| &info.chunks)
+        .map(|chunk| chunk.key(manifest.chunk_compression))
+}
+
+// The chunks of a full backup, each once, by the object they are in and their
+// hash, with the bytes they take up there: all of the object for a chunk of its
+// own, only its part of the pack for a packed one. `object_sizes` caches the
+// sizes of the objects.
+pub(super) fn stored_chunks<'a>(
+    ctx: &Context,
+    manifest: &'a Manifest,
+    object_sizes: &mut HashMap<String, u64>,
+) -> Result<HashMap<(String, &'a blake3::Hash), u64>> {
+    let BackupKind::Full { files } = &manifest.data else {
+        return Ok(HashMap::new());
+    };
+
+    let mut chunks = HashMap::new();
+    for chunk in files.values().flat_map(|info| &info.chunks) {
+        let key = chunk.key(manifest.chunk_compression);
+        if !object_sizes.contains_key(&key) {
+            object_sizes.insert(key.clone(), ctx.storage.size(&key)?);
+        }
+
+        let size = match chunk {
+            ChunkRef::Object(_) => object_sizes[&key],
+            ChunkRef::Packed { len, .. } => *len,
+        };
+        chunks.insert((key, chunk.hash()), size);
+    }
+
+    Ok(chunks)
+}
+
+// Returns the number of bytes freed, objects already removed by an earlier
+// interrupted run count as nothing.
+pub(super) fn delete(ctx: &Context, key: &str, dry_run: bool) -> Result<u64> {
+    if !ctx.storage.exists(key)? {
+        return Ok(0);
+    }
+
+    let size = ctx.storage.size(key)?;
+    if !dry_run {
+        ctx.storage.delete(key)?;
+    }
+
+    Ok(size)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{HashMap, HashSet};
+
+    use time::{format_description::well_known::Rfc3339, Duration, OffsetDateTime};
+    use uuid::Uuid;
+
+    use super::{oldest_start, parse_duration, retained, wal_expired, Options};
+    use crate::backup::{
+        create,
+        manifest::{self, BackupKind, Manifest, SCHEMA_VERSION},
+    };
+
+    fn backups(created_at: &[&str]) -> Vec<Manifest> {
+        let mut backups = created_at
+            .iter()
+            .map(|created_at| Manifest {
+                schema_version: SCHEMA_VERSION,
+                id: Uuid::new_v4(),
+                created_at: OffsetDateTime::parse(created_at, &Rfc3339).unwrap(),
+                label: created_at.to_string(),
+                backup_label: None,
+                tablespace_map: None,
+                start_wal: String::new(),
+                pg_version: String::new(),
+                tool_version: String::new(),
+                system_identifier: None,
+                wal_segment_size: manifest::DEFAULT_WAL_SEGMENT_SIZE,
+                standalone: false,
+                pinned: false,
+                tags: Default::default(),
+                excluded: Vec::new(),
+                compression: Default::default(),
+                chunk_compression: Default::default(),
+                data: BackupKind::Full {
+                    files: HashMap::new(),
+                },
+            })
+            .collect::<Vec<_>>();
+
+        backups.sort_by_key(|manifest| std::cmp::Reverse(manifest.created_at));
+        backups
+    }
+
+    fn kept(backups: &[Manifest], opts: Options) -> HashSet<&str> {
+        let refs = backups.iter().collect::<Vec<_>>();
+        let now = OffsetDateTime::parse("2025-07-01T00:00:00Z", &Rfc3339).unwrap();
+        let retained = retained(&refs, &opts, now);
+        backups
+            .iter()
+            .filter(|manifest| retained.contains(&manifest.id))
+            .map(|manifest| manifest.label.as_str())
+            .collect()
+    }
+
+    fn policy(
+        keep_last: Option<u64>,
+        keep_daily: Option<u64>,
+        keep_weekly: Option<u64>,
+        keep_monthly: Option<u64>,
+    ) -> Options {
+        Options {
+            keep_last,
+            keep_within: None,
+            keep_daily,
+            keep_weekly,
+            keep_monthly,
+            dry_run: false,
+            wait: false,
+        }
+    }
+
+    #[test]
+    fn keep_last() {
+        let backups = backups(&[
+            "2025-01-01T00:00:00Z",
+            "2025-01-02T00:00:00Z",
+            "2025-01-03T00:00:00Z",
+        ]);
+
+        assert_eq!(
+            kept(&backups, policy(Some(2), None, None, None)),
+            HashSet::from(["2025-01-03T00:00:00Z", "2025-01-02T00:00:00Z"])
+        );
+    }
+
+    #[test]
+    fn daily_keeps_newest_per_day() {
+        let backups = backups(&[
+            "2025-03-01T01:00:00Z",
+            "2025-03-01T23:00:00Z",
+            "2025-03-02T00:00:00Z",
+            "2025-03-02T12:00:00Z",
+        ]);
+
+        assert_eq!(
+            kept(&backups, policy(None, Some(2), None, None)),
+            HashSet::from(["2025-03-02T12:00:00Z", "2025-03-01T23:00:00Z"])
+        );
+    }
+
+    #[test]
+    fn daily_counts_only_days_with_backups() {
+        let backups = backups(&[
+            "2025-01-01T00:00:00Z",
+            "2025-01-20T00:00:00Z",
+            "2025-02-28T00:00:00Z",
+            "2025-06-30T00:00:00Z",
+        ]);
+
+        assert_eq!(
+            kept(&backups, policy(None, Some(3), None, None)),
+            HashSet::from([
+                "2025-06-30T00:00:00Z",
+                "2025-02-28T00:00:00Z",
+                "2025-01-20T00:00:00Z",
+            ])
+        );
+    }
+
+    #[test]
+    fn buckets_use_utc() {
+        // Both are on the 1st of February in UTC+02:00, but not in UTC.
+        let backups = backups(&["2025-02-01T01:00:00+02:00", "2025-02-01T03:00:00+02:00"]);
+
+        assert_eq!(
+            kept(&backups, policy(None, None, None, Some(2))),
+            HashSet::from(["2025-02-01T01:00:00+02:00", "2025-02-01T03:00:00+02:00"])
+        );
+    }
+
+    #[test]
+    fn monthly_across_month_boundary() {
+        let backups = backups(&[
+            "2024-12-15T00:00:00Z",
+            "2025-01-31T23:59:59Z",
+            "2025-02-01T00:00:00Z",
+            "2025-02-15T00:00:00Z",
+        ]);
+
+        assert_eq!(
+            kept(&backups, policy(None, None, None, Some(2))),
+            HashSet::from(["2025-02-15T00:00:00Z", "2025-01-31T23:59:59Z"])
+        );
+    }
+
+    #[test]
+    fn weekly_uses_iso_weeks() {
+        // 2024-12-30 and 2024-12-31 are in ISO week 1 of 2025, 2024-12-29 is a
+        // Sunday in week 52 of 2024.
+        let backups = backups(&[
+            "2024-12-28T00:00:00Z",
+            "2024-12-29T23:59:59Z",
+            "2024-12-30T00:00:00Z",
+            "2024-12-31T00:00:00Z",
+        ]);
+
+        assert_eq!(
+            kept(&backups, policy(None, None, Some(2), None)),
+            HashSet::from(["2024-12-31T00:00:00Z", "2024-12-29T23:59:59Z"])
+        );
+    }
+
+    #[test]
+    fn policies_are_unioned() {
+        let backups = backups(&[
+            "2024-11-30T00:00:00Z",
+            "2024-12-31T00:00:00Z",
+            "2025-01-01T00:00:00Z",
+            "2025-01-02T00:00:00Z",
+            "2025-01-03T00:00:00Z",
+        ]);
+
+        assert_eq!(
+            kept(&backups, policy(Some(1), Some(2), None, Some(3))),
+            HashSet::from([
+                "2025-01-03T00:00:00Z",
+                "2025-01-02T00:00:00Z",
+                "2024-12-31T00:00:00Z",
+                "2024-11-30T00:00:00Z",
+            ])
+        );
+    }
+
+    #[test]
+    fn keep_within() {
+        let backups = backups(&[
+            "2025-06-20T00:00:00Z",
+            "2025-06-29T23:00:00Z",
+            "2025-06-30T12:00:00Z",
+        ]);
+
+        let opts = Options {
+            keep_within: Some(Duration::hours(25)),
+            ..policy(None, None, None, None)
+        };
+        assert_eq!(
+            kept(&backups, opts),
+            HashSet::from(["2025-06-30T12:00:00Z", "2025-06-29T23:00:00Z"])
+        );
+    }
+
+    #[test]
+    fn pinned_backups_are_kept_without_counting() {
+        let mut backups = backups(&[
+            "2025-01-01T00:00:00Z",
+            "2025-01-02T00:00:00Z",
+            "2025-01-03T00:00:00Z",
+        ]);
+        backups[0].pinned = true;
+        backups[2].pinned = true;
+
+        assert_eq!(
+            kept(&backups, policy(Some(1), None, None, None)),
+            HashSet::from([
+                "2025-01-03T00:00:00Z",
+                "2025-01-02T00:00:00Z",
+                "2025-01-01T00:00:00Z",
+            ])
+        );
+    }
+
+    #[test]
+    fn durations() {
+        assert_eq!(parse_duration("36h"), Ok(Duration::hours(36)));
+        assert_eq!(parse_duration("2w3d"), Ok(Duration::days(17)));
+        assert_eq!(parse_duration("90m30s"), Ok(Duration::seconds(5430)));
+        assert!(parse_duration("").is_err());
+        assert!(parse_duration("12").is_err());
+        assert!(parse_duration("3y").is_err());
+        assert!(parse_duration("3é").is_err());
+        assert!(parse_duration("0d").is_err());
+    }
+
+    #[test]
+    fn wal_expiry_compares_segments_across_timelines() {
+        const SEGMENT_SIZE: u64 = manifest::DEFAULT_WAL_SEGMENT_SIZE;
+        // The oldest kept backup starts on timeline 2 at segment 1/05.
+        let (_, start) =
+            create::parse_wal_segment("000000020000000100000005", SEGMENT_SIZE).unwrap();
+        let expired = |name| wal_expired(name, start, SEGMENT_SIZE);
+
+        // Older positions go whatever the timeline, even though the names of
+        // timeline 1 sort before and those of timeline 3 after the start.
+        assert!(expired("000000010000000100000004"));
+        assert!(expired("0000000100000000000000FF"));
+        assert!(expired("000000020000000100000004"));
+        assert!(expired("000000030000000100000004"));
+        assert!(expired("000000010000000100000004.partial"));
+        assert!(expired("000000020000000100000004.00000028.backup"));
+
+        // The start and anything after it stays, on every timeline.
+        assert!(!expired("000000020000000100000005"));
+        assert!(!expired("000000010000000100000005"));
+        assert!(!expired("000000010000000100000006"));
+        assert!(!expired("000000010000000100000005.partial"));
+        assert!(!expired("000000030000000200000000"));
+        assert!(!expired("000000020000000100000005.00000028.backup"));
+
+        // History files are never expired, nor anything unknown.
+        assert!(!expired("00000001.history"));
+        assert!(!expired("00000002.history"));
+        assert!(!expired("RECOVERYHISTORY"));
+    }
+
+    #[test]
+    fn wal_expiry_starts_at_the_oldest_kept_backup() {
+        let mut backups = backups(&[
+            "2025-01-01T00:00:00Z",
+            "2025-01-02T00:00:00Z",
+            "2025-01-03T00:00:00Z",
+        ]);
+        backups[0].start_wal = "000000030000000000000030".to_owned();
+        backups[1].start_wal = "000000020000000000000012".to_owned();
+        backups[2].start_wal = "000000010000000000000020".to_owned();
+        let refs = backups.iter().collect::<Vec<_>>();
+        let oldest = |refs: &[&Manifest]| {
+            oldest_start(refs).map(|(backup, start)| (backup.label.clone(), start))
+        };
+        assert_eq!(
+            oldest(&refs),
+            Some(("2025-01-02T00:00:00Z".to_owned(), 0x12))
+        );
+        assert_eq!(
+            oldest(&refs[..1]),
+            Some(("2025-01-03T00:00:00Z".to_owned(), 0x30))
+        );
+
+        // A backup without a known start keeps all WAL.
+        backups[1].start_wal = String::new();
+        let refs = backups.iter().collect::<Vec<_>>();
+        assert_eq!(oldest(&refs), None);
+        assert_eq!(oldest(&[]), None);
+    }
+}
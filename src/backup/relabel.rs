@@ -0,0 +1,56 @@
+use anyhow::{bail, Result};
+use clap::Args;
+use log::info;
+
+use super::manifest::{self, Manifest};
+use crate::context::Context;
+
+#[derive(Debug, Args)]
+pub struct Options {
+    #[arg(long)]
+    pub from: String,
+
+    #[arg(long)]
+    pub to: String,
+
+    /// Wait for other operations on the repository to finish instead of
+    /// failing
+    #[arg(long)]
+    pub wait: bool,
+}
+
+// Only the manifest is keyed by label, the data it references is keyed by the
+// backup id or content, so relabeling moves the manifest alone.
+pub fn run(ctx: &Context, opts: &Options) -> Result<()> {
+    if opts.to == manifest::LATEST {
+        bail!(
+            "{} is reserved and can't be used as a label",
+            manifest::LATEST
+        );
+    }
+
+    let _lock = ctx.storage.lock(opts.wait)?;
+    let old_key = Manifest::key(&opts.from);
+    let new_key = Manifest::key(&opts.to);
+    if !ctx.storage.exists(&old_key)? {
+        bail!("backup {} not found", opts.from);
+    }
+
+    if ctx.storage.exists(&new_key)? {
+        bail!("backup {} already exists", opts.to);
+    }
+
+    let mut manifest = Manifest::load(ctx, &old_key)?;
+    manifest.label = opts.to.clone();
+
+    // The new manifest is complete before the old one goes, an interruption
+    // in between leaves the backup under both labels rather than neither.
+    manifest.save(ctx)?;
+    ctx.storage.delete(&old_key)?;
+
+    info!(
+        "relabeled backup {} ({}) to {}",
+        opts.from, manifest.id, opts.to
+    );
+    Ok(())
+}
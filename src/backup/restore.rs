@@ -0,0 +1,1907 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    env,
+    ffi::CString,
+    fs::{self, DirBuilder, File, OpenOptions, Permissions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    mem::MaybeUninit,
+    os::unix::fs::{lchown, symlink, DirBuilderExt, OpenOptionsExt, PermissionsExt},
+    path::{Path, PathBuf},
+    process::Command,
+    ptr,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Mutex,
+    },
+    thread,
+    time::{Duration, Instant, UNIX_EPOCH},
+};
+
+use anyhow::{bail, Context as _, Result};
+use clap::{ArgGroup, Args, ValueEnum};
+use log::{info, warn};
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+use uuid::Uuid;
+use walkdir::WalkDir;
+
+use super::{
+    chunker::Blocks,
+    codec::Codec,
+    create,
+    import,
+    manifest::{self, BackupKind, ChunkRef, FileInfo, Manifest, BLOCK_SIZE, PG_CONTROL},
+};
+use crate::context::Context;
+
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(5);
+
+// Kept in the target directory while a restore is in progress.
+const STATE_FILE: &str = ".pgpitr_restore";
+
+// Directories excluded from or left empty in a backup that PostgreSQL
+// nevertheless expects to exist on startup.
+pub(super) const REQUIRED_DIRS: &[&str] = &[
+    "pg_wal/archive_status",
+    "pg_commit_ts",
+    "pg_dynshmem",
+    "pg_notify",
+    "pg_serial",
+    "pg_snapshots",
+    "pg_stat",
+    "pg_stat_tmp",
+    "pg_subtrans",
+    "pg_twophase",
+    "pg_replslot",
+    "pg_tblspc",
+    "pg_logical/snapshots",
+    "pg_logical/mappings",
+];
+
+#[derive(Debug, Args)]
+#[command(group(ArgGroup::new("target").args(["target_time", "target_lsn", "target_xid"])))]
+#[command(group(ArgGroup::new("source").args(["label", "input"]).required(true)))]
+pub struct Options {
+    /// Label of the backup, or "latest" for the newest one
+    #[arg(long)]
+    pub label: Option<String>,
+
+    /// Restore the tar archive written by create-backup --output or export
+    /// from FILE, or - for stdin, instead of a backup in the repository
+    #[arg(
+        long,
+        value_name = "FILE",
+        conflicts_with_all = ["delta", "resume", "dry_run", "tablespace_mappings"]
+    )]
+    pub input: Option<PathBuf>,
+
+    #[arg(long)]
+    pub target_dir: PathBuf,
+
+    #[arg(long)]
+    pub force: bool,
+
+    #[arg(long, value_parser = parse_timestamp)]
+    pub target_time: Option<OffsetDateTime>,
+
+    #[arg(long, value_parser = parse_lsn)]
+    pub target_lsn: Option<u64>,
+
+    #[arg(long)]
+    pub target_xid: Option<u32>,
+
+    /// What the server does once it reaches the recovery target, pause by
+    /// default
+    #[arg(long, value_enum, requires = "target")]
+    pub target_action: Option<TargetAction>,
+
+    #[arg(long, conflicts_with = "target")]
+    pub no_recovery_conf: bool,
+
+    /// Reuse files already in the target directory, only rewriting the blocks
+    /// that differ from the backup and removing files it doesn't contain
+    #[arg(long)]
+    pub delta: bool,
+
+    /// Restore the tablespace located at OLD into NEW instead, can be given
+    /// multiple times
+    #[arg(long = "tablespace-mapping", value_name = "OLD=NEW", value_parser = parse_tablespace_mapping)]
+    pub tablespace_mappings: Vec<(PathBuf, PathBuf)>,
+
+    /// Print what the restore would do and which WAL it needs, without
+    /// writing anything
+    #[arg(long)]
+    pub dry_run: bool,
+
+    /// Make the restored files owned by this user, which takes running as
+    /// root
+    #[arg(long, value_name = "USER")]
+    pub owner: Option<String>,
+
+    /// Continue an interrupted restore into the target directory, skipping
+    /// the files it already finished
+    #[arg(long, conflicts_with_all = ["force", "delta"])]
+    pub resume: bool,
+
+    /// Restore over a cluster in the target directory even if it is a
+    /// different one than the backup was taken from
+    #[arg(long)]
+    pub force_system_id: bool,
+
+    /// Number of tablespaces, counting the data directory, restored in
+    /// parallel
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..=256))]
+    pub jobs: u32,
+
+    /// Only restore the files matching GLOB, relative to the data directory,
+    /// and everything in matching directories, can be given multiple times.
+    /// They are written into the target directory as they are in the backup,
+    /// without preparing it for recovery
+    #[arg(
+        long = "path",
+        value_name = "GLOB",
+        value_parser = manifest::parse_glob,
+        conflicts_with_all = [
+            "target",
+            "no_recovery_conf",
+            "delta",
+            "resume",
+            "tablespace_mappings",
+            "dry_run",
+        ]
+    )]
+    pub paths: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum TargetAction {
+    /// Pause recovery to inspect the data before promoting
+    Pause,
+    /// Start accepting connections
+    Promote,
+    /// Stop the server
+    Shutdown,
+}
+
+impl TargetAction {
+    fn setting(self) -> &'static str {
+        match self {
+            Self::Pause => "pause",
+            Self::Promote => "promote",
+            Self::Shutdown => "shutdown",
+        }
+    }
+}
+
+enum RecoveryTarget {
+    Time(OffsetDateTime),
+    Lsn(u64),
+    Xid(u32),
+}
+
+impl Options {
+    fn recovery_target(&self) -> Option<RecoveryTarget> {
+        self.target_time
+            .map(RecoveryTarget::Time)
+            .or(self.target_lsn.map(RecoveryTarget::Lsn))
+            .or(self.target_xid.map(RecoveryTarget::Xid))
+    }
+}
+
+pub fn run(ctx: &Context, opts: &Options) -> Result<()> {
+    if let Some(input) = &opts.input {
+        return restore_archive(ctx, opts, input);
+    }
+
+    let manifests = manifest::load_all(ctx)?;
+    let label = opts
+        .label
+        .as_deref()
+        .context("--label or --input is required")?;
+    let manifest = manifest::find(&manifests, label)?;
+    let file_sizes = manifest.file_sizes()?;
+    if !opts.paths.is_empty() {
+        return restore_paths(ctx, opts, manifest, &manifest.chain(&manifests)?);
+    }
+
+    let Some(backup_label) = &manifest.backup_label else {
+        bail!(
+            "backup {} has no backup_label and cannot be restored consistently",
+            manifest.label
+        );
+    };
+
+    if let Some(target_time) = opts.target_time {
+        if target_time < manifest.created_at {
+            bail!(
+                "cannot recover to {}: backup {} was taken at {}",
+                target_time.format(&Rfc3339)?,
+                manifest.label,
+                manifest.created_at.format(&Rfc3339)?
+            );
+        }
+    }
+
+    let state_path = opts.target_dir.join(STATE_FILE);
+    match (state_path.exists(), opts.resume) {
+        (true, false) if !opts.force => bail!(
+            "previous restore into {:?} was interrupted, use --resume to continue it or --force \
+             to start over",
+            opts.target_dir
+        ),
+        (false, true) => bail!("no interrupted restore to resume in {:?}", opts.target_dir),
+        _ => (),
+    }
+
+    check_target_system_identifier(
+        manifest.system_identifier,
+        &format!("backup {}", manifest.label),
+        &opts.target_dir,
+        opts.force_system_id,
+    )?;
+    let owner = opts.owner.as_deref().map(lookup_user).transpose()?;
+    let layout = Layout::new(manifest, &opts.target_dir, &opts.tablespace_mappings)?;
+    let chain = manifest.chain(&manifests)?;
+    if !manifest.excluded.is_empty() {
+        info!(
+            "backup {} was taken without {:?}, they are not restored",
+            manifest.label, manifest.excluded
+        );
+    }
+
+    if opts.dry_run {
+        return print_plan(ctx, opts, manifest, &chain, &layout);
+    }
+
+    for root in layout.roots() {
+        prepare_target_dir(root, opts.force || opts.delta || opts.resume)?;
+    }
+
+    let mut dirs = layout
+        .roots()
+        .map(Path::to_path_buf)
+        .collect::<BTreeSet<_>>();
+    let mut stats = Stats::new(estimate_size(&chain));
+
+    if opts.delta {
+        remove_extra_files(&layout, &chain, &mut stats)?;
+    }
+
+    let state = RestoreState::open(&state_path, manifest, opts.resume)?;
+
+    for backup in &chain {
+        info!("restoring backup {} ({})", backup.label, backup.id);
+        match &backup.data {
+            BackupKind::Full { files } =>
+                restore_files(ctx, opts, backup, &layout, files, &mut dirs, &stats, &state)?,
+            BackupKind::Incremental { .. } =>
+                apply_bundle(ctx, backup, &layout, &mut dirs, &stats, &state)?,
+        }
+    }
+
+    if let Some(file_sizes) = file_sizes {
+        apply_file_sizes(&layout, &chain, file_sizes, &mut dirs, &mut stats)?;
+    }
+
+    write_file(&opts.target_dir.join("backup_label"), backup_label)?;
+    finish_restore(
+        ctx,
+        opts,
+        &layout,
+        &mut dirs,
+        manifest.standalone,
+        &format!("backup {}", manifest.label),
+        owner,
+    )?;
+
+    // Everything is on disk, only now is the restore no longer resumable.
+    fs::remove_file(&state_path)?;
+    File::open(&opts.target_dir)?.sync_all()?;
+
+    check_pg_version(manifest);
+    stats.log(&manifest.label);
+    Ok(())
+}
+
+// Restoring over the remains of another cluster and then recovering it from
+// this repository's WAL archive would mix two clusters' data.
+fn check_target_system_identifier(
+    expected: Option<u64>,
+    name: &str,
+    target_dir: &Path,
+    force: bool,
+) -> Result<()> {
+    let (Some(expected), Some(existing)) =
+        (expected, manifest::read_system_identifier(target_dir)?)
+    else {
+        return Ok(());
+    };
+
+    if existing != expected {
+        if !force {
+            bail!(
+                "target directory holds a cluster with system identifier {} but {} is of {}, use \
+                 --force-system-id to restore over it anyway",
+                existing,
+                name,
+                expected
+            );
+        }
+
+        warn!(
+            "restoring {} of cluster {} over cluster {}",
+            name, expected, existing
+        );
+    }
+
+    Ok(())
+}
+
+// Returns the uid and primary gid of a user.
+fn lookup_user(name: &str) -> Result<(u32, u32)> {
+    let c_name = CString::new(name)?;
+    let mut passwd = MaybeUninit::<libc::passwd>::uninit();
+    let mut buf = vec![0; 16384];
+    let mut result = ptr::null_mut();
+    let ret = unsafe {
+        libc::getpwnam_r(
+            c_name.as_ptr(),
+            passwd.as_mut_ptr(),
+            buf.as_mut_ptr(),
+            buf.len(),
+            &mut result,
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::from_raw_os_error(ret))
+            .with_context(|| format!("failed to look up user {}", name));
+    }
+
+    if result.is_null() {
+        bail!("user {} not found", name);
+    }
+
+    let passwd = unsafe { passwd.assume_init() };
+    let euid = unsafe { libc::geteuid() };
+    if euid != 0 && euid != passwd.pw_uid {
+        bail!("restoring files owned by {} takes running as root", name);
+    }
+
+    Ok((passwd.pw_uid, passwd.pw_gid))
+}
+
+// Archives from create-backup --output and export already hold backup_label,
+// and no tablespaces. Their entries get the same checks as files from the
+// repository before being written, an archive may come from anywhere.
+fn restore_archive(ctx: &Context, opts: &Options, input: &Path) -> Result<()> {
+    let (reader, name): (Box<dyn Read>, _) = if input == Path::new("-") {
+        (Box::new(io::stdin().lock()), "stdin".to_owned())
+    } else {
+        let file =
+            File::open(input).with_context(|| format!("failed to open {}", input.display()))?;
+        (Box::new(file), input.display().to_string())
+    };
+
+    let owner = opts.owner.as_deref().map(lookup_user).transpose()?;
+    prepare_target_dir(&opts.target_dir, opts.force)?;
+    let layout = Layout {
+        target_dir: opts.target_dir.clone(),
+        tablespaces: BTreeMap::new(),
+        excluded: Vec::new(),
+        paths: opts.paths.clone(),
+    };
+    let mut dirs = BTreeSet::from([opts.target_dir.clone()]);
+    let stats = Stats::new((0, 1));
+    let mut standalone = false;
+    let mut has_backup_label = false;
+
+    // Archives written by pg_pitr start with pg_control, so over another
+    // cluster nothing is written before its system identifier is checked.
+    let mut unchecked = opts.paths.is_empty()
+        && !opts.force_system_id
+        && manifest::read_system_identifier(&opts.target_dir)?.is_some();
+
+    let mut archive = tar::Archive::new(import::decompress(reader)?);
+    for entry in archive.entries()? {
+        let mut entry = entry.with_context(|| format!("failed to read archive {}", name))?;
+        let path = entry.path()?.into_owned();
+        if layout.skips(&path) {
+            continue;
+        }
+
+        if unchecked && entry.header().entry_type() != tar::EntryType::XGlobalHeader {
+            if path != Path::new(PG_CONTROL) {
+                bail!(
+                    "archive {} doesn't start with {}, use --force-system-id to restore it over \
+                     the cluster in the target directory anyway",
+                    name,
+                    PG_CONTROL
+                );
+            }
+
+            unchecked = false;
+        }
+
+        match entry.header().entry_type() {
+            tar::EntryType::Regular => {
+                let mode = entry.header().mode()? & 0o7777;
+                let mtime = entry.header().mtime()? as i64;
+                let dest_path = create_parent(&layout, &path, &mut dirs)?;
+                let mut contents = Vec::new();
+                if path == Path::new(PG_CONTROL) {
+                    entry.read_to_end(&mut contents)?;
+                    check_target_system_identifier(
+                        manifest::parse_system_identifier(&contents),
+                        &name,
+                        &opts.target_dir,
+                        opts.force_system_id,
+                    )?;
+                }
+
+                let mut file = create_file(&dest_path)?;
+                file.write_all(&contents)?;
+                stats.add_written(contents.len() as u64 + io::copy(&mut entry, &mut file)?);
+                set_metadata(&file, Some(mode), Some(mtime))?;
+                file.sync_all()?;
+                stats.add_file(&path);
+                standalone |= path.starts_with("pg_wal");
+                has_backup_label |= path == Path::new("backup_label");
+            },
+            tar::EntryType::Directory => {
+                let dest_path = create_parent(&layout, &path, &mut dirs)?;
+                match dest_path.symlink_metadata() {
+                    Ok(metadata) if metadata.is_dir() => (),
+                    Ok(_) => bail!("{:?} is in the way of directory {:?}", dest_path, path),
+                    Err(err) if err.kind() == io::ErrorKind::NotFound =>
+                        DirBuilder::new().mode(0o700).create(&dest_path)?,
+                    Err(err) => return Err(err.into()),
+                }
+                dirs.insert(dest_path);
+            },
+            tar::EntryType::Symlink => {
+                let Some(target) = entry.link_name()? else {
+                    bail!("symlink {:?} in archive {} has no target", path, name);
+                };
+                let dest_path = create_parent(&layout, &path, &mut dirs)?;
+                if dest_path.symlink_metadata().is_ok() {
+                    fs::remove_file(&dest_path)?;
+                }
+
+                symlink(target, &dest_path)?;
+            },
+            tar::EntryType::XGlobalHeader => (),
+            entry_type => bail!(
+                "unsupported entry {:?} of type {:?} in archive {}",
+                path,
+                entry_type,
+                name
+            ),
+        }
+    }
+
+    if !opts.paths.is_empty() {
+        return finish_paths(&layout, &dirs, &stats, &name, owner);
+    }
+
+    if !has_backup_label {
+        bail!(
+            "archive {} has no backup_label and cannot be restored consistently",
+            name
+        );
+    }
+
+    finish_restore(
+        ctx,
+        opts,
+        &layout,
+        &mut dirs,
+        standalone,
+        &format!("archive {}", name),
+        owner,
+    )?;
+    stats.log(&name);
+    Ok(())
+}
+
+// The files matching --path are restored from each backup in the chain the
+// same way a whole backup is, into the target directory alone: tablespaces
+// aren't followed, there is no cluster to check the system identifier of and
+// nothing to resume.
+fn restore_paths(
+    ctx: &Context,
+    opts: &Options,
+    manifest: &Manifest,
+    chain: &[&Manifest],
+) -> Result<()> {
+    let layout = Layout {
+        target_dir: opts.target_dir.clone(),
+        tablespaces: BTreeMap::new(),
+        excluded: manifest.excluded.clone(),
+        paths: opts.paths.clone(),
+    };
+    let matches = chain.iter().any(|backup| match &backup.data {
+        BackupKind::Full { files } => files.keys().any(|path| !layout.skips(path)),
+        BackupKind::Incremental { changed_blocks, .. } =>
+            changed_blocks.keys().any(|path| !layout.skips(path)),
+    });
+    if !matches {
+        bail!(
+            "no files in backup {} match {}",
+            manifest.label,
+            opts.paths.join(", ")
+        );
+    }
+
+    let owner = opts.owner.as_deref().map(lookup_user).transpose()?;
+    prepare_target_dir(&opts.target_dir, opts.force)?;
+    let mut dirs = BTreeSet::from([opts.target_dir.clone()]);
+    let mut stats = Stats::new((0, 1));
+    let state = RestoreState::untracked();
+    for backup in chain {
+        info!(
+            "restoring matching files of backup {} ({})",
+            backup.label, backup.id
+        );
+        match &backup.data {
+            BackupKind::Full { files } =>
+                restore_files(ctx, opts, backup, &layout, files, &mut dirs, &stats, &state)?,
+            BackupKind::Incremental { .. } =>
+                apply_bundle(ctx, backup, &layout, &mut dirs, &stats, &state)?,
+        }
+    }
+
+    if let Some(file_sizes) = manifest.file_sizes()? {
+        apply_file_sizes(&layout, chain, file_sizes, &mut dirs, &mut stats)?;
+    }
+
+    finish_paths(&layout, &dirs, &stats, &manifest.label, owner)
+}
+
+fn finish_paths(
+    layout: &Layout,
+    dirs: &BTreeSet<PathBuf>,
+    stats: &Stats,
+    name: &str,
+    owner: Option<(u32, u32)>,
+) -> Result<()> {
+    if stats.files.lock().unwrap().is_empty() {
+        bail!("no files in {} match {}", name, layout.paths.join(", "));
+    }
+
+    // Only what was restored, the target directory may hold anything else.
+    if let Some((uid, gid)) = owner {
+        let restored = stats.files.lock().unwrap();
+        for path in dirs
+            .iter()
+            .filter(|dir| dir.starts_with(&layout.target_dir))
+            .cloned()
+            .chain(restored.iter().map(|path| layout.resolve(path)))
+        {
+            lchown(&path, Some(uid), Some(gid))
+                .with_context(|| format!("failed to change the owner of {:?}", path))?;
+        }
+    }
+
+    for dir in dirs {
+        File::open(dir)?.sync_all()?;
+    }
+
+    stats.log(name);
+    Ok(())
+}
+
+// Shared by restores from the repository and from archives, once the files are
+// in place.
+fn finish_restore(
+    ctx: &Context,
+    opts: &Options,
+    layout: &Layout,
+    dirs: &mut BTreeSet<PathBuf>,
+    standalone: bool,
+    name: &str,
+    owner: Option<(u32, u32)>,
+) -> Result<()> {
+    for dir in REQUIRED_DIRS {
+        let dir_path = opts.target_dir.join(dir);
+        DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(&dir_path)?;
+        dirs.extend(dir_path.ancestors().map(Path::to_path_buf));
+    }
+
+    layout.link_tablespaces()?;
+
+    if opts.target_dir.join("standby.signal").exists() {
+        warn!("target directory contains standby.signal, the server will start as a standby");
+    }
+
+    // Without recovery.signal the server replays the WAL in pg_wal up to the
+    // end of the backup and starts.
+    let target = opts.recovery_target();
+    if standalone && target.is_none() {
+        info!(
+            "{} contains the WAL it needs, not setting a restore_command",
+            name
+        );
+    } else if !opts.no_recovery_conf {
+        write_recovery_conf(ctx, &opts.target_dir, target, opts.target_action)?;
+    }
+
+    if let Some((uid, gid)) = owner {
+        for root in layout.roots() {
+            for entry in WalkDir::new(root) {
+                let entry = entry?;
+                lchown(entry.path(), Some(uid), Some(gid))
+                    .with_context(|| format!("failed to change the owner of {:?}", entry.path()))?;
+            }
+        }
+    }
+
+    for dir in dirs
+        .iter()
+        .filter(|dir| layout.roots().any(|root| dir.starts_with(root)))
+    {
+        File::open(dir)?.sync_all()?;
+    }
+
+    Ok(())
+}
+
+// A data directory only starts with binaries of the major version that wrote
+// it, which is easy to miss until the server refuses to start.
+fn check_pg_version(manifest: &Manifest) {
+    let Some(backup_major) = manifest.pg_major_version() else {
+        return;
+    };
+
+    let installed = match Command::new("pg_ctl").arg("--version").output() {
+        Ok(output) if output.status.success() =>
+            String::from_utf8_lossy(&output.stdout).into_owned(),
+        _ => {
+            info!("pg_ctl not found, skipping PostgreSQL version check");
+            return;
+        },
+    };
+
+    match manifest::parse_major_version(&installed) {
+        Some(installed_major) if installed_major != backup_major => warn!(
+            "backup {} was taken from PostgreSQL {} but the installed pg_ctl is version {}, the \
+             restored cluster will not start with it",
+            manifest.label, backup_major, installed_major
+        ),
+        _ => (),
+    }
+}
+
+// Everything a restore would do that can be worked out without touching the
+// target directory. The WAL is only looked for on the timeline of the backup,
+// recovery may follow later timelines from there.
+fn print_plan(
+    ctx: &Context,
+    opts: &Options,
+    manifest: &Manifest,
+    chain: &[&Manifest],
+    layout: &Layout,
+) -> Result<()> {
+    println!("backup:       {} ({})", manifest.label, manifest.id);
+    for backup in chain {
+        match &backup.data {
+            BackupKind::Full { files } => println!(
+                "extract:      {}, full, {} files",
+                backup.label,
+                files.len()
+            ),
+            BackupKind::Incremental { changed_blocks, .. } => println!(
+                "extract:      {}, incremental, {} blocks from {}",
+                backup.label,
+                changed_blocks.values().map(HashMap::len).sum::<usize>(),
+                backup.bundle_key()
+            ),
+        }
+    }
+
+    for path in &manifest.excluded {
+        println!("excluded:     {}", path.display());
+    }
+
+    for root in layout.roots() {
+        let state = match check_target_dir(root, opts.force || opts.delta || opts.resume)? {
+            true if opts.resume => "exists, finished files are skipped",
+            true if opts.delta => "exists, only differing blocks are rewritten",
+            true => "exists",
+            false => "created",
+        };
+        println!("target:       {} ({})", root.display(), state);
+    }
+
+    let (write_bytes, unknown_size) = estimate_size(chain);
+    if unknown_size > 0 {
+        println!(
+            "write:        {} MiB and {} files of unknown size",
+            write_bytes / 1024 / 1024,
+            unknown_size
+        );
+    } else {
+        println!("write:        {} MiB", write_bytes / 1024 / 1024);
+    }
+
+    check_pg_version(manifest);
+    let target = opts.recovery_target();
+    if manifest.standalone && target.is_none() {
+        println!("WAL:          contained in the backup");
+        return Ok(());
+    }
+
+    let segment_size = manifest.wal_segment_size;
+    let start_segment = manifest.start_wal_segment()?;
+    let Some((timeline, first)) = create::parse_wal_segment(start_segment, segment_size) else {
+        bail!("invalid WAL segment name {}", start_segment);
+    };
+
+    let archived = ctx
+        .storage
+        .list("wal/")?
+        .iter()
+        .filter_map(|key| key.strip_prefix("wal/")?.split('-').next())
+        .filter_map(|name| create::parse_wal_segment(name, segment_size))
+        .filter(|(segment_timeline, _)| *segment_timeline == timeline)
+        .map(|(_, segment)| segment)
+        .collect::<BTreeSet<_>>();
+
+    // Without an LSN there's no telling where recovery stops short of reading
+    // the WAL, so everything archived after the backup may be replayed.
+    let (last, bound) = match target {
+        Some(RecoveryTarget::Lsn(lsn)) if lsn / segment_size < first => bail!(
+            "target LSN {} is before the start of the backup",
+            format_lsn(lsn)
+        ),
+        Some(RecoveryTarget::Lsn(lsn)) => (lsn / segment_size, ""),
+        _ => (
+            archived.last().copied().unwrap_or(first).max(first),
+            "up to ",
+        ),
+    };
+
+    println!(
+        "WAL:          {}{} segments, {} to {}",
+        bound,
+        last - first + 1,
+        start_segment,
+        create::wal_segment_name(timeline, last, segment_size)
+    );
+
+    let missing = (first..=last)
+        .filter(|segment| !archived.contains(segment))
+        .map(|segment| create::wal_segment_name(timeline, segment, segment_size))
+        .collect::<Vec<_>>();
+    if missing.is_empty() {
+        println!("missing WAL:  none");
+    }
+    for segment in missing {
+        println!("missing WAL:  {}", segment);
+    }
+
+    Ok(())
+}
+
+// Returns the number of bytes a restore of the chain writes and the number of
+// files, from backups taken before sizes were recorded, not counted in it.
+fn estimate_size(chain: &[&Manifest]) -> (u64, usize) {
+    let mut bytes = 0;
+    let mut unknown = 0;
+    for backup in chain {
+        match &backup.data {
+            BackupKind::Full { files } =>
+                for info in files.values() {
+                    match info.size {
+                        Some(size) => bytes += size,
+                        None => unknown += 1,
+                    }
+                },
+            BackupKind::Incremental { changed_blocks, .. } =>
+                bytes += changed_blocks
+                    .values()
+                    .map(|blocks| (blocks.len() * BLOCK_SIZE) as u64)
+                    .sum::<u64>(),
+        }
+    }
+
+    (bytes, unknown)
+}
+
+// Returns whether the directory exists.
+fn check_target_dir(target_dir: &Path, force: bool) -> Result<bool> {
+    if !target_dir.exists() {
+        return Ok(false);
+    }
+
+    if !force && target_dir.read_dir()?.next().is_some() {
+        bail!(
+            "target directory {:?} is not empty, use --force to restore anyway",
+            target_dir
+        );
+    }
+
+    Ok(true)
+}
+
+fn prepare_target_dir(target_dir: &Path, force: bool) -> Result<()> {
+    if !check_target_dir(target_dir, force)? {
+        DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(target_dir)?;
+    }
+
+    Ok(())
+}
+
+// Where the files of a backup go, tablespaces live outside of the data
+// directory and are reached through symlinks in `pg_tblspc`.
+struct Layout {
+    target_dir: PathBuf,
+    tablespaces: BTreeMap<String, PathBuf>,
+    // Left out of the backup being restored, so what the backups it builds on
+    // have of them isn't restored either.
+    excluded: Vec<PathBuf>,
+    // Globs from --path, when only the files matching them are restored.
+    paths: Vec<String>,
+}
+
+impl Layout {
+    fn new(
+        manifest: &Manifest,
+        target_dir: &Path,
+        mappings: &[(PathBuf, PathBuf)],
+    ) -> Result<Self> {
+        let mut tablespaces = BTreeMap::new();
+        let mut unmapped = Vec::new();
+        for (oid, location) in manifest.tablespaces() {
+            match mappings.iter().find(|(old, _)| *old == location) {
+                Some((_, new)) => {
+                    tablespaces.insert(oid.to_owned(), new.clone());
+                },
+                None => unmapped.push(format!("{} ({})", oid, location.display())),
+            }
+        }
+
+        if !unmapped.is_empty() {
+            bail!(
+                "tablespaces {} need to be mapped with --tablespace-mapping OLD=NEW",
+                unmapped.join(", ")
+            );
+        }
+
+        for (old, _) in mappings {
+            if !manifest
+                .tablespaces()
+                .any(|(_, location)| location == old.as_path())
+            {
+                warn!("backup {} has no tablespace at {:?}", manifest.label, old);
+            }
+        }
+
+        Ok(Self {
+            target_dir: target_dir.to_owned(),
+            tablespaces,
+            excluded: manifest.excluded.clone(),
+            paths: Vec::new(),
+        })
+    }
+
+    fn roots(&self) -> impl Iterator<Item = &Path> {
+        [self.target_dir.as_path()]
+            .into_iter()
+            .chain(self.tablespaces.values().map(PathBuf::as_path))
+    }
+
+    // The data directory or tablespace location the path is restored into.
+    fn root(&self, path: &Path) -> &Path {
+        let oid = path
+            .strip_prefix("pg_tblspc")
+            .ok()
+            .and_then(|rest| rest.components().next())
+            .and_then(|oid| oid.as_os_str().to_str());
+        match oid.and_then(|oid| self.tablespaces.get(oid)) {
+            Some(location) => location,
+            None => &self.target_dir,
+        }
+    }
+
+    // Whether the file isn't restored, because it was excluded or doesn't
+    // match --path.
+    fn skips(&self, path: &Path) -> bool {
+        self.excluded
+            .iter()
+            .any(|excluded| path.starts_with(excluded))
+            || !self.paths.is_empty()
+                && !path.ancestors().any(|ancestor| {
+                    !ancestor.as_os_str().is_empty()
+                        && self
+                            .paths
+                            .iter()
+                            .any(|pattern| manifest::glob_matches(pattern, ancestor))
+                })
+    }
+
+    fn resolve(&self, path: &Path) -> PathBuf {
+        if let Ok(rest) = path.strip_prefix("pg_tblspc") {
+            let mut components = rest.components();
+            let oid = components.next().and_then(|oid| oid.as_os_str().to_str());
+            if let Some(location) = oid.and_then(|oid| self.tablespaces.get(oid)) {
+                return location.join(components.as_path());
+            }
+        }
+
+        self.target_dir.join(path)
+    }
+
+    // PostgreSQL recreates the symlinks from tablespace_map when starting from
+    // a backup, but creating them upfront leaves a usable directory either way.
+    fn link_tablespaces(&self) -> Result<()> {
+        if self.tablespaces.is_empty() {
+            return Ok(());
+        }
+
+        let mut tablespace_map = String::new();
+        for (oid, location) in &self.tablespaces {
+            let link = self.target_dir.join("pg_tblspc").join(oid);
+            if link.symlink_metadata().is_ok() {
+                fs::remove_file(&link)?;
+            }
+
+            symlink(location, &link)?;
+            tablespace_map.push_str(&format!("{} {}\n", oid, location.display()));
+        }
+
+        write_file(&self.target_dir.join("tablespace_map"), &tablespace_map)
+    }
+}
+
+// The files a restore has finished writing and synced, one line each after the
+// id of the backup being restored, so that an interrupted restore continues
+// from there with --resume. Incremental bundles are still read from the start
+// on resume, but the blocks of finished files aren't written again. Files that
+// are not listed may be partially written and are rewritten whole.
+struct RestoreState {
+    finished: HashSet<(Uuid, PathBuf)>,
+    file: Option<Mutex<File>>,
+}
+
+impl RestoreState {
+    fn open(path: &Path, manifest: &Manifest, resume: bool) -> Result<Self> {
+        let header = format!("restore {}", manifest.id);
+        let mut finished = HashSet::new();
+        if resume {
+            let contents = fs::read_to_string(path)?;
+            let mut lines = contents.lines();
+            if lines.next() != Some(header.as_str()) {
+                bail!(
+                    "interrupted restore in {:?} is of another backup than {}",
+                    path.parent().unwrap(),
+                    manifest.label
+                );
+            }
+
+            // The last line is incomplete if the restore died writing it, the
+            // file it names is then simply restored again.
+            for line in lines {
+                let Some((id, file)) = line.split_once(' ') else {
+                    continue;
+                };
+                if let Ok(id) = Uuid::parse_str(id) {
+                    finished.insert((id, PathBuf::from(file)));
+                }
+            }
+
+            info!(
+                "resuming restore of backup {}, {} files already restored",
+                manifest.label,
+                finished.len()
+            );
+        } else {
+            let mut file = File::create(path)?;
+            writeln!(file, "{}", header)?;
+            file.sync_all()?;
+            File::open(path.parent().unwrap())?.sync_all()?;
+        }
+
+        let file = OpenOptions::new().append(true).open(path)?;
+        Ok(Self {
+            finished,
+            file: Some(Mutex::new(file)),
+        })
+    }
+
+    // For restores that can't be resumed, which record nothing.
+    fn untracked() -> Self {
+        Self {
+            finished: HashSet::new(),
+            file: None,
+        }
+    }
+
+    fn is_finished(&self, backup: &Manifest, path: &Path) -> bool {
+        self.finished.contains(&(backup.id, path.to_owned()))
+    }
+
+    // The file must be synced already, so that it is never listed with
+    // contents that didn't make it to disk.
+    fn finish(&self, backup: &Manifest, path: &Path) -> Result<()> {
+        if let Some(file) = &self.file {
+            let mut file = file.lock().unwrap();
+            writeln!(file, "{} {}", backup.id, path.display())?;
+            file.sync_data()?;
+        }
+
+        Ok(())
+    }
+}
+
+// Files are grouped by the data directory or tablespace they go to, and the
+// groups are handed out to up to --jobs workers so tablespaces on different
+// disks are written in parallel. The first error stops the other workers
+// before their next chunk, and is the one returned.
+#[allow(clippy::too_many_arguments)]
+fn restore_files(
+    ctx: &Context,
+    opts: &Options,
+    backup: &Manifest,
+    layout: &Layout,
+    files: &HashMap<PathBuf, FileInfo>,
+    dirs: &mut BTreeSet<PathBuf>,
+    stats: &Stats,
+    state: &RestoreState,
+) -> Result<()> {
+    // A file inside of a symlink would be written wherever the link leads.
+    let links = files
+        .iter()
+        .filter(|(_, info)| info.link_target.is_some())
+        .map(|(path, _)| path.as_path())
+        .collect::<HashSet<_>>();
+    if !links.is_empty() {
+        if let Some(path) = files
+            .keys()
+            .find(|path| path.ancestors().skip(1).any(|dir| links.contains(dir)))
+        {
+            bail!("backup {} has {:?} inside of a symlink", backup.label, path);
+        }
+    }
+
+    let mut groups = BTreeMap::<&Path, Vec<_>>::new();
+    for file in files.iter().filter(|(path, _)| !layout.skips(path)) {
+        groups.entry(layout.root(file.0)).or_default().push(file);
+    }
+
+    let workers = groups.len().min(opts.jobs as usize);
+    let queue = Mutex::new(groups.into_values().collect::<Vec<_>>());
+    let cancelled = AtomicBool::new(false);
+    let first_error = Mutex::new(None);
+    let worker = || {
+        let mut dirs = BTreeSet::new();
+        loop {
+            let Some(group) = queue.lock().unwrap().pop() else {
+                break;
+            };
+
+            for (path, info) in group {
+                if state.is_finished(backup, path) {
+                    stats.add_skipped(info.size.unwrap_or(0));
+                    continue;
+                }
+
+                let result = restore_file(
+                    ctx,
+                    backup.chunk_compression,
+                    opts.delta,
+                    layout,
+                    path,
+                    info,
+                    &mut dirs,
+                    stats,
+                    &cancelled,
+                )
+                .and_then(|finished| match finished {
+                    true => state.finish(backup, path),
+                    false => Ok(()),
+                });
+                if let Err(err) = result {
+                    cancelled.store(true, Ordering::Relaxed);
+                    first_error
+                        .lock()
+                        .unwrap()
+                        .get_or_insert(err.context(format!("failed to restore {:?}", path)));
+                }
+
+                if cancelled.load(Ordering::Relaxed) {
+                    return dirs;
+                }
+            }
+        }
+
+        dirs
+    };
+
+    thread::scope(|scope| {
+        let handles = (0..workers)
+            .map(|_| scope.spawn(worker))
+            .collect::<Vec<_>>();
+        for handle in handles {
+            dirs.extend(handle.join().unwrap());
+        }
+    });
+
+    match first_error.into_inner().unwrap() {
+        Some(err) => Err(err),
+        None => Ok(()),
+    }
+}
+
+// Returns whether the file was written completely, rather than stopped short
+// because another worker failed.
+#[allow(clippy::too_many_arguments)]
+fn restore_file(
+    ctx: &Context,
+    codec: Codec,
+    delta: bool,
+    layout: &Layout,
+    path: &Path,
+    info: &FileInfo,
+    dirs: &mut BTreeSet<PathBuf>,
+    stats: &Stats,
+    cancelled: &AtomicBool,
+) -> Result<bool> {
+    let dest_path = create_parent(layout, path, dirs)?;
+    if let Some(target) = &info.link_target {
+        if dest_path.symlink_metadata().is_ok() {
+            fs::remove_file(&dest_path)?;
+        }
+
+        symlink(target, &dest_path)?;
+    } else if delta
+        && dest_path
+            .symlink_metadata()
+            .is_ok_and(|metadata| metadata.is_file())
+    {
+        restore_file_delta(ctx, codec, &dest_path, info, stats)?;
+    } else {
+        let mut dest_file = create_file(&dest_path)?;
+        let mut data = Vec::new();
+        for chunk in &info.chunks {
+            if cancelled.load(Ordering::Relaxed) {
+                warn!("restore stopped with {:?} partially written", dest_path);
+                return Ok(false);
+            }
+
+            chunk.read(ctx, codec, &mut data)?;
+            dest_file.write_all(&data)?;
+            stats.add_written(data.len() as u64);
+        }
+
+        set_metadata(&dest_file, info.mode, info.mtime)?;
+        dest_file.sync_all()?;
+    }
+
+    stats.add_file(path);
+    Ok(true)
+}
+
+// Whatever is at `dest_path` is replaced rather than written through, a
+// symlink there, planted by an earlier entry of an archive or otherwise, would
+// have the file written wherever it points.
+fn create_file(dest_path: &Path) -> Result<File> {
+    match dest_path.symlink_metadata() {
+        Ok(metadata) if metadata.is_dir() =>
+            bail!("directory {:?} is in the way of a file", dest_path),
+        Ok(_) => fs::remove_file(dest_path)?,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => (),
+        Err(err) => return Err(err.into()),
+    }
+
+    OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .custom_flags(libc::O_NOFOLLOW)
+        .open(dest_path)
+        .with_context(|| format!("failed to create {:?}", dest_path))
+}
+
+// Paths are checked to stay in the target directory, and not to pass through a
+// symlink already in it, which could lead anywhere.
+fn create_parent(layout: &Layout, path: &Path, dirs: &mut BTreeSet<PathBuf>) -> Result<PathBuf> {
+    manifest::validate_path(path)?;
+    let dest_path = layout.resolve(path);
+    let parent = dest_path.parent().unwrap();
+    if !dirs.contains(parent) {
+        let root = layout.root(path);
+        for dir in parent.ancestors().take_while(|dir| *dir != root) {
+            if dir
+                .symlink_metadata()
+                .is_ok_and(|metadata| metadata.is_symlink())
+            {
+                bail!(
+                    "{:?} is a symlink, refusing to restore {:?} through it",
+                    dir,
+                    path
+                );
+            }
+        }
+
+        DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(parent)?;
+        dirs.extend(parent.ancestors().map(Path::to_path_buf));
+    }
+
+    Ok(dest_path)
+}
+
+// Compares an existing file against the blocks recorded in the backup and
+// rewrites only the ones that differ. Chunks are only fetched when something
+// differs, their boundaries within the file aren't known until decompressed.
+fn restore_file_delta(
+    ctx: &Context,
+    codec: Codec,
+    dest_path: &Path,
+    info: &FileInfo,
+    stats: &Stats,
+) -> Result<()> {
+    let mut dest_file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .custom_flags(libc::O_NOFOLLOW)
+        .open(dest_path)?;
+    let mut stale = HashSet::new();
+    let mut len = 0;
+    let mut block = Vec::with_capacity(BLOCK_SIZE);
+    for (index, expected) in info.blocks.iter().enumerate() {
+        block.clear();
+        (&mut dest_file)
+            .take(BLOCK_SIZE as u64)
+            .read_to_end(&mut block)?;
+        if ChunkRef::Object(blake3::hash(&block)) != *expected {
+            stale.insert(index);
+        }
+
+        len += block.len() as u64;
+    }
+
+    if stale.is_empty() {
+        // Everything recorded matched, at most the file is too long.
+        dest_file.set_len(len)?;
+        set_metadata(&dest_file, info.mode, info.mtime)?;
+        dest_file.sync_all()?;
+        stats.add_skipped(len);
+        return Ok(());
+    }
+
+    let mut index = 0;
+    let mut len = 0;
+    let mut write_block = |block: &[u8]| -> Result<()> {
+        if stale.contains(&index) {
+            dest_file.seek(SeekFrom::Start(len))?;
+            dest_file.write_all(block)?;
+            stats.add_written(block.len() as u64);
+        } else {
+            stats.add_skipped(block.len() as u64);
+        }
+
+        index += 1;
+        len += block.len() as u64;
+        Ok(())
+    };
+
+    let mut blocks = Blocks::new();
+    let mut data = Vec::new();
+    for chunk in &info.chunks {
+        chunk.read(ctx, codec, &mut data)?;
+        blocks.push(&data, &mut write_block)?;
+    }
+
+    blocks.finish(&mut write_block)?;
+    dest_file.set_len(len)?;
+    set_metadata(&dest_file, info.mode, info.mtime)?;
+    dest_file.sync_all()?;
+    Ok(())
+}
+
+// Removes the files in the target directory and tablespaces that none of the
+// backups in the chain contain, so a delta restore leaves nothing stale behind.
+// Excluded paths were never backed up, whatever is there is left alone.
+fn remove_extra_files(layout: &Layout, chain: &[&Manifest], stats: &mut Stats) -> Result<()> {
+    let mut expected = HashSet::new();
+    for backup in chain {
+        match &backup.data {
+            BackupKind::Full { files } =>
+                expected.extend(files.keys().map(|path| layout.resolve(path))),
+            BackupKind::Incremental { changed_blocks, .. } =>
+                expected.extend(changed_blocks.keys().map(|path| layout.resolve(path))),
+        }
+    }
+
+    let excluded = layout
+        .excluded
+        .iter()
+        .map(|path| layout.resolve(path))
+        .collect::<HashSet<_>>();
+    for root in layout.roots() {
+        for entry in WalkDir::new(root)
+            .into_iter()
+            .filter_entry(|entry| !excluded.contains(entry.path()))
+        {
+            let entry = entry?;
+            if entry.file_type().is_file() && !expected.contains(entry.path()) {
+                fs::remove_file(entry.path())?;
+                stats.removed_files += 1;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// Incremental bundles only hold the blocks that changed, so once the chain is
+// applied its files are cut to the size they had when the last backup was
+// taken, files created empty are created and the ones deleted by then removed.
+// Replay starts past the truncations and deletions and would never redo them.
+fn apply_file_sizes(
+    layout: &Layout,
+    chain: &[&Manifest],
+    file_sizes: &HashMap<PathBuf, u64>,
+    dirs: &mut BTreeSet<PathBuf>,
+    stats: &mut Stats,
+) -> Result<()> {
+    let mut paths = file_sizes.keys().collect::<BTreeSet<_>>();
+    for backup in chain {
+        match &backup.data {
+            BackupKind::Full { files } => paths.extend(
+                files
+                    .iter()
+                    .filter(|(_, info)| info.link_target.is_none())
+                    .map(|(path, _)| path),
+            ),
+            BackupKind::Incremental { changed_blocks, .. } => paths.extend(changed_blocks.keys()),
+        }
+    }
+
+    for path in paths.into_iter().filter(|path| !layout.skips(path)) {
+        let Some(&size) = file_sizes.get(path) else {
+            match fs::remove_file(layout.resolve(path)) {
+                Ok(()) => stats.removed_files += 1,
+                Err(err) if err.kind() == io::ErrorKind::NotFound => (),
+                Err(err) => return Err(err.into()),
+            }
+            continue;
+        };
+
+        let dest_path = create_parent(layout, path, dirs)?;
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .mode(0o600)
+            .custom_flags(libc::O_NOFOLLOW)
+            .open(&dest_path)
+            .with_context(|| format!("failed to open {:?}", dest_path))?;
+        let metadata = file.metadata()?;
+        if metadata.len() != size {
+            file.set_len(size)?;
+            file.set_modified(metadata.modified()?)?;
+            file.sync_all()?;
+        }
+    }
+
+    Ok(())
+}
+
+// A file an incremental bundle is applied to, with the mode and mtime it gets
+// once all of its blocks are written. Files an interrupted restore finished
+// aren't opened.
+type BundleFile = (PathBuf, Option<(File, u32, i64)>);
+
+// Incremental bundles hold the changed blocks of each file as tar entries named
+// `{file}/{block index}`, grouped by file.
+fn apply_bundle(
+    ctx: &Context,
+    backup: &Manifest,
+    layout: &Layout,
+    dirs: &mut BTreeSet<PathBuf>,
+    stats: &Stats,
+    state: &RestoreState,
+) -> Result<()> {
+    let bundle_key = backup.bundle_key();
+    let decoder = backup
+        .compression
+        .decoder(ctx.storage.open_reader(&bundle_key)?)?;
+    let mut bundle = tar::Archive::new(decoder);
+    let mut current: Option<BundleFile> = None;
+    let finish = |current: Option<BundleFile>| -> Result<()> {
+        if let Some((path, Some((file, mode, mtime)))) = current {
+            // Bundles from before mtimes were recorded have them all at zero.
+            set_metadata(&file, Some(mode), Some(mtime).filter(|mtime| *mtime > 0))?;
+            file.sync_all()?;
+            state.finish(backup, &path)?;
+        }
+
+        Ok(())
+    };
+
+    for entry in bundle.entries()? {
+        let mut entry = entry?;
+        let block_path = entry.path()?.into_owned();
+        let (Some(path), Some(index)) = (
+            block_path.parent(),
+            block_path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .and_then(|name| name.parse::<u64>().ok()),
+        ) else {
+            bail!(
+                "invalid block entry {:?} in bundle {}",
+                block_path,
+                bundle_key
+            );
+        };
+
+        if entry.header().entry_type() != tar::EntryType::Regular {
+            bail!(
+                "unexpected entry {:?} of type {:?} in bundle {}",
+                block_path,
+                entry.header().entry_type(),
+                bundle_key
+            );
+        }
+
+        if layout.skips(path) {
+            continue;
+        }
+
+        if current
+            .as_ref()
+            .map(|(current_path, _)| current_path.as_path())
+            != Some(path)
+        {
+            finish(current.take())?;
+            let file = if state.is_finished(backup, path) {
+                None
+            } else {
+                let dest_path = create_parent(layout, path, dirs)?;
+                stats.add_file(path);
+                // Blocks are written into what an earlier backup restored, a
+                // symlink there would have them written wherever it points.
+                if dest_path
+                    .symlink_metadata()
+                    .is_ok_and(|metadata| !metadata.is_file())
+                {
+                    bail!(
+                        "{:?} is not a regular file, refusing to apply blocks of {:?} to it",
+                        dest_path,
+                        path
+                    );
+                }
+
+                let file = OpenOptions::new()
+                    .write(true)
+                    .create(true)
+                    .truncate(false)
+                    .custom_flags(libc::O_NOFOLLOW)
+                    .open(&dest_path)
+                    .with_context(|| format!("failed to open {:?}", dest_path))?;
+                Some((
+                    file,
+                    entry.header().mode()? & 0o7777,
+                    entry.header().mtime()? as i64,
+                ))
+            };
+            current = Some((path.to_owned(), file));
+        }
+
+        match current.as_mut().unwrap() {
+            (_, Some((file, ..))) => {
+                file.seek(SeekFrom::Start(index * BLOCK_SIZE as u64))?;
+                stats.add_written(io::copy(&mut entry, file)?);
+            },
+            (_, None) => stats.add_skipped(entry.size()),
+        }
+    }
+
+    finish(current)
+}
+
+// Files without a recorded mode get the one PostgreSQL creates files with.
+fn set_metadata(file: &File, mode: Option<u32>, mtime: Option<i64>) -> Result<()> {
+    file.set_permissions(Permissions::from_mode(mode.unwrap_or(0o600)))?;
+    if let Some(mtime) = mtime {
+        file.set_modified(UNIX_EPOCH + Duration::from_secs(mtime.max(0) as u64))?;
+    }
+
+    Ok(())
+}
+
+fn write_file(path: &Path, contents: &str) -> Result<()> {
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)?;
+    file.write_all(contents.as_bytes())?;
+    file.sync_all()?;
+    Ok(())
+}
+
+fn write_recovery_conf(
+    ctx: &Context,
+    target_dir: &Path,
+    target: Option<RecoveryTarget>,
+    action: Option<TargetAction>,
+) -> Result<()> {
+    let auto_conf_path = target_dir.join("postgresql.auto.conf");
+    let existing = match fs::read_to_string(&auto_conf_path) {
+        Ok(existing) => existing,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => String::new(),
+        Err(err) => return Err(err.into()),
+    };
+
+    let mut storage_args = ctx.storage.command_args()?.into_iter();
+    let restore_command = [env::current_exe()?.to_string_lossy().into_owned()]
+        .into_iter()
+        .chain(storage_args.next())
+        .chain([target_dir.canonicalize()?.to_string_lossy().into_owned()])
+        .chain(storage_args)
+        .map(|arg| shell_quote(&arg))
+        .chain(["wal-get", "%f", "%p"].map(String::from))
+        .collect::<Vec<_>>()
+        .join(" ");
+
+    let mut settings = vec![("restore_command", restore_command)];
+    match target {
+        Some(RecoveryTarget::Time(time)) =>
+            settings.push(("recovery_target_time", time.format(&Rfc3339)?)),
+        Some(RecoveryTarget::Lsn(lsn)) => settings.push(("recovery_target_lsn", format_lsn(lsn))),
+        Some(RecoveryTarget::Xid(xid)) => settings.push(("recovery_target_xid", xid.to_string())),
+        None => (),
+    }
+
+    // Without a target recovery runs to the end of the WAL and promotes.
+    if target.is_some() {
+        let action = action.unwrap_or(TargetAction::Pause);
+        settings.push(("recovery_target_action", action.setting().to_owned()));
+    }
+
+    let mut auto_conf = OpenOptions::new()
+        .append(true)
+        .create(true)
+        .open(&auto_conf_path)?;
+    if !existing.is_empty() && !existing.ends_with('\n') {
+        writeln!(auto_conf)?;
+    }
+
+    for (name, value) in settings {
+        info!("setting {} = '{}'", name, value);
+        writeln!(auto_conf, "{} = '{}'", name, value.replace('\'', "''"))?;
+    }
+
+    auto_conf.sync_all()?;
+    File::create(target_dir.join("recovery.signal"))?.sync_all()?;
+    Ok(())
+}
+
+pub(super) fn shell_quote(arg: &str) -> String {
+    let is_safe = |c: char| c.is_ascii_alphanumeric() || "/._-+=:,".contains(c);
+    if !arg.is_empty() && arg.chars().all(is_safe) {
+        arg.to_owned()
+    } else {
+        format!("'{}'", arg.replace('\'', r"'\''"))
+    }
+}
+
+pub(crate) fn parse_lsn(s: &str) -> Result<u64, String> {
+    let parse_half = |half: &str| {
+        if half.is_empty() || half.len() > 8 {
+            return None;
+        }
+
+        u32::from_str_radix(half, 16).ok()
+    };
+
+    match s
+        .split_once('/')
+        .map(|(hi, lo)| (parse_half(hi), parse_half(lo)))
+    {
+        Some((Some(hi), Some(lo))) => Ok((hi as u64) << 32 | lo as u64),
+        _ => Err(format!("invalid LSN {:?}, expected the form X/XXXXXXXX", s)),
+    }
+}
+
+fn format_lsn(lsn: u64) -> String {
+    format!("{:X}/{:X}", lsn >> 32, lsn as u32)
+}
+
+pub(super) fn parse_tablespace_mapping(s: &str) -> Result<(PathBuf, PathBuf), String> {
+    match s.split_once('=') {
+        Some((old, new)) if Path::new(old).is_absolute() && Path::new(new).is_absolute() =>
+            Ok((PathBuf::from(old), PathBuf::from(new))),
+        _ => Err(format!(
+            "invalid tablespace mapping {:?}, expected OLD=NEW with absolute paths",
+            s
+        )),
+    }
+}
+
+pub(super) fn parse_timestamp(s: &str) -> Result<OffsetDateTime, time::error::Parse> {
+    OffsetDateTime::parse(s, &Rfc3339)
+}
+
+// Shared by the workers restoring files in parallel.
+struct Stats {
+    start_time: Instant,
+    last_log_time: Mutex<Instant>,
+    /// Bytes the restore is expected to write, if known for every file.
+    total_bytes: Option<u64>,
+    files: Mutex<BTreeSet<PathBuf>>,
+    written_bytes: AtomicU64,
+    skipped_bytes: AtomicU64,
+    removed_files: usize,
+}
+
+impl Stats {
+    fn new((total_bytes, unknown_size): (u64, usize)) -> Self {
+        Self {
+            start_time: Instant::now(),
+            last_log_time: Mutex::new(Instant::now()),
+            total_bytes: Some(total_bytes).filter(|_| unknown_size == 0),
+            files: Mutex::new(BTreeSet::new()),
+            written_bytes: AtomicU64::new(0),
+            skipped_bytes: AtomicU64::new(0),
+            removed_files: 0,
+        }
+    }
+
+    fn add_written(&self, bytes: u64) {
+        self.written_bytes.fetch_add(bytes, Ordering::Relaxed);
+        self.log_progress();
+    }
+
+    fn add_skipped(&self, bytes: u64) {
+        self.skipped_bytes.fetch_add(bytes, Ordering::Relaxed);
+        self.log_progress();
+    }
+
+    fn add_file(&self, path: &Path) {
+        self.files.lock().unwrap().insert(path.to_owned());
+    }
+
+    // Blocks a delta restore found unchanged count as done as well.
+    fn log_progress(&self) {
+        let mut last_log_time = self.last_log_time.lock().unwrap();
+        if last_log_time.elapsed() < PROGRESS_INTERVAL {
+            return;
+        }
+
+        *last_log_time = Instant::now();
+        let done_bytes =
+            self.written_bytes.load(Ordering::Relaxed) + self.skipped_bytes.load(Ordering::Relaxed);
+        let percentage = match self.total_bytes {
+            Some(total_bytes) if total_bytes > 0 => format!(
+                " ({:.1}%)",
+                (done_bytes as f64 / total_bytes as f64 * 100.0).min(100.0)
+            ),
+            _ => String::new(),
+        };
+        info!(
+            "progress: extracted {} MiB{} @ {:.2} MiB/s, {} files written",
+            done_bytes / 1024 / 1024,
+            percentage,
+            done_bytes as f32 / self.start_time.elapsed().as_secs_f32() / 1024.0 / 1024.0,
+            self.files.lock().unwrap().len()
+        );
+    }
+
+    fn log(&self, label: &str) {
+        let elapsed_secs = self.start_time.elapsed().as_secs_f32();
+        let written_bytes = self.written_bytes.load(Ordering::Relaxed);
+        let skipped_bytes = self.skipped_bytes.load(Ordering::Relaxed);
+        info!(
+            "restored backup {}: files: {}, write: {} MiB, elapsed: {:.1}s, throughput: {:.2} \
+             MiB/s",
+            label,
+            self.files.lock().unwrap().len(),
+            written_bytes / 1024 / 1024,
+            elapsed_secs,
+            written_bytes as f32 / elapsed_secs / 1024.0 / 1024.0
+        );
+
+        if skipped_bytes > 0 || self.removed_files > 0 {
+            info!(
+                "delta: skipped: {:.2} MiB, rewritten: {:.2} MiB, removed files: {}",
+                skipped_bytes as f64 / 1024.0 / 1024.0,
+                written_bytes as f64 / 1024.0 / 1024.0,
+                self.removed_files
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::{BTreeMap, BTreeSet, HashMap},
+        fs,
+        os::unix::fs::symlink,
+        path::Path,
+    };
+
+    use clap::Parser;
+    use time::OffsetDateTime;
+    use uuid::Uuid;
+
+    use super::{apply_bundle, restore_archive, Layout, Options, RestoreState, Stats};
+    use crate::{
+        backup::{
+            codec::Codec,
+            manifest::{self, BackupKind, Manifest, SCHEMA_VERSION},
+        },
+        test_support::TestDir,
+    };
+
+    #[derive(Parser)]
+    struct Command {
+        #[command(flatten)]
+        opts: Options,
+    }
+
+    #[test]
+    fn archive_symlinks_are_replaced_not_followed() {
+        let dir = TestDir::new();
+        let outside = dir.path("outside.txt");
+        fs::write(&outside, "original").unwrap();
+
+        // A symlink out of the target followed by a file of the same name.
+        let mut builder = tar::Builder::new(Vec::new());
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Symlink);
+        header.set_size(0);
+        header.set_mode(0o777);
+        builder
+            .append_link(&mut header, "escape", &outside)
+            .unwrap();
+        let mut header = tar::Header::new_gnu();
+        header.set_size(5);
+        header.set_mode(0o600);
+        header.set_cksum();
+        builder
+            .append_data(&mut header, "escape", &b"pwned"[..])
+            .unwrap();
+        let input = dir.path("archive.tar");
+        fs::write(&input, builder.into_inner().unwrap()).unwrap();
+
+        let target = dir.path("target");
+        let opts = Command::parse_from([
+            "restore".as_ref(),
+            "--input".as_ref(),
+            input.as_os_str(),
+            "--target-dir".as_ref(),
+            target.as_os_str(),
+            "--path".as_ref(),
+            "escape".as_ref(),
+        ])
+        .opts;
+        restore_archive(&dir.ctx, &opts, &input).unwrap();
+
+        assert_eq!(fs::read_to_string(&outside).unwrap(), "original");
+        let restored = target.join("escape");
+        assert!(restored.symlink_metadata().unwrap().is_file());
+        assert_eq!(fs::read_to_string(&restored).unwrap(), "pwned");
+    }
+
+    #[test]
+    fn bundles_are_not_applied_through_symlinks() {
+        let dir = TestDir::new();
+        let outside = dir.path("outside.txt");
+        fs::write(&outside, "original").unwrap();
+        let target = dir.path("target");
+        fs::create_dir(&target).unwrap();
+        symlink(&outside, target.join("escape")).unwrap();
+
+        let backup = Manifest {
+            schema_version: SCHEMA_VERSION,
+            id: Uuid::new_v4(),
+            created_at: OffsetDateTime::UNIX_EPOCH,
+            label: "incremental".to_owned(),
+            backup_label: None,
+            tablespace_map: None,
+            start_wal: String::new(),
+            pg_version: String::new(),
+            tool_version: String::new(),
+            system_identifier: None,
+            wal_segment_size: manifest::DEFAULT_WAL_SEGMENT_SIZE,
+            standalone: false,
+            pinned: false,
+            tags: Default::default(),
+            excluded: Vec::new(),
+            compression: Codec::None,
+            chunk_compression: Codec::None,
+            data: BackupKind::Incremental {
+                references: Uuid::new_v4(),
+                changed_blocks: HashMap::new(),
+                file_sizes: Some(HashMap::new()),
+                bundle_checksum: None,
+            },
+        };
+        let mut bundle = tar::Builder::new(Vec::new());
+        let mut header = tar::Header::new_old();
+        header.set_size(5);
+        header.set_mode(0o600);
+        header.set_cksum();
+        bundle
+            .append_data(&mut header, "escape/0", &b"pwned"[..])
+            .unwrap();
+        dir.ctx
+            .storage
+            .write(&backup.bundle_key(), &bundle.into_inner().unwrap())
+            .unwrap();
+
+        let layout = Layout {
+            target_dir: target.clone(),
+            tablespaces: BTreeMap::new(),
+            excluded: Vec::new(),
+            paths: Vec::new(),
+        };
+        let err = apply_bundle(
+            &dir.ctx,
+            &backup,
+            &layout,
+            &mut BTreeSet::from([target.clone()]),
+            &Stats::new((0, 1)),
+            &RestoreState::untracked(),
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("is not a regular file"));
+        assert_eq!(fs::read_to_string(&outside).unwrap(), "original");
+    }
+
+    #[test]
+    fn archives_over_a_cluster_start_with_pg_control() {
+        let dir = TestDir::new();
+        let target = dir.path("target");
+        fs::create_dir_all(target.join("global")).unwrap();
+        fs::write(target.join("global/pg_control"), 1u64.to_ne_bytes()).unwrap();
+
+        let archive = |paths: &[&str]| {
+            let mut builder = tar::Builder::new(Vec::new());
+            for path in paths {
+                let mut header = tar::Header::new_gnu();
+                header.set_size(8);
+                header.set_mode(0o600);
+                builder
+                    .append_data(&mut header, path, &2u64.to_ne_bytes()[..])
+                    .unwrap();
+            }
+            let input = dir.path("archive.tar");
+            fs::write(&input, builder.into_inner().unwrap()).unwrap();
+            input
+        };
+        let restore = |input: &Path| {
+            let opts = Command::parse_from([
+                "restore".as_ref(),
+                "--input".as_ref(),
+                input.as_os_str(),
+                "--target-dir".as_ref(),
+                target.as_os_str(),
+                "--force".as_ref(),
+            ])
+            .opts;
+            restore_archive(&dir.ctx, &opts, input).unwrap_err()
+        };
+
+        let err = restore(&archive(&["base/1/1259", "global/pg_control"]));
+        assert!(err.to_string().contains("doesn't start with"), "{}", err);
+        assert!(!target.join("base").exists());
+
+        let err = restore(&archive(&["global/pg_control", "base/1/1259"]));
+        assert!(err.to_string().contains("system identifier"), "{}", err);
+        assert!(!target.join("base").exists());
+    }
+}
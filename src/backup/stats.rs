@@ -0,0 +1,208 @@
+use std::{
+    collections::{HashMap, HashSet},
+    io,
+};
+
+use anyhow::Result;
+use clap::Args;
+use serde::Serialize;
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+
+use super::{
+    manifest::{self, BackupKind, BLOCK_SIZE},
+    prune::stored_chunks,
+};
+use crate::{context::Context, wal_pull::WalFileKind};
+
+#[derive(Debug, Args)]
+pub struct Options {
+    #[arg(long)]
+    pub json: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct Stats {
+    backups: Vec<BackupStats>,
+    /// Size of the files in all backups before compression and dedup, not
+    /// counting files from before their sizes were recorded.
+    logical_bytes: u64,
+    backup_bytes: u64,
+    oldest: Option<i64>,
+    newest: Option<i64>,
+    wal_segments: usize,
+    /// Last segments of timelines a standby was promoted in the middle of.
+    wal_partial_segments: usize,
+    /// Timeline history and backup history files.
+    wal_history_files: usize,
+    wal_bytes: u64,
+    chunks: usize,
+    chunk_references: usize,
+    dedup_ratio: Option<f64>,
+}
+
+#[derive(Debug, Serialize)]
+struct BackupStats {
+    label: String,
+    created_at: i64,
+    logical_size: u64,
+    size: u64,
+    /// Bytes only this backup refers to, which deleting it alone frees.
+    exclusive_size: u64,
+}
+
+pub fn run(ctx: &Context, opts: &Options) -> Result<()> {
+    let manifests = manifest::load_all(ctx)?;
+    let mut backups = manifests.values().collect::<Vec<_>>();
+    backups.sort_by_key(|manifest| manifest.created_at);
+
+    // Every object is looked up once, however many backups share it. Packed
+    // chunks count with their own size, not that of their pack.
+    let mut object_sizes = HashMap::new();
+    let backup_chunks = backups
+        .iter()
+        .map(|backup| stored_chunks(ctx, backup, &mut object_sizes))
+        .collect::<Result<Vec<_>>>()?;
+    let mut object_backups = HashMap::<&str, usize>::new();
+    for chunks in &backup_chunks {
+        let objects = chunks.keys().map(|(key, _)| key.as_str());
+        for key in objects.collect::<HashSet<_>>() {
+            *object_backups.entry(key).or_default() += 1;
+        }
+    }
+
+    let mut unique_chunks = HashMap::new();
+    let mut chunk_references = 0;
+    let mut referenced_bytes = 0;
+    let mut logical_bytes = 0;
+    let mut backup_stats = Vec::new();
+    let mut backup_bytes = object_sizes.values().sum::<u64>();
+    for (backup, chunks) in backups.iter().zip(&backup_chunks) {
+        let (logical_size, size, exclusive_size) = match &backup.data {
+            BackupKind::Full { files } => {
+                let size = chunks.values().sum::<u64>();
+                chunk_references += chunks.len();
+                referenced_bytes += size;
+                unique_chunks.extend(chunks.iter().map(|(chunk, size)| (chunk.clone(), *size)));
+
+                // Deleting the backup frees only the objects no other backup
+                // refers to, packs as a whole.
+                let exclusive_size = chunks
+                    .keys()
+                    .map(|(key, _)| key.as_str())
+                    .collect::<HashSet<_>>()
+                    .into_iter()
+                    .filter(|key| object_backups[key] == 1)
+                    .map(|key| object_sizes[key])
+                    .sum();
+
+                let logical_size = files.values().filter_map(|info| info.size).sum();
+                (logical_size, size, exclusive_size)
+            },
+            BackupKind::Incremental { changed_blocks, .. } => {
+                let size = ctx.storage.size(&backup.bundle_key())?;
+                backup_bytes += size;
+                let blocks = changed_blocks.values().map(HashMap::len).sum::<usize>();
+                ((blocks * BLOCK_SIZE) as u64, size, size)
+            },
+        };
+
+        logical_bytes += logical_size;
+        backup_stats.push(BackupStats {
+            label: backup.label.clone(),
+            created_at: backup.created_at.unix_timestamp(),
+            logical_size,
+            size,
+            exclusive_size,
+        });
+    }
+
+    let unique_bytes = unique_chunks.values().sum::<u64>();
+
+    let wal_keys = ctx.storage.list("wal/")?;
+    let mut wal_bytes = 0;
+    let mut wal_segments = 0;
+    let mut wal_partial_segments = 0;
+    for key in &wal_keys {
+        wal_bytes += ctx.storage.size(key)?;
+        let kind = key
+            .strip_prefix("wal/")
+            .and_then(|name| name.split('-').next())
+            .and_then(WalFileKind::of);
+        match kind {
+            Some(WalFileKind::Segment) => wal_segments += 1,
+            Some(WalFileKind::Partial) => wal_partial_segments += 1,
+            _ => (),
+        }
+    }
+
+    let stats = Stats {
+        backups: backup_stats,
+        logical_bytes,
+        backup_bytes,
+        oldest: backups
+            .first()
+            .map(|backup| backup.created_at.unix_timestamp()),
+        newest: backups
+            .last()
+            .map(|backup| backup.created_at.unix_timestamp()),
+        wal_segments,
+        wal_partial_segments,
+        wal_history_files: wal_keys.len() - wal_segments - wal_partial_segments,
+        wal_bytes,
+        chunks: unique_chunks.len(),
+        chunk_references,
+        dedup_ratio: (unique_bytes > 0).then(|| referenced_bytes as f64 / unique_bytes as f64),
+    };
+
+    if opts.json {
+        serde_json::to_writer_pretty(io::stdout().lock(), &stats)?;
+        println!();
+        return Ok(());
+    }
+
+    let mib = |bytes: u64| bytes as f64 / 1024.0 / 1024.0;
+    let timestamp = |timestamp: Option<i64>| -> Result<String> {
+        Ok(match timestamp {
+            Some(timestamp) => OffsetDateTime::from_unix_timestamp(timestamp)?.format(&Rfc3339)?,
+            None => "-".to_owned(),
+        })
+    };
+
+    println!("backups:     {}", stats.backups.len());
+    println!("logical:     {:.2} MiB", mib(stats.logical_bytes));
+    println!("size:        {:.2} MiB", mib(stats.backup_bytes));
+    println!("oldest:      {}", timestamp(stats.oldest)?);
+    println!("newest:      {}", timestamp(stats.newest)?);
+    println!(
+        "WAL:         {} segments, {} partial segments, {} history files, {:.2} MiB",
+        stats.wal_segments,
+        stats.wal_partial_segments,
+        stats.wal_history_files,
+        mib(stats.wal_bytes)
+    );
+    println!(
+        "chunks:      {} ({} references)",
+        stats.chunks, stats.chunk_references
+    );
+    if let Some(dedup_ratio) = stats.dedup_ratio {
+        println!("dedup ratio: {:.2}x", dedup_ratio);
+    }
+
+    println!();
+    println!(
+        "{:<24} {:<26} {:>13} {:>12} {:>16}",
+        "LABEL", "CREATED AT", "LOGICAL (MiB)", "SIZE (MiB)", "EXCLUSIVE (MiB)"
+    );
+    for backup in &stats.backups {
+        println!(
+            "{:<24} {:<26} {:>13.2} {:>12.2} {:>16.2}",
+            backup.label,
+            timestamp(Some(backup.created_at))?,
+            mib(backup.logical_size),
+            mib(backup.size),
+            mib(backup.exclusive_size)
+        );
+    }
+
+    Ok(())
+}
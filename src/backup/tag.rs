@@ -0,0 +1,54 @@
+use anyhow::Result;
+use clap::{ArgGroup, Args};
+use log::info;
+
+use super::manifest;
+use crate::context::Context;
+
+#[derive(Debug, Args)]
+#[command(group(ArgGroup::new("changes").required(true).multiple(true).args(["set", "remove"])))]
+pub struct Options {
+    /// Label of the backup, or "latest" for the newest one
+    #[arg(long)]
+    pub label: String,
+
+    /// Add a tag or change its value, can be given multiple times
+    #[arg(long, value_name = "KEY=VALUE", value_parser = manifest::parse_tag)]
+    pub set: Vec<(String, String)>,
+
+    /// Remove a tag, can be given multiple times
+    #[arg(long, value_name = "KEY", value_parser = parse_key)]
+    pub remove: Vec<String>,
+
+    /// Wait for other operations on the repository to finish instead of
+    /// failing
+    #[arg(long)]
+    pub wait: bool,
+}
+
+pub fn run(ctx: &Context, opts: &Options) -> Result<()> {
+    let _lock = ctx.storage.lock(opts.wait)?;
+    let mut manifests = manifest::load_all(ctx)?;
+    let id = manifest::find(&manifests, &opts.label)?.id;
+    let mut manifest = manifests.remove(&id).unwrap();
+
+    for key in &opts.remove {
+        if manifest.tags.remove(key).is_none() {
+            info!("backup {} has no tag {}", manifest.label, key);
+        }
+    }
+
+    manifest.tags.extend(opts.set.iter().cloned());
+    manifest.save(ctx)?;
+    info!(
+        "tags of backup {}: {}",
+        manifest.label,
+        manifest::format_tags(&manifest.tags)
+    );
+    Ok(())
+}
+
+fn parse_key(s: &str) -> Result<String, String> {
+    manifest::validate_tag_key(s)?;
+    Ok(s.to_owned())
+}
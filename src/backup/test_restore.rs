@@ -0,0 +1,210 @@
+use std::{
+    env,
+    fs,
+    net::TcpListener,
+    ops::RangeInclusive,
+    path::{self, Path, PathBuf},
+    process::Command,
+    time::Duration,
+};
+
+use anyhow::{anyhow, bail, Context as _, Result};
+use clap::Args;
+use log::{info, warn};
+use scopeguard::defer;
+use uuid::Uuid;
+
+use super::{
+    clone,
+    manifest::{self, Manifest},
+};
+use crate::context::Context;
+
+// Lines of the postgres log included when a stage fails.
+const LOG_EXCERPT_LINES: usize = 20;
+
+#[derive(Debug, Args)]
+pub struct Options {
+    /// Label of the backup, or "latest" for the newest one
+    #[arg(long)]
+    pub label: String,
+
+    /// Directory the backup is restored into a new subdirectory of
+    #[arg(long, default_value_os_t = env::temp_dir())]
+    pub temp_dir: PathBuf,
+
+    /// Ports the test server may listen on, the first free one from a random
+    /// starting point is used
+    #[arg(long, default_value = "49152-65535", value_parser = parse_port_range)]
+    pub port_range: RangeInclusive<u16>,
+
+    /// Seconds to wait for the server to start and to stop
+    #[arg(long, default_value_t = 300, value_parser = clap::value_parser!(u64).range(1..))]
+    pub timeout: u64,
+
+    /// Role to run the test query as
+    #[arg(long, env = "PGUSER", default_value = "postgres")]
+    pub user: String,
+
+    /// Keep the restored directory when the test fails
+    #[arg(long)]
+    pub keep_failed: bool,
+}
+
+// Restores the backup as a clone in a directory of its own and removes it
+// again afterwards, or when the test fails and --keep-failed isn't given.
+pub fn run(ctx: &Context, opts: &Options) -> Result<()> {
+    let manifests = manifest::load_all(ctx)?;
+    let manifest = manifest::find(&manifests, &opts.label)?;
+    let dir = path::absolute(
+        opts.temp_dir
+            .join(format!("pgpitr-restore-test-{}", Uuid::new_v4())),
+    )?;
+    let port = free_port(&opts.port_range)?;
+    info!(
+        "restoring backup {} into {:?} to test it on port {}",
+        manifest.label, dir, port
+    );
+
+    let result = test(ctx, opts, manifest, &dir, port);
+    match &result {
+        Err(_) if opts.keep_failed => warn!("keeping {:?} for inspection", dir),
+        _ =>
+            if let Err(err) = fs::remove_dir_all(&dir) {
+                warn!("failed to remove {:?}: {}", dir, err);
+            },
+    }
+
+    result?;
+    info!(
+        "backup {} restored and started successfully",
+        manifest.label
+    );
+    Ok(())
+}
+
+// The clone only listens on a socket in its directory and stops recovery as
+// soon as it is consistent. Archiving is off for clones, so nothing reaches the
+// repository or the cluster the backup was taken from.
+fn test(ctx: &Context, opts: &Options, manifest: &Manifest, dir: &Path, port: u16) -> Result<()> {
+    let data_dir = dir.join("data");
+    let log_path = dir.join("postgres.log");
+    let socket_dir = format!("'{}'", dir.to_string_lossy().replace('\'', "''"));
+    clone::prepare(
+        ctx,
+        &clone::Options {
+            label: manifest.label.clone(),
+            target_dir: data_dir.clone(),
+            port,
+            force: false,
+            tablespace_mappings: manifest
+                .tablespaces()
+                .map(|(oid, location)| (location.to_owned(), dir.join("tablespaces").join(oid)))
+                .collect(),
+        },
+        &[
+            ("listen_addresses", "''"),
+            ("unix_socket_directories", &socket_dir),
+            ("recovery_target", "'immediate'"),
+            ("recovery_target_action", "'promote'"),
+        ],
+    )?;
+
+    // A server that didn't start in time may still be starting.
+    if let Err(err) = pg_ctl(&data_dir, &log_path, opts.timeout, "start") {
+        let _ = pg_ctl(&data_dir, &log_path, opts.timeout, "stop");
+        return with_log(Err(err), &log_path);
+    }
+
+    defer! {
+        if let Err(err) = pg_ctl(&data_dir, &log_path, opts.timeout, "stop") {
+            warn!("failed to stop the test server: {:#}", err);
+        }
+    }
+
+    let mut client = with_log(
+        postgres::Config::new()
+            .host_path(dir)
+            .port(port)
+            .user(&opts.user)
+            .dbname("postgres")
+            .connect_timeout(Duration::from_secs(opts.timeout))
+            .connect(postgres::NoTls),
+        &log_path,
+    )?;
+    let row = with_log(
+        client.query_one(
+            "SELECT pg_is_in_recovery(), count(*) FROM pg_database;",
+            &[],
+        ),
+        &log_path,
+    )?;
+    if row.get::<_, bool>(0) {
+        bail!(
+            "test server is still in recovery\n{}",
+            log_excerpt(&log_path)
+        );
+    }
+
+    info!("test server is up with {} databases", row.get::<_, i64>(1));
+    Ok(())
+}
+
+fn pg_ctl(data_dir: &Path, log_path: &Path, timeout: u64, action: &str) -> Result<()> {
+    let output = Command::new("pg_ctl")
+        .arg("-D")
+        .arg(data_dir)
+        .arg("-l")
+        .arg(log_path)
+        .args(["-m", "fast", "-w", "-t", &timeout.to_string(), action])
+        .output()
+        .context("failed to run pg_ctl")?;
+    if !output.status.success() {
+        bail!(
+            "pg_ctl {} failed: {}",
+            action,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+
+    Ok(())
+}
+
+fn with_log<T, E>(result: Result<T, E>, log_path: &Path) -> Result<T>
+where
+    E: Into<anyhow::Error>,
+{
+    result.map_err(|err| anyhow!("{:#}\n{}", err.into(), log_excerpt(log_path)))
+}
+
+fn log_excerpt(log_path: &Path) -> String {
+    let log = fs::read_to_string(log_path).unwrap_or_default();
+    let lines = log.lines().collect::<Vec<_>>();
+    let excerpt = &lines[lines.len().saturating_sub(LOG_EXCERPT_LINES)..];
+    if excerpt.is_empty() {
+        return "postgres log is empty".to_owned();
+    }
+
+    format!("postgres log:\n{}", excerpt.join("\n"))
+}
+
+// Starts at a random port so that concurrent tests are unlikely to race for
+// the same one between checking it and the server binding it.
+fn free_port(range: &RangeInclusive<u16>) -> Result<u16> {
+    let len = (*range.end() - *range.start()) as u128 + 1;
+    let offset = (Uuid::new_v4().as_u128() % len) as u16;
+    (0..len as u32)
+        .map(|i| range.start() + ((offset as u32 + i) % len as u32) as u16)
+        .find(|port| TcpListener::bind(("127.0.0.1", *port)).is_ok())
+        .ok_or_else(|| anyhow!("no free port in {}-{}", range.start(), range.end()))
+}
+
+fn parse_port_range(s: &str) -> Result<RangeInclusive<u16>, String> {
+    match s
+        .split_once('-')
+        .map(|(low, high)| (low.parse(), high.parse()))
+    {
+        Some((Ok(low), Ok(high))) if low <= high => Ok(low..=high),
+        _ => Err(format!("invalid port range {:?}, expected LOW-HIGH", s)),
+    }
+}
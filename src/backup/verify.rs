@@ -0,0 +1,326 @@
+use std::{
+    cell::Cell,
+    collections::{HashMap, HashSet},
+    io::{self, Read},
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, bail, Context as _, Result};
+use clap::Args;
+use log::{error, info};
+
+use super::{
+    chunker::Blocks,
+    codec::Codec,
+    manifest::{self, BackupKind, ChunkRef, FileInfo, Manifest},
+};
+use crate::context::Context;
+
+const PROGRESS_LOG_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Args)]
+pub struct Options {
+    /// Label of the backup, or "latest" for the newest one
+    #[arg(long)]
+    pub label: String,
+}
+
+// Restoring a backup needs every backup in its chain, so all of them are
+// verified. Verification carries on past problems so that a single run reports
+// everything that is wrong.
+pub fn run(ctx: &Context, opts: &Options) -> Result<()> {
+    let manifests = manifest::load_all(ctx)?;
+    let manifest = manifest::find(&manifests, &opts.label)?;
+
+    let progress = Progress::new();
+    let mut failures = Failures::default();
+    for backup in manifest.chain(&manifests)? {
+        info!("verifying backup {} ({})", backup.label, backup.id);
+        match &backup.data {
+            BackupKind::Full { files } =>
+                for (path, info) in files {
+                    verify_file(
+                        ctx,
+                        backup.chunk_compression,
+                        path,
+                        info,
+                        &progress,
+                        &mut failures,
+                    )?;
+                },
+            BackupKind::Incremental {
+                changed_blocks,
+                bundle_checksum,
+                ..
+            } => verify_bundle(
+                ctx,
+                backup,
+                changed_blocks,
+                bundle_checksum.as_ref(),
+                &progress,
+                &mut failures,
+            )?,
+        }
+    }
+
+    progress.log(true);
+    if failures.count > 0 {
+        bail!(
+            "backup {} failed verification with {} errors",
+            manifest.label,
+            failures.count
+        );
+    }
+
+    info!("backup {} is intact", manifest.label);
+    Ok(())
+}
+
+#[derive(Default)]
+struct Failures {
+    count: usize,
+}
+
+impl Failures {
+    fn add(&mut self, err: anyhow::Error) {
+        error!("{:#}", err);
+        self.count += 1;
+    }
+}
+
+fn verify_file(
+    ctx: &Context,
+    codec: Codec,
+    path: &Path,
+    info: &FileInfo,
+    progress: &Progress,
+    failures: &mut Failures,
+) -> Result<()> {
+    let mut expected_blocks = info.blocks.iter().enumerate();
+    let mut check_block = |block: &[u8]| match expected_blocks.next() {
+        Some((_, hash)) if *hash == ChunkRef::Object(blake3::hash(block)) => Ok(()),
+        Some((index, _)) => bail!("checksum mismatch in block {} of {:?}", index, path),
+        None => bail!("{:?} has more data than its recorded blocks", path),
+    };
+
+    // Once a chunk is unreadable the position of the following blocks in the
+    // file is unknown, so only the remaining chunks are checked.
+    let mut blocks = Some(Blocks::new());
+    let mut data = Vec::new();
+    for chunk in &info.chunks {
+        data.clear();
+        if let Err(err) = read_chunk(ctx, codec, chunk, &mut data) {
+            failures.add(err.context(format!("chunk {} of {:?}", chunk.describe(codec), path)));
+            blocks = None;
+            continue;
+        }
+
+        progress.add_read(data.len() as u64);
+        if blake3::hash(&data) != *chunk.hash() {
+            failures.add(anyhow!(
+                "checksum mismatch in chunk {} of {:?}",
+                chunk.describe(codec),
+                path
+            ));
+            blocks = None;
+            continue;
+        }
+
+        if let Some(current) = &mut blocks {
+            if let Err(err) = current.push(&data, &mut check_block) {
+                failures.add(err);
+                blocks = None;
+            }
+        }
+
+        progress.log(false);
+    }
+
+    if let Some(blocks) = blocks {
+        if let Err(err) = blocks.finish(&mut check_block) {
+            failures.add(err);
+        } else if expected_blocks.next().is_some() {
+            failures.add(anyhow!(
+                "{:?} is missing data for some of its recorded blocks",
+                path
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+fn read_chunk(ctx: &Context, codec: Codec, chunk: &ChunkRef, data: &mut Vec<u8>) -> Result<()> {
+    let key = chunk.key(codec);
+    let reader = match chunk.open(ctx, codec) {
+        Ok(reader) => reader,
+        Err(_) if !ctx.storage.exists(&key)? => bail!("missing"),
+        Err(err) => return Err(err),
+    };
+
+    codec
+        .decoder(reader)?
+        .read_to_end(data)
+        .context("failed to decompress")?;
+    Ok(())
+}
+
+fn verify_bundle(
+    ctx: &Context,
+    backup: &Manifest,
+    changed_blocks: &HashMap<PathBuf, HashMap<usize, ChunkRef>>,
+    bundle_checksum: Option<&ChunkRef>,
+    progress: &Progress,
+    failures: &mut Failures,
+) -> Result<()> {
+    let bundle_key = backup.bundle_key();
+    let reader = match ctx.storage.open_reader(&bundle_key) {
+        Ok(reader) => reader,
+        Err(_) if !ctx.storage.exists(&bundle_key)? => {
+            failures.add(anyhow!("bundle {} is missing", bundle_key));
+            return Ok(());
+        },
+        Err(err) => return Err(err),
+    };
+
+    let decoder = backup.compression.decoder(reader)?;
+    let mut bundle = tar::Archive::new(HashingReader {
+        inner: decoder,
+        hasher: blake3::Hasher::new(),
+    });
+    let mut seen = HashSet::new();
+    let mut data = Vec::new();
+
+    // Past a corrupt header or frame there is no telling where the next entry
+    // starts, so the rest of the bundle is given up on.
+    let entries = bundle.entries()?.map(|entry| -> Result<_> {
+        let mut entry = entry?;
+        let block_path = entry.path()?.into_owned();
+        data.clear();
+        entry.read_to_end(&mut data)?;
+        Ok((block_path, blake3::hash(&data), data.len()))
+    });
+
+    for entry in entries {
+        let (block_path, hash, len) = match entry {
+            Ok(entry) => entry,
+            Err(err) => {
+                failures.add(err.context(format!("corrupt bundle {}", bundle_key)));
+                return Ok(());
+            },
+        };
+
+        progress.add_read(len as u64);
+        let (Some(path), Some(index)) = (
+            block_path.parent(),
+            block_path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .and_then(|name| name.parse::<usize>().ok()),
+        ) else {
+            failures.add(anyhow!(
+                "invalid block entry {:?} in bundle {}",
+                block_path,
+                bundle_key
+            ));
+            continue;
+        };
+
+        match changed_blocks
+            .get(path)
+            .and_then(|blocks| blocks.get(&index))
+        {
+            Some(expected) if *expected == ChunkRef::Object(hash) => (),
+            Some(_) => failures.add(anyhow!(
+                "checksum mismatch in entry {:?} of bundle {}",
+                block_path,
+                bundle_key
+            )),
+            None => failures.add(anyhow!(
+                "entry {:?} of bundle {} is not in the manifest",
+                block_path,
+                bundle_key
+            )),
+        }
+
+        seen.insert((path.to_owned(), index));
+        progress.log(false);
+    }
+
+    // The archive stops reading at the end-of-archive marker, the padding
+    // after it is part of what was hashed too.
+    let mut reader = bundle.into_inner();
+    match io::copy(&mut reader, &mut io::sink()) {
+        Ok(_) => match bundle_checksum {
+            Some(expected) if *expected != ChunkRef::Object(reader.hasher.finalize()) =>
+                failures.add(anyhow!("checksum mismatch in bundle {}", bundle_key)),
+            _ => (),
+        },
+        Err(err) => failures.add(anyhow!("corrupt bundle {}: {}", bundle_key, err)),
+    }
+
+    for (path, blocks) in changed_blocks {
+        for index in blocks.keys() {
+            if !seen.contains(&(path.clone(), *index)) {
+                failures.add(anyhow!(
+                    "entry {:?} is missing from bundle {}",
+                    path.join(index.to_string()),
+                    bundle_key
+                ));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+struct HashingReader<R> {
+    inner: R,
+    hasher: blake3::Hasher,
+}
+
+impl<R> Read for HashingReader<R>
+where
+    R: Read,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.inner.read(buf)?;
+        self.hasher.update(&buf[..len]);
+        Ok(len)
+    }
+}
+
+struct Progress {
+    start_time: Instant,
+    last_log_time: Cell<Instant>,
+    read_bytes: Cell<u64>,
+}
+
+impl Progress {
+    fn new() -> Self {
+        Self {
+            start_time: Instant::now(),
+            last_log_time: Cell::new(Instant::now()),
+            read_bytes: Cell::new(0),
+        }
+    }
+
+    fn add_read(&self, bytes: u64) {
+        self.read_bytes.set(self.read_bytes.get() + bytes);
+    }
+
+    fn log(&self, last: bool) {
+        if last || self.last_log_time.get().elapsed() >= PROGRESS_LOG_INTERVAL {
+            self.last_log_time.set(Instant::now());
+            let read_bytes = self.read_bytes.get();
+            let elapsed_secs = self.start_time.elapsed().as_secs_f32();
+            info!(
+                "{}read: {} MiB, throughput: {:.2} MiB/s",
+                if !last { "progress: " } else { "" },
+                read_bytes / 1024 / 1024,
+                read_bytes as f32 / elapsed_secs / 1024.0 / 1024.0
+            );
+        }
+    }
+}
@@ -0,0 +1,147 @@
+use std::fs;
+
+use anyhow::{bail, Context as _, Result};
+use clap::Args;
+use uuid::Uuid;
+
+use crate::{
+    backup::create::{self, ConnectionOptions},
+    context::Context,
+};
+
+#[derive(Debug, Args)]
+pub struct Options {
+    #[command(flatten)]
+    pub connection: ConnectionOptions,
+}
+
+// Checks what create-backup needs up front. Failing hard requirements make the
+// command fail, the rest only warn about setups that can't be restored to any
+// point in time.
+pub fn run(ctx: &Context, opts: &Options) -> Result<()> {
+    let mut checks = Checks::default();
+
+    checks.hard("data directory", || {
+        let version = fs::read_to_string(ctx.cluster_data.join("PG_VERSION"))
+            .with_context(|| format!("{:?} is not a readable data directory", ctx.cluster_data))?;
+        Ok(format!(
+            "{:?}, version {}",
+            ctx.cluster_data,
+            version.trim()
+        ))
+    });
+
+    checks.hard("repository", || {
+        let key = format!("check-{}", Uuid::new_v4());
+        ctx.storage.write(&key, b"pgpitr")?;
+        ctx.storage.delete(&key)?;
+        Ok("writable".to_owned())
+    });
+
+    let mut client = None;
+    checks.hard("connection", || {
+        let mut connected = opts
+            .connection
+            .config()?
+            .connect(postgres::NoTls)
+            .map_err(|err| {
+                create::connect_error(
+                    err,
+                    &format!(
+                        "{}:{} as {}",
+                        opts.connection.host, opts.connection.port, opts.connection.user
+                    ),
+                )
+            })?;
+        let version: String = connected.query_one("SHOW server_version", &[])?.get(0);
+        client = Some(connected);
+        Ok(format!("server version {}", version))
+    });
+
+    if let Some(client) = &mut client {
+        let mut setting = |name: &str| -> Result<String> {
+            Ok(client
+                .query_one("SELECT current_setting($1)", &[&name])?
+                .get(0))
+        };
+
+        checks.hard("wal_level", || match setting("wal_level")?.as_str() {
+            level @ ("replica" | "logical") => Ok(level.to_owned()),
+            level => bail!("{}, needs to be replica or logical", level),
+        });
+
+        checks.soft("archive_mode", || match setting("archive_mode")?.as_str() {
+            mode @ ("on" | "always") => Ok(mode.to_owned()),
+            mode => bail!(
+                "{}, WAL isn't archived so backups can't be rolled forward",
+                mode
+            ),
+        });
+
+        checks.soft("archive_command", || {
+            let command = setting("archive_command")?;
+            if !command.contains("wal-push") {
+                bail!("{:?} doesn't call pgpitr wal-push", command);
+            }
+
+            Ok(command)
+        });
+
+        checks.soft("data_directory", || {
+            let data_directory = setting("data_directory")?;
+            if fs::canonicalize(&data_directory)? != fs::canonicalize(&ctx.cluster_data)? {
+                bail!(
+                    "server uses {:?}, not {:?}",
+                    data_directory,
+                    ctx.cluster_data
+                );
+            }
+
+            Ok(data_directory)
+        });
+
+        checks.hard("privileges", || {
+            let allowed: bool = client
+                .query_one(
+                    "SELECT has_function_privilege('pg_backup_start(text, boolean)', 'execute')",
+                    &[],
+                )?
+                .get(0);
+            if !allowed {
+                bail!("{} can't run pg_backup_start", opts.connection.user);
+            }
+
+            Ok("can run pg_backup_start".to_owned())
+        });
+    }
+
+    if checks.failed > 0 {
+        bail!("{} checks failed", checks.failed);
+    }
+
+    Ok(())
+}
+
+#[derive(Default)]
+struct Checks {
+    failed: usize,
+}
+
+impl Checks {
+    fn hard(&mut self, name: &str, check: impl FnOnce() -> Result<String>) {
+        match check() {
+            Ok(detail) => println!("pass {}: {}", name, detail),
+            Err(err) => {
+                println!("FAIL {}: {:#}", name, err);
+                self.failed += 1;
+            },
+        }
+    }
+
+    fn soft(&mut self, name: &str, check: impl FnOnce() -> Result<String>) {
+        match check() {
+            Ok(detail) => println!("pass {}: {}", name, detail),
+            Err(err) => println!("warn {}: {:#}", name, err),
+        }
+    }
+}
@@ -0,0 +1,284 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    ops::RangeInclusive,
+};
+
+use anyhow::{bail, Context as _, Result};
+use clap::Args;
+use log::warn;
+
+use crate::{
+    backup::{create, manifest},
+    context::Context,
+    wal_pull::{self, WalFileKind},
+};
+
+#[derive(Debug, Args)]
+pub struct Options {}
+
+// The timelines leading up to one, each with the first segment it covers.
+// Postgres takes the segment a timeline switched in from the new timeline, so
+// that one belongs to the new timeline already.
+type TimelineChain = Vec<(u32, u64)>;
+
+// Checks that the archive holds every segment from the start of the oldest
+// backup to the newest archived segment, along the history of the latest
+// timeline, the way recovery to the present would read them. Fails if any are
+// missing, listing the backups that can't be restored past the gap. Duplicate
+// segments with different contents are reported but don't fail the check,
+// wal-pull serves whichever it finds first.
+pub fn run(ctx: &Context, _opts: &Options) -> Result<()> {
+    let mut backups = manifest::load_all(ctx)?.into_values().collect::<Vec<_>>();
+    backups.sort_by_key(|backup| backup.created_at);
+    let Some(segment_size) = backups.first().map(|backup| backup.wal_segment_size) else {
+        println!("no backups, nothing to check");
+        return Ok(());
+    };
+
+    let mut keys = BTreeMap::<String, BTreeSet<String>>::new();
+    let mut latest_timeline = 1;
+    for key in ctx.storage.list("wal/")? {
+        let Some((name, checksum)) = key
+            .strip_prefix("wal/")
+            .map(|key_name| key_name.trim_end_matches(".zst"))
+            .and_then(|key_name| key_name.split_once('-'))
+        else {
+            continue;
+        };
+
+        match WalFileKind::of(name) {
+            Some(WalFileKind::Segment) => {
+                keys.entry(name.to_owned())
+                    .or_default()
+                    .insert(checksum.to_owned());
+            },
+            Some(WalFileKind::History) => {
+                latest_timeline = latest_timeline.max(u32::from_str_radix(&name[..8], 16)?);
+            },
+            _ => (),
+        }
+    }
+
+    let mut segments = BTreeSet::new();
+    for (name, checksums) in &keys {
+        if checksums.len() > 1 {
+            println!(
+                "DUPLICATE {} with different contents: {}",
+                name,
+                checksums.iter().cloned().collect::<Vec<_>>().join(", ")
+            );
+        }
+
+        if let Some(segment) = create::parse_wal_segment(name, segment_size) {
+            latest_timeline = latest_timeline.max(segment.0);
+            segments.insert(segment);
+        }
+    }
+
+    let chain = timeline_chain(ctx, latest_timeline, segment_size)?;
+    for (timeline, first) in &chain {
+        let archived = segments
+            .iter()
+            .filter(|(segment_timeline, _)| segment_timeline == timeline)
+            .map(|(_, segment)| *segment)
+            .collect::<Vec<_>>();
+        match (archived.first(), archived.last()) {
+            (Some(oldest), Some(newest)) => println!(
+                "timeline {}: {} to {}, {} segments",
+                timeline,
+                create::wal_segment_name(*timeline, *oldest, segment_size),
+                create::wal_segment_name(*timeline, *newest, segment_size),
+                archived.len()
+            ),
+            _ => println!(
+                "timeline {}: no segments archived, starts at {}",
+                timeline,
+                create::wal_segment_name(*timeline, *first, segment_size)
+            ),
+        }
+    }
+
+    let mut starts = Vec::new();
+    let mut unrestorable = Vec::new();
+    for backup in &backups {
+        let start_wal = match backup.start_wal_segment() {
+            Ok(start_wal) => start_wal,
+            Err(err) => {
+                warn!("{:#}, skipping it", err);
+                continue;
+            },
+        };
+        match create::parse_wal_segment(start_wal, segment_size) {
+            Some((timeline, segment)) if chain.iter().any(|(known, _)| *known == timeline) =>
+                starts.push((backup, segment)),
+            _ => {
+                warn!(
+                    "backup {} starts at {}, which timeline {} doesn't descend from",
+                    backup.label, start_wal, latest_timeline
+                );
+                unrestorable.push(&backup.label);
+            },
+        }
+    }
+
+    let Some(start) = starts.iter().map(|(_, segment)| *segment).min() else {
+        bail!(
+            "no backup can be restored along timeline {}",
+            latest_timeline
+        );
+    };
+
+    let gaps = find_gaps(&segments, &chain, start);
+    let mut missing = 0;
+    for gap in &gaps {
+        missing += gap.end() - gap.start() + 1;
+        println!(
+            "MISSING {} to {} ({} segments)",
+            segment_name(&chain, *gap.start(), segment_size),
+            segment_name(&chain, *gap.end(), segment_size),
+            gap.end() - gap.start() + 1
+        );
+    }
+
+    // A gap cuts off every backup starting before it.
+    if let Some(last_gap) = gaps.last() {
+        unrestorable.extend(
+            starts
+                .iter()
+                .filter(|(_, segment)| segment <= last_gap.end())
+                .map(|(backup, _)| &backup.label),
+        );
+    }
+
+    for label in &unrestorable {
+        println!("backup {} can't be restored to the present", label);
+    }
+
+    if missing > 0 {
+        bail!(
+            "{} segments are missing from the WAL archive, {} of {} backups can't be restored to \
+             the present",
+            missing,
+            unrestorable.len(),
+            backups.len()
+        );
+    }
+
+    println!(
+        "WAL archive is complete from {}",
+        segment_name(&chain, start, segment_size)
+    );
+    Ok(())
+}
+
+// Reads the chain from the history file of `timeline`, timeline 1 has none.
+fn timeline_chain(ctx: &Context, timeline: u32, segment_size: u64) -> Result<TimelineChain> {
+    let switches = if timeline > 1 {
+        let name = format!("{:08X}.history", timeline);
+        let contents = String::from_utf8(wal_pull::read_wal(ctx, &name)?)
+            .with_context(|| format!("{} isn't valid UTF-8", name))?;
+        wal_pull::parse_history(&contents).with_context(|| format!("failed to parse {}", name))?
+    } else {
+        Vec::new()
+    };
+
+    Ok(chain_from_history(timeline, &switches, segment_size))
+}
+
+fn chain_from_history(timeline: u32, switches: &[(u32, u64)], segment_size: u64) -> TimelineChain {
+    let mut chain = Vec::new();
+    let mut first = 0;
+    for (parent, lsn) in switches {
+        chain.push((*parent, first));
+        first = lsn / segment_size;
+    }
+
+    chain.push((timeline, first));
+    chain
+}
+
+fn timeline_of(chain: &TimelineChain, segment: u64) -> u32 {
+    chain
+        .iter()
+        .rev()
+        .find(|(_, first)| *first <= segment)
+        .map_or(chain[0].0, |(timeline, _)| *timeline)
+}
+
+fn segment_name(chain: &TimelineChain, segment: u64, segment_size: u64) -> String {
+    create::wal_segment_name(timeline_of(chain, segment), segment, segment_size)
+}
+
+// Ranges of segments missing from `start` to the newest segment archived along
+// the chain. Nothing archived from `start` on is one gap up to `start`, the
+// backup needs at least that.
+fn find_gaps(
+    segments: &BTreeSet<(u32, u64)>,
+    chain: &TimelineChain,
+    start: u64,
+) -> Vec<RangeInclusive<u64>> {
+    let newest = segments
+        .iter()
+        .filter(|(timeline, segment)| *timeline == timeline_of(chain, *segment))
+        .map(|(_, segment)| *segment)
+        .max();
+    let end = match newest {
+        Some(newest) if newest >= start => newest,
+        _ => return vec![start..=start],
+    };
+
+    let mut gaps = Vec::<RangeInclusive<u64>>::new();
+    for segment in start..=end {
+        if segments.contains(&(timeline_of(chain, segment), segment)) {
+            continue;
+        }
+
+        match gaps.last_mut() {
+            Some(gap) if *gap.end() + 1 == segment => *gap = *gap.start()..=segment,
+            _ => gaps.push(segment..=segment),
+        }
+    }
+
+    gaps
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use super::{chain_from_history, find_gaps, timeline_of};
+
+    const SEGMENT_SIZE: u64 = 16 << 20;
+
+    #[test]
+    fn chains_timelines_from_history() {
+        let chain = chain_from_history(3, &[(1, 0x5000000), (2, 0x8800000)], SEGMENT_SIZE);
+        assert_eq!(chain, [(1, 0), (2, 5), (3, 8)]);
+        assert_eq!(timeline_of(&chain, 4), 1);
+        assert_eq!(timeline_of(&chain, 5), 2);
+        assert_eq!(timeline_of(&chain, 7), 2);
+        assert_eq!(timeline_of(&chain, 8), 3);
+        assert_eq!(timeline_of(&chain, 100), 3);
+        assert_eq!(chain_from_history(1, &[], SEGMENT_SIZE), [(1, 0)]);
+    }
+
+    #[test]
+    fn finds_gaps_along_the_chain() {
+        let chain = chain_from_history(2, &[(1, 0x5800000)], SEGMENT_SIZE);
+        let archive = |segments: &[(u32, u64)]| segments.iter().copied().collect::<BTreeSet<_>>();
+
+        // Segment 5 comes from timeline 2, the old timeline's copy of it and
+        // anything the old timeline archived past the switch don't count.
+        let complete = archive(&[(1, 3), (1, 4), (1, 5), (1, 6), (2, 5), (2, 6), (2, 7)]);
+        assert!(find_gaps(&complete, &chain, 3).is_empty());
+        assert!(find_gaps(&complete, &chain, 6).is_empty());
+
+        let gappy = archive(&[(1, 2), (1, 4), (1, 5), (2, 8), (2, 9), (2, 11)]);
+        assert_eq!(find_gaps(&gappy, &chain, 2), [3..=3, 5..=7, 10..=10]);
+        assert_eq!(find_gaps(&gappy, &chain, 8), [10..=10]);
+
+        // Nothing archived since the backup started.
+        assert_eq!(find_gaps(&gappy, &chain, 12), [12..=12]);
+        assert_eq!(find_gaps(&archive(&[]), &chain, 0), [0..=0]);
+    }
+}
@@ -0,0 +1,247 @@
+use std::{
+    env,
+    fs,
+    io,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context as _, Result};
+use clap::Command;
+use serde::Deserialize;
+
+const FILE_NAME: &str = "pgpitr.toml";
+
+/// Defaults for command line options, read from `pgpitr.toml` in the working
+/// directory or else in `$XDG_CONFIG_HOME/pgpitr`. Options given on the command
+/// line or through their environment variables take precedence.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub storage: Option<PathBuf>,
+    pub cluster_data: Option<PathBuf>,
+    pub encryption_key_file: Option<PathBuf>,
+    pub compression: Option<String>,
+    pub compression_level: Option<i32>,
+    pub connection: ConnectionConfig,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConnectionConfig {
+    pub host: Option<String>,
+    pub port: Option<u16>,
+    pub user: Option<String>,
+    pub dbname: Option<String>,
+    pub password_file: Option<PathBuf>,
+}
+
+impl Config {
+    pub fn load() -> Result<Self> {
+        for path in search_paths() {
+            match fs::read_to_string(&path) {
+                Ok(contents) =>
+                    return toml::from_str(&contents)
+                        .with_context(|| format!("failed to parse {:?}", path)),
+                Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+                Err(err) => return Err(err).with_context(|| format!("failed to read {:?}", path)),
+            }
+        }
+
+        Ok(Self::default())
+    }
+
+    // The values become the defaults of the options, so clap still validates
+    // them and the command line and environment variables override them.
+    pub fn apply(&self, mut command: Command) -> Command {
+        command = set_default(
+            command,
+            "encryption_key_file",
+            self.encryption_key_file.as_deref().map(path),
+        );
+
+        // Only zstd takes a level, so with another --compression the level
+        // from the file is left out instead of being rejected.
+        let level = self.compression_level.map(|level| level.to_string());
+        command = command.mut_subcommand("create-backup", |subcommand| {
+            let subcommand = set_default(subcommand, "compression", self.compression.clone());
+            match level.clone() {
+                Some(level) => subcommand.mut_arg("compression_level", |arg| {
+                    arg.default_value_if("compression", "zstd", Some(&*level.leak()))
+                }),
+                None => subcommand,
+            }
+        });
+        command = command.mut_subcommand("import", |subcommand| {
+            set_default(subcommand, "compression_level", level)
+        });
+
+        let connection = &self.connection;
+        for name in ["create-backup", "check"] {
+            command = command.mut_subcommand(name, |subcommand| {
+                let subcommand = set_default(subcommand, "host", connection.host.clone());
+                let subcommand = set_default(
+                    subcommand,
+                    "port",
+                    connection.port.map(|port| port.to_string()),
+                );
+                let subcommand = set_default(subcommand, "user", connection.user.clone());
+                let subcommand = set_default(subcommand, "dbname", connection.dbname.clone());
+                set_default(
+                    subcommand,
+                    "password_file",
+                    connection.password_file.as_deref().map(path),
+                )
+            });
+        }
+
+        command
+    }
+
+    // Clap skips positional arguments that have a default value, so the
+    // repository and data directory are filled in after parsing instead.
+    pub fn fill(&self, storage: &mut Option<PathBuf>, cluster_data: &mut Option<PathBuf>) {
+        if storage.is_none() {
+            storage.clone_from(&self.storage);
+        }
+        if cluster_data.is_none() {
+            cluster_data.clone_from(&self.cluster_data);
+        }
+    }
+}
+
+fn search_paths() -> Vec<PathBuf> {
+    let config_home = env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".config")));
+
+    [PathBuf::from(FILE_NAME)]
+        .into_iter()
+        .chain(config_home.map(|dir| dir.join("pgpitr").join(FILE_NAME)))
+        .collect()
+}
+
+fn set_default(command: Command, id: &str, value: Option<String>) -> Command {
+    match value {
+        Some(value) if command.get_arguments().any(|arg| arg.get_id() == id) =>
+            command.mut_arg(id, |arg| arg.default_value(&*value.leak())),
+        _ => command,
+    }
+}
+
+fn path(path: &Path) -> String {
+    path.to_string_lossy().into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::{CommandFactory, FromArgMatches};
+
+    use super::*;
+    use crate::{Args, Command as Subcommand};
+
+    fn parse(config: &str, args: &[&str]) -> Args {
+        let config: Config = toml::from_str(config).unwrap();
+        let matches = config
+            .apply(Args::command())
+            .try_get_matches_from(args)
+            .unwrap();
+        let mut args = Args::from_arg_matches(&matches).unwrap();
+        config.fill(&mut args.global.storage, &mut args.global.cluster_data);
+        args
+    }
+
+    #[test]
+    fn precedence() {
+        let config = "storage = \"/file/repo\"\nencryption_key_file = \"/file/key\"";
+
+        let args = parse(config, &["pg_pitr", "list"]);
+        assert_eq!(args.global.storage, Some(PathBuf::from("/file/repo")));
+        let args = parse(config, &["pg_pitr", "/cli/repo", "list"]);
+        assert_eq!(args.global.storage, Some(PathBuf::from("/cli/repo")));
+        assert_eq!(args.global.cluster_data, None);
+
+        // The only test that sets the variable, so it can't leak into others.
+        env::set_var("PGPITR_ENCRYPTION_KEY_FILE", "/env/key");
+        let from_env = parse(config, &["pg_pitr", "list"]);
+        let from_cli = parse(
+            config,
+            &["pg_pitr", "list", "--encryption-key-file", "/cli/key"],
+        );
+        env::remove_var("PGPITR_ENCRYPTION_KEY_FILE");
+        let from_file = parse(config, &["pg_pitr", "list"]);
+
+        assert_eq!(
+            from_cli.global.encryption_key_file,
+            Some(PathBuf::from("/cli/key"))
+        );
+        assert_eq!(
+            from_env.global.encryption_key_file,
+            Some(PathBuf::from("/env/key"))
+        );
+        assert_eq!(
+            from_file.global.encryption_key_file,
+            Some(PathBuf::from("/file/key"))
+        );
+    }
+
+    #[test]
+    fn compression_level() {
+        let config = "compression_level = 9";
+        let level = |args: &[&str]| match parse(config, args).subcommand {
+            Subcommand::CreateBackup(opts) => opts.compression_level,
+            _ => unreachable!(),
+        };
+
+        assert_eq!(level(&["pg_pitr", "create-backup"]), Some(9));
+        assert_eq!(
+            level(&["pg_pitr", "create-backup", "--compression-level", "5"]),
+            Some(5)
+        );
+        assert_eq!(
+            level(&["pg_pitr", "create-backup", "--compression", "lz4"]),
+            None
+        );
+
+        let args = parse(
+            "compression = \"none\"\ncompression_level = 9",
+            &["pg_pitr", "create-backup"],
+        );
+        match args.subcommand {
+            Subcommand::CreateBackup(opts) => assert_eq!(opts.compression_level, None),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn unknown_fields() {
+        assert!(toml::from_str::<Config>("storage = \"/repo\"").is_ok());
+        assert!(toml::from_str::<Config>("storge = \"/repo\"").is_err());
+        assert!(toml::from_str::<Config>("[connection]\nhostname = \"db\"").is_err());
+    }
+
+    #[test]
+    fn search_path_fallback() {
+        let local = PathBuf::from(FILE_NAME);
+
+        env::set_var("XDG_CONFIG_HOME", "/xdg");
+        env::set_var("HOME", "/home/pgpitr");
+        assert_eq!(
+            search_paths(),
+            [local.clone(), PathBuf::from("/xdg/pgpitr/pgpitr.toml")]
+        );
+
+        env::set_var("XDG_CONFIG_HOME", "");
+        assert_eq!(
+            search_paths(),
+            [
+                local.clone(),
+                PathBuf::from("/home/pgpitr/.config/pgpitr/pgpitr.toml")
+            ]
+        );
+
+        env::remove_var("XDG_CONFIG_HOME");
+        env::remove_var("HOME");
+        assert_eq!(search_paths(), [local]);
+    }
+}
@@ -1,12 +1,14 @@
 use std::path::PathBuf;
 
+use crate::storage::Storage;
+
 pub struct Context {
-    pub storage: PathBuf,
+    pub storage: Box<dyn Storage>,
     pub cluster_data: PathBuf,
 }
 
 impl Context {
-    pub fn new(storage: PathBuf, cluster_data: PathBuf) -> Self {
+    pub fn new(storage: Box<dyn Storage>, cluster_data: PathBuf) -> Self {
         Self {
             storage,
             cluster_data,
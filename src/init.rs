@@ -0,0 +1,212 @@
+use std::cmp::Ordering;
+
+use anyhow::{bail, Context as _, Result};
+use clap::Args;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{
+    backup::{
+        codec::Codec,
+        manifest::{self, ChunkRef},
+    },
+    context::Context,
+};
+
+const STAMP_KEY: &str = "pgpitr.repo";
+const FORMAT_VERSION: u32 = 3;
+const PREFIXES: &[&str] = &["backups/", "bundles/", "chunks/", "packs/", "wal/"];
+
+#[derive(Debug, Args)]
+pub struct Options {
+    /// Initialize even if the repository already holds data, such as one
+    /// created before init existed
+    #[arg(long)]
+    pub force: bool,
+
+    /// Upgrade a repository created by an older version to the current
+    /// layout
+    #[arg(long, conflicts_with = "force")]
+    pub upgrade: bool,
+}
+
+/// Identifies a repository and the version of its layout.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Stamp {
+    pub version: u32,
+    pub id: Uuid,
+    /// System identifier of the cluster the repository holds backups of,
+    /// recorded with the first backup.
+    #[serde(default)]
+    pub system_identifier: Option<u64>,
+}
+
+pub fn run(ctx: &Context, opts: &Options) -> Result<()> {
+    if opts.upgrade {
+        return upgrade(ctx);
+    }
+
+    if ctx.storage.exists(STAMP_KEY)? {
+        bail!("repository is already initialized");
+    }
+
+    if !opts.force && !ctx.storage.list("")?.is_empty() {
+        bail!("repository is not empty, use --force to initialize it anyway");
+    }
+
+    ctx.storage.create_prefixes(PREFIXES)?;
+
+    // The stamp goes last, a repository is only usable once it exists.
+    let stamp = Stamp {
+        version: FORMAT_VERSION,
+        id: Uuid::new_v4(),
+        system_identifier: None,
+    };
+    write_stamp(ctx, &stamp)?;
+
+    info!("initialized repository {}", stamp.id);
+    Ok(())
+}
+
+// Version 1 kept every chunk directly in chunks/. They are moved one at a time
+// and the stamp is only rewritten after the last one, so an interrupted upgrade
+// carries on where it left off when run again. Version 2 had no packs/, and
+// older versions can't read the packed chunks of backups taken since.
+fn upgrade(ctx: &Context) -> Result<()> {
+    let _lock = ctx.storage.lock(false)?;
+    let mut stamp = read_stamp(ctx)?;
+    match stamp.version.cmp(&FORMAT_VERSION) {
+        Ordering::Less => (),
+        Ordering::Equal => {
+            info!("repository is already at version {}", FORMAT_VERSION);
+            return Ok(());
+        },
+        Ordering::Greater => bail!(
+            "repository version {} is newer than the supported version {}",
+            stamp.version,
+            FORMAT_VERSION
+        ),
+    }
+
+    let mut moved = 0;
+    for key in ctx.storage.list("chunks/")? {
+        let Some(hex) = key
+            .strip_prefix("chunks/")
+            .filter(|name| !name.contains('/'))
+        else {
+            continue;
+        };
+
+        let hash = blake3::Hash::from_hex(hex)
+            .with_context(|| format!("unexpected object {} in chunks/", key))?;
+        ctx.storage.write(
+            &ChunkRef::Object(hash).key(Codec::Zstd),
+            &ctx.storage.read(&key)?,
+        )?;
+        ctx.storage.delete(&key)?;
+        moved += 1;
+    }
+
+    ctx.storage.create_prefixes(PREFIXES)?;
+    stamp.version = FORMAT_VERSION;
+    write_stamp(ctx, &stamp)?;
+    info!(
+        "upgraded repository {} to version {}, moved {} chunks",
+        stamp.id, FORMAT_VERSION, moved
+    );
+    Ok(())
+}
+
+/// Reads the stamp, failing unless the repository was initialized with a
+/// layout this version understands.
+pub fn check(ctx: &Context) -> Result<Stamp> {
+    let stamp = read_stamp(ctx)?;
+    if stamp.version < FORMAT_VERSION {
+        bail!(
+            "repository version {} is outdated, run init --upgrade to upgrade it to version {}",
+            stamp.version,
+            FORMAT_VERSION
+        );
+    } else if stamp.version != FORMAT_VERSION {
+        bail!(
+            "unsupported repository version {}, expected {}",
+            stamp.version,
+            FORMAT_VERSION
+        );
+    }
+
+    Ok(stamp)
+}
+
+/// Checks that a cluster being backed up is the one the repository holds
+/// backups of, or records it if this is the first backup. Needs the repository
+/// lock to be held.
+pub fn check_system_identifier(ctx: &Context, system_identifier: u64, force: bool) -> Result<()> {
+    let mut stamp = check(ctx)?;
+    match stamp.system_identifier {
+        None => {
+            stamp.system_identifier = Some(system_identifier);
+            write_stamp(ctx, &stamp)
+        },
+        Some(expected) if expected == system_identifier => Ok(()),
+        Some(expected) if force => {
+            warn!(
+                "cluster has system identifier {} but the repository holds backups of {}",
+                system_identifier, expected
+            );
+            Ok(())
+        },
+        Some(expected) => bail!(
+            "cluster has system identifier {} but the repository holds backups of {}, use \
+             --force-system-id to store it here anyway",
+            system_identifier,
+            expected
+        ),
+    }
+}
+
+/// Checks that the cluster in the data directory, if there is one, is the one
+/// the repository holds backups of, so that it isn't fed the WAL of another.
+pub fn check_data_dir(ctx: &Context, force: bool) -> Result<()> {
+    let stamp = check(ctx)?;
+    let (Some(expected), Some(system_identifier)) = (
+        stamp.system_identifier,
+        manifest::read_system_identifier(&ctx.cluster_data)?,
+    ) else {
+        return Ok(());
+    };
+
+    if expected != system_identifier {
+        if !force {
+            bail!(
+                "cluster in {:?} has system identifier {} but the repository holds backups of {}, \
+                 use --force-system-id to use its WAL anyway",
+                ctx.cluster_data,
+                system_identifier,
+                expected
+            );
+        }
+
+        warn!(
+            "cluster in {:?} has system identifier {} but the repository holds backups of {}",
+            ctx.cluster_data, system_identifier, expected
+        );
+    }
+
+    Ok(())
+}
+
+fn write_stamp(ctx: &Context, stamp: &Stamp) -> Result<()> {
+    ctx.storage
+        .write(STAMP_KEY, serde_yaml::to_string(stamp)?.as_bytes())
+}
+
+fn read_stamp(ctx: &Context) -> Result<Stamp> {
+    if !ctx.storage.exists(STAMP_KEY)? {
+        bail!("repository not initialized, run init first");
+    }
+
+    let data = ctx.storage.read(STAMP_KEY)?;
+    serde_yaml::from_slice(&data).with_context(|| format!("failed to parse {}", STAMP_KEY))
+}
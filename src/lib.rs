@@ -0,0 +1,41 @@
+//! Point in time recovery for PostgreSQL clusters: backups of the data
+//! directory into a repository along with the WAL archived since, and
+//! restores from them. The `pg_pitr` binary is a thin command line wrapper
+//! around this crate, [`backup()`] takes a backup without going through it.
+
+pub mod backup;
+pub mod check;
+pub mod check_wal;
+pub mod context;
+pub mod init;
+pub mod pgpass;
+pub mod signal;
+pub mod storage;
+pub mod wal_flush;
+pub mod wal_list;
+pub mod wal_pull;
+pub mod wal_push;
+
+#[cfg(test)]
+mod test_support;
+
+use std::path::Path;
+
+use anyhow::Result;
+
+use self::storage::{EncryptedStorage, LocalStorage};
+pub use self::{
+    backup::{create::BackupOptions, manifest::Manifest},
+    context::Context,
+};
+
+/// Takes a backup of the cluster with its data directory at `cluster_data`
+/// into the initialized, unencrypted repository at `storage`. Use
+/// [`backup::create::backup`] with a [`Context`] for other storages.
+pub fn backup(storage: &Path, cluster_data: &Path, opts: &BackupOptions) -> Result<Manifest> {
+    let storage =
+        EncryptedStorage::new(Box::new(LocalStorage::new(storage.to_owned())), None, false)?;
+    let ctx = Context::new(Box::new(storage), cluster_data.to_owned());
+    init::check(&ctx)?;
+    backup::create::backup(&ctx, opts)
+}
@@ -1,13 +1,25 @@
-mod backup;
-mod context;
-mod wal_pull;
-mod wal_push;
+mod config;
 
-use std::path::PathBuf;
+use std::{path::PathBuf, process};
 
-use anyhow::Result;
-use clap::{Parser, Subcommand};
-use context::Context;
+use anyhow::{Context as _, Result};
+use clap::{CommandFactory, FromArgMatches, Parser, Subcommand};
+use config::Config;
+#[cfg(feature = "s3")]
+use pgpitr::storage::S3Storage;
+use pgpitr::{
+    backup,
+    check,
+    check_wal,
+    init,
+    signal,
+    storage::{EncryptedStorage, LocalStorage, Storage},
+    wal_flush,
+    wal_list,
+    wal_pull,
+    wal_push,
+    Context,
+};
 
 #[derive(Debug, Parser)]
 #[command(version, about, long_about = None)]
@@ -21,27 +33,185 @@ struct Args {
 
 #[derive(Debug, clap::Args)]
 struct GlobalOptions {
-    storage: PathBuf,
-    cluster_data: PathBuf,
+    /// Repository directory, or the key prefix within the bucket with --bucket
+    #[arg(env = "PGPITR_STORAGE")]
+    storage: Option<PathBuf>,
+
+    #[arg(env = "PGDATA")]
+    cluster_data: Option<PathBuf>,
+
+    /// File holding the key new objects are encrypted with and encrypted ones
+    /// are decrypted with
+    #[arg(long, global = true, env = "PGPITR_ENCRYPTION_KEY_FILE")]
+    encryption_key_file: Option<PathBuf>,
+
+    /// Read objects that aren't encrypted, or were encrypted by older versions
+    /// without being bound to their name, such as when copying a repository to
+    /// an encrypted one. They aren't authenticated
+    #[arg(long, global = true)]
+    allow_plaintext: bool,
+
+    #[cfg(feature = "s3")]
+    #[arg(long, global = true, requires = "bucket")]
+    endpoint: Option<String>,
+
+    #[cfg(feature = "s3")]
+    #[arg(long, global = true)]
+    bucket: Option<String>,
+
+    #[cfg(feature = "s3")]
+    #[arg(long, global = true, env = "AWS_REGION", default_value = "us-east-1")]
+    region: String,
+}
+
+impl GlobalOptions {
+    fn storage(&self) -> Result<Box<dyn Storage>> {
+        let path = self.storage.clone().context(
+            "no repository given, pass it as the first argument, in PGPITR_STORAGE or in \
+             pgpitr.toml",
+        )?;
+        let backend = self.backend(path)?;
+        Ok(Box::new(EncryptedStorage::new(
+            backend,
+            self.encryption_key_file.as_deref(),
+            self.allow_plaintext,
+        )?))
+    }
+
+    fn cluster_data(&self) -> Result<PathBuf> {
+        self.cluster_data.clone().context(
+            "no data directory given, pass it as the second argument, in PGDATA or in pgpitr.toml",
+        )
+    }
+
+    fn backend(&self, path: PathBuf) -> Result<Box<dyn Storage>> {
+        #[cfg(feature = "s3")]
+        if let Some(bucket) = &self.bucket {
+            return Ok(Box::new(S3Storage::new(
+                self.endpoint.clone(),
+                bucket.clone(),
+                self.region.clone(),
+                path.to_string_lossy().into_owned(),
+            )?));
+        }
+
+        Ok(Box::new(LocalStorage::new(path)))
+    }
 }
 
 #[derive(Debug, Subcommand)]
 enum Command {
+    Init(init::Options),
     CreateBackup(backup::create::Options),
+    Import(backup::import::Options),
+    Export(backup::export::Options),
+    Restore(backup::restore::Options),
+    Clone(backup::clone::Options),
+    Copy(backup::copy::Options),
+    List(backup::list::Options),
+    Ls(backup::ls::Options),
+    Cat(backup::cat::Options),
+    Diff(backup::diff::Options),
+    Info(backup::info::Options),
+    Verify(backup::verify::Options),
+    TestRestore(backup::test_restore::Options),
+    Prune(backup::prune::Options),
+    Delete(backup::delete::Options),
+    Relabel(backup::relabel::Options),
+    Pin(backup::pin::Options),
+    Unpin(backup::pin::Options),
+    Tag(backup::tag::Options),
+    Gc(backup::gc::Options),
+    Cleanup(backup::cleanup::Options),
+    Stats(backup::stats::Options),
+    Du(backup::du::Options),
+    Check(check::Options),
+    CheckWal(check_wal::Options),
+    #[command(alias = "archive-wal")]
     WalPush(wal_push::Options),
+    #[command(aliases = ["wal-get", "restore-wal"])]
     WalPull(wal_pull::Options),
+    WalFlush(wal_flush::Options),
+    WalList(wal_list::Options),
 }
 
 fn main() -> Result<()> {
     env_logger::init();
 
-    let args = Args::parse();
-    let context = Context::new(args.global.storage, args.global.cluster_data);
+    let result = run();
+    if let Some(interrupted) = result
+        .as_ref()
+        .err()
+        .and_then(|err| err.downcast_ref::<signal::Interrupted>())
+    {
+        eprintln!("Error: {}", interrupted);
+        process::exit(interrupted.exit_code());
+    }
+
+    if let Some(timed_out) = result
+        .as_ref()
+        .err()
+        .and_then(|err| err.downcast_ref::<signal::TimedOut>())
+    {
+        eprintln!("Error: {}", timed_out);
+        process::exit(signal::TimedOut::EXIT_CODE);
+    }
+
+    // Not an error as far as restore_command is concerned, it was logged as
+    // missing already.
+    if result
+        .as_ref()
+        .is_err_and(|err| err.is::<wal_pull::NotFound>())
+    {
+        process::exit(wal_pull::NotFound::EXIT_CODE);
+    }
+
+    result
+}
+
+fn run() -> Result<()> {
+    let config = Config::load()?;
+    let matches = config.apply(Args::command()).get_matches();
+    let mut args = Args::from_arg_matches(&matches).unwrap_or_else(|err| err.exit());
+    config.fill(&mut args.global.storage, &mut args.global.cluster_data);
+    let storage = args.global.storage()?;
+    let context = Context::new(storage, args.global.cluster_data()?);
+
+    if !matches!(args.subcommand, Command::Init(_)) {
+        init::check(&context)?;
+    }
 
     match args.subcommand {
+        Command::Init(opts) => init::run(&context, &opts)?,
         Command::CreateBackup(opts) => backup::create::run(&context, &opts)?,
+        Command::Import(opts) => backup::import::run(&context, &opts)?,
+        Command::Export(opts) => backup::export::run(&context, &opts)?,
+        Command::Restore(opts) => backup::restore::run(&context, &opts)?,
+        Command::Clone(opts) => backup::clone::run(&context, &opts)?,
+        Command::Copy(opts) => backup::copy::run(&context, &opts)?,
+        Command::List(opts) => backup::list::run(&context, &opts)?,
+        Command::Ls(opts) => backup::ls::run(&context, &opts)?,
+        Command::Cat(opts) => backup::cat::run(&context, &opts)?,
+        Command::Diff(opts) => backup::diff::run(&context, &opts)?,
+        Command::Info(opts) => backup::info::run(&context, &opts)?,
+        Command::Verify(opts) => backup::verify::run(&context, &opts)?,
+        Command::TestRestore(opts) => backup::test_restore::run(&context, &opts)?,
+        Command::Prune(opts) => backup::prune::run(&context, &opts)?,
+        Command::Delete(opts) => backup::delete::run(&context, &opts)?,
+        Command::Relabel(opts) => backup::relabel::run(&context, &opts)?,
+        Command::Pin(opts) => backup::pin::run(&context, &opts, true)?,
+        Command::Unpin(opts) => backup::pin::run(&context, &opts, false)?,
+        Command::Tag(opts) => backup::tag::run(&context, &opts)?,
+        Command::Gc(opts) => backup::gc::run(&context, &opts)?,
+        Command::Cleanup(opts) => backup::cleanup::run(&context, &opts)?,
+        Command::Stats(opts) => backup::stats::run(&context, &opts)?,
+        Command::Du(opts) => backup::du::run(&context, &opts)?,
+        Command::Check(opts) => check::run(&context, &opts)?,
+        Command::CheckWal(opts) => check_wal::run(&context, &opts)?,
         Command::WalPush(opts) => wal_push::run(&context, &opts)?,
         Command::WalPull(opts) => wal_pull::run(&context, &opts)?,
+        Command::WalFlush(opts) => wal_flush::run(&context, &opts)?,
+        Command::WalList(opts) => wal_list::run(&context, &opts)?,
     }
 
     Ok(())
@@ -52,8 +222,6 @@ fn main() -> Result<()> {
 //       - proper restore features
 //       - storages: local, s3, gcs, azure, wasabi, b2
 //       - encryption
-//       - repository format
 //       - repository management
-//       - config files
 //       - async/batched archive/restore
 //       - track wal archives needed for backup
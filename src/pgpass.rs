@@ -0,0 +1,149 @@
+use std::{
+    env,
+    fs,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context as _, Result};
+
+/// `~/.pgpass`, the password file libpq reads when PGPASSFILE isn't set.
+pub fn default_path() -> Option<PathBuf> {
+    env::var_os("HOME")
+        .filter(|home| !home.is_empty())
+        .map(|home| Path::new(&home).join(".pgpass"))
+}
+
+// libpq ignores a password file other users can read or write, and so does
+// this.
+pub fn check_permissions(path: &Path) -> Result<()> {
+    let mode = fs::metadata(path)
+        .with_context(|| format!("failed to read {:?}", path))?
+        .permissions()
+        .mode();
+    if mode & 0o077 != 0 {
+        bail!(
+            "password file {:?} has group or world access, it needs to be u=rw (0600) or less",
+            path
+        );
+    }
+
+    Ok(())
+}
+
+/// Looks up the password for a connection in a file in the format of
+/// `~/.pgpass`, `hostname:port:database:username:password` lines where `*`
+/// matches anything in the first four fields. The first matching line wins.
+pub fn lookup(
+    path: &Path,
+    host: &str,
+    port: u16,
+    dbname: &str,
+    user: &str,
+) -> Result<Option<String>> {
+    let contents =
+        fs::read_to_string(path).with_context(|| format!("failed to read {:?}", path))?;
+    Ok(find(&contents, [host, &port.to_string(), dbname, user]))
+}
+
+fn find(contents: &str, wanted: [&str; 4]) -> Option<String> {
+    contents
+        .lines()
+        .filter(|line| !line.starts_with('#'))
+        .filter_map(parse_line)
+        .find(|fields| {
+            fields[..4]
+                .iter()
+                .zip(wanted)
+                .all(|(field, wanted)| field == "*" || field == wanted)
+        })
+        .map(|mut fields| fields.pop().unwrap())
+}
+
+// Splits a line at unescaped colons, `\:` and `\\` being a literal colon and
+// backslash. Lines with fewer than five fields are skipped, any colons past
+// the fourth are part of the password.
+fn parse_line(line: &str) -> Option<Vec<String>> {
+    let mut fields = vec![String::new()];
+    let mut chars = line.trim_end_matches('\r').chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => fields.last_mut().unwrap().extend(chars.next()),
+            ':' if fields.len() < 5 => fields.push(String::new()),
+            c => fields.last_mut().unwrap().push(c),
+        }
+    }
+
+    (fields.len() == 5).then_some(fields)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, os::unix::fs::PermissionsExt};
+
+    use super::{check_permissions, find, lookup};
+    use crate::test_support::TestDir;
+
+    const PGPASS: &str = "# staging
+db.internal:5432:app:backup:s3cret
+*:5433:*:postgres:wild\\:card
+localhost:*:*:postgres:local:with:colons
+*:*:*:*:fallback
+";
+
+    #[test]
+    fn first_matching_line_wins() {
+        let password = |host, port, dbname, user| find(PGPASS, [host, port, dbname, user]);
+        assert_eq!(
+            password("db.internal", "5432", "app", "backup").as_deref(),
+            Some("s3cret")
+        );
+        assert_eq!(
+            password("db.internal", "5433", "app", "postgres").as_deref(),
+            Some("wild:card")
+        );
+        assert_eq!(
+            password("localhost", "5432", "postgres", "postgres").as_deref(),
+            Some("local:with:colons")
+        );
+        assert_eq!(
+            password("db.internal", "5432", "other", "backup").as_deref(),
+            Some("fallback")
+        );
+        assert_eq!(
+            find(
+                "db.internal:5432:app:backup\n",
+                ["db.internal", "5432", "app", "backup"]
+            ),
+            None
+        );
+        assert_eq!(
+            find(
+                "a\\:b:5432:app:backup:escaped\\\\\r\n",
+                ["a:b", "5432", "app", "backup"]
+            )
+            .as_deref(),
+            Some("escaped\\")
+        );
+    }
+
+    #[test]
+    fn rejects_readable_files() {
+        let dir = TestDir::new();
+        let path = dir.path("pgpass");
+        fs::write(&path, PGPASS).unwrap();
+
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+        let err = check_permissions(&path).unwrap_err();
+        assert!(err.to_string().contains("group or world access"), "{}", err);
+
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o600)).unwrap();
+        check_permissions(&path).unwrap();
+        assert_eq!(
+            lookup(&path, "db.internal", 5432, "app", "backup")
+                .unwrap()
+                .as_deref(),
+            Some("s3cret")
+        );
+    }
+}
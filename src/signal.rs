@@ -0,0 +1,104 @@
+use std::{
+    fmt,
+    io,
+    mem,
+    sync::{
+        atomic::{AtomicI32, Ordering},
+        OnceLock,
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+
+static RECEIVED: AtomicI32 = AtomicI32::new(0);
+static DEADLINE: OnceLock<(Instant, Duration)> = OnceLock::new();
+
+/// Returned by [`check`] once SIGINT or SIGTERM was received.
+#[derive(Debug)]
+pub struct Interrupted(libc::c_int);
+
+impl Interrupted {
+    /// What a shell reports for a process killed by the signal.
+    pub fn exit_code(&self) -> i32 {
+        128 + self.0
+    }
+}
+
+impl fmt::Display for Interrupted {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0 {
+            libc::SIGINT => write!(f, "interrupted by SIGINT"),
+            libc::SIGTERM => write!(f, "interrupted by SIGTERM"),
+            signal => write!(f, "interrupted by signal {}", signal),
+        }
+    }
+}
+
+impl std::error::Error for Interrupted {}
+
+/// Returned by [`check`] once the timeout set with [`set_timeout`] has passed.
+#[derive(Debug)]
+pub struct TimedOut(Duration);
+
+impl TimedOut {
+    /// Same as timeout(1).
+    pub const EXIT_CODE: i32 = 124;
+}
+
+impl fmt::Display for TimedOut {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "timed out after {}s", self.0.as_secs())
+    }
+}
+
+impl std::error::Error for TimedOut {}
+
+// Instead of killing the process, SIGINT and SIGTERM make the next `check`
+// fail, so the command unwinds through its cleanup: backups are stopped on the
+// server, partial objects and files removed and the repository lock released.
+// A second signal kills the process as usual.
+pub fn install() -> Result<()> {
+    for signal in [libc::SIGINT, libc::SIGTERM] {
+        unsafe {
+            let mut action = mem::zeroed::<libc::sigaction>();
+            action.sa_sigaction = handle as libc::sighandler_t;
+            action.sa_flags = libc::SA_RESTART | libc::SA_RESETHAND;
+            libc::sigemptyset(&mut action.sa_mask);
+            if libc::sigaction(signal, &action, std::ptr::null_mut()) != 0 {
+                return Err(io::Error::last_os_error().into());
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// Makes `check` fail once `timeout` has passed, the same way it does after a
+// signal.
+pub fn set_timeout(timeout: Duration) {
+    let _ = DEADLINE.set((Instant::now() + timeout, timeout));
+}
+
+/// Time left until the timeout, if one is set.
+pub fn remaining() -> Option<Duration> {
+    DEADLINE
+        .get()
+        .map(|(deadline, _)| deadline.saturating_duration_since(Instant::now()))
+}
+
+pub fn check() -> Result<()> {
+    match RECEIVED.load(Ordering::Relaxed) {
+        0 => (),
+        signal => return Err(Interrupted(signal).into()),
+    }
+
+    match DEADLINE.get() {
+        Some((deadline, timeout)) if Instant::now() >= *deadline => Err(TimedOut(*timeout).into()),
+        _ => Ok(()),
+    }
+}
+
+extern "C" fn handle(signal: libc::c_int) {
+    RECEIVED.store(signal, Ordering::Relaxed);
+}
@@ -0,0 +1,540 @@
+use std::{
+    fs,
+    io::{self, Cursor, Read, Write},
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use anyhow::{anyhow, bail, Context as _, Result};
+use ring::{
+    aead::{self, Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305},
+    hkdf::{Salt, HKDF_SHA256},
+    rand::{SecureRandom, SystemRandom},
+};
+
+use super::{Lock, ObjectWriter, Storage};
+
+// Every encrypted object starts with a header naming the format and cipher,
+// followed by a random salt the object's key is derived from.
+const MAGIC: &[u8] = b"pgpitr-enc";
+// Version 1 didn't bind objects to their key, so they could be swapped for one
+// another unnoticed.
+const VERSION: u8 = 2;
+const CIPHER_CHACHA20_POLY1305: u8 = 1;
+const SALT_LEN: usize = 32;
+const HEADER_LEN: usize = MAGIC.len() + 2 + SALT_LEN;
+
+// Plaintext is sealed in frames of this size, only the last one is shorter so
+// a truncated object fails to decrypt.
+const FRAME_SIZE: usize = 64 * 1024;
+const MIN_KEY_LEN: usize = 32;
+
+/// Encrypts every object written with ChaCha20-Poly1305 when given a key, and
+/// decrypts objects that were encrypted when read. Each object's key is derived
+/// from its name, so objects can't be swapped for one another. With a key,
+/// objects that aren't encrypted are refused unless `allow_plaintext` is set,
+/// which is meant for copying an existing repository to an encrypted one.
+pub struct EncryptedStorage {
+    inner: Box<dyn Storage>,
+    key: Option<MasterKey>,
+    allow_plaintext: bool,
+}
+
+struct MasterKey {
+    path: PathBuf,
+    material: Vec<u8>,
+}
+
+impl EncryptedStorage {
+    pub fn new(
+        inner: Box<dyn Storage>,
+        key_file: Option<&Path>,
+        allow_plaintext: bool,
+    ) -> Result<Self> {
+        let key = match key_file {
+            Some(path) => {
+                let material = fs::read(path)
+                    .with_context(|| format!("failed to read encryption key {:?}", path))?;
+                if material.len() < MIN_KEY_LEN {
+                    bail!(
+                        "encryption key {:?} must be at least {} bytes, such as from head -c 32 \
+                         /dev/urandom",
+                        path,
+                        MIN_KEY_LEN
+                    );
+                }
+
+                Some(MasterKey {
+                    path: path.canonicalize()?,
+                    material,
+                })
+            },
+            None => None,
+        };
+
+        Ok(Self {
+            inner,
+            key,
+            allow_plaintext,
+        })
+    }
+
+    // Objects that aren't encrypted can be written by anyone with access to the
+    // storage, nothing vouches for them.
+    fn check_plaintext(&self, key: &str) -> Result<()> {
+        if self.key.is_some() && !self.allow_plaintext {
+            bail!(
+                "{} is not encrypted, pass --allow-plaintext to read it anyway",
+                key
+            );
+        }
+
+        Ok(())
+    }
+
+    // The key `header`, which starts with the magic number, says the object
+    // was encrypted with.
+    fn object_key(&self, key: &str, header: &[u8]) -> Result<LessSafeKey> {
+        let Some(master_key) = &self.key else {
+            bail!("{} is encrypted, pass --encryption-key-file", key);
+        };
+
+        if header.len() < HEADER_LEN {
+            bail!("{} has a truncated encryption header", key);
+        }
+
+        let salt = &header[MAGIC.len() + 2..];
+        match (header[MAGIC.len()], header[MAGIC.len() + 1]) {
+            (VERSION, CIPHER_CHACHA20_POLY1305) => master_key.object_key(salt, Some(key)),
+            (1, CIPHER_CHACHA20_POLY1305) if self.allow_plaintext =>
+                master_key.object_key(salt, None),
+            (1, CIPHER_CHACHA20_POLY1305) => bail!(
+                "{} was encrypted without being bound to its name, pass --allow-plaintext to read \
+                 it anyway",
+                key
+            ),
+            (version, cipher) => bail!(
+                "{} uses unsupported encryption version {} with cipher {}",
+                key,
+                version,
+                cipher
+            ),
+        }
+    }
+}
+
+impl MasterKey {
+    // Without a name for objects of version 1.
+    fn object_key(&self, salt: &[u8], name: Option<&str>) -> Result<LessSafeKey> {
+        let prk = Salt::new(HKDF_SHA256, salt).extract(&self.material);
+        let info = [
+            b"pgpitr object key",
+            name.map(str::as_bytes).unwrap_or_default(),
+        ];
+        let okm = prk
+            .expand(&info, &CHACHA20_POLY1305)
+            .map_err(|_| anyhow!("failed to derive object key"))?;
+        Ok(LessSafeKey::new(UnboundKey::from(okm)))
+    }
+}
+
+impl Storage for EncryptedStorage {
+    fn create_writer(&self, key: &str) -> Result<Box<dyn ObjectWriter + '_>> {
+        let mut inner = self.inner.create_writer(key)?;
+        let Some(master_key) = &self.key else {
+            return Ok(inner);
+        };
+
+        let mut header = Vec::with_capacity(HEADER_LEN);
+        header.extend_from_slice(MAGIC);
+        header.extend_from_slice(&[VERSION, CIPHER_CHACHA20_POLY1305]);
+        let mut salt = [0; SALT_LEN];
+        SystemRandom::new()
+            .fill(&mut salt)
+            .map_err(|_| anyhow!("failed to generate salt"))?;
+        header.extend_from_slice(&salt);
+        inner.write_all(&header)?;
+
+        Ok(Box::new(EncryptingWriter {
+            inner,
+            key: master_key.object_key(&salt, Some(key))?,
+            header,
+            counter: 0,
+            buffer: Vec::with_capacity(FRAME_SIZE + CHACHA20_POLY1305.tag_len()),
+        }))
+    }
+
+    fn open_reader(&self, key: &str) -> Result<Box<dyn Read + '_>> {
+        let mut inner = self.inner.open_reader(key)?;
+        let mut header = Vec::with_capacity(HEADER_LEN);
+        (&mut inner)
+            .take(HEADER_LEN as u64)
+            .read_to_end(&mut header)?;
+
+        if !header.starts_with(MAGIC) {
+            self.check_plaintext(key)?;
+            return Ok(Box::new(Cursor::new(header).chain(inner)));
+        }
+
+        Ok(Box::new(DecryptingReader {
+            inner,
+            key: self.object_key(key, &header)?,
+            header,
+            counter: 0,
+            frame: Vec::new(),
+            position: 0,
+            done: false,
+        }))
+    }
+
+    // Only the frames holding the range are read and authenticated, so unlike
+    // reading the whole object this doesn't notice it being cut short after
+    // them.
+    fn read_range(&self, key: &str, offset: u64, len: u64) -> Result<Vec<u8>> {
+        let header = self.inner.read_range(key, 0, HEADER_LEN as u64)?;
+        if !header.starts_with(MAGIC) {
+            self.check_plaintext(key)?;
+            return self.inner.read_range(key, offset, len);
+        }
+
+        let object_key = self.object_key(key, &header)?;
+        if len == 0 {
+            return Ok(Vec::new());
+        }
+
+        let sealed_len = FRAME_SIZE + CHACHA20_POLY1305.tag_len();
+        let first = offset / FRAME_SIZE as u64;
+        let last = (offset + len - 1) / FRAME_SIZE as u64;
+        let mut sealed = self.inner.read_range(
+            key,
+            HEADER_LEN as u64 + first * sealed_len as u64,
+            (last - first + 1) * sealed_len as u64,
+        )?;
+
+        let mut data = Vec::new();
+        for (counter, frame) in (first..).zip(sealed.chunks_mut(sealed_len)) {
+            let frame_last = frame.len() < sealed_len;
+            let plaintext = object_key
+                .open_in_place(nonce(counter, frame_last), Aad::from(&header), frame)
+                .map_err(|_| anyhow!("failed to decrypt {}", key))?;
+            data.extend_from_slice(plaintext);
+        }
+
+        let start = ((offset - first * FRAME_SIZE as u64) as usize).min(data.len());
+        data.drain(..start);
+        data.truncate(len as usize);
+        Ok(data)
+    }
+
+    fn exists(&self, key: &str) -> Result<bool> {
+        self.inner.exists(key)
+    }
+
+    fn size(&self, key: &str) -> Result<u64> {
+        self.inner.size(key)
+    }
+
+    fn delete(&self, key: &str) -> Result<()> {
+        self.inner.delete(key)
+    }
+
+    fn list(&self, prefix: &str) -> Result<Vec<String>> {
+        self.inner.list(prefix)
+    }
+
+    fn list_partial(&self, prefix: &str) -> Result<Vec<(String, SystemTime)>> {
+        self.inner.list_partial(prefix)
+    }
+
+    fn create_prefixes(&self, prefixes: &[&str]) -> Result<()> {
+        self.inner.create_prefixes(prefixes)
+    }
+
+    fn lock(&self, wait: bool) -> Result<Box<dyn Lock + '_>> {
+        self.inner.lock(wait)
+    }
+
+    fn command_args(&self) -> Result<Vec<String>> {
+        let mut args = self.inner.command_args()?;
+        if let Some(key) = &self.key {
+            args.push("--encryption-key-file".to_owned());
+            args.push(key.path.to_string_lossy().into_owned());
+            if self.allow_plaintext {
+                args.push("--allow-plaintext".to_owned());
+            }
+        }
+
+        Ok(args)
+    }
+}
+
+// Frames are numbered so they can't be reordered, and the last one is marked so
+// the object can't be cut short at a frame boundary. The header is
+// authenticated along with every frame.
+fn nonce(counter: u64, last: bool) -> Nonce {
+    let mut nonce = [0; aead::NONCE_LEN];
+    nonce[..8].copy_from_slice(&counter.to_be_bytes());
+    nonce[aead::NONCE_LEN - 1] = last as u8;
+    Nonce::assume_unique_for_key(nonce)
+}
+
+struct EncryptingWriter<'a> {
+    inner: Box<dyn ObjectWriter + 'a>,
+    key: LessSafeKey,
+    header: Vec<u8>,
+    counter: u64,
+    buffer: Vec<u8>,
+}
+
+impl EncryptingWriter<'_> {
+    fn seal_frame(&mut self, last: bool) -> io::Result<()> {
+        self.key
+            .seal_in_place_append_tag(
+                nonce(self.counter, last),
+                Aad::from(&self.header),
+                &mut self.buffer,
+            )
+            .map_err(|_| io::Error::new(io::ErrorKind::Other, "encryption failed"))?;
+        self.inner.write_all(&self.buffer)?;
+        self.buffer.clear();
+        self.counter += 1;
+        Ok(())
+    }
+}
+
+impl Write for EncryptingWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = buf.len().min(FRAME_SIZE - self.buffer.len());
+        self.buffer.extend_from_slice(&buf[..len]);
+        if self.buffer.len() == FRAME_SIZE {
+            self.seal_frame(false)?;
+        }
+
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl ObjectWriter for EncryptingWriter<'_> {
+    fn finish(mut self: Box<Self>) -> Result<()> {
+        // Full frames are sealed as soon as they fill up, so the last one is
+        // always short, empty if need be.
+        self.seal_frame(true)?;
+        self.inner.finish()
+    }
+}
+
+struct DecryptingReader<R> {
+    inner: R,
+    key: LessSafeKey,
+    header: Vec<u8>,
+    counter: u64,
+    frame: Vec<u8>,
+    position: usize,
+    done: bool,
+}
+
+impl<R> DecryptingReader<R>
+where
+    R: Read,
+{
+    fn open_frame(&mut self) -> io::Result<()> {
+        let sealed_len = FRAME_SIZE + CHACHA20_POLY1305.tag_len();
+        self.frame.clear();
+        (&mut self.inner)
+            .take(sealed_len as u64)
+            .read_to_end(&mut self.frame)?;
+
+        let last = self.frame.len() < sealed_len;
+        let plaintext = self
+            .key
+            .open_in_place(
+                nonce(self.counter, last),
+                Aad::from(&self.header),
+                &mut self.frame,
+            )
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "decryption failed"))?;
+        let len = plaintext.len();
+        self.frame.truncate(len);
+        self.position = 0;
+        self.counter += 1;
+        self.done = last;
+        Ok(())
+    }
+}
+
+impl<R> Read for DecryptingReader<R>
+where
+    R: Read,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.position == self.frame.len() {
+            if self.done {
+                return Ok(0);
+            }
+
+            self.open_frame()?;
+        }
+
+        let len = buf.len().min(self.frame.len() - self.position);
+        buf[..len].copy_from_slice(&self.frame[self.position..self.position + len]);
+        self.position += len;
+        Ok(len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, io::Write};
+
+    use ring::aead::CHACHA20_POLY1305;
+
+    use super::{EncryptedStorage, FRAME_SIZE, HEADER_LEN};
+    use crate::{
+        storage::{LocalStorage, Storage},
+        test_support::TestDir,
+    };
+
+    fn storage(dir: &TestDir, encrypted: bool) -> EncryptedStorage {
+        migrating(dir, encrypted, false)
+    }
+
+    fn migrating(dir: &TestDir, encrypted: bool, allow_plaintext: bool) -> EncryptedStorage {
+        let key_file = dir.path("key");
+        fs::write(&key_file, [7; 32]).unwrap();
+        EncryptedStorage::new(
+            Box::new(LocalStorage::new(dir.path("repo"))),
+            encrypted.then_some(key_file.as_path()),
+            allow_plaintext,
+        )
+        .unwrap()
+    }
+
+    fn data(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    #[test]
+    fn round_trip() {
+        let dir = TestDir::new();
+        let storage = storage(&dir, true);
+        for len in [0, 1, FRAME_SIZE - 1, FRAME_SIZE, 2 * FRAME_SIZE + 5] {
+            let mut writer = storage.create_writer("object").unwrap();
+            // Written in odd pieces so frames don't line up with writes.
+            for piece in data(len).chunks(1000) {
+                writer.write_all(piece).unwrap();
+            }
+            writer.finish().unwrap();
+
+            let stored = fs::read(dir.path("repo/object")).unwrap();
+            assert_ne!(&stored[HEADER_LEN..], &data(len)[..]);
+            assert_eq!(storage.read("object").unwrap(), data(len));
+        }
+    }
+
+    #[test]
+    fn tampering_is_detected() {
+        let dir = TestDir::new();
+        let storage = storage(&dir, true);
+        storage.write("object", &data(FRAME_SIZE + 100)).unwrap();
+
+        let path = dir.path("repo/object");
+        let mut stored = fs::read(&path).unwrap();
+        stored[HEADER_LEN + FRAME_SIZE / 2] ^= 1;
+        fs::write(&path, &stored).unwrap();
+        assert!(storage.read("object").is_err());
+    }
+
+    #[test]
+    fn truncation_is_detected() {
+        let dir = TestDir::new();
+        let storage = storage(&dir, true);
+        storage
+            .write("object", &data(2 * FRAME_SIZE + 100))
+            .unwrap();
+
+        // Cut right after the first frame, which on its own is well-formed.
+        let path = dir.path("repo/object");
+        let stored = fs::read(&path).unwrap();
+        let frame_len = FRAME_SIZE + CHACHA20_POLY1305.tag_len();
+        fs::write(&path, &stored[..HEADER_LEN + frame_len]).unwrap();
+        assert!(storage.read("object").is_err());
+    }
+
+    #[test]
+    fn range_reads() {
+        let dir = TestDir::new();
+        let len = 3 * FRAME_SIZE + 10;
+        for encrypted in [true, false] {
+            let storage = storage(&dir, encrypted);
+            storage.write("object", &data(len)).unwrap();
+            for (offset, range_len) in [
+                (0, 10),
+                (FRAME_SIZE - 5, 10),
+                (FRAME_SIZE, FRAME_SIZE),
+                (2 * FRAME_SIZE + 1, FRAME_SIZE + 100),
+                (len, 10),
+                (5, 0),
+            ] {
+                let end = (offset + range_len).min(len);
+                assert_eq!(
+                    storage
+                        .read_range("object", offset as u64, range_len as u64)
+                        .unwrap(),
+                    &data(len)[offset..end],
+                    "{} bytes at {}",
+                    range_len,
+                    offset
+                );
+            }
+        }
+
+        // Flip a bit in the second frame, which only ranges covering it see.
+        let path = dir.path("repo/object");
+        storage(&dir, true).write("object", &data(len)).unwrap();
+        let mut stored = fs::read(&path).unwrap();
+        stored[HEADER_LEN + FRAME_SIZE + CHACHA20_POLY1305.tag_len() + 1] ^= 1;
+        fs::write(&path, &stored).unwrap();
+        let storage = storage(&dir, true);
+        assert!(storage.read_range("object", 0, 10).is_ok());
+        assert!(storage
+            .read_range("object", FRAME_SIZE as u64 - 1, 2)
+            .is_err());
+    }
+
+    #[test]
+    fn plaintext_objects_need_allow_plaintext() {
+        let dir = TestDir::new();
+        storage(&dir, false)
+            .write("plain", b"not encrypted")
+            .unwrap();
+        storage(&dir, true).write("secret", b"encrypted").unwrap();
+
+        let err = storage(&dir, true).read("plain").unwrap_err();
+        assert!(err.to_string().contains("is not encrypted"), "{}", err);
+        assert!(storage(&dir, true).read_range("plain", 0, 3).is_err());
+        assert_eq!(
+            migrating(&dir, true, true).read("plain").unwrap(),
+            b"not encrypted"
+        );
+        assert!(storage(&dir, false).read("secret").is_err());
+    }
+
+    #[test]
+    fn objects_are_bound_to_their_name() {
+        let dir = TestDir::new();
+        let storage = storage(&dir, true);
+        storage.write("backups/a.manifest", b"a").unwrap();
+        storage.write("backups/b.manifest", b"b").unwrap();
+        fs::copy(
+            dir.path("repo/backups/a.manifest"),
+            dir.path("repo/backups/b.manifest"),
+        )
+        .unwrap();
+        assert!(storage.read("backups/b.manifest").is_err());
+        assert_eq!(storage.read("backups/a.manifest").unwrap(), b"a");
+    }
+}
@@ -0,0 +1,253 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    os::fd::AsRawFd,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use anyhow::{Context as _, Result};
+use walkdir::{DirEntry, WalkDir};
+
+use super::{lock_held_error, Lock, ObjectWriter, Storage, LOCK_KEY};
+
+// Objects are written under this suffix and renamed into place once durable,
+// so one left behind by an interrupted write is never taken for complete.
+const PARTIAL_SUFFIX: &str = ".partial";
+
+pub struct LocalStorage {
+    root: PathBuf,
+}
+
+impl LocalStorage {
+    pub fn new(root: PathBuf) -> Self {
+        Self { root }
+    }
+
+    // Every file whose key starts with `prefix`, partial ones included.
+    fn files(&self, prefix: &str) -> Result<Vec<(String, DirEntry)>> {
+        let dir = match prefix.rfind('/') {
+            Some(pos) => self.root.join(&prefix[..pos]),
+            None => self.root.clone(),
+        };
+
+        if !dir.exists() {
+            return Ok(Vec::new());
+        }
+
+        let mut files = Vec::new();
+        for entry in WalkDir::new(&dir) {
+            let entry = entry?;
+            if !entry.file_type().is_file() {
+                continue;
+            }
+
+            let key = entry
+                .path()
+                .strip_prefix(&self.root)?
+                .to_string_lossy()
+                .into_owned();
+
+            if key.starts_with(prefix) {
+                files.push((key, entry));
+            }
+        }
+
+        Ok(files)
+    }
+}
+
+impl Storage for LocalStorage {
+    fn create_writer(&self, key: &str) -> Result<Box<dyn ObjectWriter + '_>> {
+        let path = self.root.join(key);
+        if let Some(parent) = path.parent() {
+            create_dir_durable(parent)?;
+        }
+
+        let mut partial_path = path.clone().into_os_string();
+        partial_path.push(PARTIAL_SUFFIX);
+        let partial_path = PathBuf::from(partial_path);
+        let file = File::create(&partial_path)
+            .with_context(|| format!("failed to create {:?}", partial_path))?;
+        Ok(Box::new(LocalWriter {
+            file,
+            path,
+            partial_path,
+            finished: false,
+        }))
+    }
+
+    fn open_reader(&self, key: &str) -> Result<Box<dyn Read + '_>> {
+        let path = self.root.join(key);
+        let file = File::open(&path).with_context(|| format!("failed to open {:?}", path))?;
+        Ok(Box::new(file))
+    }
+
+    fn read_range(&self, key: &str, offset: u64, len: u64) -> Result<Vec<u8>> {
+        let path = self.root.join(key);
+        let mut file = File::open(&path).with_context(|| format!("failed to open {:?}", path))?;
+        file.seek(SeekFrom::Start(offset))?;
+        let mut data = Vec::new();
+        file.take(len).read_to_end(&mut data)?;
+        Ok(data)
+    }
+
+    fn exists(&self, key: &str) -> Result<bool> {
+        Ok(self.root.join(key).try_exists()?)
+    }
+
+    fn size(&self, key: &str) -> Result<u64> {
+        let path = self.root.join(key);
+        let metadata = fs::metadata(&path).with_context(|| format!("failed to stat {:?}", path))?;
+        Ok(metadata.len())
+    }
+
+    fn delete(&self, key: &str) -> Result<()> {
+        let path = self.root.join(key);
+        match fs::remove_file(&path) {
+            Ok(()) => Ok(()),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+            Err(err) => Err(err).with_context(|| format!("failed to remove {:?}", path)),
+        }
+    }
+
+    fn list(&self, prefix: &str) -> Result<Vec<String>> {
+        Ok(self
+            .files(prefix)?
+            .into_iter()
+            .map(|(key, _)| key)
+            .filter(|key| !key.ends_with(PARTIAL_SUFFIX))
+            .collect())
+    }
+
+    fn list_partial(&self, prefix: &str) -> Result<Vec<(String, SystemTime)>> {
+        let mut partial = Vec::new();
+        for (key, entry) in self.files(prefix)? {
+            if key.ends_with(PARTIAL_SUFFIX) {
+                partial.push((key, entry.metadata()?.modified()?));
+            }
+        }
+
+        Ok(partial)
+    }
+
+    fn create_prefixes(&self, prefixes: &[&str]) -> Result<()> {
+        for prefix in prefixes {
+            let dir = self.root.join(prefix);
+            fs::create_dir_all(&dir).with_context(|| format!("failed to create {:?}", dir))?;
+            File::open(&dir)?.sync_all()?;
+        }
+
+        File::open(&self.root)?.sync_all()?;
+        Ok(())
+    }
+
+    // flock locks go away with the file descriptor, so a process that dies
+    // never leaves the repository locked.
+    fn lock(&self, wait: bool) -> Result<Box<dyn Lock + '_>> {
+        fs::create_dir_all(&self.root)?;
+        let path = self.root.join(LOCK_KEY);
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+            .with_context(|| format!("failed to open {:?}", path))?;
+
+        let operation = if wait {
+            libc::LOCK_EX
+        } else {
+            libc::LOCK_EX | libc::LOCK_NB
+        };
+
+        // SAFETY: the descriptor belongs to `file`, which outlives the call.
+        if unsafe { libc::flock(file.as_raw_fd(), operation) } != 0 {
+            let err = io::Error::last_os_error();
+            if err.kind() == io::ErrorKind::WouldBlock {
+                return Err(lock_held_error());
+            }
+
+            return Err(err).with_context(|| format!("failed to lock {:?}", path));
+        }
+
+        Ok(Box::new(LocalLock { _file: file }))
+    }
+
+    fn command_args(&self) -> Result<Vec<String>> {
+        Ok(vec![self
+            .root
+            .canonicalize()?
+            .to_string_lossy()
+            .into_owned()])
+    }
+}
+
+// Like `fs::create_dir_all`, but the entry of every directory it creates is
+// synced into its parent, so a renamed object can't vanish with its directory
+// in a crash.
+fn create_dir_durable(dir: &Path) -> Result<()> {
+    if dir.as_os_str().is_empty() || dir.is_dir() {
+        return Ok(());
+    }
+
+    let parent = dir.parent().unwrap_or(Path::new("/"));
+    create_dir_durable(parent)?;
+    match fs::create_dir(dir) {
+        Ok(()) => (),
+        Err(err) if err.kind() == io::ErrorKind::AlreadyExists && dir.is_dir() => return Ok(()),
+        Err(err) => return Err(err).with_context(|| format!("failed to create {:?}", dir)),
+    }
+
+    let parent = if parent.as_os_str().is_empty() {
+        Path::new(".")
+    } else {
+        parent
+    };
+    File::open(parent)?.sync_all()?;
+    Ok(())
+}
+
+struct LocalLock {
+    _file: File,
+}
+
+impl Lock for LocalLock {}
+
+struct LocalWriter {
+    file: File,
+    path: PathBuf,
+    partial_path: PathBuf,
+    finished: bool,
+}
+
+impl Write for LocalWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl ObjectWriter for LocalWriter {
+    fn finish(mut self: Box<Self>) -> Result<()> {
+        self.file.sync_all()?;
+        fs::rename(&self.partial_path, &self.path)
+            .with_context(|| format!("failed to rename {:?}", self.partial_path))?;
+        self.finished = true;
+        if let Some(parent) = self.path.parent() {
+            File::open(parent)?.sync_all()?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for LocalWriter {
+    fn drop(&mut self) {
+        if !self.finished {
+            let _ = fs::remove_file(&self.partial_path);
+        }
+    }
+}
@@ -0,0 +1,92 @@
+mod encrypted;
+mod local;
+#[cfg(feature = "s3")]
+mod s3;
+
+use std::{
+    io::{self, Read, Write},
+    time::SystemTime,
+};
+
+use anyhow::{anyhow, Result};
+
+#[cfg(feature = "s3")]
+pub use self::s3::S3Storage;
+pub use self::{encrypted::EncryptedStorage, local::LocalStorage};
+
+const LOCK_KEY: &str = ".lock";
+
+/// A flat key/value object store holding the repository. Keys are
+/// `/`-separated paths relative to the repository root.
+pub trait Storage: Send + Sync {
+    fn create_writer(&self, key: &str) -> Result<Box<dyn ObjectWriter + '_>>;
+
+    fn open_reader(&self, key: &str) -> Result<Box<dyn Read + '_>>;
+
+    fn exists(&self, key: &str) -> Result<bool>;
+
+    fn size(&self, key: &str) -> Result<u64>;
+
+    /// Removes the object, succeeding if it does not exist.
+    fn delete(&self, key: &str) -> Result<()>;
+
+    /// Returns every key starting with `prefix`, in no particular order.
+    fn list(&self, prefix: &str) -> Result<Vec<String>>;
+
+    /// Returns the keys of objects starting with `prefix` whose writers never
+    /// finished, along with when they were last written to. They can be
+    /// passed to `delete`.
+    fn list_partial(&self, _prefix: &str) -> Result<Vec<(String, SystemTime)>> {
+        Ok(Vec::new())
+    }
+
+    /// Prepares an empty repository for objects under `prefixes`, which only
+    /// matters for stores with real directories.
+    fn create_prefixes(&self, _prefixes: &[&str]) -> Result<()> {
+        Ok(())
+    }
+
+    /// Takes the exclusive lock on the repository, held until the returned
+    /// value is dropped. If another process holds it this fails, or with
+    /// `wait` blocks until it is released.
+    fn lock(&self, wait: bool) -> Result<Box<dyn Lock + '_>>;
+
+    /// The global command line arguments that select this storage, used when
+    /// generating commands that call back into pgpitr.
+    fn command_args(&self) -> Result<Vec<String>>;
+
+    fn read(&self, key: &str) -> Result<Vec<u8>> {
+        let mut data = Vec::new();
+        self.open_reader(key)?.read_to_end(&mut data)?;
+        Ok(data)
+    }
+
+    /// Reads `len` bytes of the object starting at `offset`, fewer if it ends
+    /// before that.
+    fn read_range(&self, key: &str, offset: u64, len: u64) -> Result<Vec<u8>> {
+        let mut reader = self.open_reader(key)?;
+        io::copy(&mut (&mut reader).take(offset), &mut io::sink())?;
+        let mut data = Vec::new();
+        reader.take(len).read_to_end(&mut data)?;
+        Ok(data)
+    }
+
+    fn write(&self, key: &str, data: &[u8]) -> Result<()> {
+        let mut writer = self.create_writer(key)?;
+        writer.write_all(data)?;
+        writer.finish()
+    }
+}
+
+pub trait ObjectWriter: Write {
+    /// Makes the written object durable. An object whose writer is dropped
+    /// without being finished is discarded.
+    fn finish(self: Box<Self>) -> Result<()>;
+}
+
+/// A held repository lock, released when dropped.
+pub trait Lock {}
+
+fn lock_held_error() -> anyhow::Error {
+    anyhow!("another operation is in progress on the repository, use --wait to wait for it")
+}
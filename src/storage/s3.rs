@@ -0,0 +1,689 @@
+use std::{
+    env,
+    io::{self, Read, Write},
+    process,
+    thread,
+    time::Duration,
+};
+
+use anyhow::{anyhow, bail, Context as _, Result};
+use hmac::{Hmac, Mac};
+use log::warn;
+use sha2::{Digest, Sha256};
+use time::OffsetDateTime;
+
+use super::{lock_held_error, Lock, ObjectWriter, Storage, LOCK_KEY};
+
+// S3 requires every part but the last to be at least 5 MiB.
+const PART_SIZE: usize = 8 * 1024 * 1024;
+const LOCK_POLL_INTERVAL: Duration = Duration::from_secs(5);
+const UNSIGNED_PAYLOAD: &str = "UNSIGNED-PAYLOAD";
+
+/// Stores the repository under `prefix` in an S3-compatible bucket, addressed
+/// with path-style URLs so it also works against MinIO and friends.
+/// Credentials are taken from the standard `AWS_*` environment variables.
+pub struct S3Storage {
+    agent: ureq::Agent,
+    endpoint: String,
+    host: String,
+    bucket: String,
+    region: String,
+    prefix: String,
+    credentials: Credentials,
+}
+
+struct Credentials {
+    access_key_id: String,
+    secret_access_key: String,
+    session_token: Option<String>,
+}
+
+impl S3Storage {
+    pub fn new(
+        endpoint: Option<String>,
+        bucket: String,
+        region: String,
+        prefix: String,
+    ) -> Result<Self> {
+        let endpoint = endpoint
+            .unwrap_or_else(|| format!("https://s3.{}.amazonaws.com", region))
+            .trim_end_matches('/')
+            .to_owned();
+
+        let Some((_, host)) = endpoint.split_once("://") else {
+            bail!(
+                "invalid endpoint {:?}, expected a http:// or https:// URL",
+                endpoint
+            );
+        };
+
+        let host = host.to_owned();
+        let var = |name| env::var(name).with_context(|| format!("{} is not set", name));
+        let credentials = Credentials {
+            access_key_id: var("AWS_ACCESS_KEY_ID")?,
+            secret_access_key: var("AWS_SECRET_ACCESS_KEY")?,
+            session_token: env::var("AWS_SESSION_TOKEN").ok(),
+        };
+
+        Ok(Self {
+            agent: ureq::Agent::new(),
+            endpoint,
+            host,
+            bucket,
+            region,
+            prefix: prefix.trim_matches('/').to_owned(),
+            credentials,
+        })
+    }
+
+    fn full_key(&self, key: &str) -> String {
+        if self.prefix.is_empty() {
+            key.to_owned()
+        } else {
+            format!("{}/{}", self.prefix, key)
+        }
+    }
+
+    /// Builds a signed request for `key` in the bucket, or the bucket itself if
+    /// no key is given.
+    fn request(&self, method: &str, key: Option<&str>, query: &[(&str, &str)]) -> ureq::Request {
+        let mut path = format!("/{}", uri_encode(&self.bucket, false));
+        if let Some(key) = key {
+            path.push('/');
+            path.push_str(&uri_encode(&self.full_key(key), true));
+        }
+
+        let mut query = query
+            .iter()
+            .map(|(name, value)| (uri_encode(name, false), uri_encode(value, false)))
+            .collect::<Vec<_>>();
+        query.sort();
+        let query = query
+            .iter()
+            .map(|(name, value)| format!("{}={}", name, value))
+            .collect::<Vec<_>>()
+            .join("&");
+
+        let now = OffsetDateTime::now_utc();
+        let timestamp = format!(
+            "{:04}{:02}{:02}T{:02}{:02}{:02}Z",
+            now.year(),
+            now.month() as u8,
+            now.day(),
+            now.hour(),
+            now.minute(),
+            now.second()
+        );
+
+        let mut headers = vec![
+            ("host", self.host.as_str()),
+            ("x-amz-content-sha256", UNSIGNED_PAYLOAD),
+            ("x-amz-date", &timestamp),
+        ];
+        if let Some(token) = &self.credentials.session_token {
+            headers.push(("x-amz-security-token", token));
+        }
+
+        let authorization = self.authorization(method, &path, &query, &headers, &timestamp);
+        let url = if query.is_empty() {
+            format!("{}{}", self.endpoint, path)
+        } else {
+            format!("{}{}?{}", self.endpoint, path, query)
+        };
+
+        headers
+            .into_iter()
+            .fold(
+                self.agent.request(method, &url),
+                |request, (name, value)| request.set(name, value),
+            )
+            .set("authorization", &authorization)
+    }
+
+    // See https://docs.aws.amazon.com/AmazonS3/latest/API/sig-v4-header-based-auth.html,
+    // `headers` must be lowercase and sorted by name.
+    fn authorization(
+        &self,
+        method: &str,
+        path: &str,
+        query: &str,
+        headers: &[(&str, &str)],
+        timestamp: &str,
+    ) -> String {
+        let signed_headers = headers
+            .iter()
+            .map(|(name, _)| *name)
+            .collect::<Vec<_>>()
+            .join(";");
+        let canonical_headers = headers
+            .iter()
+            .map(|(name, value)| format!("{}:{}", name, value.trim()))
+            .collect::<Vec<_>>()
+            .join("\n");
+        let canonical_request = format!(
+            "{}\n{}\n{}\n{}\n\n{}\n{}",
+            method, path, query, canonical_headers, signed_headers, UNSIGNED_PAYLOAD
+        );
+
+        let date = &timestamp[..8];
+        let scope = format!("{}/{}/s3/aws4_request", date, self.region);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            timestamp,
+            scope,
+            hex::encode(Sha256::digest(canonical_request))
+        );
+
+        let secret = format!("AWS4{}", self.credentials.secret_access_key);
+        let signing_key = [date, &self.region, "s3", "aws4_request"]
+            .into_iter()
+            .fold(secret.into_bytes(), |key, part| hmac(&key, part.as_bytes()));
+
+        format!(
+            "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+            self.credentials.access_key_id,
+            scope,
+            signed_headers,
+            hex::encode(hmac(&signing_key, string_to_sign.as_bytes()))
+        )
+    }
+
+    fn head(&self, key: &str) -> Result<Option<ureq::Response>> {
+        match self.request("HEAD", Some(key), &[]).call() {
+            Ok(response) => Ok(Some(response)),
+            Err(ureq::Error::Status(404, _)) => Ok(None),
+            Err(err) => Err(request_error(err, "HEAD", key)),
+        }
+    }
+
+    fn put_object(&self, key: &str, data: &[u8]) -> Result<()> {
+        self.request("PUT", Some(key), &[])
+            .send_bytes(data)
+            .map_err(|err| request_error(err, "PUT", key))?;
+        Ok(())
+    }
+}
+
+impl Storage for S3Storage {
+    fn create_writer(&self, key: &str) -> Result<Box<dyn ObjectWriter + '_>> {
+        Ok(Box::new(S3Writer {
+            storage: self,
+            key: key.to_owned(),
+            buffer: Vec::with_capacity(PART_SIZE),
+            upload: None,
+        }))
+    }
+
+    fn open_reader(&self, key: &str) -> Result<Box<dyn Read + '_>> {
+        let response = self
+            .request("GET", Some(key), &[])
+            .call()
+            .map_err(|err| request_error(err, "GET", key))?;
+        Ok(Box::new(response.into_reader()))
+    }
+
+    fn read_range(&self, key: &str, offset: u64, len: u64) -> Result<Vec<u8>> {
+        if len == 0 {
+            return Ok(Vec::new());
+        }
+
+        // Range isn't signed, S3 accepts unsigned headers alongside the signed
+        // ones.
+        let response = self
+            .request("GET", Some(key), &[])
+            .set("Range", &format!("bytes={}-{}", offset, offset + len - 1))
+            .call()
+            .map_err(|err| request_error(err, "GET", key))?;
+        // A store that ignores the range answers with the whole object.
+        let skip = if response.status() == 206 { 0 } else { offset };
+        let mut reader = response.into_reader();
+        io::copy(&mut (&mut reader).take(skip), &mut io::sink())?;
+        let mut data = Vec::new();
+        reader.take(len).read_to_end(&mut data)?;
+        Ok(data)
+    }
+
+    fn exists(&self, key: &str) -> Result<bool> {
+        Ok(self.head(key)?.is_some())
+    }
+
+    fn size(&self, key: &str) -> Result<u64> {
+        let response = self
+            .head(key)?
+            .ok_or_else(|| anyhow!("object {} not found", self.full_key(key)))?;
+        response
+            .header("content-length")
+            .and_then(|len| len.parse().ok())
+            .ok_or_else(|| anyhow!("missing content length for {}", self.full_key(key)))
+    }
+
+    fn delete(&self, key: &str) -> Result<()> {
+        match self.request("DELETE", Some(key), &[]).call() {
+            Ok(_) | Err(ureq::Error::Status(404, _)) => Ok(()),
+            Err(err) => Err(request_error(err, "DELETE", key)),
+        }
+    }
+
+    fn list(&self, prefix: &str) -> Result<Vec<String>> {
+        let full_prefix = self.full_key(prefix);
+        let mut keys = Vec::new();
+        let mut continuation_token: Option<String> = None;
+
+        loop {
+            let mut query = vec![("list-type", "2"), ("prefix", full_prefix.as_str())];
+            if let Some(token) = &continuation_token {
+                query.push(("continuation-token", token.as_str()));
+            }
+
+            let body = self
+                .request("GET", None, &query)
+                .call()
+                .and_then(|response| Ok(response.into_string()?))
+                .map_err(|err| request_error(err, "LIST", prefix))?;
+
+            for key in xml_values(&body, "Key") {
+                let key = match self.prefix.is_empty() {
+                    true => key.as_str(),
+                    false => &key[self.prefix.len() + 1..],
+                };
+
+                keys.push(key.to_owned());
+            }
+
+            continuation_token = xml_values(&body, "NextContinuationToken").pop();
+            if continuation_token.is_none() {
+                break;
+            }
+        }
+
+        Ok(keys)
+    }
+
+    // The lock is an object created with a conditional write, which fails if it
+    // already exists. One left behind by a process that died has to be removed
+    // by hand.
+    fn lock(&self, wait: bool) -> Result<Box<dyn Lock + '_>> {
+        let owner = format!("pid {} since {}", process::id(), OffsetDateTime::now_utc());
+        loop {
+            match self
+                .request("PUT", Some(LOCK_KEY), &[])
+                .set("if-none-match", "*")
+                .send_bytes(owner.as_bytes())
+            {
+                Ok(_) => return Ok(Box::new(S3Lock { storage: self })),
+                Err(ureq::Error::Status(409 | 412, _)) if wait => thread::sleep(LOCK_POLL_INTERVAL),
+                Err(ureq::Error::Status(409 | 412, _)) =>
+                    return Err(lock_held_error().context(format!(
+                        "{} exists, remove it if the operation holding it has died",
+                        self.full_key(LOCK_KEY)
+                    ))),
+                Err(err) => return Err(request_error(err, "PUT", LOCK_KEY)),
+            }
+        }
+    }
+
+    fn command_args(&self) -> Result<Vec<String>> {
+        Ok(vec![
+            self.prefix.clone(),
+            "--endpoint".to_owned(),
+            self.endpoint.clone(),
+            "--bucket".to_owned(),
+            self.bucket.clone(),
+            "--region".to_owned(),
+            self.region.clone(),
+        ])
+    }
+}
+
+// Buffers up to a part worth of data, starting a multipart upload once the
+// object outgrows a single part. Objects smaller than that are uploaded with a
+// single PUT on finish.
+struct S3Writer<'a> {
+    storage: &'a S3Storage,
+    key: String,
+    buffer: Vec<u8>,
+    upload: Option<MultipartUpload>,
+}
+
+struct MultipartUpload {
+    id: String,
+    etags: Vec<String>,
+}
+
+impl S3Writer<'_> {
+    fn upload_part(&mut self) -> Result<()> {
+        let upload = match &mut self.upload {
+            Some(upload) => upload,
+            None => {
+                let body = self
+                    .storage
+                    .request("POST", Some(&self.key), &[("uploads", "")])
+                    .call()
+                    .and_then(|response| Ok(response.into_string()?))
+                    .map_err(|err| request_error(err, "POST", &self.key))?;
+                let id = xml_values(&body, "UploadId")
+                    .pop()
+                    .ok_or_else(|| anyhow!("missing upload id for {}", self.key))?;
+                self.upload.insert(MultipartUpload {
+                    id,
+                    etags: Vec::new(),
+                })
+            },
+        };
+
+        let part_number = (upload.etags.len() + 1).to_string();
+        let response = self
+            .storage
+            .request(
+                "PUT",
+                Some(&self.key),
+                &[("partNumber", &part_number), ("uploadId", &upload.id)],
+            )
+            .send_bytes(&self.buffer)
+            .map_err(|err| request_error(err, "PUT", &self.key))?;
+        let etag = response
+            .header("etag")
+            .ok_or_else(|| anyhow!("missing etag for part {} of {}", part_number, self.key))?;
+
+        upload.etags.push(etag.to_owned());
+        self.buffer.clear();
+        Ok(())
+    }
+
+    fn complete(&self, upload: &MultipartUpload) -> Result<()> {
+        let mut body = String::from("<CompleteMultipartUpload>");
+        for (i, etag) in upload.etags.iter().enumerate() {
+            body.push_str(&format!(
+                "<Part><PartNumber>{}</PartNumber><ETag>{}</ETag></Part>",
+                i + 1,
+                xml_escape(etag)
+            ));
+        }
+        body.push_str("</CompleteMultipartUpload>");
+
+        let response = self
+            .storage
+            .request("POST", Some(&self.key), &[("uploadId", &upload.id)])
+            .send_string(&body)
+            .and_then(|response| Ok(response.into_string()?))
+            .map_err(|err| request_error(err, "POST", &self.key))?;
+
+        // Completing an upload can fail after the 200 status has been sent, in
+        // which case the error is only reported in the body.
+        if response.contains("<Error>") {
+            bail!(
+                "failed to complete upload of {}: {}",
+                self.key,
+                xml_values(&response, "Message").join(", ")
+            );
+        }
+
+        Ok(())
+    }
+
+    fn abort(&self, upload: &MultipartUpload) -> Result<()> {
+        self.storage
+            .request("DELETE", Some(&self.key), &[("uploadId", &upload.id)])
+            .call()
+            .map_err(|err| request_error(err, "DELETE", &self.key))?;
+        Ok(())
+    }
+}
+
+impl Write for S3Writer<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = buf.len().min(PART_SIZE - self.buffer.len());
+        self.buffer.extend_from_slice(&buf[..len]);
+        if self.buffer.len() == PART_SIZE {
+            self.upload_part().map_err(io::Error::other)?;
+        }
+
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl ObjectWriter for S3Writer<'_> {
+    fn finish(mut self: Box<Self>) -> Result<()> {
+        if self.upload.is_none() {
+            return self.storage.put_object(&self.key, &self.buffer);
+        }
+
+        if !self.buffer.is_empty() {
+            self.upload_part()?;
+        }
+
+        let upload = self.upload.take().unwrap();
+        let result = self.complete(&upload);
+        if result.is_err() {
+            self.upload = Some(upload);
+        }
+
+        result
+    }
+}
+
+// Parts of an upload that is never completed keep taking up (billed) space
+// until the upload is aborted.
+impl Drop for S3Writer<'_> {
+    fn drop(&mut self) {
+        if let Some(upload) = self.upload.take() {
+            if let Err(err) = self.abort(&upload) {
+                warn!(
+                    "failed to abort upload {} of {}: {:#}",
+                    upload.id, self.key, err
+                );
+            }
+        }
+    }
+}
+
+struct S3Lock<'a> {
+    storage: &'a S3Storage,
+}
+
+impl Lock for S3Lock<'_> {}
+
+impl Drop for S3Lock<'_> {
+    fn drop(&mut self) {
+        if let Err(err) = self.storage.delete(LOCK_KEY) {
+            warn!("failed to release repository lock: {:#}", err);
+        }
+    }
+}
+
+fn request_error(err: ureq::Error, method: &str, key: &str) -> anyhow::Error {
+    match err {
+        ureq::Error::Status(status, response) => {
+            let message = response
+                .into_string()
+                .ok()
+                .and_then(|body| xml_values(&body, "Message").pop())
+                .unwrap_or_default();
+            anyhow!(
+                "{} {} failed with status {}: {}",
+                method,
+                key,
+                status,
+                message
+            )
+        },
+        ureq::Error::Transport(err) => anyhow!("{} {} failed: {}", method, key, err),
+    }
+}
+
+fn hmac(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).unwrap();
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn uri_encode(s: &str, keep_slash: bool) -> String {
+    let mut encoded = String::with_capacity(s.len());
+    for byte in s.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' =>
+                encoded.push(byte as char),
+            b'/' if keep_slash => encoded.push('/'),
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+
+    encoded
+}
+
+// The responses we care about are small and flat enough that pulling out the
+// text of the elements by name beats pulling in an XML parser.
+fn xml_values(body: &str, tag: &str) -> Vec<String> {
+    let open = format!("<{}>", tag);
+    let close = format!("</{}>", tag);
+    let mut values = Vec::new();
+    let mut rest = body;
+
+    while let Some(start) = rest.find(&open) {
+        rest = &rest[start + open.len()..];
+        let Some(end) = rest.find(&close) else {
+            break;
+        };
+
+        values.push(xml_unescape(&rest[..end]));
+        rest = &rest[end + close.len()..];
+    }
+
+    values
+}
+
+fn xml_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn xml_unescape(s: &str) -> String {
+    s.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&#34;", "\"")
+        .replace("&amp;", "&")
+}
+
+#[cfg(all(test, feature = "minio-tests"))]
+mod tests {
+    use std::{env, io::Write};
+
+    use uuid::Uuid;
+
+    use super::{xml_values, S3Storage, LOCK_KEY, PART_SIZE};
+    use crate::storage::Storage;
+
+    const BUCKET: &str = "pgpitr-tests";
+
+    // Expects the MinIO service from docker-compose.yml, MINIO_ENDPOINT and the
+    // AWS_* variables point the tests elsewhere.
+    fn storage() -> S3Storage {
+        for (name, default) in [
+            ("AWS_ACCESS_KEY_ID", "minioadmin"),
+            ("AWS_SECRET_ACCESS_KEY", "minioadmin"),
+        ] {
+            if env::var_os(name).is_none() {
+                env::set_var(name, default);
+            }
+        }
+
+        let endpoint =
+            env::var("MINIO_ENDPOINT").unwrap_or_else(|_| "http://localhost:9000".to_owned());
+        let storage = S3Storage::new(
+            Some(endpoint),
+            BUCKET.to_owned(),
+            "us-east-1".to_owned(),
+            format!("tests/{}", Uuid::new_v4()),
+        )
+        .unwrap();
+
+        match storage.request("PUT", None, &[]).call() {
+            Ok(_) | Err(ureq::Error::Status(409, _)) => storage,
+            Err(err) => panic!("failed to create bucket {}: {}", BUCKET, err),
+        }
+    }
+
+    fn pending_uploads(storage: &S3Storage, key: &str) -> Vec<String> {
+        let body = storage
+            .request(
+                "GET",
+                None,
+                &[("uploads", ""), ("prefix", &storage.full_key(key))],
+            )
+            .call()
+            .unwrap()
+            .into_string()
+            .unwrap();
+        xml_values(&body, "UploadId")
+    }
+
+    #[test]
+    fn small_object_round_trip() {
+        let storage = storage();
+        storage.write("backups/a.manifest", b"hello").unwrap();
+
+        assert!(storage.exists("backups/a.manifest").unwrap());
+        assert!(!storage.exists("backups/b.manifest").unwrap());
+        assert_eq!(storage.size("backups/a.manifest").unwrap(), 5);
+        assert_eq!(storage.read("backups/a.manifest").unwrap(), b"hello");
+        assert_eq!(
+            storage.list("backups/").unwrap(),
+            vec!["backups/a.manifest".to_owned()]
+        );
+    }
+
+    #[test]
+    fn multipart_upload() {
+        let storage = storage();
+        let data = (0..PART_SIZE * 2 + 1234)
+            .map(|i| (i % 251) as u8)
+            .collect::<Vec<_>>();
+
+        let mut writer = storage.create_writer("bundles/big.tar.zst").unwrap();
+        for chunk in data.chunks(1024 * 1024 + 7) {
+            writer.write_all(chunk).unwrap();
+        }
+        writer.finish().unwrap();
+
+        assert_eq!(
+            storage.size("bundles/big.tar.zst").unwrap(),
+            data.len() as u64
+        );
+        assert!(storage.read("bundles/big.tar.zst").unwrap() == data);
+        assert!(pending_uploads(&storage, "bundles/big.tar.zst").is_empty());
+    }
+
+    #[test]
+    fn unfinished_upload_is_aborted() {
+        let storage = storage();
+        let mut writer = storage.create_writer("bundles/partial.tar.zst").unwrap();
+        writer.write_all(&vec![0; PART_SIZE + 1]).unwrap();
+        assert_eq!(
+            pending_uploads(&storage, "bundles/partial.tar.zst").len(),
+            1
+        );
+
+        drop(writer);
+        assert!(pending_uploads(&storage, "bundles/partial.tar.zst").is_empty());
+        assert!(!storage.exists("bundles/partial.tar.zst").unwrap());
+    }
+
+    #[test]
+    fn lock_is_exclusive() {
+        let storage = storage();
+        let lock = storage.lock(false).unwrap();
+        assert!(storage.lock(false).is_err());
+
+        drop(lock);
+        assert!(!storage.exists(LOCK_KEY).unwrap());
+        drop(storage.lock(false).unwrap());
+    }
+}
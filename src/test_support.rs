@@ -0,0 +1,38 @@
+use std::{
+    env,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use uuid::Uuid;
+
+use crate::{context::Context, storage::LocalStorage};
+
+// A scratch directory for tests with a repository in `repo` and a data
+// directory in `data`, removed again once dropped.
+pub(crate) struct TestDir {
+    pub(crate) root: PathBuf,
+    pub(crate) ctx: Context,
+}
+
+impl TestDir {
+    pub(crate) fn new() -> Self {
+        let root = env::temp_dir().join(format!("pgpitr-test-{}", Uuid::new_v4()));
+        fs::create_dir_all(root.join("data")).unwrap();
+        let ctx = Context::new(
+            Box::new(LocalStorage::new(root.join("repo"))),
+            root.join("data"),
+        );
+        Self { root, ctx }
+    }
+
+    pub(crate) fn path(&self, path: impl AsRef<Path>) -> PathBuf {
+        self.root.join(path)
+    }
+}
+
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.root);
+    }
+}
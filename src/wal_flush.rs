@@ -0,0 +1,147 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    os::fd::AsRawFd,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context as _, Result};
+use clap::Args;
+use log::info;
+
+use crate::{
+    context::Context,
+    wal_push::{self, CompressionOptions},
+};
+
+const LOCK_FILE: &str = ".lock";
+const PARTIAL_SUFFIX: &str = ".partial";
+
+#[derive(Debug, Args)]
+pub struct Options {
+    /// Spool directory given to wal-push --spool
+    #[arg(long, value_name = "DIR")]
+    pub spool: PathBuf,
+
+    #[command(flatten)]
+    pub compression: CompressionOptions,
+}
+
+pub fn run(ctx: &Context, opts: &Options) -> Result<()> {
+    let flushed = flush(ctx, &opts.spool, &opts.compression, true)?;
+    info!("archived {} WAL files from {:?}", flushed, opts.spool);
+    Ok(())
+}
+
+// Segments are copied rather than hardlinked, Postgres recycles segments by
+// renaming and overwriting them once they are archived.
+pub fn spool(spool: &Path, name: &str, data: &[u8]) -> Result<()> {
+    wal_push::check_name(name)?;
+    fs::create_dir_all(spool).with_context(|| format!("failed to create {:?}", spool))?;
+    let path = spool.join(name);
+    match fs::read(&path) {
+        Ok(existing) if existing == data => {
+            info!("WAL file {} already spooled, skipping", name);
+            return Ok(());
+        },
+        Ok(_) => bail!("WAL file {} already spooled with different contents", name),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => (),
+        Err(err) => return Err(err).with_context(|| format!("failed to read {:?}", path)),
+    }
+
+    let partial_path = spool.join(format!("{}{}", name, PARTIAL_SUFFIX));
+    let mut file = File::create(&partial_path)
+        .with_context(|| format!("failed to create {:?}", partial_path))?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    fs::rename(&partial_path, &path)
+        .with_context(|| format!("failed to rename {:?}", partial_path))?;
+    File::open(spool)?.sync_all()?;
+    info!("spooled WAL file {} to {:?}", name, path);
+    Ok(())
+}
+
+pub fn spool_size(spool: &Path) -> Result<u64> {
+    spooled_files(spool)?
+        .iter()
+        .try_fold(0, |size, (_, path)| Ok(size + fs::metadata(path)?.len()))
+}
+
+// Archives the spooled files oldest first, stopping at the first failure so
+// that the archive never has a gap behind a later segment. Without `wait` an
+// ongoing flush is left to finish instead.
+pub fn flush(
+    ctx: &Context,
+    spool: &Path,
+    compression: &CompressionOptions,
+    wait: bool,
+) -> Result<usize> {
+    if !spool.exists() {
+        return Ok(0);
+    }
+
+    let lock_path = spool.join(LOCK_FILE);
+    let lock = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&lock_path)
+        .with_context(|| format!("failed to open {:?}", lock_path))?;
+    let operation = if wait {
+        libc::LOCK_EX
+    } else {
+        libc::LOCK_EX | libc::LOCK_NB
+    };
+
+    // SAFETY: the descriptor belongs to `lock`, which outlives the call.
+    if unsafe { libc::flock(lock.as_raw_fd(), operation) } != 0 {
+        let err = io::Error::last_os_error();
+        if err.kind() == io::ErrorKind::WouldBlock {
+            info!("spool {:?} is being flushed already", spool);
+            return Ok(0);
+        }
+
+        return Err(err).with_context(|| format!("failed to lock {:?}", lock_path));
+    }
+
+    let files = spooled_files(spool)?;
+    for (name, path) in &files {
+        let data = fs::read(path).with_context(|| format!("failed to read {:?}", path))?;
+        wal_push::push(ctx, name, &data, compression)?;
+        fs::remove_file(path)?;
+    }
+
+    if !files.is_empty() {
+        File::open(spool)?.sync_all()?;
+    }
+
+    Ok(files.len())
+}
+
+// WAL file names sort in the order they were written in. Partial files are
+// left by a push that failed before syncing its copy, which was never
+// acknowledged and will be pushed again.
+fn spooled_files(spool: &Path) -> Result<Vec<(String, PathBuf)>> {
+    let entries = match fs::read_dir(spool) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err).with_context(|| format!("failed to read {:?}", spool)),
+    };
+
+    let mut files = Vec::new();
+    for entry in entries {
+        let entry = entry?;
+        let Ok(name) = entry.file_name().into_string() else {
+            continue;
+        };
+
+        if name.starts_with('.') || name.ends_with(PARTIAL_SUFFIX) {
+            continue;
+        }
+
+        files.push((name, entry.path()));
+    }
+
+    files.sort();
+    Ok(files)
+}
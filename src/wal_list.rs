@@ -0,0 +1,74 @@
+use std::io;
+
+use anyhow::Result;
+use clap::Args;
+use serde::Serialize;
+
+use crate::{context::Context, wal_pull::WalFileKind};
+
+#[derive(Debug, Args)]
+pub struct Options {
+    #[arg(long)]
+    pub json: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct WalRecord {
+    name: String,
+    /// `segment`, `partial`, `history` or `backup`, missing for keys that
+    /// aren't named like anything Postgres archives.
+    kind: Option<&'static str>,
+    /// Bytes in the repository, after compression.
+    size: u64,
+    key: String,
+}
+
+// Lists the archived WAL files by name, which sorts segments by timeline and
+// position. Partial segments are listed under their own name and kind, wal-pull
+// only serves them in place of the segment when the timeline history allows.
+pub fn run(ctx: &Context, opts: &Options) -> Result<()> {
+    let mut records = Vec::new();
+    for key in ctx.storage.list("wal/")? {
+        let name = key
+            .trim_start_matches("wal/")
+            .split('-')
+            .next()
+            .unwrap_or_default()
+            .to_owned();
+        records.push(WalRecord {
+            kind: WalFileKind::of(&name).map(kind_name),
+            size: ctx.storage.size(&key)?,
+            name,
+            key,
+        });
+    }
+
+    records.sort_by(|a, b| a.name.cmp(&b.name));
+
+    if opts.json {
+        serde_json::to_writer_pretty(io::stdout().lock(), &records)?;
+        println!();
+        return Ok(());
+    }
+
+    println!("{:<40} {:<8} {:>12}", "NAME", "KIND", "SIZE (KiB)");
+    for record in &records {
+        println!(
+            "{:<40} {:<8} {:>12}",
+            record.name,
+            record.kind.unwrap_or("unknown"),
+            record.size / 1024
+        );
+    }
+
+    Ok(())
+}
+
+fn kind_name(kind: WalFileKind) -> &'static str {
+    match kind {
+        WalFileKind::Segment => "segment",
+        WalFileKind::Partial => "partial",
+        WalFileKind::History => "history",
+        WalFileKind::BackupHistory => "backup",
+    }
+}
@@ -1,49 +1,237 @@
 use std::{
+    fmt,
     fs::{self, File},
-    io,
-    io::Write,
+    io::{self, Read, Write},
     path::PathBuf,
 };
 
-use anyhow::{anyhow, bail, Result};
-use clap::Args;
+use anyhow::{anyhow, bail, Context as _, Result};
+use clap::{ArgGroup, Args};
 use log::info;
+use scopeguard::{guard, ScopeGuard};
 
-use crate::context::Context;
+use crate::{
+    backup::{create, restore},
+    context::Context,
+    init,
+};
 
 #[derive(Debug, Args)]
+#[command(group(ArgGroup::new("wal_name").args(["name", "name_arg"]).required(true)))]
+#[command(group(ArgGroup::new("wal_path").args(["path", "path_arg"]).required(true)))]
 pub struct Options {
-    #[arg(long)]
-    pub path: PathBuf,
+    /// File name of the segment or history file, as %f in restore_command
+    #[arg(value_name = "NAME")]
+    name_arg: Option<String>,
+
+    /// Path to write it to relative to the cluster data directory, as %p in
+    /// restore_command
+    #[arg(value_name = "PATH")]
+    path_arg: Option<PathBuf>,
 
+    #[arg(long, alias = "target-path")]
+    pub path: Option<PathBuf>,
+
+    #[arg(long, alias = "wal-name")]
+    pub name: Option<String>,
+
+    /// Restore the WAL file even if the cluster is a different one than the
+    /// repository holds backups of
     #[arg(long)]
-    pub name: String,
+    pub force_system_id: bool,
+}
+
+impl Options {
+    fn name(&self) -> &str {
+        self.name.as_deref().or(self.name_arg.as_deref()).unwrap()
+    }
+
+    fn path(&self) -> &PathBuf {
+        self.path.as_ref().or(self.path_arg.as_ref()).unwrap()
+    }
 }
 
+/// Returned when the archive doesn't have the requested file. Postgres asks
+/// for files past the end of the archive all the time, so this isn't reported
+/// as an error, only through the exit code.
+#[derive(Debug)]
+pub struct NotFound(String);
+
+impl NotFound {
+    pub const EXIT_CODE: i32 = 1;
+}
+
+impl fmt::Display for NotFound {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "WAL file {} not found", self.0)
+    }
+}
+
+impl std::error::Error for NotFound {}
+
+// A missing file fails the command, which is how restore_command tells
+// Postgres that the archive has nothing more to replay. The file is written
+// next to the destination and renamed into place once complete, so a killed
+// invocation never leaves Postgres a truncated file under the name it asked
+// for.
 pub fn run(ctx: &Context, opts: &Options) -> Result<()> {
-    info!("pulling WAL file {}...", opts.name);
-    let wal_dir_path = ctx.storage.join("wal");
-    let entries = fs::read_dir(&wal_dir_path)?
-        .map(|res| res.map(|e| e.file_name()))
-        .collect::<Result<Vec<_>, io::Error>>()?;
-
-    let wal_file = entries
-        .iter()
-        .find(|file_name| {
-            let name = file_name.to_string_lossy();
-            name.split("-").nth(0) == Some(&opts.name)
-        })
-        .ok_or_else(|| anyhow::anyhow!("WAL file not found"))?;
-
-    let wal_str = wal_file.to_string_lossy();
-    let stored_checksum = wal_str
-        .trim_end_matches(".zst")
-        .split("-")
-        .nth(1)
-        .ok_or_else(|| anyhow!("WAL file name is invalid"))?;
+    init::check_data_dir(ctx, opts.force_system_id)?;
+    let name = opts.name();
+    info!("pulling WAL file {}...", name);
+
+    let dest_path = ctx.cluster_data.join(opts.path());
+    let mut partial_path = dest_path.clone().into_os_string();
+    partial_path.push(".pgpitr.partial");
+    let partial_path = guard(PathBuf::from(partial_path), |path| {
+        let _ = fs::remove_file(path);
+    });
+
+    if let Some((wal_key, stored_checksum)) = find_wal(ctx, name)? {
+        info!("restoring WAL file to {:?}", dest_path);
+        let mut decoder = decoder(&wal_key, ctx.storage.open_reader(&wal_key)?)?;
+        let mut dest = HashingWriter {
+            inner: File::create(&*partial_path)
+                .with_context(|| format!("failed to create {:?}", *partial_path))?,
+            hasher: blake3::Hasher::new(),
+        };
+        io::copy(&mut decoder, &mut dest)
+            .with_context(|| format!("failed to restore {} from {}", name, wal_key))?;
+
+        if hex::encode(dest.hasher.finalize().as_bytes()) != stored_checksum {
+            bail!("WAL checksum mismatch in {}", wal_key);
+        }
+
+        dest.inner.sync_all()?;
+    } else if let Some(data) = partial_segment(ctx, name)? {
+        info!(
+            "restoring WAL file {}.partial in place of {} to {:?}",
+            name, name, dest_path
+        );
+        let mut dest = File::create(&*partial_path)
+            .with_context(|| format!("failed to create {:?}", *partial_path))?;
+        dest.write_all(&data)?;
+        dest.sync_all()?;
+    } else {
+        let err = NotFound(name.to_owned());
+        info!("{}", err);
+        return Err(err.into());
+    }
+
+    fs::rename(&*partial_path, &dest_path)
+        .with_context(|| format!("failed to rename {:?}", *partial_path))?;
+    ScopeGuard::into_inner(partial_path);
+    info!("WAL file restored");
+    Ok(())
+}
+
+// A standby promoted in the middle of a segment starts its new timeline with a
+// copy of that segment and archives the old timeline's one as
+// `{segment}.partial`, since it was never completed. Postgres only ever asks
+// for the segment itself, which of the two files is served for it goes:
+//
+// 1. The segment, whenever it is archived.
+// 2. Its `.partial` file, under the name of the segment, if the history file
+//    of a later timeline shows the segment's timeline ending in it. The WAL in
+//    it is only valid up to that switch, which is as far as recovery along the
+//    old timeline reads.
+// 3. Neither, a `.partial` file without such a history is the leftover of a
+//    timeline Postgres can't tell the end of.
+//
+// The history files are only read once the segment is missing, which Postgres
+// runs into at the end of every recovery, so only when a `.partial` file is
+// archived as well.
+fn partial_segment(ctx: &Context, name: &str) -> Result<Option<Vec<u8>>> {
+    if WalFileKind::of(name) != Some(WalFileKind::Segment)
+        || find_wal(ctx, &format!("{}.partial", name))?.is_none()
+    {
+        return Ok(None);
+    }
+
+    let data = read_wal(ctx, &format!("{}.partial", name))?;
+    let Some(segment_size) = header_segment_size(&data) else {
+        bail!("WAL file {}.partial has no valid page header", name);
+    };
+
+    let mut switches = Vec::new();
+    for key in ctx.storage.list("wal/")? {
+        let Some(history_name) = key
+            .strip_prefix("wal/")
+            .and_then(|key_name| key_name.split('-').next())
+            .filter(|key_name| WalFileKind::of(key_name) == Some(WalFileKind::History))
+        else {
+            continue;
+        };
+
+        let contents = String::from_utf8(read_wal(ctx, history_name)?)
+            .with_context(|| format!("{} isn't valid UTF-8", history_name))?;
+        switches.extend(
+            parse_history(&contents)
+                .with_context(|| format!("failed to parse {}", history_name))?,
+        );
+    }
+
+    if !ends_timeline(name, &switches, segment_size) {
+        info!(
+            "not restoring WAL file {}.partial, no timeline history ends its timeline in it",
+            name
+        );
+        return Ok(None);
+    }
+
+    Ok(Some(data))
+}
+
+// The segment size from the long header of the first page, as wal-push checks
+// it.
+fn header_segment_size(data: &[u8]) -> Option<u64> {
+    let segment_size = u32::from_ne_bytes(data.get(32..36)?.try_into().unwrap()) as u64;
+    (segment_size.is_power_of_two() && (1 << 20..=1 << 30).contains(&segment_size))
+        .then_some(segment_size)
+}
+
+/// Parses a timeline history file into the timelines it lists and the LSN each
+/// one switched to the next at.
+pub fn parse_history(contents: &str) -> Result<Vec<(u32, u64)>> {
+    let mut switches = Vec::new();
+    for line in contents.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let mut fields = line.split_whitespace();
+        let (Some(timeline), Some(lsn)) = (fields.next(), fields.next()) else {
+            bail!("invalid line {:?}", line);
+        };
+        let timeline = timeline
+            .parse()
+            .with_context(|| format!("invalid timeline in line {:?}", line))?;
+        let lsn = restore::parse_lsn(lsn).map_err(|err| anyhow!("{} in line {:?}", err, line))?;
+        switches.push((timeline, lsn));
+    }
+
+    Ok(switches)
+}
 
-    let wal_data = fs::read(wal_dir_path.join(&wal_file))?;
-    let raw_wal_data = zstd::bulk::decompress(&wal_data, usize::MAX)?;
+// Whether a timeline switch happened within the segment, the switch LSN being
+// the first byte of the new timeline. The segment holding the byte before it is
+// the old timeline's last, which is what Postgres archives as `.partial`.
+fn ends_timeline(name: &str, switches: &[(u32, u64)], segment_size: u64) -> bool {
+    let Some((timeline, segment)) = create::parse_wal_segment(name, segment_size) else {
+        return false;
+    };
+
+    switches.iter().any(|(switched, lsn)| {
+        *switched == timeline && *lsn > 0 && (lsn - 1) / segment_size == segment
+    })
+}
+
+/// Fetches a WAL file from the archive, checking it against the checksum in
+/// its key.
+pub fn read_wal(ctx: &Context, name: &str) -> Result<Vec<u8>> {
+    let (wal_key, stored_checksum) =
+        find_wal(ctx, name)?.ok_or_else(|| anyhow!("WAL file {} not found", name))?;
+    let mut raw_wal_data = Vec::new();
+    decoder(&wal_key, ctx.storage.open_reader(&wal_key)?)?.read_to_end(&mut raw_wal_data)?;
     let hash = blake3::hash(&raw_wal_data);
     let checksum = hex::encode(hash.as_bytes());
 
@@ -51,11 +239,225 @@ pub fn run(ctx: &Context, opts: &Options) -> Result<()> {
         bail!("WAL checksum mismatch");
     }
 
-    let dest_path = ctx.cluster_data.join(&opts.path);
-    info!("restoring WAL file to {:?}", dest_path);
-    let mut dest_file = File::create(&dest_path)?;
-    dest_file.write_all(&raw_wal_data)?;
-    dest_file.sync_all()?;
-    info!("WAL file restored");
-    Ok(())
+    Ok(raw_wal_data)
+}
+
+/// The kinds of files Postgres archives, told apart by their names.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WalFileKind {
+    /// `{timeline}{log}{segment}`, 24 hex digits.
+    Segment,
+    /// `{timeline}.history`, the timelines a timeline branched off from.
+    History,
+    /// `{segment}.{offset}.backup`, the start and end of a backup.
+    BackupHistory,
+    /// `{segment}.partial`, the last segment of a timeline a standby was
+    /// promoted in the middle of.
+    Partial,
+}
+
+impl WalFileKind {
+    pub fn of(name: &str) -> Option<Self> {
+        let is_hex = |s: &str, len| s.len() == len && s.chars().all(|c| c.is_ascii_hexdigit());
+        match name.split('.').collect::<Vec<_>>()[..] {
+            [segment] if is_hex(segment, 24) => Some(Self::Segment),
+            [timeline, "history"] if is_hex(timeline, 8) => Some(Self::History),
+            [segment, offset, "backup"] if is_hex(segment, 24) && is_hex(offset, 8) =>
+                Some(Self::BackupHistory),
+            [segment, "partial"] if is_hex(segment, 24) => Some(Self::Partial),
+            _ => None,
+        }
+    }
+}
+
+/// Returns the key of the WAL file and the checksum recorded in it.
+pub fn find_wal(ctx: &Context, name: &str) -> Result<Option<(String, String)>> {
+    let entries = ctx.storage.list(&format!("wal/{}-", name))?;
+
+    let Some(wal_key) = entries.into_iter().find(|key| {
+        let key_name = key.trim_start_matches("wal/");
+        key_name.split("-").next() == Some(name)
+    }) else {
+        return Ok(None);
+    };
+
+    let stored_checksum = wal_key
+        .trim_end_matches(".zst")
+        .split("-")
+        .nth(1)
+        .ok_or_else(|| anyhow!("WAL file name is invalid"))?
+        .to_owned();
+
+    Ok(Some((wal_key, stored_checksum)))
+}
+
+/// WAL is stored compressed with zstd, under a `.zst` key, unless it was pushed
+/// with --no-compress.
+pub fn decoder<'a>(key: &str, reader: Box<dyn Read + 'a>) -> Result<Box<dyn Read + 'a>> {
+    if key.ends_with(".zst") {
+        Ok(Box::new(zstd::stream::read::Decoder::new(reader)?))
+    } else {
+        Ok(reader)
+    }
+}
+
+struct HashingWriter<W> {
+    inner: W,
+    hasher: blake3::Hasher,
+}
+
+impl<W> Write for HashingWriter<W>
+where
+    W: Write,
+{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.inner.write(buf)?;
+        self.hasher.update(&buf[..len]);
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ends_timeline, parse_history, partial_segment, WalFileKind};
+    use crate::{
+        test_support::TestDir,
+        wal_push::{self, CompressionOptions},
+    };
+
+    const SEGMENT_SIZE: u64 = 1 << 20;
+
+    fn push(dir: &TestDir, name: &str, data: &[u8]) {
+        let compression = CompressionOptions {
+            compress_level: 3,
+            no_compress: false,
+        };
+        wal_push::push(&dir.ctx, name, data, &compression).unwrap();
+    }
+
+    // Only the segment size of the header matters here.
+    fn segment_data(fill: u8) -> Vec<u8> {
+        let mut data = vec![fill; SEGMENT_SIZE as usize];
+        data[32..36].copy_from_slice(&(SEGMENT_SIZE as u32).to_ne_bytes());
+        data
+    }
+
+    #[test]
+    fn wal_file_kinds() {
+        for (name, kind) in [
+            ("000000010000000000000002", Some(WalFileKind::Segment)),
+            (
+                "000000010000000000000002.partial",
+                Some(WalFileKind::Partial),
+            ),
+            ("00000002.history", Some(WalFileKind::History)),
+            (
+                "000000010000000000000002.00000028.backup",
+                Some(WalFileKind::BackupHistory),
+            ),
+            ("00000001000000000000002.partial", None),
+            ("000000010000000000000002.partial.tmp", None),
+            ("00000002.partial", None),
+        ] {
+            assert_eq!(WalFileKind::of(name), kind, "{}", name);
+        }
+    }
+
+    #[test]
+    fn timeline_history() {
+        let history = "1\t0/3000000\tno recovery target specified\n\n# \
+                       comment\n2\t1/A0000D8\tbefore 2024-05-02 10:00:00+00\n";
+        assert_eq!(
+            parse_history(history).unwrap(),
+            [(1, 0x3000000), (2, 0x1_0A00_00D8)]
+        );
+        assert!(parse_history("1\n").is_err());
+        assert!(parse_history("x\t0/3000000\n").is_err());
+        assert!(parse_history("1\t3000000\n").is_err());
+    }
+
+    #[test]
+    fn partial_segment_ends_its_timeline() {
+        let switches = [(1, 0x2_0018_0000), (2, 0x3_0000_0000)];
+        // The switch is within the segment, or at its very end.
+        assert!(ends_timeline(
+            "000000010000000200000001",
+            &switches,
+            SEGMENT_SIZE
+        ));
+        assert!(ends_timeline(
+            "000000020000000200000FFF",
+            &switches,
+            SEGMENT_SIZE
+        ));
+        // Right before or after the segment, on another timeline, or with
+        // another segment size.
+        assert!(!ends_timeline(
+            "000000010000000200000000",
+            &switches,
+            SEGMENT_SIZE
+        ));
+        assert!(!ends_timeline(
+            "000000010000000200000002",
+            &switches,
+            SEGMENT_SIZE
+        ));
+        assert!(!ends_timeline(
+            "000000020000000200000001",
+            &switches,
+            SEGMENT_SIZE
+        ));
+        assert!(!ends_timeline(
+            "000000010000000200000001",
+            &switches,
+            16 << 20
+        ));
+        assert!(!ends_timeline(
+            "000000010000000200000001",
+            &[],
+            SEGMENT_SIZE
+        ));
+    }
+
+    #[test]
+    fn serves_partial_segments_by_precedence() {
+        let dir = TestDir::new();
+        let partial = segment_data(1);
+        push(&dir, "000000010000000000000003.partial", &partial);
+
+        // Without a history the end of timeline 1 is unknown.
+        assert!(partial_segment(&dir.ctx, "000000010000000000000003")
+            .unwrap()
+            .is_none());
+
+        push(
+            &dir,
+            "00000002.history",
+            b"1\t0/380000\tno recovery target specified\n",
+        );
+        assert_eq!(
+            partial_segment(&dir.ctx, "000000010000000000000003").unwrap(),
+            Some(partial)
+        );
+        // Other segments have no partial file, and the partial file itself is
+        // only found under its own name.
+        assert!(partial_segment(&dir.ctx, "000000010000000000000004")
+            .unwrap()
+            .is_none());
+        assert!(
+            partial_segment(&dir.ctx, "000000010000000000000003.partial")
+                .unwrap()
+                .is_none()
+        );
+
+        // A history ending the timeline elsewhere doesn't count.
+        push(&dir, "000000010000000000000005.partial", &segment_data(2));
+        assert!(partial_segment(&dir.ctx, "000000010000000000000005")
+            .unwrap()
+            .is_none());
+    }
 }
@@ -1,67 +1,703 @@
 use std::{
     fs::{self, File},
-    io::Write,
-    path::PathBuf,
+    path::{Path, PathBuf},
 };
 
-use anyhow::{bail, Result};
-use clap::Args;
-use log::info;
+use anyhow::{anyhow, bail, Context as _, Result};
+use clap::{ArgGroup, Args};
+use log::{error, info, warn};
 
-use crate::context::Context;
+use crate::{
+    backup::{create, manifest},
+    context::Context,
+    wal_flush,
+    wal_pull::{self, WalFileKind},
+};
+
+// XLogLongPageHeaderData, which every segment starts with.
+const LONG_PAGE_HEADER_SIZE: usize = 40;
+const XLP_LONG_HEADER: u16 = 0x0002;
+
+// XLOG_PAGE_MAGIC of each major version, which changes whenever the WAL
+// format does.
+const PAGE_MAGIC: [(u32, u16); 8] = [
+    (10, 0xD097),
+    (11, 0xD098),
+    (12, 0xD101),
+    (13, 0xD106),
+    (14, 0xD10D),
+    (15, 0xD110),
+    (16, 0xD113),
+    (17, 0xD116),
+];
 
 #[derive(Debug, Args)]
+#[command(group(ArgGroup::new("wal_path").args(["path", "path_arg"]).required(true)))]
+#[command(group(ArgGroup::new("wal_name").args(["name", "name_arg"]).required(true)))]
 pub struct Options {
+    /// Path of the segment relative to the cluster data directory, as %p in
+    /// archive_command
+    #[arg(value_name = "PATH")]
+    path_arg: Option<PathBuf>,
+
+    /// File name of the segment, as %f in archive_command
+    #[arg(value_name = "NAME")]
+    name_arg: Option<String>,
+
+    #[arg(long, alias = "wal-path")]
+    pub path: Option<PathBuf>,
+
+    #[arg(long, alias = "wal-name")]
+    pub name: Option<String>,
+
+    /// Archive up to N segments, the requested one and the oldest others
+    /// Postgres has marked ready, marking the others done
+    #[arg(
+        long,
+        value_name = "N",
+        default_value_t = 1,
+        value_parser = clap::value_parser!(u64).range(1..),
+        conflicts_with = "spool"
+    )]
+    pub batch: u64,
+
+    /// Copy the segment into DIR and return once the copy is synced, leaving
+    /// it to the next wal-push or wal-flush to archive
+    #[arg(long, value_name = "DIR")]
+    pub spool: Option<PathBuf>,
+
+    /// Archive the segment right away instead of spooling it when the spool
+    /// would grow past this many bytes
+    #[arg(long, default_value_t = 1 << 30, requires = "spool")]
+    pub max_spool_bytes: u64,
+
+    /// Archive segments without checking that their headers match their
+    /// names and sizes, names Postgres wouldn't archive are still refused
     #[arg(long)]
-    pub path: PathBuf,
+    pub no_verify: bool,
+
+    #[command(flatten)]
+    pub compression: CompressionOptions,
+}
+
+#[derive(Debug, Args)]
+pub struct CompressionOptions {
+    /// zstd level segments are compressed with
+    #[arg(
+        long,
+        default_value_t = 3,
+        value_parser = clap::value_parser!(i32).range(1..=22),
+        conflicts_with = "no_compress"
+    )]
+    pub compress_level: i32,
 
+    /// Store segments uncompressed
     #[arg(long)]
-    pub name: String,
+    pub no_compress: bool,
+}
+
+impl Options {
+    fn path(&self) -> &PathBuf {
+        self.path.as_ref().or(self.path_arg.as_ref()).unwrap()
+    }
+
+    fn name(&self) -> &str {
+        self.name.as_deref().or(self.name_arg.as_deref()).unwrap()
+    }
 }
 
 pub fn run(ctx: &Context, opts: &Options) -> Result<()> {
-    let raw_wal_path = ctx.cluster_data.join(&opts.path);
+    let name = opts.name();
+    let raw_wal_path = ctx.cluster_data.join(opts.path());
     info!("pushing WAL file at {:?}", raw_wal_path);
-    let raw_wal_data = fs::read(&raw_wal_path)?;
-    let wal_data = zstd::bulk::compress(&raw_wal_data, 3)?;
-    info!(
-        "compressed WAL from {} bytes to {} bytes, ratio: {:.2}x",
-        raw_wal_data.len(),
-        wal_data.len(),
-        (raw_wal_data.len() as f32) / (wal_data.len() as f32),
-    );
-
-    let hash = blake3::hash(&raw_wal_data);
-    let wal_dir_path = ctx.storage.join("wal");
-    let checksum = hex::encode(hash.as_bytes());
-    let wal_target_path = wal_dir_path.join(format!("{}-{}.zst", opts.name, checksum));
+    let raw_wal_data =
+        fs::read(&raw_wal_path).with_context(|| format!("failed to read {:?}", raw_wal_path))?;
+    if !opts.no_verify {
+        verify_wal_file(ctx, name, &raw_wal_data)?;
+    }
+
+    // The spool is only there to ride out a slow or unavailable repository,
+    // so failing to drain it doesn't fail the push.
+    if let Some(spool) = &opts.spool {
+        if let Err(err) = wal_flush::flush(ctx, spool, &opts.compression, false) {
+            warn!("failed to flush spool {:?}: {:#}", spool, err);
+        }
+
+        let spooled = wal_flush::spool_size(spool)?;
+        if spooled + raw_wal_data.len() as u64 <= opts.max_spool_bytes {
+            return wal_flush::spool(spool, name, &raw_wal_data);
+        }
+
+        warn!(
+            "spool {:?} holds {} bytes, archiving {} synchronously",
+            spool, spooled, name
+        );
+    }
+
+    push(ctx, name, &raw_wal_data, &opts.compression)?;
+
+    // The requested segment is archived, Postgres retries the others anyway.
+    if opts.batch > 1 {
+        let wal_dir = raw_wal_path.parent().unwrap_or(Path::new("."));
+        if let Err(err) = push_ready(
+            ctx,
+            wal_dir,
+            name,
+            opts.batch - 1,
+            !opts.no_verify,
+            &opts.compression,
+        ) {
+            warn!("failed to archive the next ready WAL files: {:#}", err);
+        }
+    }
+
+    Ok(())
+}
+
+// Archives the oldest files in `wal_dir` Postgres has marked ready, other than
+// the one it asked for, renaming each one's `.ready` status file to `.done`
+// once it is stored, which is what Postgres does after archive_command
+// succeeds and makes it skip the file. Stops at the first failure, so a file
+// is never marked done while an older one is still waiting to be archived.
+fn push_ready(
+    ctx: &Context,
+    wal_dir: &Path,
+    name: &str,
+    limit: u64,
+    verify: bool,
+    compression: &CompressionOptions,
+) -> Result<usize> {
+    let status_dir = wal_dir.join("archive_status");
+    let mut ready = Vec::new();
+    for entry in
+        fs::read_dir(&status_dir).with_context(|| format!("failed to read {:?}", status_dir))?
+    {
+        let file_name = entry?.file_name();
+        let Some(ready_name) = file_name
+            .to_str()
+            .and_then(|file_name| file_name.strip_suffix(".ready"))
+        else {
+            continue;
+        };
+
+        if ready_name != name {
+            ready.push(ready_name.to_owned());
+        }
+    }
+
+    ready.sort();
+    ready.truncate(limit.try_into().unwrap_or(usize::MAX));
+    for ready_name in &ready {
+        let path = wal_dir.join(ready_name);
+        info!("pushing ready WAL file at {:?}", path);
+        let data = fs::read(&path).with_context(|| format!("failed to read {:?}", path))?;
+        if verify {
+            verify_wal_file(ctx, ready_name, &data)?;
+        }
+
+        push(ctx, ready_name, &data, compression)?;
+        fs::rename(
+            status_dir.join(format!("{}.ready", ready_name)),
+            status_dir.join(format!("{}.done", ready_name)),
+        )?;
+    }
 
-    if !wal_dir_path.exists() {
-        fs::create_dir(&wal_dir_path)?;
+    if !ready.is_empty() {
+        File::open(&status_dir)?.sync_all()?;
     }
 
-    if wal_target_path.exists() {
-        let existing_data = fs::read(&wal_target_path)?;
-        let existing_hash = blake3::hash(&existing_data);
+    Ok(ready.len())
+}
+
+// Timeline history and backup history files are small text files without a
+// header, they are archived as they are. A `.partial` segment is a whole
+// segment with only its beginning written and gets the same checks.
+fn verify_wal_file(ctx: &Context, name: &str, data: &[u8]) -> Result<()> {
+    match check_name(name)? {
+        WalFileKind::Segment | WalFileKind::Partial => verify_segment(ctx, name, data),
+        WalFileKind::History | WalFileKind::BackupHistory => Ok(()),
+    }
+}
+
+// Names go into repository keys and spool paths, so only the names of files
+// Postgres archives are accepted, with or without --no-verify.
+pub(crate) fn check_name(name: &str) -> Result<WalFileKind> {
+    WalFileKind::of(name).ok_or_else(|| {
+        anyhow!(
+            "{} is not the name of a WAL segment, partial segment, timeline history or backup \
+             history file",
+            name
+        )
+    })
+}
+
+// A truncated or corrupted segment would only be noticed when a restore needs
+// it, so segments are checked against the header of their first page: the
+// magic number of the cluster's version, the segment size, which the file
+// must be, and the address of the page, which must be the start of the
+// segment the file is named after.
+fn verify_segment(ctx: &Context, name: &str, data: &[u8]) -> Result<()> {
+    if data.len() < LONG_PAGE_HEADER_SIZE {
+        bail!("WAL file {} is truncated to {} bytes", name, data.len());
+    }
+
+    let magic = u16::from_ne_bytes(data[0..2].try_into().unwrap());
+    let info = u16::from_ne_bytes(data[2..4].try_into().unwrap());
+    let page_addr = u64::from_ne_bytes(data[8..16].try_into().unwrap());
+    let segment_size = u32::from_ne_bytes(data[32..36].try_into().unwrap()) as u64;
+
+    // Without a known version only the magic number is left unchecked.
+    if let Some((version, expected)) = cluster_page_magic(ctx) {
+        if magic != expected {
+            bail!(
+                "WAL file {} has magic number {:#06X}, PostgreSQL {} writes {:#06X}",
+                name,
+                magic,
+                version,
+                expected
+            );
+        }
+    }
+
+    if info & XLP_LONG_HEADER == 0 {
+        bail!("WAL file {} doesn't start with a long page header", name);
+    }
+
+    if !segment_size.is_power_of_two() || !(1 << 20..=1 << 30).contains(&segment_size) {
+        bail!(
+            "WAL file {} has an invalid segment size of {} bytes in its header",
+            name,
+            segment_size
+        );
+    }
+
+    if data.len() as u64 != segment_size {
+        bail!(
+            "WAL file {} is {} bytes, its header gives a segment size of {} bytes",
+            name,
+            data.len(),
+            segment_size
+        );
+    }
+
+    let segment_name = name.strip_suffix(".partial").unwrap_or(name);
+    let (timeline, segment) = create::parse_wal_segment(segment_name, segment_size).unwrap();
+    if page_addr != segment * segment_size {
+        bail!(
+            "WAL file {} holds segment {} according to its header",
+            name,
+            create::wal_segment_name(timeline, page_addr / segment_size, segment_size)
+        );
+    }
+
+    Ok(())
+}
+
+fn cluster_page_magic(ctx: &Context) -> Option<(u32, u16)> {
+    let version = fs::read_to_string(ctx.cluster_data.join("PG_VERSION")).ok()?;
+    let version = manifest::parse_major_version(version.trim())?;
+    PAGE_MAGIC.into_iter().find(|(known, _)| *known == version)
+}
+
+// Segments are stored under `wal/{name}-{hash}`, with a `.zst` suffix when
+// compressed, where the hash is the blake3 hash of the uncompressed contents.
+pub fn push(
+    ctx: &Context,
+    name: &str,
+    raw_wal_data: &[u8],
+    compression: &CompressionOptions,
+) -> Result<()> {
+    check_name(name)?;
+    let hash = blake3::hash(raw_wal_data);
+    let checksum = hex::encode(hash.as_bytes());
 
-        if existing_hash == hash {
+    // Postgres retries archiving a file until it succeeds, so finding it
+    // already archived with the same contents counts as success, however it
+    // was compressed. The key holds the hash of the contents, so a retry
+    // costs a listing rather than downloading the segment again, and objects
+    // are only ever visible once completely written. Different contents under
+    // the same name mean two clusters share the archive.
+    if let Some((existing_key, existing_checksum)) = wal_pull::find_wal(ctx, name)? {
+        if existing_checksum == checksum {
             info!(
-                "WAL file already exists at {:?} with matching hash, skipping",
-                wal_target_path
+                "WAL file already exists at {} with matching hash, skipping",
+                existing_key
             );
             return Ok(());
-        } else {
-            bail!(
-                "WAL file already exists at {:?} with different hash",
-                wal_target_path
-            );
         }
+
+        error!(
+            "refusing to overwrite {} with different contents, is another cluster archiving into \
+             this repository?",
+            existing_key
+        );
+        bail!(
+            "WAL file already exists at {} with different hash",
+            existing_key
+        );
     }
 
-    info!("writing WAL data to {:?}", wal_target_path);
-    let mut target_file = File::create(&wal_target_path)?;
-    target_file.write_all(&wal_data)?;
-    target_file.sync_all()?;
+    let (wal_target_key, wal_data) = if compression.no_compress {
+        (format!("wal/{}-{}", name, checksum), raw_wal_data.to_vec())
+    } else {
+        let wal_data = zstd::bulk::compress(raw_wal_data, compression.compress_level)?;
+        info!(
+            "compressed WAL from {} bytes to {} bytes, ratio: {:.2}x",
+            raw_wal_data.len(),
+            wal_data.len(),
+            (raw_wal_data.len() as f32) / (wal_data.len() as f32),
+        );
+        (format!("wal/{}-{}.zst", name, checksum), wal_data)
+    };
+
+    info!("writing WAL data to {}", wal_target_key);
+    // The write syncs the object and its directory before returning, so
+    // Postgres only recycles the segment once it is durably archived.
+    ctx.storage.write(&wal_target_key, &wal_data)?;
     info!("completed WAL file push");
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, ops::Deref, path::PathBuf};
+
+    use super::{push_ready, verify_segment, verify_wal_file, CompressionOptions};
+    use crate::{backup::create, test_support::TestDir, wal_flush, wal_pull};
+
+    const SEGMENT_SIZE: u64 = 1 << 20;
+
+    const SEGMENTS: [&str; 4] = [
+        "000000010000000000000001",
+        "000000010000000000000002",
+        "000000010000000000000003",
+        "000000010000000000000004",
+    ];
+
+    struct Cluster(TestDir);
+
+    impl Cluster {
+        // A pg_wal where every segment but the first is marked ready, the
+        // first being the one archive_command was called for.
+        fn new() -> Self {
+            let dir = TestDir::new();
+            let root = &dir.root;
+            fs::create_dir_all(root.join("data/pg_wal/archive_status")).unwrap();
+            for (i, segment) in SEGMENTS.iter().enumerate() {
+                fs::write(
+                    root.join("data/pg_wal").join(segment),
+                    segment_data(segment, i as u8),
+                )
+                .unwrap();
+                fs::write(
+                    root.join("data/pg_wal/archive_status")
+                        .join(format!("{}.ready", segment)),
+                    "",
+                )
+                .unwrap();
+            }
+
+            Self(dir)
+        }
+
+        fn wal_dir(&self) -> PathBuf {
+            self.root.join("data/pg_wal")
+        }
+
+        fn status(&self, segment: &str) -> &'static str {
+            let status_dir = self.wal_dir().join("archive_status");
+            match (
+                status_dir.join(format!("{}.ready", segment)).exists(),
+                status_dir.join(format!("{}.done", segment)).exists(),
+            ) {
+                (true, false) => "ready",
+                (false, true) => "done",
+                _ => "invalid",
+            }
+        }
+
+        fn archived(&self, segment: &str) -> bool {
+            !self
+                .ctx
+                .storage
+                .list(&format!("wal/{}-", segment))
+                .unwrap()
+                .is_empty()
+        }
+    }
+
+    impl Deref for Cluster {
+        type Target = TestDir;
+
+        fn deref(&self) -> &TestDir {
+            &self.0
+        }
+    }
+
+    // A segment of the smallest size with the header Postgres 15 writes.
+    fn segment_data(name: &str, fill: u8) -> Vec<u8> {
+        let (_, segment) = create::parse_wal_segment(name, SEGMENT_SIZE).unwrap();
+        let mut data = vec![fill; SEGMENT_SIZE as usize];
+        data[0..2].copy_from_slice(&0xD110u16.to_ne_bytes());
+        data[2..4].copy_from_slice(&0x0002u16.to_ne_bytes());
+        data[8..16].copy_from_slice(&(segment * SEGMENT_SIZE).to_ne_bytes());
+        data[32..36].copy_from_slice(&(SEGMENT_SIZE as u32).to_ne_bytes());
+        data
+    }
+
+    fn compression() -> CompressionOptions {
+        CompressionOptions {
+            compress_level: 3,
+            no_compress: false,
+        }
+    }
+
+    #[test]
+    fn pushes_ready_files_in_order() {
+        let cluster = Cluster::new();
+        let pushed = push_ready(
+            &cluster.ctx,
+            &cluster.wal_dir(),
+            SEGMENTS[0],
+            2,
+            true,
+            &compression(),
+        )
+        .unwrap();
+        assert_eq!(pushed, 2);
+        // The requested segment is left for Postgres to mark done.
+        assert_eq!(cluster.status(SEGMENTS[0]), "ready");
+        assert!(!cluster.archived(SEGMENTS[0]));
+        for segment in &SEGMENTS[1..3] {
+            assert_eq!(cluster.status(segment), "done");
+            assert!(cluster.archived(segment));
+        }
+        assert_eq!(cluster.status(SEGMENTS[3]), "ready");
+        assert!(!cluster.archived(SEGMENTS[3]));
+    }
+
+    #[test]
+    fn stops_at_the_first_failure() {
+        let cluster = Cluster::new();
+        fs::remove_file(cluster.wal_dir().join(SEGMENTS[2])).unwrap();
+        assert!(push_ready(
+            &cluster.ctx,
+            &cluster.wal_dir(),
+            SEGMENTS[0],
+            3,
+            true,
+            &compression()
+        )
+        .is_err());
+        assert_eq!(cluster.status(SEGMENTS[1]), "done");
+        assert_eq!(cluster.status(SEGMENTS[2]), "ready");
+        // Archiving a later segment would leave a gap in the archive.
+        assert_eq!(cluster.status(SEGMENTS[3]), "ready");
+        assert!(!cluster.archived(SEGMENTS[3]));
+    }
+
+    #[test]
+    fn resumes_after_crash_between_store_and_mark() {
+        let cluster = Cluster::new();
+        // Stored by a previous run that died before marking it done.
+        let data = fs::read(cluster.wal_dir().join(SEGMENTS[1])).unwrap();
+        super::push(&cluster.ctx, SEGMENTS[1], &data, &compression()).unwrap();
+        assert_eq!(cluster.status(SEGMENTS[1]), "ready");
+
+        let pushed = push_ready(
+            &cluster.ctx,
+            &cluster.wal_dir(),
+            SEGMENTS[0],
+            3,
+            true,
+            &compression(),
+        )
+        .unwrap();
+        assert_eq!(pushed, 3);
+        for segment in &SEGMENTS[1..] {
+            assert_eq!(cluster.status(segment), "done");
+            assert!(cluster.archived(segment));
+        }
+    }
+
+    #[test]
+    fn skips_identical_segments() {
+        let cluster = Cluster::new();
+        let data = segment_data(SEGMENTS[0], 0);
+        super::push(&cluster.ctx, SEGMENTS[0], &data, &compression()).unwrap();
+        let keys = cluster
+            .ctx
+            .storage
+            .list(&format!("wal/{}-", SEGMENTS[0]))
+            .unwrap();
+
+        // However it would be stored this time.
+        let uncompressed = CompressionOptions {
+            compress_level: 3,
+            no_compress: true,
+        };
+        super::push(&cluster.ctx, SEGMENTS[0], &data, &uncompressed).unwrap();
+        assert_eq!(
+            cluster
+                .ctx
+                .storage
+                .list(&format!("wal/{}-", SEGMENTS[0]))
+                .unwrap(),
+            keys
+        );
+
+        let err = super::push(
+            &cluster.ctx,
+            SEGMENTS[0],
+            &segment_data(SEGMENTS[0], 1),
+            &compression(),
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("with different hash"), "{}", err);
+    }
+
+    #[test]
+    fn verifies_segment_headers() {
+        let cluster = Cluster::new();
+        let name = SEGMENTS[1];
+        let valid = segment_data(name, 0);
+        verify_segment(&cluster.ctx, name, &valid).unwrap();
+
+        let error = |data: &[u8]| {
+            verify_segment(&cluster.ctx, name, data)
+                .unwrap_err()
+                .to_string()
+        };
+        assert_eq!(
+            error(&valid[..20]),
+            "WAL file 000000010000000000000002 is truncated to 20 bytes"
+        );
+        assert_eq!(
+            error(&valid[..valid.len() / 2]),
+            "WAL file 000000010000000000000002 is 524288 bytes, its header gives a segment size \
+             of 1048576 bytes"
+        );
+        assert_eq!(
+            error(&segment_data(SEGMENTS[2], 0)),
+            "WAL file 000000010000000000000002 holds segment 000000010000000000000003 according \
+             to its header"
+        );
+
+        let mut short_header = valid.clone();
+        short_header[2] = 0;
+        assert_eq!(
+            error(&short_header),
+            "WAL file 000000010000000000000002 doesn't start with a long page header"
+        );
+
+        let mut odd_size = valid.clone();
+        odd_size[32..36].copy_from_slice(&1000u32.to_ne_bytes());
+        assert_eq!(
+            error(&odd_size),
+            "WAL file 000000010000000000000002 has an invalid segment size of 1000 bytes in its \
+             header"
+        );
+
+        // The magic number is only known once the cluster's version is.
+        let mut other_version = valid.clone();
+        other_version[0..2].copy_from_slice(&0xD113u16.to_ne_bytes());
+        verify_segment(&cluster.ctx, name, &other_version).unwrap();
+        fs::write(cluster.root.join("data/PG_VERSION"), "15\n").unwrap();
+        assert_eq!(
+            error(&other_version),
+            "WAL file 000000010000000000000002 has magic number 0xD113, PostgreSQL 15 writes \
+             0xD110"
+        );
+        verify_segment(&cluster.ctx, name, &valid).unwrap();
+    }
+
+    #[test]
+    fn archives_history_files() {
+        let cluster = Cluster::new();
+        let files: [(&str, &[u8]); 2] = [
+            (
+                "00000002.history",
+                b"1\t0/3000000\tno recovery target specified\n",
+            ),
+            (
+                "000000010000000000000002.00000028.backup",
+                b"START WAL LOCATION: 0/2000028 (file 000000010000000000000002)\nSTOP WAL \
+                  LOCATION: 0/2000100 (file 000000010000000000000002)\nCHECKPOINT LOCATION: \
+                  0/2000060\nBACKUP METHOD: streamed\nBACKUP FROM: primary\nSTART TIME: \
+                  2024-05-02 10:00:00 UTC\nLABEL: pgpitr\nSTART TIMELINE: 1\nSTOP TIME: \
+                  2024-05-02 10:00:01 UTC\nSTOP TIMELINE: 1\n",
+            ),
+        ];
+        for (name, data) in files {
+            verify_wal_file(&cluster.ctx, name, data).unwrap();
+            super::push(&cluster.ctx, name, data, &compression()).unwrap();
+            assert_eq!(wal_pull::read_wal(&cluster.ctx, name).unwrap(), data);
+        }
+
+        // A promoted standby archives the segment it switched timelines in
+        // with the header of the segment it is named after.
+        let partial = segment_data(SEGMENTS[1], 0);
+        verify_wal_file(&cluster.ctx, "000000010000000000000002.partial", &partial).unwrap();
+        let err = verify_wal_file(&cluster.ctx, "000000010000000000000003.partial", &partial)
+            .unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("holds segment 000000010000000000000002"),
+            "{}",
+            err
+        );
+
+        // Postgres probes for the history files of timelines that may not
+        // exist.
+        assert!(wal_pull::find_wal(&cluster.ctx, "00000003.history")
+            .unwrap()
+            .is_none());
+
+        for name in [
+            "00000002.history.tmp",
+            "0000002.history",
+            "000000010000000000000002.backup",
+            "00000001000000000000002.partial",
+            "RECOVERYHISTORY",
+            "archive_status",
+        ] {
+            let err = verify_wal_file(&cluster.ctx, name, b"").unwrap_err();
+            assert!(err.to_string().contains("is not the name"), "{}", err);
+        }
+    }
+
+    #[test]
+    fn refuses_unknown_names_without_verify() {
+        let cluster = Cluster::new();
+        let data = segment_data(SEGMENTS[1], 0);
+        let message = "../escape is not the name of a WAL segment, partial segment, timeline \
+                       history or backup history file";
+
+        let err = super::push(&cluster.ctx, "../escape", &data, &compression()).unwrap_err();
+        assert_eq!(err.to_string(), message);
+        let err = wal_flush::spool(&cluster.path("spool"), "../escape", &data).unwrap_err();
+        assert_eq!(err.to_string(), message);
+
+        fs::write(cluster.wal_dir().join("archive_status/escape.ready"), "").unwrap();
+        fs::write(cluster.wal_dir().join("escape"), &data).unwrap();
+        let err = push_ready(
+            &cluster.ctx,
+            &cluster.wal_dir(),
+            SEGMENTS[0],
+            10,
+            false,
+            &compression(),
+        )
+        .unwrap_err();
+        assert!(err.to_string().starts_with("escape is not"), "{}", err);
+        assert_eq!(cluster.status("escape"), "ready");
+        assert!(cluster.archived(SEGMENTS[3]));
+
+        for dir in ["", "repo", "repo/wal"] {
+            for entry in fs::read_dir(cluster.path(dir)).unwrap() {
+                let name = entry.unwrap().file_name();
+                assert!(!name.to_string_lossy().contains("escape"), "{:?}", name);
+            }
+        }
+    }
+}
@@ -0,0 +1,453 @@
+// End to end tests against a throwaway cluster, which needs initdb and pg_ctl
+// on the PATH or in PG_BIN and can't run as root:
+//
+//     cargo test --features postgres-tests --test backup
+#![cfg(feature = "postgres-tests")]
+
+use std::{
+    collections::BTreeMap,
+    env,
+    fs::{self, File, OpenOptions},
+    io::Write,
+    net::TcpListener,
+    path::{Path, PathBuf},
+    process::Command,
+    thread,
+    time::{Duration, Instant},
+};
+
+use pgpitr::{backup::codec::Codec, BackupOptions};
+use uuid::Uuid;
+
+const PG_PITR: &str = env!("CARGO_BIN_EXE_pg_pitr");
+
+struct Cluster {
+    dir: PathBuf,
+    data: PathBuf,
+    repo: PathBuf,
+    port: u16,
+}
+
+impl Cluster {
+    // A fresh cluster archiving its WAL into an initialized repository.
+    fn start() -> Self {
+        let dir = env::temp_dir().join(format!("pgpitr-test-{}", Uuid::new_v4()));
+        let cluster = Self {
+            data: dir.join("data"),
+            repo: dir.join("repo"),
+            dir,
+            port: free_port(),
+        };
+        fs::create_dir_all(&cluster.repo).unwrap();
+
+        pg_command("initdb")
+            .args(["--username", "postgres", "--auth", "trust", "--no-sync"])
+            .arg("--pgdata")
+            .arg(&cluster.data)
+            .status_ok();
+        cluster.configure(
+            &cluster.data,
+            cluster.port,
+            &format!(
+                "archive_mode = on\narchive_command = '{} {} {} wal-push %p %f'\n",
+                PG_PITR,
+                cluster.repo.display(),
+                cluster.data.display()
+            ),
+        );
+        cluster.pg_pitr(&["init"]);
+        start(&cluster.dir, &cluster.data);
+        cluster
+    }
+
+    fn configure(&self, data: &Path, port: u16, extra: &str) {
+        let mut conf = OpenOptions::new()
+            .append(true)
+            .open(data.join("postgresql.conf"))
+            .unwrap();
+        write!(
+            conf,
+            "\nport = {}\nlisten_addresses = '127.0.0.1'\nunix_socket_directories = '{}'\n{}",
+            port,
+            self.dir.display(),
+            extra
+        )
+        .unwrap();
+    }
+
+    fn pg_pitr(&self, args: &[&str]) {
+        Command::new(PG_PITR)
+            .arg(&self.repo)
+            .arg(&self.data)
+            .args(args)
+            .status_ok();
+    }
+
+    // Writes a standalone backup archive to `output` through the command line.
+    fn backup_to(&self, output: &Path) {
+        self.pg_pitr(&[
+            "create-backup",
+            "--port",
+            &self.port.to_string(),
+            "--output",
+            output.to_str().unwrap(),
+            "--wal-method",
+            "fetch",
+        ]);
+    }
+
+    fn backup_options(&self) -> BackupOptions {
+        let mut opts = BackupOptions::default();
+        opts.connection.host("127.0.0.1").port(self.port);
+        opts
+    }
+
+    // Starts the restored cluster at `data` on a port of its own, once it has
+    // recovered.
+    fn start_restored(&self, data: &Path) -> postgres::Client {
+        let port = free_port();
+        self.configure(data, port, "archive_mode = off\n");
+        start(&self.dir, data);
+
+        let deadline = Instant::now() + Duration::from_secs(30);
+        let mut client = connect(port);
+        while client
+            .query_one("SELECT pg_is_in_recovery();", &[])
+            .unwrap()
+            .get::<_, bool>(0)
+        {
+            assert!(Instant::now() < deadline, "recovery didn't finish");
+            thread::sleep(Duration::from_millis(100));
+        }
+
+        client
+    }
+}
+
+impl Drop for Cluster {
+    fn drop(&mut self) {
+        for entry in fs::read_dir(&self.dir).unwrap().flatten() {
+            if entry.path().join("postmaster.pid").exists() {
+                let _ = pg_command("pg_ctl")
+                    .args(["stop", "--mode", "immediate", "--pgdata"])
+                    .arg(entry.path())
+                    .output();
+            }
+        }
+
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+trait StatusOk {
+    fn status_ok(&mut self);
+}
+
+impl StatusOk for Command {
+    fn status_ok(&mut self) {
+        let output = self.output().unwrap();
+        assert!(
+            output.status.success(),
+            "{:?} failed: {}",
+            self,
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+}
+
+fn pg_command(name: &str) -> Command {
+    match env::var_os("PG_BIN") {
+        Some(dir) => Command::new(Path::new(&dir).join(name)),
+        None => Command::new(name),
+    }
+}
+
+fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+fn start(dir: &Path, data: &Path) {
+    let log = dir.join(format!(
+        "{}.log",
+        data.file_name().unwrap().to_string_lossy()
+    ));
+    pg_command("pg_ctl")
+        .args(["start", "--wait", "--pgdata"])
+        .arg(data)
+        .arg("--log")
+        .arg(log)
+        .status_ok();
+}
+
+fn connect(port: u16) -> postgres::Client {
+    postgres::Config::new()
+        .host("127.0.0.1")
+        .port(port)
+        .user("postgres")
+        .connect(postgres::NoTls)
+        .unwrap()
+}
+
+fn seed(client: &mut postgres::Client) {
+    client
+        .batch_execute(
+            "CREATE TABLE accounts (id int PRIMARY KEY, balance bigint NOT NULL);
+             INSERT INTO accounts SELECT i, i * 10 FROM generate_series(1, 10000) i;
+             CREATE TABLE events (id serial PRIMARY KEY, payload text NOT NULL);
+             INSERT INTO events (payload) SELECT md5(i::text) FROM generate_series(1, 5000) i;
+             CREATE INDEX ON events (payload);",
+        )
+        .unwrap();
+}
+
+fn checksum(client: &mut postgres::Client) -> (i64, i64, String) {
+    let row = client
+        .query_one(
+            "SELECT (SELECT sum(balance) FROM accounts)::bigint,
+                    (SELECT count(*) FROM events),
+                    (SELECT md5(string_agg(payload, ',' ORDER BY id)) FROM events);",
+            &[],
+        )
+        .unwrap();
+    (row.get(0), row.get(1), row.get(2))
+}
+
+#[test]
+fn archive_untars_with_backup_label() {
+    let cluster = Cluster::start();
+    seed(&mut connect(cluster.port));
+
+    let output = cluster.dir.join("backup.tar.zst");
+    cluster.backup_to(&output);
+    assert!(!cluster.dir.join("backup.tar.zst.partial").exists());
+
+    let unpacked = cluster.dir.join("unpacked");
+    let decoder = Codec::Zstd.decoder(File::open(&output).unwrap()).unwrap();
+    tar::Archive::new(decoder).unpack(&unpacked).unwrap();
+
+    let backup_label = fs::read_to_string(unpacked.join("backup_label")).unwrap();
+    let start_wal = backup_label
+        .lines()
+        .find_map(|line| line.strip_prefix("START WAL LOCATION: "))
+        .and_then(|location| location.split("(file ").nth(1))
+        .and_then(|file| file.strip_suffix(')'))
+        .expect("backup_label has no START WAL LOCATION");
+    assert!(backup_label.contains("CHECKPOINT LOCATION: "));
+    assert!(unpacked.join("pg_wal").join(start_wal).is_file());
+    assert!(unpacked.join("global/pg_control").is_file());
+    assert!(!unpacked.join("postmaster.pid").exists());
+}
+
+#[test]
+fn archive_round_trip() {
+    let cluster = Cluster::start();
+    let mut client = connect(cluster.port);
+    seed(&mut client);
+    let expected = checksum(&mut client);
+
+    let output = cluster.dir.join("backup.tar.zst");
+    cluster.backup_to(&output);
+    client
+        .batch_execute("UPDATE accounts SET balance = 0;")
+        .unwrap();
+
+    let restored = cluster.dir.join("restored");
+    cluster.pg_pitr(&[
+        "restore",
+        "--input",
+        output.to_str().unwrap(),
+        "--target-dir",
+        restored.to_str().unwrap(),
+    ]);
+    assert_eq!(checksum(&mut cluster.start_restored(&restored)), expected);
+}
+
+#[test]
+fn repository_round_trip() {
+    let cluster = Cluster::start();
+    let mut client = connect(cluster.port);
+    seed(&mut client);
+    client
+        .batch_execute("CREATE TABLE scratch AS SELECT generate_series(1, 1000) i;")
+        .unwrap();
+    let scratch: String = client
+        .query_one("SELECT pg_relation_filepath('scratch')", &[])
+        .unwrap()
+        .get(0);
+
+    let full = pgpitr::backup(
+        &cluster.repo,
+        &cluster.data,
+        &BackupOptions {
+            label: Some("full".to_owned()),
+            ..cluster.backup_options()
+        },
+    )
+    .unwrap();
+    assert_eq!(full.label, "full");
+    assert!(full
+        .backup_label
+        .as_deref()
+        .unwrap()
+        .contains("START WAL LOCATION: "));
+
+    // The truncation by VACUUM and the dropped table happen before the
+    // incremental starts, replay doesn't redo them.
+    client
+        .batch_execute(
+            "UPDATE accounts SET balance = balance + 1 WHERE id % 7 = 0;
+             DELETE FROM events WHERE id % 3 = 0 OR id > 2500;
+             DROP TABLE scratch;",
+        )
+        .unwrap();
+    client.batch_execute("VACUUM events").unwrap();
+    let expected = checksum(&mut client);
+
+    let incremental = pgpitr::backup(
+        &cluster.repo,
+        &cluster.data,
+        &BackupOptions {
+            label: Some("incremental".to_owned()),
+            delta: Some("full".to_owned()),
+            ..cluster.backup_options()
+        },
+    )
+    .unwrap();
+    assert_ne!(incremental.id, full.id);
+
+    let restored = cluster.dir.join("restored");
+    cluster.pg_pitr(&[
+        "restore",
+        "--label",
+        "incremental",
+        "--target-dir",
+        restored.to_str().unwrap(),
+    ]);
+    assert!(!restored.join(&scratch).exists());
+
+    // An export of the chain extracts to the files the restore wrote, other
+    // than its recovery settings.
+    let exported = cluster.dir.join("exported.tar");
+    cluster.pg_pitr(&[
+        "export",
+        "--label",
+        "incremental",
+        "--out",
+        exported.to_str().unwrap(),
+    ]);
+    let unpacked = cluster.dir.join("unpacked");
+    tar::Archive::new(File::open(&exported).unwrap())
+        .unpack(&unpacked)
+        .unwrap();
+    let mut restored_files = files(&restored);
+    restored_files.remove(Path::new("recovery.signal"));
+    restored_files.remove(Path::new("postgresql.auto.conf"));
+    let mut unpacked_files = files(&unpacked);
+    unpacked_files.remove(Path::new("postgresql.auto.conf"));
+    assert_eq!(
+        restored_files.keys().collect::<Vec<_>>(),
+        unpacked_files.keys().collect::<Vec<_>>()
+    );
+    assert!(restored_files == unpacked_files);
+
+    assert_eq!(checksum(&mut cluster.start_restored(&restored)), expected);
+}
+
+// The contents of the files under `dir`, by their path relative to it.
+fn files(dir: &Path) -> BTreeMap<PathBuf, Vec<u8>> {
+    let mut files = BTreeMap::new();
+    let mut dirs = vec![dir.to_owned()];
+    while let Some(next) = dirs.pop() {
+        for entry in fs::read_dir(next).unwrap() {
+            let path = entry.unwrap().path();
+            if path.is_dir() {
+                dirs.push(path);
+            } else {
+                let contents = fs::read(&path).unwrap();
+                files.insert(path.strip_prefix(dir).unwrap().to_owned(), contents);
+            }
+        }
+    }
+
+    files
+}
+
+#[test]
+fn restores_matching_paths() {
+    let cluster = Cluster::start();
+    seed(&mut connect(cluster.port));
+    pgpitr::backup(
+        &cluster.repo,
+        &cluster.data,
+        &BackupOptions {
+            label: Some("full".to_owned()),
+            ..cluster.backup_options()
+        },
+    )
+    .unwrap();
+
+    let restored = cluster.dir.join("restored");
+    cluster.pg_pitr(&[
+        "restore",
+        "--label",
+        "full",
+        "--target-dir",
+        restored.to_str().unwrap(),
+        "--path",
+        "postgresql.conf",
+        "--path",
+        "base/*/PG_VERSION",
+    ]);
+    assert_eq!(
+        fs::read(restored.join("postgresql.conf")).unwrap(),
+        fs::read(cluster.data.join("postgresql.conf")).unwrap()
+    );
+    let mut restored_files = walkdir::WalkDir::new(&restored)
+        .into_iter()
+        .map(|entry| entry.unwrap())
+        .filter(|entry| entry.file_type().is_file())
+        .map(|entry| entry.path().strip_prefix(&restored).unwrap().to_owned())
+        .collect::<Vec<_>>();
+    restored_files.sort();
+    assert_eq!(
+        restored_files.first().unwrap(),
+        Path::new("base/1/PG_VERSION")
+    );
+    assert!(restored_files[..restored_files.len() - 1]
+        .iter()
+        .all(|path| path.starts_with("base") && path.ends_with("PG_VERSION")));
+    assert_eq!(restored_files.last().unwrap(), Path::new("postgresql.conf"));
+    assert!(!restored.join("backup_label").exists());
+}
+
+#[test]
+fn writes_report() {
+    let cluster = Cluster::start();
+    seed(&mut connect(cluster.port));
+
+    let report_path = cluster.dir.join("report.json");
+    cluster.pg_pitr(&[
+        "create-backup",
+        "--port",
+        &cluster.port.to_string(),
+        "--label",
+        "nightly",
+        "--report",
+        report_path.to_str().unwrap(),
+    ]);
+
+    let report: serde_json::Value =
+        serde_json::from_slice(&fs::read(&report_path).unwrap()).unwrap();
+    assert_eq!(report["label"], "nightly");
+    Uuid::parse_str(report["id"].as_str().unwrap()).unwrap();
+    assert!(report["created_at"].as_i64().unwrap() > 0);
+    assert!(report["bytes_read"].as_u64().unwrap() > report["bytes_written"].as_u64().unwrap());
+    assert!(report["compression_ratio"].as_f64().unwrap() > 1.0);
+    assert!(report["duration_secs"].as_f64().unwrap() > 0.0);
+    let start_wal = report["start_wal"].as_str().unwrap();
+    assert!(start_wal.len() == 24 && start_wal.starts_with("00000001"));
+}